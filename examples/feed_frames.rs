@@ -0,0 +1,108 @@
+// Encodes frames rendered by the app itself instead of captured ones, here
+// two seconds of a slowly changing solid color, into feed.mp4.
+use displayrecorder::{
+    apartment::ensure_apartment,
+    d3d::{create_d3d_device, create_direct3d_surface},
+    media::MF_VERSION,
+    video::{
+        encoding_session::{VideoEncoderSessionFactory, VideoEncodingSettings},
+        mf::{encoder_device::VideoEncoderDevice, encoding_session::MFVideoEncodingSessionFactory},
+    },
+};
+use windows::{
+    core::{Result, HSTRING},
+    Foundation::TimeSpan,
+    Graphics::SizeInt32,
+    Win32::{
+        Graphics::{
+            Direct3D11::{D3D11_BIND_RENDER_TARGET, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT},
+            Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC},
+        },
+        Media::MediaFoundation::{
+            MFCreateFile, MFShutdown, MFStartup, MFSTARTUP_FULL, MF_ACCESSMODE_WRITE,
+            MF_FILEFLAGS_NONE, MF_OPENMODE_DELETE_IF_EXIST,
+        },
+    },
+};
+
+const FRAME_RATE: u32 = 60;
+const FRAME_COUNT: u32 = 120;
+
+fn main() -> Result<()> {
+    let _apartment = ensure_apartment()?;
+    unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? };
+
+    let d3d_device = create_d3d_device()?;
+    let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+    let encoder_device = VideoEncoderDevice::enumerate()?
+        .into_iter()
+        .next()
+        .expect("No H264 encoders found!");
+
+    let size = SizeInt32 {
+        Width: 1280,
+        Height: 720,
+    };
+    let texture_desc = D3D11_TEXTURE2D_DESC {
+        Width: size.Width as u32,
+        Height: size.Height as u32,
+        ArraySize: 1,
+        MipLevels: 1,
+        Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            ..Default::default()
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_RENDER_TARGET.0 as u32,
+        ..Default::default()
+    };
+    let texture = unsafe {
+        let mut texture = None;
+        d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+        texture.unwrap()
+    };
+    let render_target_view = unsafe {
+        let mut rtv = None;
+        d3d_device.CreateRenderTargetView(&texture, None, Some(&mut rtv))?;
+        rtv.unwrap()
+    };
+    let surface = create_direct3d_surface(&texture)?;
+
+    let stream = unsafe {
+        MFCreateFile(
+            MF_ACCESSMODE_WRITE,
+            MF_OPENMODE_DELETE_IF_EXIST,
+            MF_FILEFLAGS_NONE,
+            &HSTRING::from("feed.mp4"),
+        )?
+    };
+    let settings = VideoEncodingSettings {
+        frame_rate: FRAME_RATE,
+        ..Default::default()
+    };
+    let factory = MFVideoEncodingSessionFactory::new(encoder_device);
+    let mut session =
+        factory.create_feed_session(d3d_device.clone(), size, &settings, stream.into())?;
+    session.start()?;
+
+    for i in 0..FRAME_COUNT {
+        let t = i as f32 / FRAME_COUNT as f32;
+        unsafe {
+            d3d_context.ClearRenderTargetView(&render_target_view, &[t, 0.2, 1.0 - t, 1.0]);
+        }
+        // The timestamps don't have to start at zero, only increase
+        let timestamp = TimeSpan {
+            Duration: i as i64 * 10_000_000 / FRAME_RATE as i64,
+        };
+        session.submit_frame(&surface, timestamp)?;
+    }
+
+    session.stop()?;
+    println!(
+        "Encoded {} frames to feed.mp4",
+        session.frames_encoded().unwrap_or(0)
+    );
+    unsafe { MFShutdown()? };
+    Ok(())
+}
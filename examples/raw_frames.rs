@@ -0,0 +1,45 @@
+// Reads raw frames from the first display the way another capturer (e.g. a
+// remote desktop tool) would, repacking them into tightly packed BGRA rows
+// before handing them off.
+use displayrecorder::{
+    apartment::ensure_apartment, capture::create_capture_item_for_monitor,
+    displays::get_display_handle_from_index, raw_frame::RawFrameReader,
+};
+use windows::{
+    core::Result,
+    Win32::UI::HiDpi::{SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2},
+};
+
+fn main() -> Result<()> {
+    unsafe {
+        let _ = SetProcessDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2);
+    }
+    let _apartment = ensure_apartment()?;
+
+    let display_handle = get_display_handle_from_index(0).expect("No displays found!");
+    let item = create_capture_item_for_monitor(display_handle)?;
+    let mut reader = RawFrameReader::new(item)?;
+
+    let mut packed = Vec::new();
+    for _ in 0..10 {
+        let frame = if let Some(frame) = reader.next_raw_frame()? {
+            frame
+        } else {
+            break;
+        };
+        // The readback's rows are padded out to the stride
+        packed.clear();
+        for y in 0..frame.height {
+            packed.extend_from_slice(frame.row(y));
+        }
+        println!(
+            "{}x{} frame (stride {}) at {:?}, {} bytes packed",
+            frame.width,
+            frame.height,
+            frame.stride,
+            frame.timestamp,
+            packed.len()
+        );
+    }
+    Ok(())
+}
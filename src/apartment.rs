@@ -0,0 +1,44 @@
+use windows::{
+    core::Result,
+    Win32::System::{
+        Com::{CoGetApartmentType, APTTYPE, APTTYPEQUALIFIER},
+        WinRT::{RoInitialize, RoUninitialize, RO_INIT_MULTITHREADED},
+    },
+};
+
+// Keeps WinRT initialized on the calling thread for as long as it's alive,
+// but only uninitializes it if we were the ones to initialize it.
+pub struct ApartmentGuard {
+    initialized: bool,
+}
+
+impl ApartmentGuard {
+    // Whether the thread already had an apartment, which we left alone.
+    pub fn is_borrowed(&self) -> bool {
+        !self.initialized
+    }
+}
+
+impl Drop for ApartmentGuard {
+    fn drop(&mut self) {
+        if self.initialized {
+            unsafe { RoUninitialize() };
+        }
+    }
+}
+
+// Makes sure the calling thread has an apartment. Threads that were already
+// initialized (e.g. an app's STA UI thread) are used as is instead of failing
+// with RPC_E_CHANGED_MODE, otherwise we join the MTA. Recording works from
+// either, the frame pool is free threaded (unless FramePoolMode::ThreadAffine
+// says otherwise) and encoding happens on our own threads. Keep in mind that Recorder::new blocks while it opens the output,
+// which a UI thread will feel.
+pub fn ensure_apartment() -> Result<ApartmentGuard> {
+    let mut apartment_type = APTTYPE::default();
+    let mut qualifier = APTTYPEQUALIFIER::default();
+    if unsafe { CoGetApartmentType(&mut apartment_type, &mut qualifier) }.is_ok() {
+        return Ok(ApartmentGuard { initialized: false });
+    }
+    unsafe { RoInitialize(RO_INIT_MULTITHREADED)? };
+    Ok(ApartmentGuard { initialized: true })
+}
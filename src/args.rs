@@ -1,35 +1,301 @@
 use clap::{Parser, Subcommand};
 
-use crate::{resolution::Resolution, video::backend::EncoderBackend};
+use displayrecorder::{
+    capture_api::CaptureApi,
+    capture_bit_depth::CaptureBitDepth,
+    capture_source::CaptureSource,
+    desktop::Desktop,
+    resolution::Resolution,
+    video::{
+        audio_codec::AudioCodec, backend::EncoderBackend, bit_rate::BitRate, chroma::Chroma,
+        container::Container, encoder_input::EncoderInput, fit::Fit,
+        input_color_space::InputColorSpace, ladder::Rendition,
+        pixel_aspect_ratio::PixelAspectRatio, preset::Preset, raw_format::RawFormat,
+        scaler::Scaler, timestamp_base::TimestampBase, watermark::WatermarkPosition,
+    },
+};
+use windows::Graphics::SizeInt32;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
-    /// The index of the display you'd like to record.
+    /// What to record: monitor (everything on the display, including the taskbar and fullscreen apps) or desktop (only the shell's desktop window, i.e. the wallpaper and icons, without any other windows).
+    #[clap(long, default_value_t = CaptureSource::Monitor)]
+    pub source: CaptureSource,
+
+    /// The index of the display you'd like to record (only used with --source monitor).
     #[clap(short, long, default_value_t = 0)]
     pub display: usize,
 
-    /// The bit rate you would like to encode at (in Mbps).
-    #[clap(short, long, default_value_t = 18)]
-    pub bit_rate: u32,
+    /// The name of the display you'd like to record instead of its index, e.g. "DELL U2720Q", as listed by the info command. Displays of the same model get " (1)", " (2)" and so on appended, and ones that don't report a name go by their device name (e.g. \\.\DISPLAY1). Case doesn't matter.
+    #[clap(long, conflicts_with = "display")]
+    pub display_name: Option<String>,
+
+    /// How frames are captured: wgc (Windows.Graphics.Capture) or dxgi (DXGI desktop duplication). dxgi can only record a monitor driven by the adapter doing the encoding, doesn't draw the mouse cursor, and can't record rotated displays, but it's a useful point of comparison when the capture seems slow (only available with the mf backend).
+    #[clap(long, default_value_t = CaptureApi::Wgc)]
+    pub capture_api: CaptureApi,
+
+    /// The desktop to record: default, or winlogon for the logon and lock screens. winlogon needs the dxgi capture api and has to be run as SYSTEM in the console session, e.g. from a service (only available with the mf backend).
+    #[clap(long, default_value_t = Desktop::Default)]
+    pub desktop: Desktop,
+
+    /// The bit depth frames are captured at: 8, 10, or 16 (half float). On an HDR display 10 and 16 keep more precision through the conversion, but the output is still 8 bit SDR and anything brighter than SDR white is clipped. Needs the wgc capture api and the auto scaler, and can't be combined with --watermark, --hud-burn-in, --dedup-threshold or snapshots (only available with the mf backend).
+    #[clap(long, default_value_t = CaptureBitDepth::Eight)]
+    pub capture_bit_depth: CaptureBitDepth,
 
-    /// The frame rate you would like to encode at.
-    #[clap(short, long, default_value_t = 60)]
+    /// The gamma the captured frames are converted from: srgb or linear. Defaults to linear for 16 bit captures and srgb otherwise. Getting it wrong makes the output look washed out or crushed. Needs the auto scaler (only available with the mf backend).
+    #[clap(long)]
+    pub input_colorspace: Option<InputColorSpace>,
+
+    /// The bit rate you would like to encode at, in Mbps unless suffixed with k (kbps) or M (Mbps), e.g. 6000k. Use auto to pick one based on the resolution and frame rate.
+    #[clap(short, long, default_value = "18", value_parser = parse_bit_rate)]
+    pub bit_rate: BitRate,
+
+    /// The frame rate you would like to encode at, from 1 to 240. Anything higher is clamped to 240.
+    #[clap(short, long, alias = "output-fps", default_value_t = 60, value_parser = parse_frame_rate)]
     pub frame_rate: u32,
 
+    /// The rate frames are taken from the capture at, which must be at least the output frame rate. Captured frames are then decimated down to the output frame rate, also with --vfr where it acts as a cap. Without it, every captured frame is encoded (only available with the mf backend).
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub capture_fps: Option<u32>,
+
     /// The resolution you would like to encode at: native, 720p, 1080p, 2160p, or 4320p.
     #[clap(short, long, default_value_t = Resolution::Native)]
     pub resolution: Resolution,
 
+    /// How the capture fits into --resolution when their aspect ratios differ: letterbox (pad with black bars), height (keep the height and the capture's aspect ratio, so 1080p on a 3440x1440 ultrawide records at 2580x1080), width (keep the width instead), or stretch (fill it, distorting the image; only available with the mf backend).
+    #[clap(long, default_value_t = Fit::Letterbox)]
+    pub fit: Fit,
+
+    /// Records several renditions of the same capture in one pass, e.g. 1080p@8M,720p@4M,480p@2M. The first one replaces --resolution and --bit-rate for the output file, the rest are written next to it and named after their height (recording-720p.mp4, ...). Sizes are a height (16:9 is assumed) or WIDTHxHEIGHT (only available with the mf backend).
+    #[clap(long, value_delimiter = ',', value_parser = parse_rendition, conflicts_with_all = ["resolution", "fit", "bit_rate"])]
+    pub ladder: Vec<Rendition>,
+
+    /// The pixel aspect ratio to signal in the output (num:den), for content meant for non-square-pixel displays.
+    #[clap(long, default_value_t = PixelAspectRatio::SQUARE)]
+    pub pixel_aspect: PixelAspectRatio,
+
+    /// Forces a keyframe every this many seconds of output (at exactly 0s, 2s, 4s, ... for 2), for streams that get cut into segments later. The encoder may still place keyframes of its own in between. Only available with the mf backend.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keyframe_every: Option<u64>,
+
+    /// The chroma subsampling: 420 (works everywhere) or 444 (sharper colored text, needs an encoder with H.264 High 4:4:4 support and the auto scaler, otherwise falls back to 420 with a warning). Only available with the mf backend.
+    #[clap(long, default_value_t = Chroma::Yuv420)]
+    pub chroma: Chroma,
+
+    /// The format frames are handed to the encoder in: nv12, p010 (10 bit, for encoders that support it), or argb (the encoder converts to YUV itself). Defaults to the best one the encoder takes for the capture bit depth. Fails if the encoder doesn't support the one asked for. Only applies to 420 chroma subsampling and needs the auto scaler for anything but nv12 (only available with the mf backend).
+    #[clap(long)]
+    pub encoder_input: Option<EncoderInput>,
+
+    /// Trades encoding speed for quality: quality, balanced, or speed. Encoders that don't support presets keep their default (only available with the mf backend).
+    #[clap(long)]
+    pub preset: Option<Preset>,
+
+    /// Limits how many threads a software encoder (see --force-software) spreads the work over, so that recording in the background doesn't starve everything else. By default it uses every core. Fewer threads means more CPU headroom, but the encoder may fall behind and drop frames at high resolutions or frame rates. Hardware encoders ignore it (only available with the mf backend).
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub encoder_threads: Option<u32>,
+
+    /// The scaler used to resize and convert frames before encoding: auto, bilinear, or high-quality (only available with the mf backend).
+    #[clap(long, default_value_t = Scaler::Auto)]
+    pub scaler: Scaler,
+
+    /// Draws an image (e.g. a PNG logo) over every frame, keeping its transparency (only available with the mf backend).
+    #[clap(long)]
+    pub watermark: Option<String>,
+
+    /// The corner the watermark goes in: tl, tr, bl, or br.
+    #[clap(long, default_value_t = WatermarkPosition::BottomRight, requires = "watermark")]
+    pub watermark_pos: WatermarkPosition,
+
+    /// How opaque the watermark is, from 0.0 (invisible) to 1.0.
+    #[clap(long, default_value_t = 1.0, requires = "watermark")]
+    pub watermark_opacity: f32,
+
+    /// The watermark's width as a fraction of the frame's width, e.g. 0.1 for a tenth. Without it, the image is drawn at its own size in captured pixels.
+    #[clap(long, requires = "watermark", value_parser = parse_watermark_scale)]
+    pub watermark_scale: Option<f32>,
+
+    /// The timestamp of the first frame: zero (rebase the recording to start at 0, most compatible) or qpc (keep the original system relative time). Only available with the mf backend.
+    #[clap(long, default_value_t = TimestampBase::Zero)]
+    pub timestamp_base: TimestampBase,
+
+    /// Writes a variable frame rate timeline, where each frame lasts until the next one was captured (only available with the mf backend).
+    #[clap(long)]
+    pub vfr: bool,
+
+    /// Multiplies the time between frames in the output by this factor, so that it plays back faster (below 1, for a timelapse) or slower (above 1, for slow motion) than it happened. Capturing and pacing still happen in real time, and the output is signaled at --frame-rate divided by the factor. For example, --capture-fps 1 --frame-rate 1 --speed 0.0333 takes one frame a second and plays them back at 30 fps, turning 30 minutes into 1 minute. --duration is still in real time. Can't be combined with audio, which would fall out of sync (only available with the mf backend).
+    #[clap(long, default_value_t = 1.0, conflicts_with_all = ["silent_audio", "audio_process"])]
+    pub speed: f64,
+
+    /// Skips frames that are identical to the previous one, extending its duration instead. The value is the allowed difference between frames, from 0.0 (identical) to 1.0. Above 0.0 frames are compared at a reduced size, so the threshold is approximate (requires --vfr).
+    #[clap(long, requires = "vfr")]
+    pub dedup_threshold: Option<f32>,
+
+    /// The longest a single frame can be stretched by --dedup-threshold before it is refreshed (in milliseconds).
+    #[clap(long, default_value_t = 1000)]
+    pub dedup_max_duration: u64,
+
+    /// Stops the recording automatically after the given number of seconds.
+    #[clap(long)]
+    pub duration: Option<u64>,
+
+    /// Stops the recording after exactly this many frames have been encoded (only available with the mf backend).
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub frames: Option<u64>,
+
+    /// Suppresses the progress line shown while recording with --duration.
+    #[clap(short, long)]
+    pub quiet: bool,
+
+    /// Replaces the output file if it already exists, without asking. By default you're asked first, or the recording fails when there's no terminal to ask in (or with --quiet).
+    #[clap(long, conflicts_with = "no_overwrite")]
+    pub overwrite: bool,
+
+    /// Fails instead of replacing an output file that already exists, segments and renditions included.
+    #[clap(long)]
+    pub no_overwrite: bool,
+
+    /// Runs the encoder thread at time critical priority (and as an MMCSS capture task) to reduce dropped frames. This can starve other work on the machine (only available with the mf backend).
+    #[clap(long)]
+    pub high_priority: bool,
+
+    /// Tunes the whole pipeline for live use and reports the estimated latency, warning if it can't reach the given target in milliseconds. The estimate is worked out from the frame rate, not measured. This overrides the capture buffer count (1 instead of 2), only ever hands the encoder the newest captured frame (dropping any that queued up behind it), turns on the encoder's low latency mode, and turns off B-frames (only available with the mf backend).
+    #[clap(long)]
+    pub target_latency: Option<u64>,
+
+    /// Follows the window in the foreground, switching the capture over whenever focus moves to another window. The recording keeps the size of the display (or --resolution) and windows are scaled to fit. The desktop, the taskbar, and this console are skipped (only available with the mf backend).
+    #[clap(long)]
+    pub follow_foreground: bool,
+
+    /// Shows an overlay to drag out the part of the screen to record instead of recording a whole display. The display the region is on is recorded regardless of --display, and Esc cancels (only available with the mf backend).
+    #[clap(long, conflicts_with = "follow_foreground")]
+    pub select_region: bool,
+
+    /// The container to write: mp4, or fmp4 (fragmented MP4, which plays back even if the recording is cut short and can be written to a named pipe). Defaults to what the output file's extension implies, and lets any extension be used when given (only available with the mf backend).
+    #[clap(long)]
+    pub container: Option<Container>,
+
+    /// Shorthand for --container fmp4, and an error with any other container.
+    #[clap(long)]
+    pub fragmented: bool,
+
+    /// Adds a silent audio track (AAC unless --audio-codec says otherwise) that lasts as long as the video, for editors and upload targets that reject video-only files (only available with the mf backend).
+    #[clap(long)]
+    pub silent_audio: bool,
+
+    /// Records the audio played by a process (given by id or executable name) and its child processes, instead of silence. Needs Windows build 20348 or later (only available with the mf backend).
+    #[clap(long, conflicts_with = "silent_audio")]
+    pub audio_process: Option<String>,
+
+    /// The codec used for the audio track: aac, mp3, or flac. FLAC can't be stored in an MP4, so it's rejected for now.
+    #[clap(long, default_value_t = AudioCodec::Aac)]
+    pub audio_codec: AudioCodec,
+
+    /// Keeps the alpha channel by writing every frame to its own PNG instead of a video, since H.264 can't store transparency. The output path must end in .png and is used as the base name, e.g. frames.png becomes frames-000001.png, frames-000002.png, and so on. Frames are always at the capture size, and PNG encoding is slow enough that frames may be dropped at high resolutions.
+    #[clap(long)]
+    pub alpha: bool,
+
+    /// Publishes every frame as BGRA to a named shared memory section instead of encoding it, for another process to read (see video::shared_memory for the layout). The section holds the last three frames at the capture size. The output path and backend are ignored.
+    #[clap(long, conflicts_with = "alpha")]
+    pub shared_memory: Option<String>,
+
+    /// How long to wait, in seconds, for the encoder to finish the frames it's still working on once the recording is stopped. Past that, the file is finalized without them (only available with the mf backend).
+    #[clap(long)]
+    pub stop_timeout: Option<u64>,
+
+    /// Sets up the capture and the encoder right away, but only starts recording once resumed with SHIFT+CTRL+P (or ENTER with --console-mode). Unlike a countdown, this waits for as long as it takes. The first frame after resuming is at 0s (only available with the mf backend).
+    #[clap(long)]
+    pub start_paused: bool,
+
+    /// Splits the recording into a new file every this many minutes, named after the output (recording.mp4 becomes recording-001.mp4, recording-002.mp4, and so on). Each file is finalized as soon as the next one starts, so they all play on their own, and a long unattended recording loses at most one segment if something goes wrong. Segments start on a keyframe, so they can run slightly long (only available with the mf backend).
+    #[clap(long)]
+    pub segment: Option<u64>,
+
+    /// Picks up an interrupted --segment recording where it left off, starting after the last segment already on disk instead of overwriting the first one. A last segment that was never finalized (e.g. after a crash or power loss) can't be played and is replaced.
+    #[clap(long, requires = "segment")]
+    pub resume: bool,
+
+    /// How long to wait, in seconds, for the capture to deliver its first frame before giving up with an error. The first frame always comes right away unless the capture is broken. Later gaps this long are only warned about, since nothing on screen may be changing. Defaults to 5, 0 waits forever (only available with the mf backend).
+    #[clap(long)]
+    pub no_frames_timeout: Option<u64>,
+
+    /// Keeps recording while the secure desktop (a UAC prompt, the lock screen, or CTRL+ALT+DEL) is showing. Windows hides it from the capture, so by default the recording pauses until it's gone and the output skips straight past it. With this, the last frame before it stays on screen for as long as it was up instead (only available with the mf backend).
+    #[clap(long)]
+    pub no_secure_desktop_pause: bool,
+
+    /// Writes the output on a separate thread, behind a queue of this many encoded frames, so that a slow disk doesn't hold up encoding. A warning is logged if the queue fills up, after which encoding waits for the disk rather than dropping frames. Everything queued is written before the file is finalized (only available with the mf backend).
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub write_queue: Option<u64>,
+
+    /// Lets the sink writer buffer encoded frames without limit when the output falls behind, instead of holding up encoding (and dropping captured frames) until it catches up. This can use a lot of memory on a slow disk (only available with the mf backend).
+    #[clap(long)]
+    pub no_writer_throttling: bool,
+
+    /// Logs every encoded frame to a CSV file (frame, timestamp, type, size, qp) for quality analysis. Sizes are always there, but not every encoder reports the frame type or QP, those columns are left empty (or "?" for the type) when it doesn't (only available with the mf backend).
+    #[clap(long)]
+    pub stats_csv: Option<String>,
+
+    /// Takes exactly one frame per refresh of the display being recorded (or every few refreshes, whichever gets closest to --frame-rate) and gives them evenly spaced timestamps, repeating the last frame when nothing changed. This makes for jitter-free constant frame rate output, at the cost of waking up on every refresh even when the screen is idle (only available with the mf backend).
+    #[clap(long)]
+    pub vsync_pace: bool,
+
+    /// Records a timelapse, taking a single frame every this many seconds (repeating the last one if nothing changed) and encoding them one frame apart at --frame-rate, e.g. --timelapse-interval 10 --frame-rate 30 turns an hour into 12 seconds. Everything captured in between is thrown away as it arrives. --duration is still in real time. Can't be combined with audio, which would fall out of sync (only available with the mf backend).
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["vsync_pace", "silent_audio", "audio_process"])]
+    pub timelapse_interval: Option<u64>,
+
+    /// Only warns when the recording ends without a single frame, instead of failing. By default an empty recording exits with an error, since it usually means the wrong display was recorded or the capture never started (only available with the mf backend, the others don't count frames).
+    #[clap(long)]
+    pub allow_empty: bool,
+
+    /// Fails instead of warning when the output drive doesn't have enough free space for the recording. The space needed is estimated from the bit rate and --duration.
+    #[clap(long)]
+    pub check_space: bool,
+
+    /// Copies the finished recording into a regular MP4 at this path, without re-encoding it, e.g. to record with --fragmented (which survives being cut short) but hand off a file that every player can seek in. The original is kept. The --on-complete command gets this path instead of the output's.
+    #[clap(long)]
+    pub remux_to: Option<String>,
+
+    /// Decodes some of the finished recording back and warns if the frames are all black or a single color, which some encoder and driver combinations produce without reporting an error. The first second is skipped, since encoders can take a moment to settle. A recording of a black screen warns too.
+    #[clap(long)]
+    pub verify: bool,
+
+    /// A command to run once the recording has been finalized, e.g. to upload or transcode it. Any {file} in the command is replaced with the output path, already quoted. Runs through cmd.exe, and only if the recording succeeded unless --on-complete-always is set.
+    #[clap(long)]
+    pub on_complete: Option<String>,
+
+    /// Runs the --on-complete command even if the recording failed.
+    #[clap(long, requires = "on_complete")]
+    pub on_complete_always: bool,
+
+    /// Fails instead of recording when the encoder doesn't accept the requested bit rate, profile, or frame rate as is. Differences are always logged (only available with the mf backend).
+    #[clap(long)]
+    pub strict: bool,
+
     /// The index of the encoder you'd like to use to record (use enum-encoders command for a list of encoders and their indices).
     #[clap(short, long, default_value_t = 0)]
     pub encoder: usize,
 
+    /// Records with a software encoder even when hardware ones are available, to compare quality and speed. The encoder index picks from the software encoders (see enum-encoders --force-software). Only available with the mf backend.
+    #[clap(long)]
+    pub force_software: bool,
+
+    /// Shows a small always-on-top red dot with the elapsed time in the corner of the recorded display while recording. It's hidden from the recording itself on Windows 10 Version 2004 or later.
+    #[clap(long)]
+    pub indicator: bool,
+
+    /// Shows the frame rate, dropped frames and bit rate under the indicator (see --indicator), updated a few times a second. Dropped frames count every captured frame that wasn't encoded, including ones over the frame rate. Like the indicator, it's hidden from the recording (only available with the mf backend).
+    #[clap(long)]
+    pub hud: bool,
+
+    /// Draws the same stats as --hud into the top left corner of the recording itself (only available with the mf backend).
+    #[clap(long)]
+    pub hud_burn_in: bool,
+
     /// Disables the yellow capture border (only available on Windows 11).
     #[clap(long)]
     pub borderless: bool,
 
-    /// Enables verbose (debug) output.
+    /// Enables verbose (debug) output. RUST_LOG overrides the level when set.
     #[clap(short, long)]
     pub verbose: bool,
 
@@ -41,11 +307,15 @@ pub struct Args {
     #[clap(long)]
     pub console_mode: bool,
 
-    /// The backend to use for the video encoder.
+    /// The backend to use for the video encoder: mf, wmt, or raw (uncompressed frames at the capture size, written to a .raw, .bgra, or .nv12 file).
     #[clap(long, default_value_t = EncoderBackend::MediaFoundation)]
     pub backend: EncoderBackend,
 
-    /// The output file that will contain the recording.
+    /// The pixel format written by the raw backend: bgra, or nv12 (converted the same way frames are for the mf encoder). The file starts with a small header describing the size and format.
+    #[clap(long, default_value_t = RawFormat::Bgra)]
+    pub raw_format: RawFormat,
+
+    /// The output file that will contain the recording. This can also be a named pipe (\\.\pipe\name), which is connected to if it exists and created otherwise, in which case we wait for a reader. Pipes need --container fmp4 with the mf backend. Passing null records without writing anything, e.g. to measure performance.
     #[clap(default_value = "recording.mp4")]
     pub output_file: String,
 
@@ -58,5 +328,264 @@ pub struct Args {
 #[clap(args_conflicts_with_subcommands = true)]
 pub enum Commands {
     /// Lists the available hardware H264 encoders.
-    EnumEncoders,
+    EnumEncoders {
+        /// Lists the software encoders instead.
+        #[clap(long)]
+        force_software: bool,
+    },
+    /// Prints everything needed to diagnose a recording problem: capture support, displays (with the modes they support, to help pick a resolution and frame rate), audio devices, and encoders.
+    Info {
+        /// Prints the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Prints the version, the Windows build, and which capture and encoding features this machine supports, for bug reports. Quicker than info, which also lists every display and device.
+    Capabilities {
+        /// Prints the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Records the display at a few resolutions and bit rates without writing a file, and reports the frame rate the encoder kept up with for each.
+    Benchmark {
+        /// The index of the display to capture.
+        #[clap(short, long, default_value_t = 0)]
+        display: usize,
+
+        /// The index of the encoder to use.
+        #[clap(short, long, default_value_t = 0)]
+        encoder: usize,
+
+        /// Benchmarks a software encoder instead of a hardware one.
+        #[clap(long)]
+        force_software: bool,
+
+        /// The frame rate to ask the encoder for, clamped to 240.
+        #[clap(short, long, default_value_t = 60, value_parser = parse_frame_rate)]
+        frame_rate: u32,
+
+        /// How long to record each configuration for, in seconds.
+        #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+        seconds: u64,
+
+        /// Prints the results as JSON.
+        #[clap(long)]
+        json: bool,
+
+        /// Measures copying frames back to the CPU with one, two and three staging textures instead of encoding.
+        #[clap(long)]
+        readback: bool,
+    },
+}
+
+// Returns the bit rate in bits per second. Bare numbers are in Mbps to stay
+// compatible with how the option used to work.
+fn parse_bit_rate(value: &str) -> Result<BitRate, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(BitRate::Auto);
+    }
+    let (number, multiplier) = match value.chars().last() {
+        Some('k') | Some('K') => (&value[..value.len() - 1], 1_000.0),
+        Some('m') | Some('M') => (&value[..value.len() - 1], 1_000_000.0),
+        _ => (value, 1_000_000.0),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| {
+        format!(
+            "Invalid bit rate '{}'! Expecting e.g. 18, 8M, or 6000k.",
+            value
+        )
+    })?;
+    let bit_rate = (number * multiplier).round();
+    if !bit_rate.is_finite() || bit_rate < 1.0 || bit_rate > u32::MAX as f64 {
+        return Err(format!("The bit rate '{}' is out of range!", value));
+    }
+    Ok(BitRate::Fixed(bit_rate as u32))
+}
+
+// Encoders aren't asked for more than this, even though some would take it.
+pub const MAX_FRAME_RATE: u32 = 240;
+
+// Only rejects what can't be a frame rate at all, values above
+// MAX_FRAME_RATE get clamped (see clamp_frame_rate) with a warning.
+fn parse_frame_rate(value: &str) -> Result<u32, String> {
+    let frame_rate: u32 = value.trim().parse().map_err(|_| {
+        format!(
+            "Invalid frame rate '{}'! Expecting a whole number from 1 to {}.",
+            value, MAX_FRAME_RATE
+        )
+    })?;
+    if frame_rate == 0 {
+        return Err(format!(
+            "The frame rate has to be from 1 to {}!",
+            MAX_FRAME_RATE
+        ));
+    }
+    Ok(frame_rate)
+}
+
+pub fn clamp_frame_rate(frame_rate: u32) -> u32 {
+    frame_rate.min(MAX_FRAME_RATE)
+}
+
+// Anything that leaves the watermark with a size. Above 1 it's wider than
+// the frame and gets cut off, which is allowed.
+fn parse_watermark_scale(value: &str) -> Result<f32, String> {
+    let scale: f32 = value.trim().parse().map_err(|_| {
+        format!(
+            "Invalid watermark scale '{}'! Expecting a fraction of the frame's width, e.g. 0.1.",
+            value
+        )
+    })?;
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err(format!(
+            "The watermark scale has to be above 0, not '{}'!",
+            value
+        ));
+    }
+    Ok(scale)
+}
+
+// e.g. 720p@4M or 1280x720@4000k. The bit rate takes the same forms as
+// --bit-rate, except for auto.
+fn parse_rendition(value: &str) -> Result<Rendition, String> {
+    let (size, bit_rate) = value.trim().split_once('@').ok_or_else(|| {
+        format!(
+            "Invalid rendition '{}'! Expecting e.g. 720p@4M or 1280x720@4M.",
+            value
+        )
+    })?;
+    let size = parse_rendition_size(size.trim())
+        .ok_or_else(|| format!("Invalid rendition size '{}'!", size))?;
+    let bit_rate = match parse_bit_rate(bit_rate)? {
+        BitRate::Fixed(bit_rate) => bit_rate,
+        BitRate::Auto => {
+            return Err(format!(
+                "The rendition '{}' needs an explicit bit rate!",
+                value
+            ))
+        }
+    };
+    Ok(Rendition { size, bit_rate })
+}
+
+fn parse_rendition_size(value: &str) -> Option<SizeInt32> {
+    let size = if let Some(height) = value.strip_suffix(['p', 'P']) {
+        let height: i32 = height.parse().ok()?;
+        // Rounded to the nearest even width
+        let width = ((height as f64 * 16.0 / 9.0 / 2.0).round() as i32) * 2;
+        SizeInt32 {
+            Width: width,
+            Height: height,
+        }
+    } else {
+        let (width, height) = value.split_once(['x', 'X'])?;
+        SizeInt32 {
+            Width: width.parse().ok()?,
+            Height: height.parse().ok()?,
+        }
+    };
+    if size.Width < 2 || size.Height < 2 {
+        return None;
+    }
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use displayrecorder::video::ladder::Rendition;
+    use windows::Graphics::SizeInt32;
+
+    use super::{
+        clamp_frame_rate, parse_bit_rate, parse_frame_rate, parse_rendition, parse_watermark_scale,
+        BitRate, MAX_FRAME_RATE,
+    };
+
+    #[test]
+    fn bit_rate_parsing_test() {
+        assert_eq!(parse_bit_rate("18"), Ok(BitRate::Fixed(18_000_000)));
+        assert_eq!(parse_bit_rate("8M"), Ok(BitRate::Fixed(8_000_000)));
+        assert_eq!(parse_bit_rate("8m"), Ok(BitRate::Fixed(8_000_000)));
+        assert_eq!(parse_bit_rate("6000k"), Ok(BitRate::Fixed(6_000_000)));
+        assert_eq!(parse_bit_rate("6000K"), Ok(BitRate::Fixed(6_000_000)));
+        assert_eq!(parse_bit_rate("2.5M"), Ok(BitRate::Fixed(2_500_000)));
+        assert_eq!(parse_bit_rate("500k"), Ok(BitRate::Fixed(500_000)));
+        assert_eq!(parse_bit_rate("auto"), Ok(BitRate::Auto));
+        assert_eq!(parse_bit_rate("Auto"), Ok(BitRate::Auto));
+
+        assert!(parse_bit_rate("").is_err());
+        assert!(parse_bit_rate("M").is_err());
+        assert!(parse_bit_rate("0").is_err());
+        assert!(parse_bit_rate("-5").is_err());
+        assert!(parse_bit_rate("fast").is_err());
+        assert!(parse_bit_rate("18G").is_err());
+        assert!(parse_bit_rate("5000").is_err());
+    }
+
+    #[test]
+    fn frame_rate_parsing_test() {
+        assert_eq!(parse_frame_rate("1"), Ok(1));
+        assert_eq!(parse_frame_rate("60"), Ok(60));
+        assert_eq!(parse_frame_rate(" 30 "), Ok(30));
+        assert_eq!(parse_frame_rate("240"), Ok(240));
+        assert_eq!(parse_frame_rate("241"), Ok(241));
+
+        assert!(parse_frame_rate("").is_err());
+        assert!(parse_frame_rate("0").is_err());
+        assert!(parse_frame_rate("-1").is_err());
+        assert!(parse_frame_rate("29.97").is_err());
+        assert!(parse_frame_rate("fast").is_err());
+        assert!(parse_frame_rate("99999999999").is_err());
+
+        assert_eq!(clamp_frame_rate(1), 1);
+        assert_eq!(clamp_frame_rate(MAX_FRAME_RATE), MAX_FRAME_RATE);
+        assert_eq!(clamp_frame_rate(MAX_FRAME_RATE + 1), MAX_FRAME_RATE);
+        assert_eq!(clamp_frame_rate(10000), MAX_FRAME_RATE);
+    }
+
+    #[test]
+    fn watermark_scale_parsing_test() {
+        assert_eq!(parse_watermark_scale("0.1"), Ok(0.1));
+        assert_eq!(parse_watermark_scale(" 0.25 "), Ok(0.25));
+        assert_eq!(parse_watermark_scale("1"), Ok(1.0));
+        assert_eq!(parse_watermark_scale("2"), Ok(2.0));
+        assert!(parse_watermark_scale("0").is_err());
+        assert!(parse_watermark_scale("-0.1").is_err());
+        assert!(parse_watermark_scale("inf").is_err());
+        assert!(parse_watermark_scale("NaN").is_err());
+        assert!(parse_watermark_scale("tenth").is_err());
+    }
+
+    #[test]
+    fn rendition_parsing_test() {
+        let rendition = |width, height, bit_rate| Rendition {
+            size: SizeInt32 {
+                Width: width,
+                Height: height,
+            },
+            bit_rate,
+        };
+        assert_eq!(
+            parse_rendition("1080p@8M"),
+            Ok(rendition(1920, 1080, 8_000_000))
+        );
+        assert_eq!(
+            parse_rendition("720p@4000k"),
+            Ok(rendition(1280, 720, 4_000_000))
+        );
+        assert_eq!(
+            parse_rendition("480p@2M"),
+            Ok(rendition(854, 480, 2_000_000))
+        );
+        assert_eq!(
+            parse_rendition("1280x800@5"),
+            Ok(rendition(1280, 800, 5_000_000))
+        );
+
+        assert!(parse_rendition("720p").is_err());
+        assert!(parse_rendition("720p@auto").is_err());
+        assert!(parse_rendition("@4M").is_err());
+        assert!(parse_rendition("0p@4M").is_err());
+        assert!(parse_rendition("1280x@4M").is_err());
+        assert!(parse_rendition("hd@4M").is_err());
+    }
 }
@@ -0,0 +1,366 @@
+use std::{
+    sync::mpsc::channel,
+    time::{Duration, Instant},
+};
+
+use displayrecorder::{
+    apartment::ensure_apartment,
+    d3d::{create_d3d_device, StagingReadback},
+    displays::{get_display_handle_from_index, get_display_info},
+    media::MF_VERSION,
+    recorder::{Recorder, RecorderConfig},
+    video::{
+        backend::EncoderBackend, bit_rate::BitRate, encoding_session::VideoEncodingSettings,
+        output_stream::OutputStream,
+    },
+};
+use windows::{
+    core::{Error, Result},
+    Graphics::SizeInt32,
+    Win32::{
+        Foundation::E_INVALIDARG,
+        Graphics::{
+            Direct3D11::{ID3D11Device, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT},
+            Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC},
+        },
+        Media::MediaFoundation::{MFShutdown, MFStartup, MFSTARTUP_FULL},
+    },
+};
+
+use crate::info::json_string;
+
+const RESOLUTIONS: [(i32, i32); 3] = [(2560, 1440), (1920, 1080), (1280, 720)];
+const BIT_RATES: [u32; 3] = [5_000_000, 15_000_000, 40_000_000];
+const READBACK_TEXTURES: [usize; 3] = [1, 2, 3];
+
+pub struct BenchmarkOptions {
+    pub display_index: usize,
+    pub encoder_index: usize,
+    pub force_software: bool,
+    pub frame_rate: u32,
+    pub duration: Duration,
+    pub json: bool,
+    // Measures reading frames back to the CPU instead of encoding them.
+    pub readback: bool,
+}
+
+struct BenchmarkResult {
+    resolution: SizeInt32,
+    bit_rate: u32,
+    // Fails if the encoder couldn't be set up with these settings.
+    stats: Result<BenchmarkStats>,
+}
+
+struct ReadbackResult {
+    resolution: SizeInt32,
+    // How many staging textures were in flight, 1 being the naive approach.
+    textures: usize,
+    frames: u64,
+    fps: f64,
+}
+
+struct BenchmarkStats {
+    frames_encoded: u64,
+    elapsed: Duration,
+    fps: f64,
+    // How many of the frames we'd expect at the requested frame rate never
+    // made it out of the encoder, from 0.0 to 1.0.
+    drop_rate: f64,
+}
+
+pub fn run_benchmark(options: &BenchmarkOptions) -> Result<()> {
+    if options.readback {
+        return run_readback_benchmark(options);
+    }
+    let _apartment = ensure_apartment()?;
+    unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? };
+    let result = run_all(options);
+    unsafe { MFShutdown()? };
+    let results = result?;
+
+    if options.json {
+        println!("{}", format_json(&results));
+    } else {
+        print_table(&results);
+    }
+    Ok(())
+}
+
+fn benchmark_resolutions(display_index: usize) -> Result<Vec<SizeInt32>> {
+    let display_size = get_display_size(display_index)?;
+    let mut resolutions = vec![display_size];
+    for (width, height) in RESOLUTIONS {
+        if width < display_size.Width && height < display_size.Height {
+            resolutions.push(SizeInt32 {
+                Width: width,
+                Height: height,
+            });
+        }
+    }
+    Ok(resolutions)
+}
+
+fn run_all(options: &BenchmarkOptions) -> Result<Vec<BenchmarkResult>> {
+    let mut results = Vec::new();
+    for resolution in benchmark_resolutions(options.display_index)? {
+        for bit_rate in BIT_RATES {
+            if !options.json {
+                println!(
+                    "Benchmarking {}x{} at {} Mbps...",
+                    resolution.Width,
+                    resolution.Height,
+                    bit_rate / 1_000_000
+                );
+            }
+            let stats = run_one(options, resolution, bit_rate);
+            if let Err(error) = stats.as_ref() {
+                if !options.json {
+                    println!("  Failed: {}", error.message());
+                }
+            }
+            results.push(BenchmarkResult {
+                resolution,
+                bit_rate,
+                stats,
+            });
+        }
+    }
+    Ok(results)
+}
+
+fn run_one(
+    options: &BenchmarkOptions,
+    resolution: SizeInt32,
+    bit_rate: u32,
+) -> Result<BenchmarkStats> {
+    let config = RecorderConfig {
+        display_index: options.display_index,
+        backend: EncoderBackend::MediaFoundation,
+        encoder_index: options.encoder_index,
+        force_software: options.force_software,
+        settings: VideoEncodingSettings {
+            resolution: Some(resolution),
+            bit_rate: BitRate::Fixed(bit_rate),
+            frame_rate: options.frame_rate,
+            duration: Some(options.duration),
+            ..Default::default()
+        },
+        // An idle display is measured as dropping everything
+        allow_empty: true,
+        ..Default::default()
+    };
+
+    let mut recorder = Recorder::with_output_stream(&config, OutputStream::null()?)?;
+    let cancellation_token = recorder.cancellation_token();
+    let (sender, receiver) = channel();
+    cancellation_token.on_cancelled(move || {
+        let _ = sender.send(());
+    });
+
+    let start = Instant::now();
+    recorder.start()?;
+    // The duration only advances when frames arrive, cap it on the wall clock.
+    if receiver
+        .recv_timeout(options.duration + Duration::from_secs(1))
+        .is_err()
+    {
+        cancellation_token.cancel();
+    }
+    recorder.stop()?;
+    let wall_clock = start.elapsed();
+
+    let frames_encoded = recorder.frames_encoded().unwrap_or(0);
+    // Measured using frame times, unless nothing was captured at all.
+    let elapsed = if frames_encoded > 0 {
+        recorder.elapsed()
+    } else {
+        wall_clock
+    };
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let expected_frames = seconds * options.frame_rate as f64;
+    Ok(BenchmarkStats {
+        frames_encoded,
+        elapsed,
+        fps: frames_encoded as f64 / seconds,
+        drop_rate: (1.0 - frames_encoded as f64 / expected_frames).clamp(0.0, 1.0),
+    })
+}
+
+fn run_readback_benchmark(options: &BenchmarkOptions) -> Result<()> {
+    let d3d_device = create_d3d_device()?;
+    let mut results = Vec::new();
+    for resolution in benchmark_resolutions(options.display_index)? {
+        for textures in READBACK_TEXTURES {
+            if !options.json {
+                println!(
+                    "Benchmarking readback of {}x{} with {} staging texture(s)...",
+                    resolution.Width, resolution.Height, textures
+                );
+            }
+            results.push(run_readback(
+                &d3d_device,
+                resolution,
+                textures,
+                options.duration,
+            )?);
+        }
+    }
+
+    if options.json {
+        let results: Vec<_> = results
+            .iter()
+            .map(|result| {
+                format!(
+                    "{{\"width\":{},\"height\":{},\"textures\":{},\"frames\":{},\"fps\":{:.2}}}",
+                    result.resolution.Width,
+                    result.resolution.Height,
+                    result.textures,
+                    result.frames,
+                    result.fps
+                )
+            })
+            .collect();
+        println!("[{}]", results.join(","));
+    } else {
+        println!();
+        println!(
+            "{:<12} {:>8} {:>8} {:>8}",
+            "Resolution", "Textures", "Frames", "FPS"
+        );
+        for result in &results {
+            println!(
+                "{:<12} {:>8} {:>8} {:>8.1}",
+                format!("{}x{}", result.resolution.Width, result.resolution.Height),
+                result.textures,
+                result.frames,
+                result.fps
+            );
+        }
+    }
+    Ok(())
+}
+
+// Reads the same texture back as fast as possible, copying every frame out
+// like FrameReadback does.
+fn run_readback(
+    d3d_device: &ID3D11Device,
+    resolution: SizeInt32,
+    textures: usize,
+    duration: Duration,
+) -> Result<ReadbackResult> {
+    let desc = D3D11_TEXTURE2D_DESC {
+        Width: resolution.Width as u32,
+        Height: resolution.Height as u32,
+        MipLevels: 1,
+        ArraySize: 1,
+        Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        ..Default::default()
+    };
+    let texture = unsafe {
+        let mut texture = None;
+        d3d_device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+        texture.unwrap()
+    };
+    let mut readback = StagingReadback::new(d3d_device, resolution, textures)?;
+    let row_size = resolution.Width as usize * 4;
+    let mut buffer = vec![0u8; row_size * resolution.Height as usize];
+    let mut read_frame = |readback: &mut StagingReadback| -> Result<bool> {
+        let Some(frame) = readback.read()? else {
+            return Ok(false);
+        };
+        for (y, row) in buffer.chunks_exact_mut(row_size).enumerate() {
+            row.copy_from_slice(frame.row(y));
+        }
+        Ok(true)
+    };
+
+    let mut frames = 0;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        readback.copy(&texture, None)?;
+        if readback.is_full() && read_frame(&mut readback)? {
+            frames += 1;
+        }
+    }
+    while read_frame(&mut readback)? {
+        frames += 1;
+    }
+    let seconds = start.elapsed().as_secs_f64().max(f64::EPSILON);
+    Ok(ReadbackResult {
+        resolution,
+        textures,
+        frames,
+        fps: frames as f64 / seconds,
+    })
+}
+
+fn get_display_size(display_index: usize) -> Result<SizeInt32> {
+    let display_handle = get_display_handle_from_index(display_index).ok_or_else(|| {
+        Error::new(
+            E_INVALIDARG,
+            "The provided display index was out of bounds!".into(),
+        )
+    })?;
+    let rect = get_display_info(display_handle)?.rect;
+    // The encoder wants even dimensions
+    Ok(SizeInt32 {
+        Width: (rect.right - rect.left) & !1,
+        Height: (rect.bottom - rect.top) & !1,
+    })
+}
+
+fn print_table(results: &[BenchmarkResult]) {
+    println!();
+    println!(
+        "{:<12} {:>8} {:>8} {:>8} {:>8}",
+        "Resolution", "Mbps", "Frames", "FPS", "Dropped"
+    );
+    for result in results {
+        let resolution = format!("{}x{}", result.resolution.Width, result.resolution.Height);
+        let bit_rate = result.bit_rate / 1_000_000;
+        if let Ok(stats) = result.stats.as_ref() {
+            println!(
+                "{:<12} {:>8} {:>8} {:>8.1} {:>7.1}%",
+                resolution,
+                bit_rate,
+                stats.frames_encoded,
+                stats.fps,
+                stats.drop_rate * 100.0
+            );
+        } else {
+            println!("{:<12} {:>8} {:>8}", resolution, bit_rate, "failed");
+        }
+    }
+    println!();
+    println!("Frames are only captured when something on screen changes, so keep something animating (e.g. a video) on the display while benchmarking.");
+}
+
+fn format_json(results: &[BenchmarkResult]) -> String {
+    let results: Vec<_> = results
+        .iter()
+        .map(|result| {
+            let stats = match result.stats.as_ref() {
+                Ok(stats) => format!(
+                    "\"frames_encoded\":{},\"seconds\":{:.3},\"fps\":{:.2},\"drop_rate\":{:.4}",
+                    stats.frames_encoded,
+                    stats.elapsed.as_secs_f64(),
+                    stats.fps,
+                    stats.drop_rate
+                ),
+                Err(error) => {
+                    format!("\"error\":{}", json_string(&error.message().to_string()))
+                }
+            };
+            format!(
+                "{{\"width\":{},\"height\":{},\"bit_rate\":{},{}}}",
+                result.resolution.Width, result.resolution.Height, result.bit_rate, stats
+            )
+        })
+        .collect();
+    format!("[{}]", results.join(","))
+}
@@ -0,0 +1,121 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+type CancellationCallback = Box<dyn FnOnce() + Send>;
+
+// Why a recording ended.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    // Stopped by the caller (or the user).
+    UserRequested,
+    // The display or window being captured went away.
+    SourceClosed,
+    // Reached the requested duration.
+    MaxDurationReached,
+    // Reached the requested number of frames.
+    FrameLimitReached,
+    // The capture never delivered a frame, e.g. because it's broken or the
+    // secure desktop was showing from the start.
+    NoFrames,
+    // Capturing or encoding failed part way through. Whatever was recorded
+    // up to that point is still finalized.
+    Error,
+}
+
+// A clonable, thread-safe way to stop a recording from anywhere. Cancelling
+// ends the capture/encode loop, after which the output is finalized. Each
+// recording works off a child of the token it's given (see child), which it
+// also cancels when it ends on its own, along with why (see StopReason). The
+// caller's token is never cancelled by a recording, so it can be reused.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationTokenInner>,
+}
+
+#[derive(Default)]
+struct CancellationTokenInner {
+    cancelled: AtomicBool,
+    callbacks: Mutex<Vec<CancellationCallback>>,
+    reason: Mutex<Option<StopReason>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel_with_reason(StopReason::UserRequested)
+    }
+
+    // Only the first reason is kept, anything after that is just a side
+    // effect of the recording stopping.
+    pub fn cancel_with_reason(&self, reason: StopReason) {
+        let callbacks = {
+            let mut callbacks = self.inner.callbacks.lock().unwrap();
+            if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            *self.inner.reason.lock().unwrap() = Some(reason);
+            std::mem::take(&mut *callbacks)
+        };
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    // None until the token has been cancelled.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        *self.inner.reason.lock().unwrap()
+    }
+
+    // A token that's cancelled along with this one (with the same reason),
+    // but that can also be cancelled on its own without affecting us.
+    pub fn child(&self) -> Self {
+        let child = Self::new();
+        // The parent may outlive many children, don't keep them alive.
+        let weak_child = Arc::downgrade(&child.inner);
+        let parent = Arc::downgrade(&self.inner);
+        self.on_cancelled(move || {
+            if let (Some(inner), Some(parent)) = (weak_child.upgrade(), parent.upgrade()) {
+                let reason = parent
+                    .reason
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(StopReason::UserRequested);
+                Self { inner }.cancel_with_reason(reason);
+            }
+        });
+        child
+    }
+
+    // Runs the callback once the token is cancelled, or immediately if
+    // it already has been. Used to wake up loops blocked on frames.
+    pub fn on_cancelled<F: 'static + Send + FnOnce()>(&self, callback: F) {
+        {
+            let mut callbacks = self.inner.callbacks.lock().unwrap();
+            if !self.inner.cancelled.load(Ordering::SeqCst) {
+                callbacks.push(Box::new(callback));
+                return;
+            }
+        }
+        callback();
+    }
+}
+
+impl Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
@@ -1,64 +1,483 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
+use log::error;
 use windows::{
-    core::{IInspectable, Result},
-    Foundation::TypedEventHandler,
+    core::{AgileReference, Error, IInspectable, Result, HSTRING},
+    Foundation::{Metadata::ApiInformation, TimeSpan, TypedEventHandler},
     Graphics::{
         Capture::{
             Direct3D11CaptureFrame, Direct3D11CaptureFramePool, GraphicsCaptureItem,
             GraphicsCaptureSession,
         },
-        DirectX::DirectXPixelFormat,
+        DirectX::{
+            Direct3D11::{IDirect3DDevice, IDirect3DSurface},
+            DirectXPixelFormat,
+        },
         SizeInt32,
     },
+    System::{DispatcherQueue, DispatcherQueueController, DispatcherQueueHandler},
     Win32::{
-        Graphics::{Direct3D11::ID3D11Device, Gdi::HMONITOR},
-        System::WinRT::Graphics::Capture::IGraphicsCaptureItemInterop,
+        Foundation::{ERROR_ACCESS_DENIED, E_FAIL, E_INVALIDARG, E_NOTIMPL, HWND},
+        Graphics::{
+            Direct3D11::{ID3D11Device, ID3D11Texture2D},
+            Gdi::HMONITOR,
+        },
+        System::WinRT::{
+            CreateDispatcherQueueController, DispatcherQueueOptions,
+            Graphics::Capture::IGraphicsCaptureItemInterop, DQTAT_COM_STA, DQTYPE_THREAD_DEDICATED,
+        },
+        UI::WindowsAndMessaging::{
+            GetShellWindow, GetWindowDisplayAffinity, SetWindowDisplayAffinity,
+            WDA_EXCLUDEFROMCAPTURE, WINDOW_DISPLAY_AFFINITY,
+        },
     },
 };
 
-use crate::d3d::create_direct3d_device;
+use crate::{
+    d3d::{create_direct3d_device, create_direct3d_surface, get_d3d_interface_from_object},
+    desktop::Desktop,
+    duplication::{DuplicationStarter, OutputDuplicator},
+};
+
+pub const DEFAULT_BUFFER_COUNT: i32 = 2;
+
+// How long to wait on a DispatcherQueue to create a frame pool. A queue
+// that belongs to someone else's thread only runs while that thread pumps
+// messages, and it may be blocked waiting on us.
+const FRAME_POOL_CREATE_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn create_capture_item_for_monitor(monitor_handle: HMONITOR) -> Result<GraphicsCaptureItem> {
     let interop = windows::core::factory::<GraphicsCaptureItem, IGraphicsCaptureItemInterop>()?;
     unsafe { interop.CreateForMonitor(monitor_handle) }
 }
 
+pub fn create_capture_item_for_window(window_handle: HWND) -> Result<GraphicsCaptureItem> {
+    let interop = windows::core::factory::<GraphicsCaptureItem, IGraphicsCaptureItemInterop>()?;
+    unsafe { interop.CreateForWindow(window_handle) }
+}
+
+// The shell's desktop window only contains the wallpaper and the desktop
+// icons, unlike a monitor capture which includes every window and the taskbar.
+pub fn create_capture_item_for_desktop() -> Result<GraphicsCaptureItem> {
+    let window_handle = unsafe { GetShellWindow() };
+    if window_handle.0 == 0 {
+        return Err(Error::new(
+            E_FAIL,
+            "Unable to find the desktop window, is the shell running?".into(),
+        ));
+    }
+    create_capture_item_for_window(window_handle)
+}
+
+// Minimized windows report a size of 0x0, which the frame pool and the
+// encoders will refuse with much less helpful errors.
+pub fn get_capture_item_size(item: &GraphicsCaptureItem) -> Result<SizeInt32> {
+    let size = item.Size()?;
+    ensure_valid_capture_size(size)?;
+    Ok(size)
+}
+
+fn ensure_valid_capture_size(size: SizeInt32) -> Result<()> {
+    if size.Width <= 0 || size.Height <= 0 {
+        return Err(Error::new(
+            E_INVALIDARG,
+            format!(
+                "The capture target has an invalid size ({}x{}), is it minimized?",
+                size.Width, size.Height
+            )
+            .into(),
+        ));
+    }
+    Ok(())
+}
+
+// Hides one of our own windows (e.g. a picker or an overlay) from every
+// capture, including our own. WDA_EXCLUDEFROMCAPTURE requires Windows 10
+// Version 2004 (Build 19041). On older builds the window would show up as a
+// black rectangle instead, so we leave it alone and return false.
+pub fn exclude_window_from_capture(window: HWND) -> Result<bool> {
+    if !window_exclusion_supported()? {
+        return Ok(false);
+    }
+    unsafe { SetWindowDisplayAffinity(window, WDA_EXCLUDEFROMCAPTURE)? };
+    Ok(true)
+}
+
+// Hides a window from every capture for as long as this is alive, then puts
+// back the affinity it had before. Windows only lets a process change the
+// affinity of its own windows, so anyone else's window has to cooperate
+// (i.e. exclude itself).
+pub struct ExcludedWindow {
+    window: HWND,
+    original_affinity: u32,
+}
+
+impl ExcludedWindow {
+    pub fn new(window: HWND) -> Result<Self> {
+        if !window_exclusion_supported()? {
+            return Err(Error::new(
+                E_NOTIMPL,
+                "Excluding windows from the capture requires Windows 10 Version 2004 (Build 19041)!"
+                    .into(),
+            ));
+        }
+        let mut original_affinity = 0;
+        unsafe {
+            GetWindowDisplayAffinity(window, &mut original_affinity)?;
+            SetWindowDisplayAffinity(window, WDA_EXCLUDEFROMCAPTURE).map_err(|error| {
+                if error.code() == ERROR_ACCESS_DENIED.to_hresult() {
+                    Error::new(
+                        error.code(),
+                        "Only the process that owns a window can exclude it from the capture!"
+                            .into(),
+                    )
+                } else {
+                    error
+                }
+            })?;
+        }
+        Ok(Self {
+            window,
+            original_affinity,
+        })
+    }
+}
+
+impl Drop for ExcludedWindow {
+    fn drop(&mut self) {
+        // The window may be gone by now, which is fine
+        unsafe {
+            let _ = SetWindowDisplayAffinity(
+                self.window,
+                WINDOW_DISPLAY_AFFINITY(self.original_affinity),
+            );
+        }
+    }
+}
+
+pub fn window_exclusion_supported() -> Result<bool> {
+    ApiInformation::IsApiContractPresentByMajor(
+        &HSTRING::from("Windows.Foundation.UniversalApiContract"),
+        10,
+    )
+}
+
+// Which thread FrameArrived is raised on. Either way frames are handed to
+// whoever calls try_get_next_frame, this only matters to integrations that
+// care where the pool's callbacks run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FramePoolMode {
+    // Raised on a thread pool thread. Works from any apartment and never
+    // depends on anyone pumping messages.
+    #[default]
+    FreeThreaded,
+    // Raised on a DispatcherQueue thread. If the thread creating the capture
+    // already has a DispatcherQueue (e.g. an app's UI thread), that one is
+    // used and the thread has to keep pumping messages, otherwise the capture
+    // stalls. Pools are created right away on that thread, and from any other
+    // thread (e.g. when the capture changes size) by waiting on the queue, so
+    // the thread mustn't block on the recording while it's running either.
+    // Otherwise we start a dedicated STA thread with a queue of its own for
+    // the life of the capture.
+    ThreadAffine,
+}
+
+// Creates frame pools for a FramePoolMode. Thread affine pools have to be
+// created on their DispatcherQueue's thread, after that they can be used
+// from anywhere.
+#[derive(Clone)]
+struct FramePoolFactory {
+    queue: Option<DispatcherQueue>,
+    pixel_format: DirectXPixelFormat,
+    // Only set if we own the queue's thread.
+    _dispatcher: Option<Arc<DispatcherThread>>,
+}
+
+impl FramePoolFactory {
+    fn new(mode: FramePoolMode, pixel_format: DirectXPixelFormat) -> Result<Self> {
+        if mode == FramePoolMode::FreeThreaded {
+            return Ok(Self {
+                queue: None,
+                pixel_format,
+                _dispatcher: None,
+            });
+        }
+        if let Ok(queue) = DispatcherQueue::GetForCurrentThread() {
+            return Ok(Self {
+                queue: Some(queue),
+                pixel_format,
+                _dispatcher: None,
+            });
+        }
+        let dispatcher = DispatcherThread::new()?;
+        Ok(Self {
+            queue: Some(dispatcher.controller.DispatcherQueue()?),
+            pixel_format,
+            _dispatcher: Some(Arc::new(dispatcher)),
+        })
+    }
+
+    fn create(
+        &self,
+        device: &IDirect3DDevice,
+        buffer_count: i32,
+        size: SizeInt32,
+    ) -> Result<Direct3D11CaptureFramePool> {
+        let queue = match self.queue.as_ref() {
+            Some(queue) if !queue.HasThreadAccess()? => queue,
+            Some(_) => {
+                return Direct3D11CaptureFramePool::Create(
+                    device,
+                    self.pixel_format,
+                    buffer_count,
+                    size,
+                )
+            }
+            None => {
+                return Direct3D11CaptureFramePool::CreateFreeThreaded(
+                    device,
+                    self.pixel_format,
+                    buffer_count,
+                    size,
+                )
+            }
+        };
+        // Only ever waits on another thread's queue, waiting on our own would
+        // never return. The device interface isn't Send, so it crosses over
+        // to the dispatcher thread as an agile reference.
+        let (sender, receiver) = channel();
+        queue.TryEnqueue(&DispatcherQueueHandler::new({
+            let device = AgileReference::new(device)?;
+            let pixel_format = self.pixel_format;
+            move || {
+                let _ = sender.send(device.resolve().and_then(|device| {
+                    Direct3D11CaptureFramePool::Create(&device, pixel_format, buffer_count, size)
+                }));
+                Ok(())
+            }
+        }))?;
+        receiver
+            .recv_timeout(FRAME_POOL_CREATE_TIMEOUT)
+            .map_err(|error| match error {
+                RecvTimeoutError::Timeout => Error::new(
+                    E_FAIL,
+                    "The frame pool's DispatcherQueue didn't get to creating the pool, is its thread pumping messages?"
+                        .into(),
+                ),
+                RecvTimeoutError::Disconnected => Error::new(
+                    E_FAIL,
+                    "The frame pool's DispatcherQueue shut down before it could create the pool!"
+                        .into(),
+                ),
+            })?
+    }
+}
+
+// A DispatcherQueue on an STA thread of its own, shut down on drop.
+struct DispatcherThread {
+    controller: DispatcherQueueController,
+}
+
+impl DispatcherThread {
+    fn new() -> Result<Self> {
+        let options = DispatcherQueueOptions {
+            dwSize: std::mem::size_of::<DispatcherQueueOptions>() as u32,
+            threadType: DQTYPE_THREAD_DEDICATED,
+            apartmentType: DQTAT_COM_STA,
+        };
+        let controller = unsafe { CreateDispatcherQueueController(options)? };
+        Ok(Self { controller })
+    }
+}
+
+impl Drop for DispatcherThread {
+    fn drop(&mut self) {
+        // Waits for anything already queued (e.g. a frame handler) to finish
+        let result = self
+            .controller
+            .ShutdownQueueAsync()
+            .and_then(|action| action.get());
+        if let Err(error) = result {
+            error!(
+                "Error while shutting down the frame pool's DispatcherQueue: {:?} - {}",
+                error.code(),
+                error.message()
+            );
+        }
+    }
+}
+
+// A frame from either Windows.Graphics.Capture or desktop duplication.
+// Closing it (or dropping it) gives its buffer back to whoever produced it.
+pub struct CaptureFrame {
+    surface: IDirect3DSurface,
+    texture: ID3D11Texture2D,
+    content_size: SizeInt32,
+    system_relative_time: TimeSpan,
+    owner: FrameOwner,
+}
+
+enum FrameOwner {
+    Graphics(Direct3D11CaptureFrame),
+    // Duplicated frames are copies of the desktop, the texture goes back to
+    // the duplicator to copy a later frame into.
+    Duplication(Cell<Option<Sender<ID3D11Texture2D>>>),
+}
+
+// D3D interfaces aren't marked as Send, but the texture is only used by one
+// thread at a time.
+unsafe impl Send for CaptureFrame {}
+impl CaptureFrame {
+    fn from_graphics_frame(frame: Direct3D11CaptureFrame) -> Result<Self> {
+        let surface = frame.Surface()?;
+        Ok(Self {
+            texture: get_d3d_interface_from_object(&surface)?,
+            surface,
+            content_size: frame.ContentSize()?,
+            system_relative_time: frame.SystemRelativeTime()?,
+            owner: FrameOwner::Graphics(frame),
+        })
+    }
+
+    pub(crate) fn from_duplicated_texture(
+        texture: ID3D11Texture2D,
+        content_size: SizeInt32,
+        system_relative_time: TimeSpan,
+        release_sender: Sender<ID3D11Texture2D>,
+    ) -> Result<Self> {
+        Ok(Self {
+            surface: create_direct3d_surface(&texture)?,
+            texture,
+            content_size,
+            system_relative_time,
+            owner: FrameOwner::Duplication(Cell::new(Some(release_sender))),
+        })
+    }
+
+    pub fn surface(&self) -> &IDirect3DSurface {
+        &self.surface
+    }
+
+    pub fn texture(&self) -> &ID3D11Texture2D {
+        &self.texture
+    }
+
+    pub fn content_size(&self) -> SizeInt32 {
+        self.content_size
+    }
+
+    // QPC based, in 100ns units.
+    pub fn system_relative_time(&self) -> TimeSpan {
+        self.system_relative_time
+    }
+
+    pub fn close(&self) -> Result<()> {
+        match &self.owner {
+            FrameOwner::Graphics(frame) => frame.Close(),
+            FrameOwner::Duplication(release_sender) => {
+                // The duplicator may already be gone
+                if let Some(release_sender) = release_sender.take() {
+                    let _ = release_sender.send(self.texture.clone());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for CaptureFrame {
+    fn drop(&mut self) {
+        // Graphics frames go back to their pool on their own once released
+        if let FrameOwner::Duplication(_) = &self.owner {
+            let _ = self.close();
+        }
+    }
+}
+
 pub struct CaptureFrameGenerator {
     _d3d_device: ID3D11Device,
+    source: FrameSource,
+    sender: Sender<Option<CaptureFrame>>,
+    receiver: Receiver<Option<CaptureFrame>>,
+    source_closed: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+}
+
+enum FrameSource {
+    Graphics {
+        device: IDirect3DDevice,
+        target: Arc<Mutex<CaptureTarget>>,
+    },
+    Duplication(OutputDuplicator),
+}
+
+// Starts a capture whose generator is owned by someone else (e.g. the
+// encoder's sample callback).
+#[derive(Clone)]
+pub struct CaptureStarter(Starter);
+
+#[derive(Clone)]
+enum Starter {
+    Graphics(GraphicsCaptureSession),
+    Duplication(DuplicationStarter),
+}
+
+impl CaptureStarter {
+    pub fn start(&self) -> Result<()> {
+        match &self.0 {
+            Starter::Graphics(session) => session.StartCapture(),
+            Starter::Duplication(starter) => {
+                starter.start();
+                Ok(())
+            }
+        }
+    }
+}
+
+// The item being captured along with its frame pool and session. These get
+// replaced together when the capture is retargeted to another item.
+struct CaptureTarget {
     _item: GraphicsCaptureItem,
     frame_pool: Direct3D11CaptureFramePool,
+    frame_pool_factory: FramePoolFactory,
+    buffer_count: i32,
     session: GraphicsCaptureSession,
-    sender: Sender<Option<Direct3D11CaptureFrame>>,
-    receiver: Receiver<Option<Direct3D11CaptureFrame>>,
+    closed: Arc<AtomicBool>,
 }
 
-impl CaptureFrameGenerator {
-    pub fn new(
-        d3d_device: ID3D11Device,
+impl CaptureTarget {
+    fn new(
+        device: &IDirect3DDevice,
         item: GraphicsCaptureItem,
         size: SizeInt32,
+        buffer_count: i32,
+        frame_pool_factory: FramePoolFactory,
+        sender: &Sender<Option<CaptureFrame>>,
     ) -> Result<Self> {
-        let device = create_direct3d_device(&d3d_device)?;
-        let frame_pool = Direct3D11CaptureFramePool::CreateFreeThreaded(
-            &device,
-            DirectXPixelFormat::B8G8R8A8UIntNormalized,
-            2,
-            size,
-        )?;
+        ensure_valid_capture_size(size)?;
+        let frame_pool = frame_pool_factory.create(device, buffer_count, size)?;
         let session = frame_pool.CreateCaptureSession(&item)?;
 
-        let (sender, receiver) = channel();
+        let closed = Arc::new(AtomicBool::new(false));
         frame_pool.FrameArrived(
             &TypedEventHandler::<Direct3D11CaptureFramePool, IInspectable>::new({
                 let session = session.clone();
                 let sender = sender.clone();
+                let closed = closed.clone();
                 move |frame_pool, _| {
                     let frame_pool = frame_pool.as_ref().unwrap();
-                    let frame = frame_pool.TryGetNextFrame()?;
+                    let frame = CaptureFrame::from_graphics_frame(frame_pool.TryGetNextFrame()?)?;
+                    // Nobody is listening anymore
                     if sender.send(Some(frame)).is_err() {
-                        frame_pool.Close()?;
-                        session.Close()?;
+                        close_capture(&closed, frame_pool, &session)?;
                     }
                     Ok(())
                 }
@@ -66,20 +485,183 @@ impl CaptureFrameGenerator {
         )?;
 
         Ok(Self {
-            _d3d_device: d3d_device,
             _item: item,
             frame_pool,
+            frame_pool_factory,
+            buffer_count,
             session,
+            closed,
+        })
+    }
+
+    fn close(&self) -> Result<()> {
+        close_capture(&self.closed, &self.frame_pool, &self.session)
+    }
+}
+
+impl CaptureFrameGenerator {
+    pub fn new(
+        d3d_device: ID3D11Device,
+        item: GraphicsCaptureItem,
+        size: SizeInt32,
+    ) -> Result<Self> {
+        Self::with_buffer_count(d3d_device, item, size, DEFAULT_BUFFER_COUNT)
+    }
+
+    // Fewer buffers means frames spend less time queued, at the cost of
+    // dropping more of them when the consumer falls behind.
+    pub fn with_buffer_count(
+        d3d_device: ID3D11Device,
+        item: GraphicsCaptureItem,
+        size: SizeInt32,
+        buffer_count: i32,
+    ) -> Result<Self> {
+        Self::with_frame_pool_mode(
+            d3d_device,
+            item,
+            size,
+            buffer_count,
+            FramePoolMode::default(),
+        )
+    }
+
+    pub fn with_frame_pool_mode(
+        d3d_device: ID3D11Device,
+        item: GraphicsCaptureItem,
+        size: SizeInt32,
+        buffer_count: i32,
+        frame_pool_mode: FramePoolMode,
+    ) -> Result<Self> {
+        Self::with_pixel_format(
+            d3d_device,
+            item,
+            size,
+            buffer_count,
+            frame_pool_mode,
+            DirectXPixelFormat::B8G8R8A8UIntNormalized,
+        )
+    }
+
+    // Frames are BGRA8 unless asked otherwise, the other formats are for
+    // keeping the precision of HDR displays. Windows.Graphics.Capture may
+    // not support every format on every version of Windows.
+    pub fn with_pixel_format(
+        d3d_device: ID3D11Device,
+        item: GraphicsCaptureItem,
+        size: SizeInt32,
+        buffer_count: i32,
+        frame_pool_mode: FramePoolMode,
+        pixel_format: DirectXPixelFormat,
+    ) -> Result<Self> {
+        let device = create_direct3d_device(&d3d_device)?;
+        let (sender, receiver) = channel();
+        let frame_pool_factory = FramePoolFactory::new(frame_pool_mode, pixel_format)?;
+        let target = CaptureTarget::new(
+            &device,
+            item.clone(),
+            size,
+            buffer_count,
+            frame_pool_factory,
+            &sender,
+        )?;
+
+        // Ends the capture when the display is disconnected or the window is
+        // closed. Only the original item counts, once the capture has been
+        // retargeted the item it started with has no say.
+        let source_closed = Arc::new(AtomicBool::new(false));
+        item.Closed(
+            &TypedEventHandler::<GraphicsCaptureItem, IInspectable>::new({
+                let sender = sender.clone();
+                let target_closed = target.closed.clone();
+                let source_closed = source_closed.clone();
+                move |_, _| {
+                    if !target_closed.load(Ordering::SeqCst) {
+                        source_closed.store(true, Ordering::SeqCst);
+                        let _ = sender.send(None);
+                    }
+                    Ok(())
+                }
+            }),
+        )?;
+
+        Ok(Self {
+            _d3d_device: d3d_device,
+            source: FrameSource::Graphics {
+                device,
+                target: Arc::new(Mutex::new(target)),
+            },
+            sender,
+            receiver,
+            source_closed,
+            failed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Captures the display with DXGI desktop duplication instead. Frames are
+    // always the size of the display (whatever it changes to), and don't
+    // include the mouse cursor. There's no session to configure, use
+    // starter to start the capture.
+    pub fn for_output_duplication(
+        d3d_device: ID3D11Device,
+        monitor: HMONITOR,
+        desktop: Desktop,
+    ) -> Result<Self> {
+        let (sender, receiver) = channel();
+        let source_closed = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicBool::new(false));
+        let duplicator = OutputDuplicator::new(
+            &d3d_device,
+            monitor,
+            desktop,
+            sender.clone(),
+            source_closed.clone(),
+            failed.clone(),
+        )?;
+        Ok(Self {
+            _d3d_device: d3d_device,
+            source: FrameSource::Duplication(duplicator),
             sender,
             receiver,
+            source_closed,
+            failed,
         })
     }
 
-    pub fn session(&self) -> &GraphicsCaptureSession {
-        &self.session
+    // Whether the capture ended because the item went away, rather than
+    // being stopped.
+    pub fn source_closed(&self) -> bool {
+        self.source_closed.load(Ordering::SeqCst)
+    }
+
+    // Whether the capture ended because it failed part way through. Only
+    // duplication ends like this, e.g. when it can't follow a desktop switch.
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    // The session of the current target. Sessions created by a retarget are
+    // started (and configured) by the retargeter. Duplication doesn't have
+    // one.
+    pub fn session(&self) -> Option<GraphicsCaptureSession> {
+        match &self.source {
+            FrameSource::Graphics { target, .. } => Some(target.lock().unwrap().session.clone()),
+            FrameSource::Duplication(_) => None,
+        }
     }
 
-    pub fn try_get_next_frame(&mut self) -> Result<Option<Direct3D11CaptureFrame>> {
+    // Starts the session of the current target, or the duplication.
+    pub fn starter(&self) -> CaptureStarter {
+        match &self.source {
+            FrameSource::Graphics { target, .. } => {
+                CaptureStarter(Starter::Graphics(target.lock().unwrap().session.clone()))
+            }
+            FrameSource::Duplication(duplicator) => {
+                CaptureStarter(Starter::Duplication(duplicator.starter()))
+            }
+        }
+    }
+
+    pub fn try_get_next_frame(&mut self) -> Result<Option<CaptureFrame>> {
         if let Some(frame) = self.receiver.recv().unwrap() {
             Ok(Some(frame))
         } else {
@@ -87,6 +669,38 @@ impl CaptureFrameGenerator {
         }
     }
 
+    // The outer None means nothing arrived before the timeout, the inner one
+    // that the capture was stopped (see try_get_next_frame).
+    pub fn try_get_next_frame_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Option<CaptureFrame>>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+    }
+
+    // Frames that arrive after this are allocated at the new size. Frames
+    // that are already queued keep the old one. Duplicated frames always
+    // follow the display's size, so there's nothing to do for those.
+    pub fn resize(&mut self, size: SizeInt32) -> Result<()> {
+        ensure_valid_capture_size(size)?;
+        match &self.source {
+            FrameSource::Graphics { device, target } => {
+                let target = target.lock().unwrap();
+                target.frame_pool.Recreate(
+                    device,
+                    target.frame_pool_factory.pixel_format,
+                    target.buffer_count,
+                    size,
+                )
+            }
+            FrameSource::Duplication(_) => Ok(()),
+        }
+    }
+
     pub fn stop_capture(&mut self) -> Result<()> {
         self.sender.send(None).unwrap();
         Ok(())
@@ -95,21 +709,104 @@ impl CaptureFrameGenerator {
     pub fn stop_signal(&self) -> CaptureFrameGeneratorStopSignal {
         CaptureFrameGeneratorStopSignal::new(self.sender.clone())
     }
+
+    // Duplication is tied to its display, so it can't be retargeted.
+    pub fn retargeter(&self) -> Option<CaptureRetargeter> {
+        match &self.source {
+            FrameSource::Graphics { device, target } => Some(CaptureRetargeter {
+                device: device.clone(),
+                target: target.clone(),
+                sender: self.sender.clone(),
+            }),
+            FrameSource::Duplication(_) => None,
+        }
+    }
 }
 
 impl Drop for CaptureFrameGenerator {
     fn drop(&mut self) {
-        self.session.Close().unwrap();
-        self.frame_pool.Close().unwrap();
+        // The duplicator stops on its own when dropped
+        let target = match &self.source {
+            FrameSource::Graphics { target, .. } => target,
+            FrameSource::Duplication(_) => return,
+        };
+        // Panicking here would abort if we're already unwinding from a
+        // failed recording, and there's nothing left to do about it anyway.
+        if let Err(error) = target.lock().unwrap().close() {
+            error!(
+                "Error while closing the capture session: {:?} - {}",
+                error.code(),
+                error.message()
+            );
+        }
+    }
+}
+
+// Switches a running capture over to another item from any thread. Frames
+// from the new item go to the same receiver, so whoever consumes them only
+// sees the frame size change (see CaptureFrameGenerator::resize).
+#[derive(Clone)]
+pub struct CaptureRetargeter {
+    device: IDirect3DDevice,
+    target: Arc<Mutex<CaptureTarget>>,
+    sender: Sender<Option<CaptureFrame>>,
+}
+
+unsafe impl Send for CaptureRetargeter {}
+impl CaptureRetargeter {
+    // The new session is started right away, with the same border setting
+    // as the one it replaces.
+    pub fn retarget(&self, item: GraphicsCaptureItem) -> Result<()> {
+        let size = get_capture_item_size(&item)?;
+        let (buffer_count, frame_pool_factory) = {
+            let target = self.target.lock().unwrap();
+            (target.buffer_count, target.frame_pool_factory.clone())
+        };
+        let new_target = CaptureTarget::new(
+            &self.device,
+            item,
+            size,
+            buffer_count,
+            frame_pool_factory,
+            &self.sender,
+        )?;
+        let mut target = self.target.lock().unwrap();
+        // Not available on older builds, which always draw the border anyway
+        if let Ok(border_required) = target.session.IsBorderRequired() {
+            new_target.session.SetIsBorderRequired(border_required)?;
+        }
+        new_target.session.StartCapture()?;
+        let old_target = std::mem::replace(&mut *target, new_target);
+        old_target.close()
+    }
+}
+
+// The pool and session can be closed either by the frame handler (once the
+// receiver is gone) or when the generator is dropped, whichever comes first.
+// Once they've been closed the other caller has nothing to do, but if
+// closing failed it gets to try again. Closing either of them twice is
+// harmless, so the two racing each other is fine. The session goes first so that no new
+// frames get delivered to a closed pool.
+fn close_capture(
+    closed: &AtomicBool,
+    frame_pool: &Direct3D11CaptureFramePool,
+    session: &GraphicsCaptureSession,
+) -> Result<()> {
+    if closed.load(Ordering::SeqCst) {
+        return Ok(());
     }
+    session.Close()?;
+    frame_pool.Close()?;
+    closed.store(true, Ordering::SeqCst);
+    Ok(())
 }
 
 pub struct CaptureFrameGeneratorStopSignal {
-    sender: Sender<Option<Direct3D11CaptureFrame>>,
+    sender: Sender<Option<CaptureFrame>>,
 }
 
 impl CaptureFrameGeneratorStopSignal {
-    fn new(sender: Sender<Option<Direct3D11CaptureFrame>>) -> Self {
+    fn new(sender: Sender<Option<CaptureFrame>>) -> Self {
         Self { sender }
     }
 
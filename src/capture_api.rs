@@ -0,0 +1,43 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureApi {
+    // Windows.Graphics.Capture
+    Wgc,
+    // DXGI desktop duplication
+    Dxgi,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseCaptureApiError(&'static str);
+
+impl FromStr for CaptureApi {
+    type Err = ParseCaptureApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wgc" => Ok(CaptureApi::Wgc),
+            "dxgi" => Ok(CaptureApi::Dxgi),
+            _ => Err(ParseCaptureApiError(
+                "Invalid capture api value! Expecting: wgc, or dxgi.",
+            )),
+        }
+    }
+}
+
+impl Display for CaptureApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            CaptureApi::Wgc => "wgc",
+            CaptureApi::Dxgi => "dxgi",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseCaptureApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseCaptureApiError {}
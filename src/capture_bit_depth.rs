@@ -0,0 +1,76 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::{
+    Graphics::DirectX::DirectXPixelFormat,
+    Win32::Graphics::Dxgi::Common::{
+        DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM,
+        DXGI_FORMAT_R16G16B16A16_FLOAT,
+    },
+};
+
+// The precision frames are captured at. Anything above 8 bits keeps more of
+// an HDR display's range, but is converted to 8 bit NV12 for the encoder
+// unless it takes P010 (see EncoderInput).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureBitDepth {
+    // BGRA8
+    Eight,
+    // R10G10B10A2
+    Ten,
+    // R16G16B16A16 float (scRGB)
+    Sixteen,
+}
+
+impl CaptureBitDepth {
+    pub fn pixel_format(&self) -> DirectXPixelFormat {
+        match self {
+            CaptureBitDepth::Eight => DirectXPixelFormat::B8G8R8A8UIntNormalized,
+            CaptureBitDepth::Ten => DirectXPixelFormat::R10G10B10A2UIntNormalized,
+            CaptureBitDepth::Sixteen => DirectXPixelFormat::R16G16B16A16Float,
+        }
+    }
+
+    pub fn dxgi_format(&self) -> DXGI_FORMAT {
+        match self {
+            CaptureBitDepth::Eight => DXGI_FORMAT_B8G8R8A8_UNORM,
+            CaptureBitDepth::Ten => DXGI_FORMAT_R10G10B10A2_UNORM,
+            CaptureBitDepth::Sixteen => DXGI_FORMAT_R16G16B16A16_FLOAT,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseCaptureBitDepthError(&'static str);
+
+impl FromStr for CaptureBitDepth {
+    type Err = ParseCaptureBitDepthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CaptureBitDepth::Eight),
+            "10" => Ok(CaptureBitDepth::Ten),
+            "16" => Ok(CaptureBitDepth::Sixteen),
+            _ => Err(ParseCaptureBitDepthError(
+                "Invalid capture bit depth value! Expecting: 8, 10, or 16.",
+            )),
+        }
+    }
+}
+
+impl Display for CaptureBitDepth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            CaptureBitDepth::Eight => "8",
+            CaptureBitDepth::Ten => "10",
+            CaptureBitDepth::Sixteen => "16",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseCaptureBitDepthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseCaptureBitDepthError {}
@@ -0,0 +1,41 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureSource {
+    Monitor,
+    Desktop,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseCaptureSourceError(&'static str);
+
+impl FromStr for CaptureSource {
+    type Err = ParseCaptureSourceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "monitor" => Ok(CaptureSource::Monitor),
+            "desktop" => Ok(CaptureSource::Desktop),
+            _ => Err(ParseCaptureSourceError(
+                "Invalid capture source value! Expecting: monitor, or desktop.",
+            )),
+        }
+    }
+}
+
+impl Display for CaptureSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            CaptureSource::Monitor => "monitor",
+            CaptureSource::Desktop => "desktop",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseCaptureSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseCaptureSourceError {}
@@ -0,0 +1,167 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Chapter markers dropped while recording, written next to the output as a
+// WebVTT file once it's done. Times are measured like the recording's
+// elapsed time, so paused time is left out, and are then scaled by the
+// playback rate (see VideoEncodingSettings::playback_rate) so that they line
+// up with the output.
+pub struct ChapterMarkers {
+    marks: Vec<Duration>,
+    playback_rate: f64,
+}
+
+impl ChapterMarkers {
+    pub fn new(playback_rate: f64) -> Self {
+        Self {
+            marks: Vec::new(),
+            playback_rate,
+        }
+    }
+
+    // Returns the chapter's number, counted from 1. Marks that don't move
+    // past the previous one are ignored and return None.
+    pub fn mark(&mut self, elapsed: Duration) -> Option<usize> {
+        if let Some(last) = self.marks.last() {
+            if elapsed <= *last {
+                return None;
+            }
+        }
+        self.marks.push(elapsed);
+        Some(self.marks.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    // Each chapter runs until the next one starts, the last one until the
+    // end of the recording.
+    pub fn write(&self, path: &Path, end: Duration) -> std::io::Result<()> {
+        std::fs::write(path, self.to_vtt(end))
+    }
+
+    fn to_vtt(&self, end: Duration) -> String {
+        let mut vtt = String::from("WEBVTT\n");
+        for (index, start) in self.marks.iter().enumerate() {
+            let chapter_end = self
+                .marks
+                .get(index + 1)
+                .copied()
+                .unwrap_or(end)
+                .max(*start);
+            vtt.push_str(&format!(
+                "\n{}\n{} --> {}\nChapter {}\n",
+                index + 1,
+                format_timestamp(start.mul_f64(self.playback_rate)),
+                format_timestamp(chapter_end.mul_f64(self.playback_rate)),
+                index + 1
+            ));
+        }
+        vtt
+    }
+}
+
+// recording.mp4 gets recording.vtt.
+pub fn chapters_path(output_path: &Path) -> PathBuf {
+    output_path.with_extension("vtt")
+}
+
+// HH:MM:SS.mmm, as WebVTT wants it.
+pub fn format_timestamp(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_timestamp, ChapterMarkers};
+
+    #[test]
+    fn timestamp_formatting() {
+        assert_eq!(format_timestamp(Duration::ZERO), "00:00:00.000");
+        assert_eq!(format_timestamp(Duration::from_millis(1)), "00:00:00.001");
+        assert_eq!(
+            format_timestamp(Duration::from_millis(61_500)),
+            "00:01:01.500"
+        );
+        assert_eq!(
+            format_timestamp(Duration::from_secs(3 * 3600 + 25 * 60 + 7)),
+            "03:25:07.000"
+        );
+        // Hours don't wrap
+        assert_eq!(
+            format_timestamp(Duration::from_secs(100 * 3600)),
+            "100:00:00.000"
+        );
+        // Anything below a millisecond is dropped
+        assert_eq!(
+            format_timestamp(Duration::from_micros(1999)),
+            "00:00:00.001"
+        );
+    }
+
+    #[test]
+    fn chapter_marking() {
+        let mut chapters = ChapterMarkers::new(1.0);
+        assert!(chapters.is_empty());
+        assert_eq!(chapters.mark(Duration::from_secs(5)), Some(1));
+        // Has to move past the last mark
+        assert_eq!(chapters.mark(Duration::from_secs(5)), None);
+        assert_eq!(chapters.mark(Duration::from_secs(4)), None);
+        assert_eq!(chapters.mark(Duration::from_secs(6)), Some(2));
+        assert!(!chapters.is_empty());
+    }
+
+    #[test]
+    fn chapter_vtt() {
+        let mut chapters = ChapterMarkers::new(1.0);
+        assert_eq!(chapters.to_vtt(Duration::from_secs(10)), "WEBVTT\n");
+        chapters.mark(Duration::ZERO);
+        chapters.mark(Duration::from_millis(90_250));
+        assert_eq!(
+            chapters.to_vtt(Duration::from_secs(120)),
+            "WEBVTT\n\
+             \n1\n00:00:00.000 --> 00:01:30.250\nChapter 1\n\
+             \n2\n00:01:30.250 --> 00:02:00.000\nChapter 2\n"
+        );
+        // The last chapter doesn't end before it starts
+        assert_eq!(
+            chapters.to_vtt(Duration::from_secs(60)),
+            "WEBVTT\n\
+             \n1\n00:00:00.000 --> 00:01:30.250\nChapter 1\n\
+             \n2\n00:01:30.250 --> 00:01:30.250\nChapter 2\n"
+        );
+    }
+
+    #[test]
+    fn chapter_vtt_playback_rate() {
+        // A 30x timelapse
+        let mut chapters = ChapterMarkers::new(1.0 / 30.0);
+        chapters.mark(Duration::from_secs(60));
+        chapters.mark(Duration::from_secs(90));
+        assert_eq!(
+            chapters.to_vtt(Duration::from_secs(300)),
+            "WEBVTT\n\
+             \n1\n00:00:02.000 --> 00:00:03.000\nChapter 1\n\
+             \n2\n00:00:03.000 --> 00:00:10.000\nChapter 2\n"
+        );
+        // Slow motion
+        let mut chapters = ChapterMarkers::new(2.0);
+        chapters.mark(Duration::from_secs(1));
+        assert_eq!(
+            chapters.to_vtt(Duration::from_secs(3)),
+            "WEBVTT\n\n1\n00:00:02.000 --> 00:00:06.000\nChapter 1\n"
+        );
+    }
+}
@@ -1,9 +1,23 @@
-use windows::core::{ComInterface, Interface, Result};
+use std::{collections::VecDeque, time::Duration};
+
+use windows::core::{ComInterface, Error, Interface, Result};
 use windows::Graphics::DirectX::Direct3D11::{IDirect3DDevice, IDirect3DSurface};
-use windows::Win32::Graphics::Direct3D11::{ID3D11Texture2D, D3D11_CREATE_DEVICE_DEBUG};
+use windows::Graphics::SizeInt32;
+use windows::Win32::Foundation::{BOOL, E_FAIL};
+use windows::Win32::Graphics::Direct3D11::{
+    ID3D11DeviceContext, ID3D11Query, ID3D11Texture2D, D3D11_ASYNC_GETDATA_DONOTFLUSH, D3D11_BOX,
+    D3D11_CPU_ACCESS_READ, D3D11_CREATE_DEVICE_DEBUG, D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ,
+    D3D11_QUERY_DESC, D3D11_QUERY_EVENT, D3D11_TEXTURE2D_DESC, D3D11_USAGE_STAGING,
+};
+use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC};
 use windows::Win32::Graphics::Dxgi::IDXGISurface;
 use windows::Win32::Graphics::{
-    Direct3D::{D3D_DRIVER_TYPE, D3D_DRIVER_TYPE_HARDWARE, D3D_DRIVER_TYPE_WARP},
+    Direct3D::{
+        D3D_DRIVER_TYPE, D3D_DRIVER_TYPE_HARDWARE, D3D_DRIVER_TYPE_WARP, D3D_FEATURE_LEVEL,
+        D3D_FEATURE_LEVEL_10_0, D3D_FEATURE_LEVEL_10_1, D3D_FEATURE_LEVEL_11_0,
+        D3D_FEATURE_LEVEL_11_1, D3D_FEATURE_LEVEL_9_1, D3D_FEATURE_LEVEL_9_2,
+        D3D_FEATURE_LEVEL_9_3,
+    },
     Direct3D11::{
         D3D11CreateDevice, ID3D11Device, D3D11_CREATE_DEVICE_BGRA_SUPPORT,
         D3D11_CREATE_DEVICE_FLAG, D3D11_SDK_VERSION,
@@ -15,9 +29,32 @@ use windows::Win32::System::WinRT::Direct3D11::{
     IDirect3DDxgiInterfaceAccess,
 };
 
+// Highest first, which is the order D3D11CreateDevice tries them in.
+const FEATURE_LEVELS: [D3D_FEATURE_LEVEL; 7] = [
+    D3D_FEATURE_LEVEL_11_1,
+    D3D_FEATURE_LEVEL_11_0,
+    D3D_FEATURE_LEVEL_10_1,
+    D3D_FEATURE_LEVEL_10_0,
+    D3D_FEATURE_LEVEL_9_3,
+    D3D_FEATURE_LEVEL_9_2,
+    D3D_FEATURE_LEVEL_9_1,
+];
+
+// What to ask for when creating a device. BGRA support is always requested
+// regardless, since capture frames are BGRA and Direct2D interop needs it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct D3DDeviceOptions {
+    // The lowest acceptable feature level. None takes whatever the adapter
+    // supports. A hardware adapter that can't reach it falls back to WARP.
+    pub min_feature_level: Option<D3D_FEATURE_LEVEL>,
+    // Skips the hardware adapter and always uses WARP.
+    pub use_warp: bool,
+}
+
 fn create_d3d_device_with_type(
     driver_type: D3D_DRIVER_TYPE,
     flags: D3D11_CREATE_DEVICE_FLAG,
+    feature_levels: Option<&[D3D_FEATURE_LEVEL]>,
     device: *mut Option<ID3D11Device>,
 ) -> Result<()> {
     unsafe {
@@ -26,7 +63,7 @@ fn create_d3d_device_with_type(
             driver_type,
             None,
             flags,
-            None,
+            feature_levels,
             D3D11_SDK_VERSION,
             Some(device),
             None,
@@ -36,6 +73,10 @@ fn create_d3d_device_with_type(
 }
 
 pub fn create_d3d_device() -> Result<ID3D11Device> {
+    create_d3d_device_with_options(&D3DDeviceOptions::default())
+}
+
+pub fn create_d3d_device_with_options(options: &D3DDeviceOptions) -> Result<ID3D11Device> {
     let mut device = None;
     let flags = {
         let mut flags = D3D11_CREATE_DEVICE_BGRA_SUPPORT;
@@ -44,13 +85,29 @@ pub fn create_d3d_device() -> Result<ID3D11Device> {
         }
         flags
     };
-    let mut result = create_d3d_device_with_type(D3D_DRIVER_TYPE_HARDWARE, flags, &mut device);
-    if let Err(error) = &result {
-        if error.code() == DXGI_ERROR_UNSUPPORTED {
-            result = create_d3d_device_with_type(D3D_DRIVER_TYPE_WARP, flags, &mut device);
+    let feature_levels: Option<Vec<_>> = options.min_feature_level.map(|min_feature_level| {
+        FEATURE_LEVELS
+            .iter()
+            .copied()
+            .filter(|feature_level| feature_level.0 >= min_feature_level.0)
+            .collect()
+    });
+    let feature_levels = feature_levels.as_deref();
+
+    if !options.use_warp {
+        // There's no hardware device in some VMs, WARP at least lets us run there
+        match create_d3d_device_with_type(
+            D3D_DRIVER_TYPE_HARDWARE,
+            flags,
+            feature_levels,
+            &mut device,
+        ) {
+            Ok(_) => return Ok(device.unwrap()),
+            Err(error) if error.code() == DXGI_ERROR_UNSUPPORTED => {}
+            Err(error) => return Err(error),
         }
     }
-    result?;
+    create_d3d_device_with_type(D3D_DRIVER_TYPE_WARP, flags, feature_levels, &mut device)?;
     Ok(device.unwrap())
 }
 
@@ -66,6 +123,213 @@ pub fn create_direct3d_surface(d3d_texture: &ID3D11Texture2D) -> Result<IDirect3
     inspectable.cast()
 }
 
+// How long StagingReadback sleeps between checks on a copy that isn't done.
+const READBACK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// A ring of BGRA8 staging textures for reading frames back to the CPU. With
+// more than one texture the CPU can read an older frame while the GPU is still
+// copying the newest one, instead of waiting on every copy. Frames are read
+// in the order they were copied, and a ring of one behaves like a single
+// staging texture.
+pub struct StagingReadback {
+    d3d_context: ID3D11DeviceContext,
+    textures: Vec<StagingTexture>,
+    size: SizeInt32,
+    // Indices into textures, oldest copy first.
+    pending: VecDeque<usize>,
+    next: usize,
+}
+
+struct StagingTexture {
+    texture: ID3D11Texture2D,
+    // Signaled once the GPU is done with the last copy into the texture.
+    fence: ID3D11Query,
+}
+
+impl StagingReadback {
+    pub fn new(d3d_device: &ID3D11Device, size: SizeInt32, count: usize) -> Result<Self> {
+        let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: size.Width as u32,
+            Height: size.Height as u32,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Usage: D3D11_USAGE_STAGING,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ.0 as u32,
+            ..Default::default()
+        };
+        let query_desc = D3D11_QUERY_DESC {
+            Query: D3D11_QUERY_EVENT,
+            MiscFlags: 0,
+        };
+        let textures = (0..count.max(1))
+            .map(|_| unsafe {
+                let mut texture = None;
+                d3d_device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+                let mut fence = None;
+                d3d_device.CreateQuery(&query_desc, Some(&mut fence))?;
+                Ok(StagingTexture {
+                    texture: texture.unwrap(),
+                    fence: fence.unwrap(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            d3d_context,
+            textures,
+            size,
+            pending: VecDeque::new(),
+            next: 0,
+        })
+    }
+
+    pub fn size(&self) -> SizeInt32 {
+        self.size
+    }
+
+    // Every texture holds a frame that hasn't been read yet.
+    pub fn is_full(&self) -> bool {
+        self.pending.len() == self.textures.len()
+    }
+
+    // Queues a copy of the region (or all) of the texture into the ring,
+    // without waiting for the GPU. Fails if the ring is full, read a frame
+    // first.
+    pub fn copy(&mut self, texture: &ID3D11Texture2D, region: Option<&D3D11_BOX>) -> Result<()> {
+        self.copy_subresource(texture, 0, region)
+    }
+
+    // Like copy, but from a mip level (or array slice) of the texture.
+    pub fn copy_subresource(
+        &mut self,
+        texture: &ID3D11Texture2D,
+        subresource: u32,
+        region: Option<&D3D11_BOX>,
+    ) -> Result<()> {
+        if self.is_full() {
+            return Err(Error::new(
+                E_FAIL,
+                "Every staging texture is waiting to be read!".into(),
+            ));
+        }
+        let staging = &self.textures[self.next];
+        unsafe {
+            self.d3d_context.CopySubresourceRegion(
+                &staging.texture,
+                0,
+                0,
+                0,
+                0,
+                texture,
+                subresource,
+                region.map(|region| region as *const _),
+            );
+            self.d3d_context.End(&staging.fence);
+        }
+        self.pending.push_back(self.next);
+        self.next = (self.next + 1) % self.textures.len();
+        Ok(())
+    }
+
+    // Whether the oldest frame that hasn't been read yet is done copying, so
+    // that reading it won't wait. False if there's nothing to read.
+    pub fn is_ready(&self) -> Result<bool> {
+        match self.pending.front() {
+            Some(index) => self.is_copied(*index, true),
+            None => Ok(false),
+        }
+    }
+
+    // Maps the oldest frame that hasn't been read yet, waiting for its copy
+    // if it isn't done. The texture is free again once the frame is dropped.
+    pub fn read(&mut self) -> Result<Option<MappedFrame<'_>>> {
+        let Some(index) = self.pending.pop_front() else {
+            return Ok(None);
+        };
+        // Map would wait as well, but by spinning in the driver. Only the
+        // first check has to submit the copy to the GPU.
+        let mut flush = true;
+        while !self.is_copied(index, flush)? {
+            flush = false;
+            std::thread::sleep(READBACK_POLL_INTERVAL);
+        }
+        let staging = &self.textures[index];
+        unsafe {
+            let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+            self.d3d_context
+                .Map(&staging.texture, 0, D3D11_MAP_READ, 0, Some(&mut mapped))?;
+            Ok(Some(MappedFrame {
+                d3d_context: &self.d3d_context,
+                texture: &staging.texture,
+                data: mapped.pData as *const u8,
+                row_pitch: mapped.RowPitch as usize,
+                size: self.size,
+            }))
+        }
+    }
+
+    fn is_copied(&self, index: usize, flush: bool) -> Result<bool> {
+        let flags = if flush {
+            0
+        } else {
+            D3D11_ASYNC_GETDATA_DONOTFLUSH.0 as u32
+        };
+        let mut done = BOOL(0);
+        unsafe {
+            self.d3d_context.GetData(
+                &self.textures[index].fence,
+                Some(&mut done as *mut _ as *mut _),
+                std::mem::size_of::<BOOL>() as u32,
+                flags,
+            )?
+        };
+        Ok(done.as_bool())
+    }
+}
+
+// A staging texture mapped for reading. Rows are RowPitch bytes apart, which
+// is usually more than the width in pixels times 4.
+pub struct MappedFrame<'a> {
+    d3d_context: &'a ID3D11DeviceContext,
+    texture: &'a ID3D11Texture2D,
+    data: *const u8,
+    row_pitch: usize,
+    size: SizeInt32,
+}
+
+impl<'a> MappedFrame<'a> {
+    pub fn row_pitch(&self) -> usize {
+        self.row_pitch
+    }
+
+    // Every row, padding included.
+    pub fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data, self.row_pitch * self.size.Height as usize) }
+    }
+
+    // The BGRA pixels of a row, without the padding.
+    pub fn row(&self, y: usize) -> &[u8] {
+        assert!(y < self.size.Height as usize);
+        unsafe {
+            std::slice::from_raw_parts(
+                self.data.add(y * self.row_pitch),
+                self.size.Width as usize * 4,
+            )
+        }
+    }
+}
+
+impl<'a> Drop for MappedFrame<'a> {
+    fn drop(&mut self) {
+        unsafe { self.d3d_context.Unmap(self.texture, 0) };
+    }
+}
+
 pub fn get_d3d_interface_from_object<S: Interface + ComInterface, R: Interface + ComInterface>(
     object: &S,
 ) -> Result<R> {
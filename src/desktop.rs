@@ -0,0 +1,135 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::{
+    core::{Error, Result, HSTRING},
+    Win32::{
+        Foundation::{E_ACCESSDENIED, GENERIC_ALL},
+        System::StationsAndDesktops::{
+            CloseDesktop, OpenDesktopW, OpenInputDesktop, SetThreadDesktop, DESKTOP_ACCESS_FLAGS,
+            DESKTOP_CONTROL_FLAGS, HDESK,
+        },
+    },
+};
+
+// The desktop to capture from. Winlogon is the secure desktop that shows the
+// logon and lock screens (and UAC prompts on most machines). Only processes
+// running as SYSTEM in the console session can open it, e.g. one started by
+// a service with the interactive user's session id, and only desktop
+// duplication can see it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Desktop {
+    Default,
+    Winlogon,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseDesktopError(&'static str);
+
+impl Desktop {
+    fn name(&self) -> &'static str {
+        match self {
+            Desktop::Default => "Default",
+            Desktop::Winlogon => "Winlogon",
+        }
+    }
+}
+
+// Switches the calling thread over to the desktop. This fails for threads
+// that already have windows or hooks on their current desktop. The desktop
+// is left open, the thread uses it from here on.
+pub fn attach_to_desktop(desktop: Desktop) -> Result<()> {
+    let map_error = |error: Error| {
+        if error.code() == E_ACCESSDENIED {
+            Error::new(
+                error.code(),
+                format!(
+                    "Access to the {} desktop was denied! It can only be opened by a process running as SYSTEM in the console session.",
+                    desktop.name()
+                )
+                .into(),
+            )
+        } else {
+            Error::new(
+                error.code(),
+                format!(
+                    "Unable to attach to the {} desktop: {}",
+                    desktop.name(),
+                    error.message()
+                )
+                .into(),
+            )
+        }
+    };
+    unsafe {
+        let handle = OpenDesktopW(
+            &HSTRING::from(desktop.name()),
+            DESKTOP_CONTROL_FLAGS(0),
+            false,
+            GENERIC_ALL.0,
+        )
+        .map_err(map_error)?;
+        SetThreadDesktop(handle).map_err(map_error)
+    }
+}
+
+// The desktop a thread was switched to by attach_to_input_desktop, which is
+// closed once this is dropped. The thread has to have moved on by then, the
+// desktop a thread is on can't be closed.
+pub struct InputDesktop(HDESK);
+
+// Switches the calling thread over to whichever desktop is receiving input,
+// e.g. back to the Default desktop once the secure desktop goes away. Fails
+// with E_ACCESSDENIED while that's a desktop we aren't allowed to open.
+pub fn attach_to_input_desktop() -> Result<InputDesktop> {
+    unsafe {
+        let handle = OpenInputDesktop(
+            DESKTOP_CONTROL_FLAGS(0),
+            false,
+            DESKTOP_ACCESS_FLAGS(GENERIC_ALL.0),
+        )?;
+        if let Err(error) = SetThreadDesktop(handle) {
+            let _ = CloseDesktop(handle);
+            return Err(error);
+        }
+        Ok(InputDesktop(handle))
+    }
+}
+
+impl Drop for InputDesktop {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseDesktop(self.0);
+        }
+    }
+}
+
+impl FromStr for Desktop {
+    type Err = ParseDesktopError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(Desktop::Default),
+            "winlogon" => Ok(Desktop::Winlogon),
+            _ => Err(ParseDesktopError(
+                "Invalid desktop value! Expecting: default or winlogon.",
+            )),
+        }
+    }
+}
+
+impl Display for Desktop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Desktop::Default => "default",
+            Desktop::Winlogon => "winlogon",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseDesktopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseDesktopError {}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use displayrecorder::{
+    pipe::is_named_pipe_path,
+    recorder::{create_capture_item, get_source_size, RecorderConfig},
+    video::{backend::EncoderBackend, output_stream::is_null_output_path, raw_format::RawFormat},
+};
+use log::{error, info, warn};
+use windows::{
+    core::{Result, HSTRING},
+    Graphics::SizeInt32,
+    Win32::{Media::MediaFoundation::MFVideoFormat_H264, Storage::FileSystem::GetDiskFreeSpaceExW},
+};
+
+const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+// Compares what the recording will roughly need against the free space on
+// the output's drive. Fixed-duration recordings get a warning (or an error
+// when required is set) if they won't fit, open-ended ones just get told
+// how long they can go. Returns false if the recording shouldn't start.
+pub fn check_disk_space(config: &RecorderConfig, required: bool) -> Result<bool> {
+    let output_path = &config.output_path;
+    // PNG sizes depend entirely on the content, and pipes and the null
+    // output don't take up any space.
+    if config.alpha || is_named_pipe_path(output_path) || is_null_output_path(output_path) {
+        return Ok(true);
+    }
+    let bytes_per_second = if let Some(bytes_per_second) = estimate_bytes_per_second(config) {
+        bytes_per_second
+    } else {
+        return Ok(true);
+    };
+    let free_bytes = get_free_space(output_path)?;
+
+    if let Some(duration) = config.settings.duration {
+        let needed_bytes = (bytes_per_second as f64 * duration.as_secs_f64()) as u64;
+        if needed_bytes > free_bytes {
+            let message = format!(
+                "The recording needs about {:.1} GB, but only {:.1} GB are free on the output drive.",
+                needed_bytes as f64 / BYTES_PER_GB,
+                free_bytes as f64 / BYTES_PER_GB
+            );
+            if required {
+                error!("{}", message);
+            } else {
+                warn!("{}", message);
+            }
+            return Ok(!required);
+        }
+    } else {
+        let minutes = free_bytes as f64 / bytes_per_second as f64 / 60.0;
+        info!(
+            "{:.1} GB free on the output drive, enough for about {:.0} minutes.",
+            free_bytes as f64 / BYTES_PER_GB,
+            minutes
+        );
+    }
+    Ok(true)
+}
+
+// Bit rates and raw frame sizes add up quickly, so this is all done in u64.
+fn estimate_bytes_per_second(config: &RecorderConfig) -> Option<u64> {
+    let settings = &config.settings;
+    let capture_size = get_capture_size(config)?;
+    let bytes_per_second = match config.backend {
+        // Raw frames are always at the capture size
+        EncoderBackend::Raw => {
+            let pixels = capture_size.Width as u64 * capture_size.Height as u64;
+            let frame_bytes = match settings.raw_format {
+                RawFormat::Bgra => pixels * 4,
+                RawFormat::Nv12 => pixels * 3 / 2,
+            };
+            frame_bytes * settings.frame_rate as u64
+        }
+        _ => {
+            let output_size = settings.output_size(capture_size);
+            let bit_rate =
+                settings
+                    .bit_rate
+                    .resolve(output_size, settings.frame_rate, &MFVideoFormat_H264);
+            // Renditions share the drive with the output
+            let ladder_bit_rate: u64 = config
+                .ladder
+                .iter()
+                .map(|rendition| rendition.bit_rate as u64)
+                .sum();
+            (bit_rate as u64 + ladder_bit_rate) / 8
+        }
+    };
+    // Nothing to estimate from, e.g. a minimized window
+    (bytes_per_second > 0).then_some(bytes_per_second)
+}
+
+// The size of what's recorded, which for anything but a monitor isn't the
+// display's size.
+fn get_capture_size(config: &RecorderConfig) -> Option<SizeInt32> {
+    let item = create_capture_item(config).ok()?;
+    let item_size = get_source_size(config, item.as_ref()).ok()?;
+    Some(config.settings.crop.map_or(item_size, |crop| SizeInt32 {
+        Width: crop.Width,
+        Height: crop.Height,
+    }))
+}
+
+fn get_free_space(output_path: &Path) -> Result<u64> {
+    // The file doesn't exist yet, ask about the folder it's going in
+    let folder = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut free_bytes = 0;
+    unsafe {
+        GetDiskFreeSpaceExW(
+            &HSTRING::from(folder.as_os_str()),
+            Some(&mut free_bytes),
+            None,
+            None,
+        )?
+    };
+    Ok(free_bytes)
+}
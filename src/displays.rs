@@ -1,25 +1,392 @@
-use windows::Win32::{
-    Foundation::{BOOL, LPARAM, RECT},
-    Graphics::Gdi::{EnumDisplayMonitors, HDC, HMONITOR},
+use windows::{
+    core::{ComInterface, Result, HSTRING},
+    Win32::{
+        Devices::Display::{
+            DisplayConfigGetDeviceInfo, GetDisplayConfigBufferSizes, QueryDisplayConfig,
+            DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME, DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME,
+            DISPLAYCONFIG_DEVICE_INFO_HEADER, DISPLAYCONFIG_MODE_INFO, DISPLAYCONFIG_PATH_INFO,
+            DISPLAYCONFIG_SOURCE_DEVICE_NAME, DISPLAYCONFIG_TARGET_DEVICE_NAME,
+            QDC_ONLY_ACTIVE_PATHS,
+        },
+        Foundation::{BOOL, LPARAM, RECT},
+        Graphics::{
+            Dxgi::{
+                Common::{DXGI_COLOR_SPACE_RGB_FULL_G2084_NONE_P2020, DXGI_COLOR_SPACE_TYPE},
+                CreateDXGIFactory1, IDXGIFactory1, IDXGIOutput, IDXGIOutput6, DXGI_OUTPUT_DESC,
+                DXGI_OUTPUT_DESC1,
+            },
+            Gdi::{
+                EnumDisplayMonitors, EnumDisplaySettingsW, GetMonitorInfoW, DEVMODEW,
+                ENUM_CURRENT_SETTINGS, ENUM_DISPLAY_SETTINGS_MODE, HDC, HMONITOR, MONITORINFO,
+                MONITORINFOEXW,
+            },
+        },
+        UI::WindowsAndMessaging::MONITORINFOF_PRIMARY,
+    },
 };
 
+// sRGB's red primary is at x = 0.64, anything redder than this is a wider
+// gamut (e.g. DCI-P3 at 0.68).
+const WIDE_GAMUT_RED_X: f32 = 0.66;
+
+pub struct DisplayInfo {
+    pub handle: HMONITOR,
+    pub name: String,
+    // The monitor's name from its EDID (e.g. "DELL U2720Q"), if it has one.
+    // Several displays can share it, see get_display_names.
+    pub friendly_name: Option<String>,
+    pub rect: RECT,
+    pub is_primary: bool,
+}
+
+// What DXGI knows about the display's colors, see DXGI_OUTPUT_DESC1.
+// Luminance is in nits.
+#[derive(Copy, Clone, Debug)]
+pub struct DisplayColorInfo {
+    pub color_space: DXGI_COLOR_SPACE_TYPE,
+    pub bits_per_color: u32,
+    pub red_primary: [f32; 2],
+    pub green_primary: [f32; 2],
+    pub blue_primary: [f32; 2],
+    pub white_point: [f32; 2],
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+    pub max_full_frame_luminance: f32,
+}
+
+impl DisplayColorInfo {
+    // Windows composes HDR displays in scRGB and sends them HDR10.
+    pub fn is_hdr(&self) -> bool {
+        self.color_space == DXGI_COLOR_SPACE_RGB_FULL_G2084_NONE_P2020
+    }
+
+    pub fn is_wide_gamut(&self) -> bool {
+        self.red_primary[0] > WIDE_GAMUT_RED_X
+    }
+
+    pub fn description(&self) -> &'static str {
+        if self.is_hdr() {
+            "HDR"
+        } else if self.is_wide_gamut() {
+            "SDR, wide gamut"
+        } else {
+            "SDR"
+        }
+    }
+}
+
+// A mode the display's driver says it can be set to. The refresh rate is
+// in Hz, as reported by the driver (e.g. 59 for 59.94).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u32,
+}
+
 pub fn get_display_handle_from_index(index: usize) -> Option<HMONITOR> {
-    let displays = enumerate_displays();
+    let displays = enumerate_displays().ok()?;
     displays.get(index).copied()
 }
 
-fn enumerate_displays() -> Vec<HMONITOR> {
+// The index that get_display_handle_from_index would return the display at.
+pub fn get_display_index_from_handle(handle: HMONITOR) -> Option<usize> {
+    let displays = enumerate_displays().ok()?;
+    displays.iter().position(|display| *display == handle)
+}
+
+pub fn enumerate_display_info() -> Result<Vec<DisplayInfo>> {
+    enumerate_displays()?
+        .into_iter()
+        .map(get_display_info)
+        .collect()
+}
+
+pub fn get_display_info(handle: HMONITOR) -> Result<DisplayInfo> {
+    let info = unsafe {
+        let mut info = MONITORINFOEXW::default();
+        info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+        GetMonitorInfoW(handle, &mut info as *mut _ as *mut MONITORINFO).ok()?;
+        info
+    };
+    let name = from_wide(&info.szDevice);
+    // Not every display reports one, and neither do some remote sessions
+    let friendly_name = get_friendly_names()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(device_name, _)| *device_name == name)
+        .map(|(_, friendly_name)| friendly_name);
+    Ok(DisplayInfo {
+        handle,
+        name,
+        friendly_name,
+        rect: info.monitorInfo.rcMonitor,
+        is_primary: info.monitorInfo.dwFlags & MONITORINFOF_PRIMARY != 0,
+    })
+}
+
+// A name for every display, in the same order, that tells them apart: the
+// friendly name, with " (2)", " (3)" and so on appended when several displays
+// share it, or the device name (e.g. \\.\DISPLAY1) when there isn't one.
+pub fn get_display_names(displays: &[DisplayInfo]) -> Vec<String> {
+    let names: Vec<_> = displays
+        .iter()
+        .map(|display| (display.friendly_name.as_deref(), display.name.as_str()))
+        .collect();
+    unique_display_names(&names)
+}
+
+// The index of the display with the given name (see get_display_names) or
+// device name, ignoring case.
+pub fn find_display_by_name(name: &str) -> Result<Option<usize>> {
+    let displays = enumerate_display_info()?;
+    let index =
+        get_display_names(&displays)
+            .iter()
+            .zip(&displays)
+            .position(|(display_name, display)| {
+                display_name.eq_ignore_ascii_case(name) || display.name.eq_ignore_ascii_case(name)
+            });
+    Ok(index)
+}
+
+fn unique_display_names(names: &[(Option<&str>, &str)]) -> Vec<String> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, (friendly_name, device_name))| match friendly_name {
+            Some(friendly_name) => {
+                let same_name = |&(other, _): &(Option<&str>, &str)| other == Some(*friendly_name);
+                if names.iter().filter(|name| same_name(name)).count() > 1 {
+                    let number = names[..i].iter().filter(|name| same_name(name)).count() + 1;
+                    format!("{} ({})", friendly_name, number)
+                } else {
+                    friendly_name.to_string()
+                }
+            }
+            None => device_name.to_string(),
+        })
+        .collect()
+}
+
+// Pairs of GDI device names and EDID friendly names for the active displays.
+fn get_friendly_names() -> Result<Vec<(String, String)>> {
+    let mut paths = Vec::new();
+    let mut modes = Vec::new();
     unsafe {
-        let displays = Box::into_raw(Box::default());
-        EnumDisplayMonitors(HDC(0), None, Some(enum_monitor), LPARAM(displays as isize));
-        *Box::from_raw(displays)
+        let mut path_count = 0;
+        let mut mode_count = 0;
+        GetDisplayConfigBufferSizes(QDC_ONLY_ACTIVE_PATHS, &mut path_count, &mut mode_count)?;
+        paths.resize(path_count as usize, DISPLAYCONFIG_PATH_INFO::default());
+        modes.resize(mode_count as usize, DISPLAYCONFIG_MODE_INFO::default());
+        QueryDisplayConfig(
+            QDC_ONLY_ACTIVE_PATHS,
+            &mut path_count,
+            paths.as_mut_ptr(),
+            &mut mode_count,
+            modes.as_mut_ptr(),
+            None,
+        )?;
+        paths.truncate(path_count as usize);
     }
+
+    let mut names = Vec::new();
+    for path in paths {
+        let mut source_name = DISPLAYCONFIG_SOURCE_DEVICE_NAME {
+            header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+                r#type: DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME,
+                size: std::mem::size_of::<DISPLAYCONFIG_SOURCE_DEVICE_NAME>() as u32,
+                adapterId: path.sourceInfo.adapterId,
+                id: path.sourceInfo.id,
+            },
+            ..Default::default()
+        };
+        let mut target_name = DISPLAYCONFIG_TARGET_DEVICE_NAME {
+            header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+                r#type: DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME,
+                size: std::mem::size_of::<DISPLAYCONFIG_TARGET_DEVICE_NAME>() as u32,
+                adapterId: path.targetInfo.adapterId,
+                id: path.targetInfo.id,
+            },
+            ..Default::default()
+        };
+        // Both return a Win32 error code
+        let found = unsafe {
+            DisplayConfigGetDeviceInfo(&mut source_name.header) == 0
+                && DisplayConfigGetDeviceInfo(&mut target_name.header) == 0
+        };
+        let friendly_name = from_wide(&target_name.monitorFriendlyDeviceName);
+        if found && !friendly_name.is_empty() {
+            names.push((from_wide(&source_name.viewGdiDeviceName), friendly_name));
+        }
+    }
+    Ok(names)
 }
 
-extern "system" fn enum_monitor(monitor: HMONITOR, _: HDC, _: *mut RECT, state: LPARAM) -> BOOL {
+fn from_wide(chars: &[u16]) -> String {
+    let length = chars.iter().position(|c| *c == 0).unwrap_or(chars.len());
+    String::from_utf16_lossy(&chars[..length])
+}
+
+// The DXGI output for the display, on whichever adapter drives it. None if
+// no adapter does, e.g. an indirect display.
+pub fn get_display_output(handle: HMONITOR) -> Result<Option<IDXGIOutput>> {
+    let factory: IDXGIFactory1 = unsafe { CreateDXGIFactory1()? };
+    let mut adapter_index = 0;
+    // Both enumerations end with DXGI_ERROR_NOT_FOUND
+    while let Ok(adapter) = unsafe { factory.EnumAdapters1(adapter_index) } {
+        let mut output_index = 0;
+        while let Ok(output) = unsafe { adapter.EnumOutputs(output_index) } {
+            let mut desc = DXGI_OUTPUT_DESC::default();
+            unsafe { output.GetDesc(&mut desc)? };
+            if desc.Monitor == handle {
+                return Ok(Some(output));
+            }
+            output_index += 1;
+        }
+        adapter_index += 1;
+    }
+    Ok(None)
+}
+
+// None if no adapter drives the display, or if DXGI is too old to report
+// colors (IDXGIOutput6 needs Windows 10 1703).
+pub fn get_display_color_info(handle: HMONITOR) -> Result<Option<DisplayColorInfo>> {
+    let output: IDXGIOutput6 = match get_display_output(handle)?.map(|output| output.cast()) {
+        Some(Ok(output)) => output,
+        _ => return Ok(None),
+    };
+    let mut desc = DXGI_OUTPUT_DESC1::default();
+    unsafe { output.GetDesc1(&mut desc)? };
+    Ok(Some(DisplayColorInfo {
+        color_space: desc.ColorSpace,
+        bits_per_color: desc.BitsPerColor,
+        red_primary: desc.RedPrimary,
+        green_primary: desc.GreenPrimary,
+        blue_primary: desc.BluePrimary,
+        white_point: desc.WhitePoint,
+        min_luminance: desc.MinLuminance,
+        max_luminance: desc.MaxLuminance,
+        max_full_frame_luminance: desc.MaxFullFrameLuminance,
+    }))
+}
+
+// The mode the display is in right now.
+pub fn get_current_display_mode(handle: HMONITOR) -> Result<DisplayMode> {
+    let device_name = HSTRING::from(get_display_info(handle)?.name);
+    let mut mode = DEVMODEW {
+        dmSize: std::mem::size_of::<DEVMODEW>() as u16,
+        ..Default::default()
+    };
+    unsafe { EnumDisplaySettingsW(&device_name, ENUM_CURRENT_SETTINGS, &mut mode).ok()? };
+    Ok(DisplayMode {
+        width: mode.dmPelsWidth,
+        height: mode.dmPelsHeight,
+        refresh_rate: mode.dmDisplayFrequency,
+    })
+}
+
+// Largest and fastest first. The driver lists the same size and refresh
+// rate once per color depth and scaling mode, those are only listed once.
+pub fn get_display_modes(handle: HMONITOR) -> Result<Vec<DisplayMode>> {
+    let device_name = HSTRING::from(get_display_info(handle)?.name);
+    let mut modes = Vec::new();
+    let mut mode_index = 0;
+    loop {
+        let mut mode = DEVMODEW {
+            dmSize: std::mem::size_of::<DEVMODEW>() as u16,
+            ..Default::default()
+        };
+        // Fails once we're past the last mode
+        let found = unsafe {
+            EnumDisplaySettingsW(
+                &device_name,
+                ENUM_DISPLAY_SETTINGS_MODE(mode_index),
+                &mut mode,
+            )
+        };
+        if !found.as_bool() {
+            break;
+        }
+        modes.push(DisplayMode {
+            width: mode.dmPelsWidth,
+            height: mode.dmPelsHeight,
+            refresh_rate: mode.dmDisplayFrequency,
+        });
+        mode_index += 1;
+    }
+    modes.sort_unstable_by(|a, b| b.cmp(a));
+    modes.dedup();
+    Ok(modes)
+}
+
+fn enumerate_displays() -> Result<Vec<HMONITOR>> {
+    let mut displays: Vec<HMONITOR> = Vec::new();
     unsafe {
-        let state = Box::leak(Box::from_raw(state.0 as *mut Vec<HMONITOR>));
-        state.push(monitor);
+        EnumDisplayMonitors(
+            HDC(0),
+            None,
+            Some(enum_monitor),
+            LPARAM(&mut displays as *mut _ as isize),
+        )
+        .ok()?;
     }
+    Ok(displays)
+}
+
+extern "system" fn enum_monitor(monitor: HMONITOR, _: HDC, _: *mut RECT, state: LPARAM) -> BOOL {
+    // The vector outlives the call to EnumDisplayMonitors, which is the
+    // only place this gets called from.
+    let displays = unsafe { &mut *(state.0 as *mut Vec<HMONITOR>) };
+    displays.push(monitor);
     true.into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        enumerate_display_info, enumerate_displays, get_display_handle_from_index,
+        get_display_modes, get_display_names, unique_display_names,
+    };
+
+    #[test]
+    fn enumerate_displays_test() {
+        let displays = enumerate_displays().unwrap();
+        assert!(!displays.is_empty());
+        assert!(get_display_handle_from_index(0).is_some());
+        assert!(get_display_handle_from_index(displays.len()).is_none());
+
+        let infos = enumerate_display_info().unwrap();
+        assert_eq!(infos.len(), displays.len());
+        assert!(infos.iter().any(|info| info.is_primary));
+        assert_eq!(get_display_names(&infos).len(), infos.len());
+    }
+
+    #[test]
+    fn unique_display_names_test() {
+        let names = unique_display_names(&[
+            (Some("DELL U2720Q"), "\\\\.\\DISPLAY1"),
+            (None, "\\\\.\\DISPLAY2"),
+            (Some("DELL U2720Q"), "\\\\.\\DISPLAY3"),
+            (Some("LG ULTRAFINE"), "\\\\.\\DISPLAY4"),
+        ]);
+        assert_eq!(
+            names,
+            [
+                "DELL U2720Q (1)",
+                "\\\\.\\DISPLAY2",
+                "DELL U2720Q (2)",
+                "LG ULTRAFINE"
+            ]
+        );
+    }
+
+    #[test]
+    fn display_modes_test() {
+        let display = get_display_handle_from_index(0).unwrap();
+        let modes = get_display_modes(display).unwrap();
+        assert!(!modes.is_empty());
+        // Sorted largest first, without duplicates
+        assert!(modes.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+}
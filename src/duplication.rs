@@ -0,0 +1,453 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{debug, error, info};
+use windows::{
+    core::{ComInterface, Error, Result},
+    Foundation::TimeSpan,
+    Graphics::SizeInt32,
+    Win32::{
+        Foundation::{E_ACCESSDENIED, E_INVALIDARG},
+        Graphics::{
+            Direct3D11::{
+                ID3D11Device, ID3D11DeviceContext, ID3D11Multithread, ID3D11Texture2D,
+                D3D11_BIND_SHADER_RESOURCE, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+            },
+            Dxgi::{
+                Common::{DXGI_MODE_ROTATION_IDENTITY, DXGI_MODE_ROTATION_UNSPECIFIED},
+                IDXGIOutput, IDXGIOutput1, IDXGIOutputDuplication, IDXGIResource,
+                DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_WAIT_TIMEOUT, DXGI_OUTDUPL_DESC,
+                DXGI_OUTDUPL_FRAME_INFO, DXGI_OUTPUT_DESC,
+            },
+            Gdi::HMONITOR,
+        },
+        System::Performance::QueryPerformanceFrequency,
+    },
+};
+
+use crate::{
+    capture::{CaptureFrame, DEFAULT_BUFFER_COUNT},
+    desktop::{attach_to_desktop, attach_to_input_desktop, Desktop, InputDesktop},
+    displays::get_display_output,
+};
+
+// How long each wait for a new frame lasts, which is also how long it can
+// take to notice that we've been stopped.
+const ACQUIRE_TIMEOUT_MS: u32 = 100;
+// How often to try duplicating the display again while the secure desktop
+// keeps us out.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// Captures a display with DXGI desktop duplication on a thread of its own,
+// sending copies of the desktop image as frames. Duplication ends whenever
+// the desktop switches (e.g. to a UAC prompt) or the display mode changes,
+// in which case it's started again on whichever desktop input went to, once
+// we're allowed to. If that fails the capture ends and failed is set.
+pub(crate) struct OutputDuplicator {
+    control: Arc<DuplicationControl>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OutputDuplicator {
+    pub fn new(
+        d3d_device: &ID3D11Device,
+        monitor: HMONITOR,
+        desktop: Desktop,
+        sender: Sender<Option<CaptureFrame>>,
+        source_closed: Arc<AtomicBool>,
+        failed: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let output: IDXGIOutput1 = get_duplicated_output(monitor)?.cast()?;
+        // Failing here is better than failing once the recording has started
+        let duplication = duplicate_output(&output, d3d_device)?;
+        // The frames are copied on our thread while the encoder uses the
+        // same immediate context on its own.
+        let multithread: ID3D11Multithread = d3d_device.cast()?;
+        unsafe { multithread.SetMultithreadProtected(true) };
+
+        let mut frequency = 0;
+        unsafe { QueryPerformanceFrequency(&mut frequency)? };
+        let (release_sender, release_receiver) = channel();
+        let control = Arc::new(DuplicationControl::default());
+        let mut duplication_thread = DuplicationThread {
+            d3d_context: unsafe { d3d_device.GetImmediateContext()? },
+            d3d_device: d3d_device.clone(),
+            output,
+            duplication: Some(duplication),
+            copy_pool: CopyPool::new(release_sender, release_receiver),
+            frequency,
+            control: control.clone(),
+            sender,
+            source_closed,
+            input_desktop: None,
+        };
+        let thread = std::thread::spawn(move || {
+            if !duplication_thread.control.wait_for_start() {
+                return;
+            }
+            // New threads start out on the process' desktop
+            let result = if desktop != Desktop::Default {
+                attach_to_desktop(desktop).and_then(|_| duplication_thread.run())
+            } else {
+                duplication_thread.run()
+            };
+            if let Err(error) = result {
+                error!(
+                    "Error during desktop duplication: {:?} - {}",
+                    error.code(),
+                    error.message()
+                );
+                failed.store(true, Ordering::SeqCst);
+            }
+            let _ = duplication_thread.sender.send(None);
+        });
+
+        Ok(Self {
+            control,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn starter(&self) -> DuplicationStarter {
+        DuplicationStarter {
+            control: self.control.clone(),
+        }
+    }
+}
+
+impl Drop for OutputDuplicator {
+    fn drop(&mut self) {
+        self.control.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DuplicationStarter {
+    control: Arc<DuplicationControl>,
+}
+
+impl DuplicationStarter {
+    pub fn start(&self) {
+        self.control.start();
+    }
+}
+
+#[derive(Copy, Clone, Default, PartialEq)]
+enum DuplicationState {
+    #[default]
+    Created,
+    Started,
+    Stopped,
+}
+
+#[derive(Default)]
+struct DuplicationControl {
+    state: Mutex<DuplicationState>,
+    condvar: Condvar,
+}
+
+impl DuplicationControl {
+    fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == DuplicationState::Created {
+            *state = DuplicationState::Started;
+            self.condvar.notify_all();
+        }
+    }
+
+    fn stop(&self) {
+        *self.state.lock().unwrap() = DuplicationState::Stopped;
+        self.condvar.notify_all();
+    }
+
+    // Returns false if we were stopped before ever being started.
+    fn wait_for_start(&self) -> bool {
+        let state = self
+            .condvar
+            .wait_while(self.state.lock().unwrap(), |state| {
+                *state == DuplicationState::Created
+            })
+            .unwrap();
+        *state == DuplicationState::Started
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.state.lock().unwrap() == DuplicationState::Stopped
+    }
+}
+
+struct DuplicationThread {
+    d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    output: IDXGIOutput1,
+    // Only None while it's being recreated.
+    duplication: Option<IDXGIOutputDuplication>,
+    copy_pool: CopyPool,
+    frequency: i64,
+    control: Arc<DuplicationControl>,
+    sender: Sender<Option<CaptureFrame>>,
+    source_closed: Arc<AtomicBool>,
+    // The desktop we followed input to, if we ever had to.
+    input_desktop: Option<InputDesktop>,
+}
+
+unsafe impl Send for DuplicationThread {}
+impl DuplicationThread {
+    fn run(&mut self) -> Result<()> {
+        while !self.control.is_stopped() {
+            let duplication = self.duplication.clone().unwrap();
+            let mut frame_info = DXGI_OUTDUPL_FRAME_INFO::default();
+            let mut resource = None;
+            let result = unsafe {
+                duplication.AcquireNextFrame(ACQUIRE_TIMEOUT_MS, &mut frame_info, &mut resource)
+            };
+            match result {
+                Ok(()) => {}
+                Err(error) if error.code() == DXGI_ERROR_WAIT_TIMEOUT => continue,
+                Err(error) if error.code() == DXGI_ERROR_ACCESS_LOST => {
+                    if !self.recreate_duplication()? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(error) => return Err(error),
+            }
+
+            let frame = self.copy_frame(&frame_info, resource.unwrap());
+            unsafe { duplication.ReleaseFrame()? };
+            if let Some(frame) = frame? {
+                // Nobody is listening anymore
+                if self.sender.send(Some(frame)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The image we're handed is only ours until the frame is released, so
+    // it's copied into one of our own textures.
+    fn copy_frame(
+        &mut self,
+        frame_info: &DXGI_OUTDUPL_FRAME_INFO,
+        resource: IDXGIResource,
+    ) -> Result<Option<CaptureFrame>> {
+        // Only the mouse moved, which isn't part of the image
+        if frame_info.LastPresentTime == 0 {
+            return Ok(None);
+        }
+        let desktop_texture: ID3D11Texture2D = resource.cast()?;
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { desktop_texture.GetDesc(&mut desc) };
+        let texture = if let Some(texture) = self.copy_pool.acquire(&self.d3d_device, &desc)? {
+            texture
+        } else {
+            debug!("All of the duplicated frames are still in use, dropping a frame...");
+            return Ok(None);
+        };
+        unsafe { self.d3d_context.CopyResource(&texture, &desktop_texture) };
+
+        let content_size = SizeInt32 {
+            Width: desc.Width as i32,
+            Height: desc.Height as i32,
+        };
+        // Present times are in QPC ticks, frame times in 100ns units
+        let system_relative_time = TimeSpan {
+            Duration: (frame_info.LastPresentTime as i128 * 10_000_000 / self.frequency as i128)
+                as i64,
+        };
+        Ok(Some(CaptureFrame::from_duplicated_texture(
+            texture,
+            content_size,
+            system_relative_time,
+            self.copy_pool.release_sender(),
+        )?))
+    }
+
+    // Duplication only works on the desktop receiving input, so we follow it
+    // there first. While that's the secure desktop (and we aren't allowed on
+    // it) we keep trying until it's gone. Returns false if we were stopped
+    // (or the display went away) in the meantime.
+    fn recreate_duplication(&mut self) -> Result<bool> {
+        info!("The desktop switched or the display mode changed, restarting the duplication...");
+        // Only one duplication of the display is allowed at a time
+        self.duplication = None;
+        loop {
+            if self.control.is_stopped() {
+                return Ok(false);
+            }
+            match attach_to_input_desktop() {
+                // Replacing the old one closes it, now that we've left it
+                Ok(input_desktop) => self.input_desktop = Some(input_desktop),
+                Err(error) if error.code() == E_ACCESSDENIED => {
+                    std::thread::sleep(RETRY_INTERVAL);
+                    continue;
+                }
+                Err(error) => {
+                    return Err(Error::new(
+                        error.code(),
+                        format!(
+                            "Unable to follow the input over to its desktop: {}",
+                            error.message()
+                        )
+                        .into(),
+                    ))
+                }
+            }
+            match duplicate_output(&self.output, &self.d3d_device) {
+                Ok(duplication) => {
+                    self.duplication = Some(duplication);
+                    return Ok(true);
+                }
+                Err(error) if error.code() == E_ACCESSDENIED => std::thread::sleep(RETRY_INTERVAL),
+                // Anything else means the display is gone, e.g. it was
+                // disconnected
+                Err(error) => {
+                    info!(
+                        "The display can't be duplicated anymore: {:?} - {}",
+                        error.code(),
+                        error.message()
+                    );
+                    self.source_closed.store(true, Ordering::SeqCst);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+// The size of the display's desktop image, which is what duplicating it
+// produces. Unlike an item's size this doesn't need Windows.Graphics.Capture.
+pub(crate) fn get_duplicated_output_size(monitor: HMONITOR) -> Result<SizeInt32> {
+    let output = get_duplicated_output(monitor)?;
+    let mut desc = DXGI_OUTPUT_DESC::default();
+    unsafe { output.GetDesc(&mut desc)? };
+    let rect = desc.DesktopCoordinates;
+    Ok(SizeInt32 {
+        Width: rect.right - rect.left,
+        Height: rect.bottom - rect.top,
+    })
+}
+
+fn get_duplicated_output(monitor: HMONITOR) -> Result<IDXGIOutput> {
+    get_display_output(monitor)?.ok_or_else(|| {
+        Error::new(
+            E_INVALIDARG,
+            "No adapter drives the display, it can't be duplicated!".into(),
+        )
+    })
+}
+
+fn duplicate_output(
+    output: &IDXGIOutput1,
+    d3d_device: &ID3D11Device,
+) -> Result<IDXGIOutputDuplication> {
+    let duplication = unsafe { output.DuplicateOutput(d3d_device) }.map_err(|error| {
+        if error.code() == E_INVALIDARG {
+            Error::new(
+                E_INVALIDARG,
+                "The display is driven by a different adapter than the one we're using, it can't be duplicated!".into(),
+            )
+        } else {
+            error
+        }
+    })?;
+    // The image isn't rotated for us, unlike with Windows.Graphics.Capture
+    let mut desc = DXGI_OUTDUPL_DESC::default();
+    unsafe { duplication.GetDesc(&mut desc) };
+    if desc.Rotation != DXGI_MODE_ROTATION_IDENTITY
+        && desc.Rotation != DXGI_MODE_ROTATION_UNSPECIFIED
+    {
+        return Err(Error::new(
+            E_INVALIDARG,
+            "Rotated displays can't be recorded with desktop duplication!".into(),
+        ));
+    }
+    Ok(duplication)
+}
+
+// The textures frames are copied into. Closed frames send theirs back here,
+// and at most DEFAULT_BUFFER_COUNT are handed out at once. Past that frames
+// are dropped, the same way a frame pool drops them when all of its buffers
+// are in use.
+struct CopyPool {
+    release_sender: Sender<ID3D11Texture2D>,
+    release_receiver: Receiver<ID3D11Texture2D>,
+    available: Vec<ID3D11Texture2D>,
+    in_use: usize,
+    size: (u32, u32),
+}
+
+impl CopyPool {
+    fn new(
+        release_sender: Sender<ID3D11Texture2D>,
+        release_receiver: Receiver<ID3D11Texture2D>,
+    ) -> Self {
+        Self {
+            release_sender,
+            release_receiver,
+            available: Vec::new(),
+            in_use: 0,
+            size: (0, 0),
+        }
+    }
+
+    fn release_sender(&self) -> Sender<ID3D11Texture2D> {
+        self.release_sender.clone()
+    }
+
+    // None if every texture is still in use.
+    fn acquire(
+        &mut self,
+        d3d_device: &ID3D11Device,
+        desktop_desc: &D3D11_TEXTURE2D_DESC,
+    ) -> Result<Option<ID3D11Texture2D>> {
+        // Textures from before a mode change are the wrong size
+        let size = (desktop_desc.Width, desktop_desc.Height);
+        if size != self.size {
+            self.available.clear();
+            self.size = size;
+        }
+        while let Ok(texture) = self.release_receiver.try_recv() {
+            self.in_use -= 1;
+            let mut desc = D3D11_TEXTURE2D_DESC::default();
+            unsafe { texture.GetDesc(&mut desc) };
+            if (desc.Width, desc.Height) == self.size {
+                self.available.push(texture);
+            }
+        }
+
+        if let Some(texture) = self.available.pop() {
+            self.in_use += 1;
+            return Ok(Some(texture));
+        }
+        if self.in_use >= DEFAULT_BUFFER_COUNT as usize {
+            return Ok(None);
+        }
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: desktop_desc.Width,
+            Height: desktop_desc.Height,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: desktop_desc.Format,
+            SampleDesc: desktop_desc.SampleDesc,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE.0 as u32,
+            ..Default::default()
+        };
+        let mut texture = None;
+        unsafe { d3d_device.CreateTexture2D(&desc, None, Some(&mut texture))? };
+        self.in_use += 1;
+        Ok(texture)
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    os::windows::io::{AsRawHandle, IntoRawHandle},
+};
+
+use windows::{
+    core::{Error, Result},
+    Win32::{
+        Foundation::{E_FAIL, E_INVALIDARG, HANDLE, STG_E_INVALIDFUNCTION, WIN32_ERROR},
+        Media::MediaFoundation::IMFByteStream,
+        Storage::FileSystem::{GetFileType, WriteFile, FILE_TYPE_DISK},
+        System::Com::{STREAM_SEEK, STREAM_SEEK_CUR, STREAM_SEEK_END, STREAM_SEEK_SET},
+    },
+};
+
+use crate::{
+    pipe::create_pipe_byte_stream,
+    stream::{create_byte_stream, StreamTarget},
+};
+
+// Only files on disk can seek, pipes and the like have to be written
+// sequentially.
+pub fn is_seekable_file(file: &File) -> bool {
+    unsafe { GetFileType(raw_handle(file)) == FILE_TYPE_DISK }
+}
+
+// Wraps a file the caller already opened, e.g. a sandboxed host without
+// access to the output's folder. The file has to be opened for writing and
+// is closed once the stream goes away. Files on disk are truncated and
+// written from the start, whatever they held before.
+pub fn open_file_handle(file: File) -> Result<IMFByteStream> {
+    if !is_seekable_file(&file) {
+        return create_pipe_byte_stream(HANDLE(file.into_raw_handle() as isize));
+    }
+    // Writing nothing still fails without write access. Pipes are left out,
+    // where it could block or count as a write to whoever is reading.
+    let mut written = 0;
+    if unsafe { WriteFile(raw_handle(&file), Some(&[]), Some(&mut written), None) }.is_err() {
+        return Err(Error::new(
+            E_INVALIDARG,
+            "The output file has to be opened for writing!".into(),
+        ));
+    }
+    file.set_len(0).map_err(io_error)?;
+    (&file).seek(SeekFrom::Start(0)).map_err(io_error)?;
+    create_byte_stream(FileTarget { file })
+}
+
+fn raw_handle(file: &File) -> HANDLE {
+    HANDLE(file.as_raw_handle() as isize)
+}
+
+fn io_error(error: std::io::Error) -> Error {
+    match error.raw_os_error() {
+        Some(code) => WIN32_ERROR(code as u32).into(),
+        None => Error::new(E_FAIL, error.to_string().into()),
+    }
+}
+
+// A file on disk. &File reads, writes and seeks on its own, so it doesn't
+// need a lock.
+struct FileTarget {
+    file: File,
+}
+
+impl StreamTarget for FileTarget {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        (&self.file).read(buffer).map_err(io_error)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        (&self.file).write_all(data).map_err(io_error)
+    }
+
+    fn seek(&self, offset: i64, origin: STREAM_SEEK) -> Result<u64> {
+        let position = match origin {
+            STREAM_SEEK_SET => SeekFrom::Start(offset as u64),
+            STREAM_SEEK_CUR => SeekFrom::Current(offset),
+            STREAM_SEEK_END => SeekFrom::End(offset),
+            _ => return Err(STG_E_INVALIDFUNCTION.into()),
+        };
+        (&self.file).seek(position).map_err(io_error)
+    }
+
+    fn set_size(&self, size: u64) -> Result<()> {
+        self.file.set_len(size).map_err(io_error)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata().map_err(io_error)?.len())
+    }
+}
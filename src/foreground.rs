@@ -0,0 +1,195 @@
+use std::{cell::RefCell, sync::mpsc::channel, thread::JoinHandle, time::Duration};
+
+use windows::{
+    core::{Error, Result},
+    Win32::{
+        Foundation::{HWND, LPARAM, WPARAM},
+        System::{Console::GetConsoleWindow, Threading::GetCurrentThreadId},
+        UI::{
+            Accessibility::{SetWinEventHook, UnhookWinEvent, HWINEVENTHOOK},
+            WindowsAndMessaging::{
+                DispatchMessageW, GetClassNameW, GetForegroundWindow, GetMessageW, GetShellWindow,
+                IsIconic, IsWindowVisible, KillTimer, PostThreadMessageW, SetTimer,
+                EVENT_SYSTEM_FOREGROUND, MSG, OBJID_WINDOW, WINEVENT_OUTOFCONTEXT,
+                WINEVENT_SKIPOWNPROCESS, WM_QUIT, WM_TIMER,
+            },
+        },
+    },
+};
+
+use crate::apartment::ensure_apartment;
+
+// Windows that can have focus but aren't worth recording. Switching to one of
+// these keeps the capture on the previous window instead.
+const IGNORED_WINDOW_CLASSES: [&str; 4] = [
+    "Shell_TrayWnd",
+    "Shell_SecondaryTrayWnd",
+    "Progman",
+    "WorkerW",
+];
+
+// The foreground window, if it's one we'd follow (see ForegroundWatcher).
+pub fn foreground_window() -> Option<HWND> {
+    let window = unsafe { GetForegroundWindow() };
+    if is_followable_window(window) {
+        Some(window)
+    } else {
+        None
+    }
+}
+
+// Calls back with the new foreground window whenever focus moves, once it
+// has settled for the debounce period so that alt-tabbing through a few
+// windows only reports the last one. The desktop, the taskbar, minimized
+// windows, and our own console are skipped. The callback runs on the
+// watcher's own thread, which is in the MTA.
+pub struct ForegroundWatcher {
+    thread_id: u32,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct WatcherState {
+    debounce: Duration,
+    pending_window: Option<HWND>,
+    timer_id: usize,
+    callback: Box<dyn FnMut(HWND)>,
+}
+
+thread_local! {
+    static WATCHER_STATE: RefCell<Option<WatcherState>> = RefCell::new(None);
+}
+
+impl ForegroundWatcher {
+    pub fn new<F: 'static + Send + FnMut(HWND)>(debounce: Duration, callback: F) -> Result<Self> {
+        let (sender, receiver) = channel();
+        let thread = std::thread::spawn(move || {
+            let _apartment = match ensure_apartment() {
+                Ok(apartment) => apartment,
+                Err(error) => {
+                    let _ = sender.send(Err(error));
+                    return;
+                }
+            };
+            WATCHER_STATE.with(|state| {
+                *state.borrow_mut() = Some(WatcherState {
+                    debounce,
+                    pending_window: None,
+                    timer_id: 0,
+                    callback: Box::new(callback),
+                })
+            });
+            // Out of context hooks are delivered through this thread's
+            // message loop.
+            let hook = unsafe {
+                SetWinEventHook(
+                    EVENT_SYSTEM_FOREGROUND,
+                    EVENT_SYSTEM_FOREGROUND,
+                    None,
+                    Some(on_foreground_changed),
+                    0,
+                    0,
+                    WINEVENT_OUTOFCONTEXT | WINEVENT_SKIPOWNPROCESS,
+                )
+            };
+            if hook.is_invalid() {
+                let _ = sender.send(Err(Error::from_win32()));
+                return;
+            }
+            let _ = sender.send(Ok(unsafe { GetCurrentThreadId() }));
+
+            let mut message = MSG::default();
+            unsafe {
+                while GetMessageW(&mut message, None, 0, 0).into() {
+                    // Thread timers have no window to be dispatched to
+                    if message.message == WM_TIMER {
+                        on_timer();
+                    } else {
+                        DispatchMessageW(&message);
+                    }
+                }
+                UnhookWinEvent(hook);
+            }
+        });
+        let thread_id = receiver.recv().unwrap()?;
+        Ok(Self {
+            thread_id,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ForegroundWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+extern "system" fn on_foreground_changed(
+    _hook: HWINEVENTHOOK,
+    _event: u32,
+    window: HWND,
+    object_id: i32,
+    _child_id: i32,
+    _event_thread: u32,
+    _event_time: u32,
+) {
+    if object_id != OBJID_WINDOW.0 || !is_followable_window(window) {
+        return;
+    }
+    WATCHER_STATE.with(|state| {
+        if let Some(state) = state.borrow_mut().as_mut() {
+            state.pending_window = Some(window);
+            // Passing the existing id restarts the timer
+            state.timer_id = unsafe {
+                SetTimer(
+                    None,
+                    state.timer_id,
+                    state.debounce.as_millis() as u32,
+                    None,
+                )
+            };
+        }
+    });
+}
+
+fn on_timer() {
+    WATCHER_STATE.with(|state| {
+        if let Some(state) = state.borrow_mut().as_mut() {
+            if state.timer_id != 0 {
+                unsafe {
+                    let _ = KillTimer(None, state.timer_id);
+                }
+                state.timer_id = 0;
+            }
+            // The window may have been closed or minimized while we waited
+            if let Some(window) = state.pending_window.take() {
+                if is_followable_window(window) {
+                    (state.callback)(window);
+                }
+            }
+        }
+    });
+}
+
+fn is_followable_window(window: HWND) -> bool {
+    if window.0 == 0 {
+        return false;
+    }
+    unsafe {
+        if !IsWindowVisible(window).as_bool() || IsIconic(window).as_bool() {
+            return false;
+        }
+        if window == GetShellWindow() || window == GetConsoleWindow() {
+            return false;
+        }
+        let mut class_name = [0u16; 256];
+        let length = GetClassNameW(window, &mut class_name);
+        let class_name = String::from_utf16_lossy(&class_name[..length as usize]);
+        !IGNORED_WINDOW_CLASSES.contains(&class_name.as_str())
+    }
+}
@@ -1,9 +1,12 @@
 use std::sync::atomic::{AtomicI32, Ordering};
 use windows::{
-    core::Result,
+    core::{Error, Result},
     Win32::{
-        Foundation::HWND,
-        UI::Input::KeyboardAndMouse::{RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS},
+        Foundation::{ERROR_HOTKEY_ALREADY_REGISTERED, HWND},
+        UI::Input::KeyboardAndMouse::{
+            RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS, MOD_ALT, MOD_CONTROL, MOD_SHIFT,
+            MOD_WIN,
+        },
     },
 };
 
@@ -17,10 +20,29 @@ impl HotKey {
     pub fn new(modifiers: HOT_KEY_MODIFIERS, key: u32) -> Result<Self> {
         let id = unsafe { HOT_KEY_ID.fetch_add(1, Ordering::SeqCst) + 1 };
         unsafe {
-            RegisterHotKey(HWND(0), id, modifiers, key)?;
+            RegisterHotKey(HWND(0), id, modifiers, key).map_err(|error| {
+                // Global hot keys belong to whoever registered them first,
+                // which includes another instance of us.
+                if error.code() == ERROR_HOTKEY_ALREADY_REGISTERED.to_hresult() {
+                    Error::new(
+                        error.code(),
+                        format!(
+                            "{} is already in use by another program (or another instance of this one)!",
+                            hot_key_name(modifiers, key)
+                        )
+                        .into(),
+                    )
+                } else {
+                    error
+                }
+            })?;
         }
         Ok(Self { id })
     }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
 }
 
 impl Drop for HotKey {
@@ -28,3 +50,29 @@ impl Drop for HotKey {
         unsafe { UnregisterHotKey(HWND(0), self.id).ok().unwrap() }
     }
 }
+
+pub fn is_already_registered(error: &Error) -> bool {
+    error.code() == ERROR_HOTKEY_ALREADY_REGISTERED.to_hresult()
+}
+
+// e.g. "SHIFT+CTRL+R", in the same order we print them elsewhere. Only
+// letters and digits are named, anything else shows its virtual key code.
+pub fn hot_key_name(modifiers: HOT_KEY_MODIFIERS, key: u32) -> String {
+    let mut name = String::new();
+    let names = [
+        (MOD_SHIFT, "SHIFT+"),
+        (MOD_CONTROL, "CTRL+"),
+        (MOD_ALT, "ALT+"),
+        (MOD_WIN, "WIN+"),
+    ];
+    for (modifier, modifier_name) in names {
+        if modifiers.0 & modifier.0 != 0 {
+            name.push_str(modifier_name);
+        }
+    }
+    match char::from_u32(key) {
+        Some(key) if key.is_ascii_uppercase() || key.is_ascii_digit() => name.push(key),
+        _ => name.push_str(&format!("0x{:02X}", key)),
+    }
+    name
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use windows::{
+    core::{Result, HSTRING},
+    Win32::{
+        Foundation::GENERIC_WRITE,
+        Graphics::Imaging::{
+            CLSID_WICImagingFactory, GUID_ContainerFormatPng, GUID_WICPixelFormat32bppBGRA,
+            GUID_WICPixelFormat32bppPBGRA, IWICImagingFactory, WICBitmapDitherTypeNone,
+            WICBitmapEncoderNoCache, WICBitmapPaletteTypeCustom,
+        },
+        System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER},
+    },
+};
+
+// Encodes tightly packed or pitched BGRA8 pixels as a PNG using WIC. The
+// pixels are premultiplied, as captured frames are, and get converted to
+// the straight alpha PNG expects. The calling thread needs to have COM
+// initialized.
+pub fn save_bgra_png<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    stride: u32,
+    pixels: &[u8],
+) -> Result<()> {
+    let path = path.as_ref();
+    unsafe {
+        let factory: IWICImagingFactory =
+            CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER)?;
+        let stream = factory.CreateStream()?;
+        stream.InitializeFromFilename(&HSTRING::from(path.as_os_str()), GENERIC_WRITE.0)?;
+
+        let encoder = factory.CreateEncoder(&GUID_ContainerFormatPng, std::ptr::null())?;
+        encoder.Initialize(&stream, WICBitmapEncoderNoCache)?;
+        let frame = {
+            let mut frame = None;
+            let mut options = None;
+            encoder.CreateNewFrame(&mut frame, &mut options)?;
+            frame.unwrap()
+        };
+        frame.Initialize(None)?;
+        frame.SetSize(width, height)?;
+        let mut pixel_format = GUID_WICPixelFormat32bppBGRA;
+        frame.SetPixelFormat(&mut pixel_format)?;
+
+        let bitmap = factory.CreateBitmapFromMemory(
+            width,
+            height,
+            &GUID_WICPixelFormat32bppPBGRA,
+            stride,
+            pixels,
+        )?;
+        let converter = factory.CreateFormatConverter()?;
+        converter.Initialize(
+            &bitmap,
+            &pixel_format,
+            WICBitmapDitherTypeNone,
+            None,
+            0.0,
+            WICBitmapPaletteTypeCustom,
+        )?;
+        frame.WriteSource(&converter, std::ptr::null())?;
+        frame.Commit()?;
+        encoder.Commit()?;
+    }
+    Ok(())
+}
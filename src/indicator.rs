@@ -0,0 +1,266 @@
+use std::{cell::RefCell, sync::mpsc::channel, thread::JoinHandle};
+
+use displayrecorder::{
+    capture::ExcludedWindow,
+    pause::PauseState,
+    video::{
+        hud::{HudMeter, LiveStats, HUD_REFRESH_INTERVAL},
+        progress::ElapsedTime,
+    },
+};
+use log::warn;
+use windows::{
+    core::{w, Error, Result},
+    Win32::{
+        Foundation::{COLORREF, HWND, LPARAM, LRESULT, RECT, WPARAM},
+        Graphics::Gdi::{
+            BeginPaint, CreateSolidBrush, DeleteObject, DrawTextW, Ellipse, EndPaint, FillRect,
+            GetStockObject, InvalidateRect, SelectObject, SetBkMode, SetTextColor, BLACK_BRUSH,
+            DT_LEFT, DT_SINGLELINE, DT_VCENTER, HBRUSH, NULL_PEN, PAINTSTRUCT, TRANSPARENT,
+        },
+        System::{LibraryLoader::GetModuleHandleW, Threading::GetCurrentThreadId},
+        UI::WindowsAndMessaging::{
+            CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetClientRect,
+            GetMessageW, KillTimer, PostThreadMessageW, RegisterClassW, SetLayeredWindowAttributes,
+            SetTimer, LWA_ALPHA, MSG, WM_PAINT, WM_QUIT, WM_TIMER, WNDCLASSW, WS_EX_LAYERED,
+            WS_EX_NOACTIVATE, WS_EX_TOOLWINDOW, WS_EX_TOPMOST, WS_EX_TRANSPARENT, WS_POPUP,
+            WS_VISIBLE,
+        },
+    },
+};
+
+// In physical pixels, the process is per-monitor DPI aware.
+const INDICATOR_WIDTH: i32 = 150;
+const INDICATOR_HEIGHT: i32 = 32;
+const INDICATOR_MARGIN: i32 = 16;
+// With the HUD, its line goes under the elapsed time.
+const HUD_WIDTH: i32 = 280;
+// How opaque the indicator is, out of 255.
+const INDICATOR_ALPHA: u8 = 200;
+// How often the elapsed time is redrawn, in milliseconds.
+const REFRESH_INTERVAL: u32 = 500;
+
+const RECORDING_COLOR: COLORREF = COLORREF(0x000000ff);
+const PAUSED_COLOR: COLORREF = COLORREF(0x0000c0ff);
+const TEXT_COLOR: COLORREF = COLORREF(0x00ffffff);
+
+struct IndicatorState {
+    elapsed_time: ElapsedTime,
+    pause_state: PauseState,
+    hud_meter: Option<HudMeter>,
+}
+
+thread_local! {
+    static INDICATOR_STATE: RefCell<Option<IndicatorState>> = RefCell::new(None);
+}
+
+// A small always-on-top window in the corner of the display being recorded,
+// showing a red dot (orange while paused) and the elapsed time. It's hidden
+// from the capture itself where Windows supports that. Clicks go through it.
+// The window lives on a thread of its own with its own message loop, so it
+// keeps updating whether the main thread is pumping hot key messages or
+// waiting on the console. With live stats it doubles as the HUD, showing the
+// frame rate, dropped frames and bit rate on a second line.
+pub struct RecordingIndicator {
+    thread_id: u32,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RecordingIndicator {
+    pub fn new(
+        display_rect: RECT,
+        elapsed_time: ElapsedTime,
+        pause_state: PauseState,
+        live_stats: Option<LiveStats>,
+    ) -> Result<Self> {
+        let (sender, receiver) = channel();
+        let thread = std::thread::spawn(move || {
+            let show_hud = live_stats.is_some();
+            INDICATOR_STATE.with(|state| {
+                *state.borrow_mut() = Some(IndicatorState {
+                    elapsed_time,
+                    pause_state,
+                    hud_meter: live_stats.map(HudMeter::new),
+                })
+            });
+            let window = match create_window(display_rect, show_hud) {
+                Ok(window) => window,
+                Err(error) => {
+                    let _ = sender.send(Err(error));
+                    return;
+                }
+            };
+            // Without exclusion the indicator shows up in the recording, but
+            // that's no reason not to record.
+            let _excluded = match ExcludedWindow::new(window) {
+                Ok(excluded) => Some(excluded),
+                Err(error) => {
+                    warn!(
+                        "The indicator will show up in the recording: {}",
+                        error.message()
+                    );
+                    None
+                }
+            };
+            let _ = sender.send(Ok(unsafe { GetCurrentThreadId() }));
+
+            unsafe {
+                let refresh_interval = if show_hud {
+                    HUD_REFRESH_INTERVAL.as_millis() as u32
+                } else {
+                    REFRESH_INTERVAL
+                };
+                let timer_id = SetTimer(window, 1, refresh_interval, None);
+                let mut message = MSG::default();
+                while GetMessageW(&mut message, None, 0, 0).into() {
+                    DispatchMessageW(&message);
+                }
+                let _ = KillTimer(window, timer_id);
+                let _ = DestroyWindow(window);
+            }
+        });
+        let thread_id = receiver.recv().unwrap()?;
+        Ok(Self {
+            thread_id,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for RecordingIndicator {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn create_window(display_rect: RECT, show_hud: bool) -> Result<HWND> {
+    let (width, height) = if show_hud {
+        (HUD_WIDTH, INDICATOR_HEIGHT * 2)
+    } else {
+        (INDICATOR_WIDTH, INDICATOR_HEIGHT)
+    };
+    let instance = unsafe { GetModuleHandleW(None)? };
+    let class_name = w!("displayrecorder.RecordingIndicator");
+    let class = WNDCLASSW {
+        hInstance: instance.into(),
+        lpszClassName: class_name,
+        lpfnWndProc: Some(wndproc),
+        ..Default::default()
+    };
+    // The class is still around if an earlier recording showed an indicator
+    unsafe { RegisterClassW(&class) };
+
+    unsafe {
+        let window = CreateWindowExW(
+            WS_EX_LAYERED | WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE | WS_EX_TRANSPARENT,
+            class_name,
+            w!("Recording"),
+            WS_POPUP | WS_VISIBLE,
+            display_rect.right - width - INDICATOR_MARGIN,
+            display_rect.top + INDICATOR_MARGIN,
+            width,
+            height,
+            None,
+            None,
+            instance,
+            None,
+        );
+        if window.0 == 0 {
+            return Err(Error::from_win32());
+        }
+        SetLayeredWindowAttributes(window, COLORREF(0), INDICATOR_ALPHA, LWA_ALPHA)?;
+        Ok(window)
+    }
+}
+
+extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match message {
+        WM_TIMER => unsafe {
+            InvalidateRect(window, None, false);
+        },
+        WM_PAINT => unsafe {
+            let (seconds, paused, hud_text) = INDICATOR_STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let state = state.as_mut().unwrap();
+                (
+                    state.elapsed_time.get().as_secs(),
+                    state.pause_state.is_paused(),
+                    state
+                        .hud_meter
+                        .as_mut()
+                        .map(|hud_meter| hud_meter.text().to_owned()),
+                )
+            });
+            let mut paint = PAINTSTRUCT::default();
+            let hdc = BeginPaint(window, &mut paint);
+            let mut client_rect = RECT::default();
+            let _ = GetClientRect(window, &mut client_rect);
+            FillRect(hdc, &client_rect, HBRUSH(GetStockObject(BLACK_BRUSH).0));
+
+            let dot_size = INDICATOR_HEIGHT / 2;
+            let dot_top = (INDICATOR_HEIGHT - dot_size) / 2;
+            let brush = CreateSolidBrush(if paused {
+                PAUSED_COLOR
+            } else {
+                RECORDING_COLOR
+            });
+            let previous_brush = SelectObject(hdc, brush);
+            let previous_pen = SelectObject(hdc, GetStockObject(NULL_PEN));
+            Ellipse(
+                hdc,
+                dot_top,
+                dot_top,
+                dot_top + dot_size,
+                dot_top + dot_size,
+            );
+            SelectObject(hdc, previous_pen);
+            SelectObject(hdc, previous_brush);
+            DeleteObject(brush);
+
+            let mut text: Vec<u16> = format!(
+                "{} {:02}:{:02}:{:02}",
+                if paused { "PAUSED" } else { "REC" },
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )
+            .encode_utf16()
+            .collect();
+            let mut text_rect = RECT {
+                left: dot_top * 2 + dot_size,
+                bottom: INDICATOR_HEIGHT,
+                ..client_rect
+            };
+            SetBkMode(hdc, TRANSPARENT);
+            SetTextColor(hdc, TEXT_COLOR);
+            DrawTextW(
+                hdc,
+                &mut text,
+                &mut text_rect,
+                DT_LEFT | DT_SINGLELINE | DT_VCENTER,
+            );
+            if let Some(hud_text) = hud_text {
+                let mut hud_text: Vec<u16> = hud_text.encode_utf16().collect();
+                let mut hud_rect = RECT {
+                    left: dot_top,
+                    top: INDICATOR_HEIGHT,
+                    ..client_rect
+                };
+                DrawTextW(
+                    hdc,
+                    &mut hud_text,
+                    &mut hud_rect,
+                    DT_LEFT | DT_SINGLELINE | DT_VCENTER,
+                );
+            }
+            EndPaint(window, &paint);
+        },
+        _ => return unsafe { DefWindowProcW(window, message, wparam, lparam) },
+    }
+    LRESULT(0)
+}
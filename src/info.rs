@@ -0,0 +1,359 @@
+use displayrecorder::{
+    apartment::ensure_apartment,
+    capture::window_exclusion_supported,
+    displays::{
+        enumerate_display_info, get_display_color_info, get_display_modes, get_display_names,
+        DisplayColorInfo, DisplayInfo, DisplayMode,
+    },
+    media::{enumerate_audio_capture_devices, MF_VERSION},
+    process_audio::{process_loopback_supported, windows_build_number},
+    video::mf::encoder_device::VideoEncoderDevice,
+};
+use windows::{
+    core::{h, Result, RuntimeName, HSTRING},
+    Foundation::Metadata::ApiInformation,
+    Graphics::Capture::GraphicsCaptureSession,
+    Win32::Media::MediaFoundation::{MFStartup, MFSTARTUP_FULL},
+};
+
+use crate::{borderless_capture_supported, win32_programmatic_capture_supported};
+
+struct DisplayEntry {
+    info: DisplayInfo,
+    // What --display-name matches, see get_display_names.
+    display_name: String,
+    // Color info isn't available for every display, see get_display_color_info.
+    color_info: Option<DisplayColorInfo>,
+    modes: Vec<DisplayMode>,
+    // Why the color info or modes couldn't be read. The display is still
+    // listed, without them, along with the rest.
+    error: Option<String>,
+}
+
+// What this build of displayrecorder can do on this machine. Each of the
+// support checks main.rs gates features on shows up here.
+struct Capabilities {
+    version: &'static str,
+    windows_build: Option<u32>,
+    supported: Vec<(&'static str, &'static str, bool)>,
+}
+
+struct Info {
+    capabilities: Capabilities,
+    displays: Vec<DisplayEntry>,
+    audio_devices: Vec<String>,
+    encoders: Vec<VideoEncoderDevice>,
+}
+
+fn get_capabilities() -> Result<Capabilities> {
+    Ok(Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        windows_build: windows_build_number(),
+        supported: vec![
+            (
+                "windows_graphics_capture",
+                "Windows.Graphics.Capture",
+                ApiInformation::IsTypePresent(&HSTRING::from(GraphicsCaptureSession::NAME))?
+                    && GraphicsCaptureSession::IsSupported()?,
+            ),
+            (
+                "programmatic_capture",
+                "Programmatic capture",
+                win32_programmatic_capture_supported()?,
+            ),
+            (
+                "borderless_capture",
+                "Borderless capture",
+                borderless_capture_supported(),
+            ),
+            (
+                "cursor_toggle",
+                "Hiding the cursor",
+                ApiInformation::IsPropertyPresent(
+                    &HSTRING::from(GraphicsCaptureSession::NAME),
+                    h!("IsCursorCaptureEnabled"),
+                )?,
+            ),
+            (
+                "window_exclusion",
+                "Window exclusion",
+                window_exclusion_supported()?,
+            ),
+            (
+                "process_audio",
+                "Process audio",
+                process_loopback_supported(),
+            ),
+            (
+                "hardware_h264",
+                "Hardware H264 encoding",
+                !VideoEncoderDevice::enumerate()?.is_empty(),
+            ),
+            (
+                "software_h264",
+                "Software H264 encoding",
+                !VideoEncoderDevice::enumerate_software()?.is_empty(),
+            ),
+        ],
+    })
+}
+
+// Only what the capabilities subcommand needs, which skips enumerating
+// displays and devices.
+pub fn print_capabilities(json: bool) -> Result<()> {
+    let _apartment = ensure_apartment()?;
+    unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? }
+
+    let capabilities = get_capabilities()?;
+    if json {
+        println!("{{{}}}", format_capabilities_json(&capabilities));
+    } else {
+        print_capabilities_text(&capabilities);
+    }
+    Ok(())
+}
+
+pub fn print_info(json: bool) -> Result<()> {
+    let _apartment = ensure_apartment()?;
+    unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? }
+
+    let displays = enumerate_display_info()?;
+    let display_names = get_display_names(&displays);
+    let info = Info {
+        capabilities: get_capabilities()?,
+        displays: displays
+            .into_iter()
+            .zip(display_names)
+            .map(|(display, display_name)| {
+                let details = get_display_color_info(display.handle)
+                    .and_then(|color_info| Ok((color_info, get_display_modes(display.handle)?)));
+                let (color_info, modes, error) = match details {
+                    Ok((color_info, modes)) => (color_info, modes, None),
+                    Err(error) => (
+                        None,
+                        Vec::new(),
+                        Some(format!("{:?} - {}", error.code(), error.message())),
+                    ),
+                };
+                DisplayEntry {
+                    info: display,
+                    display_name,
+                    color_info,
+                    modes,
+                    error,
+                }
+            })
+            .collect(),
+        audio_devices: enumerate_audio_capture_devices()?,
+        encoders: VideoEncoderDevice::enumerate()?,
+    };
+
+    if json {
+        println!("{}", format_json(&info));
+    } else {
+        print_text(&info);
+    }
+    Ok(())
+}
+
+fn print_capabilities_text(capabilities: &Capabilities) {
+    println!(
+        "displayrecorder {} on Windows build {}",
+        capabilities.version,
+        capabilities
+            .windows_build
+            .map_or_else(|| "unknown".to_owned(), |build| build.to_string())
+    );
+    println!("Capture support:");
+    for (_, name, supported) in &capabilities.supported {
+        println!("  {}: {}", name, if *supported { "yes" } else { "no" });
+    }
+}
+
+fn print_text(info: &Info) {
+    print_capabilities_text(&info.capabilities);
+    println!("Displays ({}):", info.displays.len());
+    for (i, entry) in info.displays.iter().enumerate() {
+        let display = &entry.info;
+        let rect = &display.rect;
+        // Displays without a friendly name go by their device name
+        let device_name = if entry.display_name == display.name {
+            String::new()
+        } else {
+            format!(" [{}]", display.name)
+        };
+        println!(
+            "  {} - {}{} ({}x{} at {},{}){}",
+            i,
+            entry.display_name,
+            device_name,
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+            rect.left,
+            rect.top,
+            if display.is_primary { " primary" } else { "" }
+        );
+        if let Some(error) = entry.error.as_ref() {
+            println!("      Unable to read the display's details: {}", error);
+        }
+        if let Some(color_info) = entry.color_info.as_ref() {
+            println!(
+                "      {}, {} bits per color, {}-{} nits",
+                color_info.description(),
+                color_info.bits_per_color,
+                color_info.min_luminance,
+                color_info.max_luminance
+            );
+        }
+        // One line per size, with every refresh rate it supports
+        let mut modes = entry.modes.iter().peekable();
+        while let Some(mode) = modes.next() {
+            let mut refresh_rates = vec![mode.refresh_rate.to_string()];
+            while let Some(next) =
+                modes.next_if(|next| next.width == mode.width && next.height == mode.height)
+            {
+                refresh_rates.push(next.refresh_rate.to_string());
+            }
+            println!(
+                "      {}x{} at {} Hz",
+                mode.width,
+                mode.height,
+                refresh_rates.join(", ")
+            );
+        }
+    }
+    println!("Audio devices ({}):", info.audio_devices.len());
+    for (i, audio_device) in info.audio_devices.iter().enumerate() {
+        println!("  {} - {}", i, audio_device);
+    }
+    println!("Encoders ({}):", info.encoders.len());
+    for (i, encoder) in info.encoders.iter().enumerate() {
+        println!(
+            "  {} - {} (H264, {})",
+            i,
+            encoder.display_name(),
+            encoder.vendor_id().unwrap_or("unknown vendor")
+        );
+    }
+}
+
+// The members of the capabilities object, without the braces, so that the
+// info report can add its own next to them.
+fn format_capabilities_json(capabilities: &Capabilities) -> String {
+    let supported: Vec<_> = capabilities
+        .supported
+        .iter()
+        .map(|(key, _, supported)| format!("\"{}\":{}", key, supported))
+        .collect();
+    format!(
+        "\"version\":{},\"windows_build\":{},\"capabilities\":{{{}}}",
+        json_string(capabilities.version),
+        capabilities
+            .windows_build
+            .map_or_else(|| "null".to_owned(), |build| build.to_string()),
+        supported.join(",")
+    )
+}
+
+fn format_json(info: &Info) -> String {
+    let displays: Vec<_> = info
+        .displays
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let display = &entry.info;
+            let rect = &display.rect;
+            let color = entry.color_info.map_or_else(|| "null".to_owned(), |color_info| {
+                format!(
+                    "{{\"hdr\":{},\"wide_gamut\":{},\"bits_per_color\":{},\"min_luminance\":{},\"max_luminance\":{},\"max_full_frame_luminance\":{}}}",
+                    color_info.is_hdr(),
+                    color_info.is_wide_gamut(),
+                    color_info.bits_per_color,
+                    color_info.min_luminance,
+                    color_info.max_luminance,
+                    color_info.max_full_frame_luminance
+                )
+            });
+            let modes: Vec<_> = entry
+                .modes
+                .iter()
+                .map(|mode| {
+                    format!(
+                        "{{\"width\":{},\"height\":{},\"refresh_rate\":{}}}",
+                        mode.width, mode.height, mode.refresh_rate
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"index\":{},\"name\":{},\"friendly_name\":{},\"display_name\":{},\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"primary\":{},\"color\":{},\"modes\":[{}],\"error\":{}}}",
+                i,
+                json_string(&display.name),
+                display
+                    .friendly_name
+                    .as_deref()
+                    .map(json_string)
+                    .unwrap_or_else(|| "null".to_owned()),
+                json_string(&entry.display_name),
+                rect.left,
+                rect.top,
+                rect.right - rect.left,
+                rect.bottom - rect.top,
+                display.is_primary,
+                color,
+                modes.join(","),
+                entry
+                    .error
+                    .as_deref()
+                    .map(json_string)
+                    .unwrap_or_else(|| "null".to_owned())
+            )
+        })
+        .collect();
+    let audio_devices: Vec<_> = info
+        .audio_devices
+        .iter()
+        .enumerate()
+        .map(|(i, name)| format!("{{\"index\":{},\"name\":{}}}", i, json_string(name)))
+        .collect();
+    let encoders: Vec<_> = info
+        .encoders
+        .iter()
+        .enumerate()
+        .map(|(i, encoder)| {
+            format!(
+                "{{\"index\":{},\"name\":{},\"codec\":\"H264\",\"vendor_id\":{}}}",
+                i,
+                json_string(encoder.display_name()),
+                encoder
+                    .vendor_id()
+                    .map(json_string)
+                    .unwrap_or_else(|| "null".to_owned())
+            )
+        })
+        .collect();
+    format!(
+        "{{{},\"displays\":[{}],\"audio_devices\":[{}],\"encoders\":[{}]}}",
+        format_capabilities_json(&info.capabilities),
+        displays.join(","),
+        audio_devices.join(","),
+        encoders.join(",")
+    )
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
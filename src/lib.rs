@@ -0,0 +1,25 @@
+pub mod apartment;
+pub mod cancellation;
+pub mod capture;
+pub mod capture_api;
+pub mod capture_bit_depth;
+pub mod capture_source;
+pub mod d3d;
+pub mod desktop;
+pub mod displays;
+mod duplication;
+pub mod file_handle;
+pub mod foreground;
+pub mod image;
+pub mod media;
+pub mod pause;
+pub mod pipe;
+pub mod process;
+pub mod process_audio;
+pub mod raw_frame;
+pub mod recorder;
+pub mod resolution;
+pub mod secure_desktop;
+mod stream;
+pub mod video;
+pub mod window;
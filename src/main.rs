@@ -1,81 +1,128 @@
 mod args;
-mod capture;
-mod d3d;
-mod displays;
+mod benchmark;
+mod chapters;
+mod disk_space;
 mod hotkey;
-mod media;
-mod resolution;
-mod video;
+mod indicator;
+mod info;
+mod progress;
+mod region_picker;
 
-use std::{path::Path, time::Duration};
+use std::{
+    io::{IsTerminal, Write},
+    os::windows::process::CommandExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use args::Args;
+use args::{clamp_frame_rate, Args, MAX_FRAME_RATE};
+use chapters::{chapters_path, format_timestamp, ChapterMarkers};
 use clap::Parser;
-use hotkey::HotKey;
-use video::{
-    backend::EncoderBackend,
-    encoding_session::{VideoEncoderSessionFactory, VideoEncodingSession},
-    mf::encoding_session::MFVideoEncodingSessionFactory,
-    wmt::encoding_session::WMTVideoEncodingSessionFactory,
+use disk_space::check_disk_space;
+use displayrecorder::{
+    apartment::ensure_apartment,
+    cancellation::StopReason,
+    capture_api::CaptureApi,
+    capture_bit_depth::CaptureBitDepth,
+    capture_source::CaptureSource,
+    desktop::{attach_to_desktop, Desktop},
+    displays::{
+        enumerate_display_info, find_display_by_name, get_display_color_info,
+        get_display_handle_from_index, get_display_info, get_display_names,
+    },
+    media::MF_VERSION,
+    pause::PauseState,
+    pipe::is_named_pipe_path,
+    process::find_process_id,
+    process_audio::{process_loopback_supported, PROCESS_LOOPBACK_MIN_BUILD},
+    recorder::{Recorder, RecorderConfig},
+    video::{
+        audio_codec::AudioCodec,
+        backend::EncoderBackend,
+        bit_rate::BitRate,
+        chroma::Chroma,
+        container::Container,
+        encoder_input::EncoderInput,
+        encoding_session::{StopOutcome, VideoEncodingSettings},
+        fit::Fit,
+        hud::LiveStats,
+        input_color_space::InputColorSpace,
+        latency::nominal_pipeline_latency,
+        mf::{
+            encoder_device::VideoEncoderDevice,
+            remux::remux_file,
+            verify::{verify_recording, VerifyOutcome},
+        },
+        output_stream::is_null_output_path,
+        progress::{FirstFrameCallback, ProgressCallback},
+        raw_format::RawFormat,
+        scaler::Scaler,
+        timestamp_base::TimestampBase,
+        watermark::WatermarkSettings,
+    },
 };
+use hotkey::HotKey;
+use indicator::RecordingIndicator;
+use log::{error, info, warn, Level, LevelFilter};
+use progress::ProgressLine;
+use region_picker::select_region;
 use windows::{
-    core::{h, Result, RuntimeName, HSTRING},
+    core::{h, Error, Result, RuntimeName, HSTRING},
     Foundation::Metadata::ApiInformation,
     Graphics::{
-        Capture::{
-            GraphicsCaptureAccess, GraphicsCaptureAccessKind, GraphicsCaptureItem,
-            GraphicsCaptureSession,
-        },
+        Capture::{GraphicsCaptureAccess, GraphicsCaptureAccessKind, GraphicsCaptureSession},
         SizeInt32,
     },
-    Storage::{
-        CreationCollisionOption, FileAccessMode, StorageFolder, Streams::IRandomAccessStream,
-    },
     Win32::{
-        Foundation::{HWND, MAX_PATH},
-        Graphics::Direct3D11::ID3D11Device,
+        Foundation::{E_INVALIDARG, HWND, LPARAM, WPARAM},
+        Graphics::Gdi::HMONITOR,
         Media::MediaFoundation::{MFStartup, MFSTARTUP_FULL},
-        Storage::FileSystem::GetFullPathNameW,
         System::{
             Diagnostics::Debug::{DebugBreak, IsDebuggerPresent},
-            Threading::GetCurrentProcessId,
-            WinRT::{RoInitialize, RO_INIT_MULTITHREADED},
+            Threading::{GetCurrentProcessId, GetCurrentThreadId},
         },
         UI::{
+            HiDpi::{SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2},
             Input::KeyboardAndMouse::{MOD_CONTROL, MOD_SHIFT},
-            WindowsAndMessaging::{DispatchMessageW, GetMessageW, MSG, WM_HOTKEY},
+            WindowsAndMessaging::{
+                DispatchMessageW, GetMessageW, PostThreadMessageW, MSG, WM_HOTKEY, WM_QUIT,
+            },
         },
     },
 };
 
-use crate::{
-    capture::create_capture_item_for_monitor, d3d::create_d3d_device,
-    displays::get_display_handle_from_index, media::MF_VERSION, resolution::Resolution,
-    video::mf::encoder_device::VideoEncoderDevice,
-};
-
-#[allow(clippy::too_many_arguments)]
-fn run(
-    display_index: usize,
-    output_path: &str,
-    bit_rate: u32,
-    frame_rate: u32,
-    resolution: Resolution,
-    encoder_index: usize,
-    borderless: bool,
-    verbose: bool,
+// How run goes about the recording, on top of what the config records.
+struct RunOptions<'a> {
     wait_for_debugger: bool,
     console_mode: bool,
-    backend: EncoderBackend,
-) -> Result<()> {
-    unsafe {
-        RoInitialize(RO_INIT_MULTITHREADED)?;
-    }
+    progress_line: Option<Arc<ProgressLine>>,
+    stop_timeout: Option<Duration>,
+    check_space: bool,
+    show_indicator: bool,
+    show_hud: bool,
+    remux_path: Option<&'a Path>,
+    verify: bool,
+}
+
+fn run(config: &RecorderConfig, options: RunOptions) -> Result<()> {
+    let RunOptions {
+        wait_for_debugger,
+        console_mode,
+        progress_line,
+        stop_timeout,
+        check_space,
+        show_indicator,
+        show_hud,
+        remux_path,
+        verify,
+    } = options;
+    let _apartment = ensure_apartment()?;
     unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? }
 
     if wait_for_debugger {
         let pid = unsafe { GetCurrentProcessId() };
-        println!("Waiting for a debugger to attach (PID: {})...", pid);
+        info!("Waiting for a debugger to attach (PID: {})...", pid);
         loop {
             if unsafe { IsDebuggerPresent().into() } {
                 break;
@@ -92,86 +139,202 @@ fn run(
         exit_with_error("The required screen capture features are not supported on this device for this release of Windows!\nPlease update your operating system (minimum: Windows 10 Version 1903, Build 18362).");
     }
 
-    if verbose {
-        println!(
-            "Using index \"{}\" and path \"{}\".",
-            display_index, output_path
-        );
+    if !check_disk_space(config, check_space)? {
+        exit_with_error("Not enough free space on the output drive!");
     }
 
-    // Get the display handle using the provided index
-    let display_handle = get_display_handle_from_index(display_index)
-        .expect("The provided display index was out of bounds!");
-    let item = create_capture_item_for_monitor(display_handle)?;
-
-    // Resolve encoding settings
-    let resolution = if let Some(resolution) = resolution.get_size() {
-        resolution
-    } else {
-        item.Size()?
-    };
-    let bit_rate = bit_rate * 1000000;
-    let session_factory = create_encoding_session_factory(backend, encoder_index, verbose)?;
-
-    // Create our file
-    let path = unsafe {
-        let mut new_path = vec![0u16; MAX_PATH as usize];
-        let length = GetFullPathNameW(&HSTRING::from(output_path), Some(&mut new_path), None);
-        new_path.resize(length as usize, 0);
-        String::from_utf16(&new_path).unwrap()
-    };
-    let path = Path::new(&path);
-    let parent_folder_path = path.parent().unwrap();
-    let parent_folder = StorageFolder::GetFolderFromPathAsync(&HSTRING::from(
-        parent_folder_path.as_os_str().to_str().unwrap(),
-    ))?
-    .get()?;
-    let file_name = path.file_name().unwrap();
-    let file = parent_folder
-        .CreateFileAsync(
-            &HSTRING::from(file_name.to_str().unwrap()),
-            CreationCollisionOption::ReplaceExisting,
-        )?
-        .get()?;
-
     // Start the recording
     {
-        let stream = file.OpenAsync(FileAccessMode::ReadWrite)?.get()?;
-        let d3d_device = create_d3d_device()?;
-        let mut session = create_encoding_session(
-            d3d_device,
-            item,
-            borderless,
-            &session_factory,
-            resolution,
-            bit_rate,
-            frame_rate,
-            stream,
-        )?;
-        if !console_mode {
+        let mut recorder = Recorder::new(config)?;
+        let cancellation_token = recorder.cancellation_token();
+        // Without the hot keys there's no way to start the recording, so we
+        // record right away and stop on ENTER instead.
+        let hot_keys = if console_mode {
+            None
+        } else {
+            match register_hot_keys() {
+                Ok(hot_keys) => Some(hot_keys),
+                Err(error) if hotkey::is_already_registered(&error) => {
+                    warn!(
+                        "{} Falling back to console mode, the recording starts now.",
+                        error.message()
+                    );
+                    None
+                }
+                Err(error) => return Err(error),
+            }
+        };
+        let mut indicator = None;
+        let mut chapters = ChapterMarkers::new(config.settings.playback_rate());
+        if let Some(hot_keys) = hot_keys.as_ref() {
+            // The recording can end on its own (e.g. --duration), in which
+            // case we need to break out of the message loop.
+            let thread_id = unsafe { GetCurrentThreadId() };
+            cancellation_token.on_cancelled(move || unsafe {
+                let _ = PostThreadMessageW(thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+            });
             let mut is_recording = false;
-            pump_messages(|| -> Result<bool> {
-                Ok(if !is_recording {
-                    is_recording = true;
-                    println!("Starting recording...");
-                    session.start()?;
-                    false
-                } else {
-                    true
+            let mut snapshot_count = 0;
+            let mut display_index = config.display_index;
+            // Armed recordings are already running, they just drop frames
+            // until they're resumed.
+            if recorder.is_paused() {
+                is_recording = true;
+                recorder.start()?;
+                if show_indicator {
+                    indicator = create_indicator(config, &recorder, display_index, show_hud);
+                }
+                info!("The recording is armed, press SHIFT+CTRL+P to start it...");
+            }
+            pump_messages(hot_keys, |action| -> Result<bool> {
+                Ok(match action {
+                    HotKeyAction::ToggleRecording => {
+                        if !is_recording {
+                            is_recording = true;
+                            info!("Starting recording...");
+                            recorder.start()?;
+                            if show_indicator {
+                                indicator =
+                                    create_indicator(config, &recorder, display_index, show_hud);
+                            }
+                            false
+                        } else {
+                            true
+                        }
+                    }
+                    HotKeyAction::Snapshot => {
+                        if is_recording {
+                            snapshot_count += 1;
+                            let path = snapshot_path(&config.output_path, snapshot_count);
+                            // Not being able to take a snapshot shouldn't end the recording
+                            if let Err(error) = recorder.snapshot(&path) {
+                                warn!("Unable to take a snapshot: {}", error.message());
+                            }
+                        }
+                        false
+                    }
+                    HotKeyAction::TogglePause => {
+                        if is_recording {
+                            if recorder.is_paused() {
+                                recorder.resume();
+                                info!("Recording resumed.");
+                            } else if let Err(error) = recorder.pause() {
+                                warn!("Unable to pause the recording: {}", error.message());
+                            } else {
+                                info!("Recording paused.");
+                            }
+                        }
+                        false
+                    }
+                    HotKeyAction::MarkChapter => {
+                        if is_recording {
+                            let elapsed = recorder.elapsed();
+                            if let Some(number) = chapters.mark(elapsed) {
+                                info!(
+                                    "Marked chapter {} at {}.",
+                                    number,
+                                    format_timestamp(elapsed)
+                                );
+                            }
+                        }
+                        false
+                    }
+                    HotKeyAction::NextDisplay => {
+                        if is_recording {
+                            if let Some(next_index) =
+                                switch_to_next_display(config, &mut recorder, display_index)
+                            {
+                                display_index = next_index;
+                                // The indicator follows the recording over
+                                if indicator.is_some() {
+                                    indicator = None;
+                                    indicator = create_indicator(
+                                        config,
+                                        &recorder,
+                                        display_index,
+                                        show_hud,
+                                    );
+                                }
+                            }
+                        }
+                        false
+                    }
                 })
             })?;
-            println!("Stopping recording...");
         } else {
-            session.start()?;
-            pause();
+            let (sender, receiver) = std::sync::mpsc::channel();
+            cancellation_token.on_cancelled(move || {
+                let _ = sender.send(());
+            });
+            recorder.start()?;
+            if show_indicator {
+                indicator = create_indicator(config, &recorder, config.display_index, show_hud);
+            }
+            std::thread::spawn({
+                let cancellation_token = cancellation_token.clone();
+                let pause_state = recorder
+                    .is_paused()
+                    .then(|| config.settings.pause_state.clone());
+                move || {
+                    if let Some(pause_state) = pause_state {
+                        println!("Press ENTER to start recording...");
+                        wait_for_enter();
+                        pause_state.resume();
+                    }
+                    pause();
+                    cancellation_token.cancel();
+                }
+            });
+            receiver.recv().unwrap();
+        }
+        // Cancel first so that nothing reports progress after we clear the line.
+        cancellation_token.cancel();
+        drop(indicator);
+        if let Some(progress_line) = progress_line {
+            progress_line.finish();
+        }
+        if hot_keys.is_some() {
+            info!("Stopping recording...");
+        }
+        let result = if let Some(stop_timeout) = stop_timeout {
+            recorder.stop_with_timeout(stop_timeout).map(|outcome| {
+                if let StopOutcome::PendingFramesDropped(frames) = outcome {
+                    warn!(
+                        "The encoder didn't finish in time, {} pending frames were dropped.",
+                        frames
+                    );
+                }
+            })
+        } else {
+            recorder.stop().map(|_| ())
+        };
+        // Explains an empty recording, if that's why stopping failed
+        if let Some(reason) = recorder.stop_reason() {
+            print_stop_reason(reason);
         }
-        session.stop()?;
+        if result.is_ok() && !chapters.is_empty() {
+            write_chapters(config, &chapters, recorder.elapsed());
+        }
+        result?;
+    }
+
+    if verify {
+        verify_output(&config.output_path);
+    }
+    if let Some(remux_path) = remux_path {
+        remux(&config.output_path, remux_path)?;
     }
 
     Ok(())
 }
 
 fn main() {
+    // Monitor bounds and sizes are virtualized for DPI unaware processes,
+    // so this needs to happen before we touch any displays.
+    unsafe {
+        let _ = SetProcessDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2);
+    }
+
     // Handle /?
     let args: Vec<_> = std::env::args().collect();
     if args.contains(&"/?".to_owned()) || args.contains(&"-?".to_owned()) {
@@ -179,33 +342,53 @@ fn main() {
         std::process::exit(0);
     }
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    init_logger(args.verbose || args.wait_for_debugger);
+    if args.frame_rate > MAX_FRAME_RATE {
+        warn!(
+            "A frame rate of {} is above the maximum of {}, clamping it...",
+            args.frame_rate, MAX_FRAME_RATE
+        );
+        args.frame_rate = clamp_frame_rate(args.frame_rate);
+    }
 
     if let Some(command) = args.command {
         match command {
-            args::Commands::EnumEncoders => enum_encoders().unwrap(),
+            args::Commands::EnumEncoders { force_software } => {
+                enum_encoders(force_software).unwrap()
+            }
+            args::Commands::Info { json } => info::print_info(json).unwrap(),
+            args::Commands::Capabilities { json } => info::print_capabilities(json).unwrap(),
+            args::Commands::Benchmark {
+                display,
+                encoder,
+                force_software,
+                frame_rate,
+                seconds,
+                json,
+                readback,
+            } => benchmark::run_benchmark(&benchmark::BenchmarkOptions {
+                display_index: display,
+                encoder_index: encoder,
+                force_software,
+                frame_rate: clamp_frame_rate(frame_rate),
+                duration: Duration::from_secs(seconds),
+                json,
+                readback,
+            })
+            .unwrap(),
         }
         return;
     }
 
-    let monitor_index: usize = args.display;
     let output_path = args.output_file.as_str();
-    let verbose = args.verbose;
     let wait_for_debugger = args.wait_for_debugger;
     let console_mode = args.console_mode;
-    let bit_rate: u32 = args.bit_rate;
-    let frame_rate: u32 = args.frame_rate;
-    let resolution: Resolution = args.resolution;
-    let encoder_index: usize = args.encoder;
     let backend: EncoderBackend = args.backend;
 
     let borderless = if args.borderless {
         // Make sure the machine we're running on supports borderless capture
-        let borderless = ApiInformation::IsPropertyPresent(
-            &HSTRING::from(GraphicsCaptureSession::NAME),
-            h!("IsBorderRequired"),
-        )
-        .unwrap_or(false);
+        let borderless = borderless_capture_supported();
         if borderless {
             let _ =
                 GraphicsCaptureAccess::RequestAccessAsync(GraphicsCaptureAccessKind::Borderless)
@@ -213,7 +396,7 @@ fn main() {
                     .get()
                     .unwrap();
         } else {
-            println!("WARNING: Borderless capture is not supported on this build of Windows, ignoring...");
+            warn!("Borderless capture is not supported on this build of Windows, ignoring...");
         }
         borderless
     } else {
@@ -221,95 +404,807 @@ fn main() {
     };
 
     // Validate some of the params
-    if !validate_path(output_path) {
+    let valid_path = if args.shared_memory.is_some() {
+        // Nothing is written to the output
+        true
+    } else if is_null_output_path(output_path) || is_named_pipe_path(output_path) {
+        !args.alpha
+    } else if args.alpha {
+        validate_png_path(output_path)
+    } else if backend == EncoderBackend::Raw {
+        validate_raw_path(output_path)
+    } else if args.container.is_some() && backend == EncoderBackend::MediaFoundation {
+        validate_file_path(output_path)
+    } else {
+        validate_path(output_path)
+    };
+    if !valid_path {
         exit_with_error("Invalid path specified!");
     }
+    // Segments, PNG frames, shared memory and the null output don't write to
+    // the path itself
+    let overwrite = if args.overwrite {
+        true
+    } else if args.segment.is_some()
+        || args.alpha
+        || args.shared_memory.is_some()
+        || is_null_output_path(output_path)
+        || is_named_pipe_path(output_path)
+        || !Path::new(output_path).is_file()
+    {
+        !args.no_overwrite
+    } else if !args.no_overwrite && confirm_overwrite(output_path, args.quiet) {
+        true
+    } else {
+        exit_with_error(&format!(
+            "\"{}\" already exists! Pass --overwrite to replace it.",
+            output_path
+        ));
+    };
+    let remux_path = args.remux_to.as_ref().map(|remux_path| {
+        if args.segment.is_some()
+            || args.alpha
+            || args.shared_memory.is_some()
+            || backend == EncoderBackend::Raw
+            || is_null_output_path(output_path)
+            || is_named_pipe_path(output_path)
+        {
+            exit_with_error("Only a single MP4 file can be remuxed!");
+        }
+        if !validate_path(remux_path) {
+            exit_with_error("Invalid remux path specified!");
+        }
+        let remux_path = PathBuf::from(remux_path);
+        if remux_path == Path::new(output_path) {
+            exit_with_error("The remuxed file has to go somewhere other than the output!");
+        }
+        if remux_path.is_file() && !args.overwrite {
+            exit_with_error(&format!(
+                "\"{}\" already exists! Pass --overwrite to replace it.",
+                remux_path.display()
+            ));
+        }
+        remux_path
+    });
+    if args.verify
+        && (args.segment.is_some()
+            || args.alpha
+            || args.shared_memory.is_some()
+            || backend == EncoderBackend::Raw
+            || is_null_output_path(output_path)
+            || is_named_pipe_path(output_path))
+    {
+        exit_with_error("Only a single encoded file can be verified!");
+    }
+    if args.alpha && backend != EncoderBackend::MediaFoundation {
+        warn!("The alpha option writes PNGs and doesn't use an encoder backend, ignoring the backend...");
+    }
+    if args.shared_memory.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The shared memory option doesn't use an encoder backend, ignoring the backend...");
+    }
+    if args.raw_format != RawFormat::Bgra && backend != EncoderBackend::Raw {
+        warn!("The raw format option is only supported by the raw backend, ignoring...");
+    }
+    // Alpha and shared memory recordings hand frames to a sink instead of
+    // the mf encoder
+    let frame_sink_output = args.alpha || args.shared_memory.is_some();
+    // The first rendition of a ladder is the output file, the rest are extra
+    let ladder_supported = backend == EncoderBackend::MediaFoundation && !frame_sink_output;
+    if !args.ladder.is_empty() && !ladder_supported {
+        warn!("The ladder option is only supported by the mf backend, ignoring...");
+    }
+    let (resolution, bit_rate, ladder) = match args.ladder.split_first() {
+        Some((first, rest)) if ladder_supported => (
+            Some(first.size),
+            BitRate::Fixed(first.bit_rate),
+            rest.to_vec(),
+        ),
+        _ => (args.resolution.get_size(), args.bit_rate, Vec::new()),
+    };
+    for (i, rendition) in args.ladder.iter().enumerate() {
+        if args.ladder[..i]
+            .iter()
+            .any(|other| other.size.Height == rendition.size.Height)
+        {
+            exit_with_error(&format!(
+                "There's more than one {}p rendition, each needs its own height!",
+                rendition.size.Height
+            ));
+        }
+    }
+    if backend == EncoderBackend::Raw && resolution.is_some() {
+        warn!("The raw backend always records at the capture size, ignoring the resolution...");
+    }
+    if args.fit == Fit::Stretch && backend != EncoderBackend::MediaFoundation {
+        warn!("The stretch fit is only supported by the mf backend, ignoring...");
+    }
+    if args.scaler != Scaler::Auto && backend != EncoderBackend::MediaFoundation {
+        warn!("The scaler option is only supported by the mf backend, ignoring...");
+    }
+    if args.keyframe_every.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The keyframe every option is only supported by the mf backend, ignoring...");
+    }
+    if args.preset.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The preset option is only supported by the mf backend, ignoring...");
+    }
+    if args.encoder_threads.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The encoder threads option is only supported by the mf backend, ignoring...");
+    }
+    if args.chroma != Chroma::Yuv420 && backend != EncoderBackend::MediaFoundation {
+        warn!("The chroma option is only supported by the mf backend, ignoring...");
+    }
+    if let Some(encoder_input) = args.encoder_input {
+        if backend != EncoderBackend::MediaFoundation {
+            warn!("The encoder input option is only supported by the mf backend, ignoring...");
+        } else if args.chroma != Chroma::Yuv420 {
+            exit_with_error("The encoder input option only applies to 420 chroma subsampling!");
+        } else if encoder_input != EncoderInput::Nv12 && args.scaler != Scaler::Auto {
+            exit_with_error(&format!(
+                "The {} scaler only supports nv12 encoder input!",
+                args.scaler
+            ));
+        }
+    }
+    if args.timestamp_base != TimestampBase::Zero && backend != EncoderBackend::MediaFoundation {
+        warn!("The timestamp base option is only supported by the mf backend, ignoring...");
+    }
+    if let Some(dedup_threshold) = args.dedup_threshold {
+        if !(0.0..=1.0).contains(&dedup_threshold) {
+            exit_with_error("The dedup threshold must be between 0.0 and 1.0!");
+        }
+    }
+    if let Some(capture_fps) = args.capture_fps {
+        if args.frame_rate > capture_fps {
+            exit_with_error("The output frame rate can't be higher than the capture frame rate!");
+        }
+        if backend != EncoderBackend::MediaFoundation {
+            warn!("The capture fps option is only supported by the mf backend, ignoring...");
+        }
+    }
+    if args.frames.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The frames option is only supported by the mf backend, ignoring...");
+    }
+    if args.high_priority && backend != EncoderBackend::MediaFoundation {
+        warn!("The high priority option is only supported by the mf backend, ignoring...");
+    }
+    let target_latency = args.target_latency.map(Duration::from_millis);
+    if let Some(target_latency) = target_latency {
+        if backend == EncoderBackend::MediaFoundation {
+            let estimate = nominal_pipeline_latency(args.frame_rate);
+            info!(
+                "Nominal pipeline latency at {} fps: about {} ms (not measured).",
+                args.frame_rate,
+                estimate.as_millis()
+            );
+            if estimate > target_latency {
+                warn!(
+                    "The target latency of {} ms can't be reached at {} fps, try a higher frame rate...",
+                    target_latency.as_millis(),
+                    args.frame_rate
+                );
+            }
+        } else {
+            warn!("The target latency option is only supported by the mf backend, ignoring...");
+        }
+    }
+    if args.follow_foreground && backend != EncoderBackend::MediaFoundation {
+        warn!("The follow foreground option is only supported by the mf backend, ignoring...");
+    }
+    if (args.container.is_some() || args.fragmented) && backend != EncoderBackend::MediaFoundation {
+        warn!("The container option is only supported by the mf backend, ignoring...");
+    }
+    let container = match (args.fragmented, args.container) {
+        (true, Some(container)) if container != Container::FragmentedMp4 => {
+            exit_with_error(&format!(
+                "The fragmented option conflicts with --container {}!",
+                container
+            ))
+        }
+        (true, _) => Container::FragmentedMp4,
+        (false, container) => container
+            .or_else(|| Container::from_path(output_path))
+            .unwrap_or(Container::Mp4),
+    };
+    if args.silent_audio && backend != EncoderBackend::MediaFoundation {
+        warn!("The silent audio option is only supported by the mf backend, ignoring...");
+    }
+    if args.audio_process.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The audio process option is only supported by the mf backend, ignoring...");
+    }
+    let audio_process = if backend == EncoderBackend::MediaFoundation {
+        args.audio_process.as_deref().map(resolve_audio_process)
+    } else {
+        None
+    };
+    let has_audio = args.silent_audio || args.audio_process.is_some();
+    if args.audio_codec != AudioCodec::Aac && !has_audio {
+        warn!("The audio codec option only applies to --silent-audio and --audio-process, ignoring...");
+    }
+    if has_audio && !args.audio_codec.supported_in_mp4() {
+        exit_with_error(&format!(
+            "The {} audio codec can't be stored in an MP4!",
+            args.audio_codec
+        ));
+    }
+    let segments_supported = backend == EncoderBackend::MediaFoundation && !frame_sink_output;
+    if args.segment.is_some() && !segments_supported {
+        warn!("The segment option is only supported by the mf backend, ignoring...");
+    }
+    let segment_duration = args
+        .segment
+        .filter(|_| segments_supported)
+        .map(|minutes| Duration::from_secs(minutes * 60));
+    if args.no_writer_throttling && backend != EncoderBackend::MediaFoundation {
+        warn!("The no writer throttling option is only supported by the mf backend, ignoring...");
+    }
+    if args.write_queue.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The write queue option is only supported by the mf backend, ignoring...");
+    }
+    if args.stats_csv.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The stats csv option is only supported by the mf backend, ignoring...");
+    }
+    let hud_supported = backend == EncoderBackend::MediaFoundation && !frame_sink_output;
+    if (args.hud || args.hud_burn_in) && !hud_supported {
+        warn!("The hud options are only supported by the mf backend, ignoring...");
+    }
+    let live_stats = (hud_supported && (args.hud || args.hud_burn_in)).then(LiveStats::default);
+    let show_hud = args.hud && live_stats.is_some();
+    let desktop_duplication = args.capture_api == CaptureApi::Dxgi;
+    if desktop_duplication {
+        if backend != EncoderBackend::MediaFoundation {
+            warn!("The dxgi capture api is only supported by the mf backend, ignoring...");
+        } else if args.source != CaptureSource::Monitor {
+            exit_with_error("The dxgi capture api can only be used to record a monitor!");
+        } else if args.follow_foreground {
+            warn!("Desktop duplication can't follow the foreground window, ignoring --follow-foreground...");
+        }
+    }
+    let desktop_duplication = desktop_duplication && backend == EncoderBackend::MediaFoundation;
+    if args.desktop != Desktop::Default {
+        if !desktop_duplication {
+            exit_with_error(&format!(
+                "The {} desktop can only be recorded with the dxgi capture api!",
+                args.desktop
+            ));
+        }
+        // Duplication starts on this thread, so it has to be on the desktop too
+        if let Err(error) = attach_to_desktop(args.desktop) {
+            exit_with_error(&error.message().to_string());
+        }
+    }
+    let capture_bit_depth_supported =
+        backend == EncoderBackend::MediaFoundation && !frame_sink_output;
+    if args.capture_bit_depth != CaptureBitDepth::Eight {
+        if !capture_bit_depth_supported {
+            warn!("The capture bit depth option is only supported by the mf backend, ignoring...");
+        } else if desktop_duplication {
+            exit_with_error("Capturing at more than 8 bits needs the wgc capture api!");
+        } else if args.scaler != Scaler::Auto {
+            exit_with_error("Capturing at more than 8 bits needs the auto scaler!");
+        } else if args.watermark.is_some() || args.hud_burn_in || args.dedup_threshold.is_some() {
+            exit_with_error(
+                "Capturing at more than 8 bits can't be combined with --watermark, --hud-burn-in or --dedup-threshold!",
+            );
+        }
+    }
+    let capture_bit_depth = if capture_bit_depth_supported {
+        args.capture_bit_depth
+    } else {
+        CaptureBitDepth::Eight
+    };
+    let input_color_space = if backend == EncoderBackend::MediaFoundation {
+        args.input_colorspace
+    } else {
+        None
+    };
+    if args.input_colorspace.is_some() {
+        if backend != EncoderBackend::MediaFoundation {
+            warn!("The input colorspace option is only supported by the mf backend, ignoring...");
+        } else if args.scaler != Scaler::Auto
+            && args.input_colorspace != Some(InputColorSpace::Srgb)
+        {
+            exit_with_error("A linear input colorspace needs the auto scaler!");
+        }
+    }
+    if args.vsync_pace && backend != EncoderBackend::MediaFoundation {
+        warn!("The vsync pace option is only supported by the mf backend, ignoring...");
+    }
+    if args.vsync_pace && (args.vfr || args.capture_fps.is_some()) {
+        warn!("Vsync pacing takes a frame on every refresh, ignoring --vfr and --capture-fps...");
+    }
+    if args.timelapse_interval.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The timelapse interval option is only supported by the mf backend, ignoring...");
+    }
+    if args.timelapse_interval.is_some() && (args.vfr || args.capture_fps.is_some()) {
+        warn!("A timelapse takes one frame every interval, ignoring --vfr and --capture-fps...");
+    }
+    if args.no_secure_desktop_pause && backend != EncoderBackend::MediaFoundation {
+        warn!(
+            "The no secure desktop pause option is only supported by the mf backend, ignoring..."
+        );
+    }
+    if args.no_frames_timeout.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The no frames timeout option is only supported by the mf backend, ignoring...");
+    }
+    let frame_timeout = Some(args.no_frames_timeout.unwrap_or(5))
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs);
+    if args.force_software && backend != EncoderBackend::MediaFoundation {
+        warn!("The force software option is only supported by the mf backend, ignoring...");
+    }
+    let start_paused =
+        args.start_paused && backend == EncoderBackend::MediaFoundation && !frame_sink_output;
+    if args.start_paused && !start_paused {
+        warn!("The start paused option is only supported by the mf backend, ignoring...");
+    }
+    if args.strict && backend != EncoderBackend::MediaFoundation {
+        warn!("The strict option is only supported by the mf backend, ignoring...");
+    }
+    let watermark = if backend == EncoderBackend::MediaFoundation && !frame_sink_output {
+        args.watermark.as_ref().map(|path| {
+            if !Path::new(path).is_file() {
+                exit_with_error(&format!("The watermark \"{}\" doesn't exist!", path));
+            }
+            if !(0.0..=1.0).contains(&args.watermark_opacity) {
+                exit_with_error("The watermark opacity has to be between 0.0 and 1.0!");
+            }
+            if let Some(scale) = args.watermark_scale {
+                if scale <= 0.0 || scale > 1.0 {
+                    exit_with_error("The watermark scale has to be above 0.0 and at most 1.0!");
+                }
+            }
+            WatermarkSettings {
+                path: PathBuf::from(path),
+                position: args.watermark_pos,
+                opacity: args.watermark_opacity,
+                scale: args.watermark_scale,
+            }
+        })
+    } else {
+        if args.watermark.is_some() {
+            warn!("The watermark option is only supported by the mf backend, ignoring...");
+        }
+        None
+    };
+    if args.vfr && backend != EncoderBackend::MediaFoundation {
+        warn!("VFR mode is only supported by the mf backend, ignoring...");
+    }
+    // Also catches NaN
+    if !(args.speed > 0.0 && args.speed.is_finite()) {
+        exit_with_error("The speed factor must be a positive number!");
+    }
+    if args.speed != 1.0 && backend != EncoderBackend::MediaFoundation {
+        warn!("The speed option is only supported by the mf backend, ignoring...");
+    }
+    // The region decides which display gets recorded, so this comes before
+    // anything else looks at the display.
+    let region = if args.select_region {
+        if backend != EncoderBackend::MediaFoundation || frame_sink_output {
+            warn!("The select region option is only supported by the mf backend, ignoring...");
+            None
+        } else {
+            if args.source != CaptureSource::Monitor {
+                exit_with_error("The select region option can only be used to record a monitor!");
+            }
+            println!("Drag a rectangle around the region to record, or press Esc to cancel...");
+            match select_region() {
+                Ok(Some(region)) => Some(region),
+                Ok(None) => exit_with_error("No region was selected!"),
+                Err(error) => {
+                    exit_with_error(&format!("Unable to select a region: {}", error.message()))
+                }
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(region) = region.as_ref() {
+        info!(
+            "Recording a {}x{} region of display {}.",
+            region.crop.Width, region.crop.Height, region.display_index
+        );
+    }
+    let display = match args.display_name.as_ref() {
+        Some(name) => match find_display_by_name(name) {
+            Ok(Some(index)) => index,
+            Ok(None) => exit_with_error(&format!(
+                "No display is named \"{}\", use the info command to list them!",
+                name
+            )),
+            Err(error) => exit_with_error(&format!(
+                "Unable to look up the display names: {}",
+                error.message()
+            )),
+        },
+        None => args.display,
+    };
+    let display_index = region
+        .as_ref()
+        .map_or(display, |region| region.display_index);
+    let crop = region.map(|region| region.crop);
+    if args.source == CaptureSource::Monitor {
+        check_display_colors(display_index);
+    }
 
-    let result = run(
-        monitor_index,
-        output_path,
-        bit_rate,
-        frame_rate,
-        resolution,
-        encoder_index,
-        borderless,
-        verbose | wait_for_debugger,
-        wait_for_debugger,
-        console_mode,
+    let content_size = if let Some(crop) = crop {
+        Some(SizeInt32 {
+            Width: crop.Width,
+            Height: crop.Height,
+        })
+    } else {
+        get_display_handle_from_index(display_index)
+            .and_then(|display_handle| get_display_info(display_handle).ok())
+            .map(|display| SizeInt32 {
+                Width: display.rect.right - display.rect.left,
+                Height: display.rect.bottom - display.rect.top,
+            })
+    };
+    let output_size = match (resolution, content_size) {
+        (Some(resolution), Some(content_size)) => {
+            Some(args.fit.output_size(resolution, content_size))
+        }
+        (resolution, content_size) => resolution.or(content_size),
+    };
+    if let (BitRate::Fixed(bit_rate), Some(output_size)) = (bit_rate, output_size) {
+        // Nothing gets compressed with the raw backend
+        if backend != EncoderBackend::Raw {
+            check_bit_rate(bit_rate, output_size, args.frame_rate);
+        }
+    }
+
+    let duration = args.duration.map(Duration::from_secs);
+    let progress_line = match duration {
+        Some(duration) if !args.quiet => Some(Arc::new(ProgressLine::new(duration))),
+        _ => None,
+    };
+
+    let config = RecorderConfig {
+        source: args.source,
+        display_index,
+        output_path: PathBuf::from(output_path),
         backend,
+        encoder_index: args.encoder,
+        force_software: args.force_software,
+        ladder,
+        // Windows only lets a process hide its own windows, and we don't
+        // have any worth hiding. Library users can exclude theirs.
+        excluded_windows: Vec::new(),
+        segment_duration,
+        resume: args.resume && segment_duration.is_some(),
+        alpha: args.alpha,
+        shared_memory: args.shared_memory.clone(),
+        allow_empty: args.allow_empty,
+        overwrite,
+        settings: VideoEncodingSettings {
+            borderless,
+            resolution,
+            fit: args.fit,
+            crop,
+            bit_rate,
+            frame_rate: args.frame_rate,
+            capture_frame_rate: args.capture_fps,
+            pixel_aspect_ratio: args.pixel_aspect,
+            scaler: args.scaler,
+            watermark,
+            timestamp_base: args.timestamp_base,
+            speed: if backend == EncoderBackend::MediaFoundation {
+                args.speed
+            } else {
+                1.0
+            },
+            variable_frame_rate: args.vfr && !args.vsync_pace && args.timelapse_interval.is_none(),
+            dedup_threshold: args.dedup_threshold,
+            dedup_max_duration: Duration::from_millis(args.dedup_max_duration),
+            duration,
+            max_frames: args.frames,
+            frame_timeout,
+            high_priority: args.high_priority,
+            follow_foreground: args.follow_foreground,
+            target_latency,
+            strict: args.strict,
+            container,
+            silent_audio: args.silent_audio,
+            audio_process,
+            audio_codec: args.audio_codec,
+            keyframe_period: args.keyframe_every.map(Duration::from_secs),
+            chroma: args.chroma,
+            encoder_input: args.encoder_input,
+            preset: args.preset,
+            encoder_threads: args.encoder_threads,
+            raw_format: args.raw_format,
+            progress_callback: progress_line.clone().map(|progress_line| {
+                ProgressCallback::new(move |progress| progress_line.update(progress))
+            }),
+            // We're the ones on the secure desktop then
+            pause_on_secure_desktop: !args.no_secure_desktop_pause
+                && args.desktop == Desktop::Default,
+            write_queue_length: args.write_queue.map(|length| length as usize),
+            sink_writer_throttling: !args.no_writer_throttling,
+            stats_path: args.stats_csv.as_ref().map(PathBuf::from),
+            live_stats,
+            hud_burn_in: args.hud_burn_in,
+            vsync_pace: if args.vsync_pace {
+                recorded_display(args.source, display_index)
+            } else {
+                None
+            },
+            timelapse_interval: args
+                .timelapse_interval
+                .filter(|_| backend == EncoderBackend::MediaFoundation)
+                .map(Duration::from_secs),
+            desktop_duplication: if desktop_duplication {
+                get_display_handle_from_index(display_index)
+            } else {
+                None
+            },
+            desktop: args.desktop,
+            capture_bit_depth,
+            input_color_space,
+            pause_state: if start_paused {
+                PauseState::paused()
+            } else {
+                PauseState::new()
+            },
+            first_frame_callback: Some(FirstFrameCallback::new({
+                let progress_line = progress_line.clone();
+                move || {
+                    if let Some(progress_line) = progress_line.as_ref() {
+                        progress_line.message("Recording active.");
+                    } else {
+                        info!("Recording active.");
+                    }
+                }
+            })),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let stop_timeout = args.stop_timeout.map(Duration::from_secs);
+    if stop_timeout.is_some() && backend != EncoderBackend::MediaFoundation {
+        warn!("The stop timeout option is only supported by the mf backend, ignoring...");
+    }
+    let result = run(
+        &config,
+        RunOptions {
+            wait_for_debugger,
+            console_mode,
+            progress_line,
+            stop_timeout,
+            check_space: args.check_space,
+            show_indicator: args.indicator || show_hud,
+            show_hud,
+            remux_path: remux_path.as_deref(),
+            verify: args.verify,
+        },
     );
 
+    if let Some(command) = args.on_complete.as_ref() {
+        if result.is_ok() || args.on_complete_always {
+            // The remuxed file is the one that's handed off
+            let output_path = match remux_path.as_deref() {
+                Some(remux_path) if result.is_ok() => remux_path,
+                _ => &config.output_path,
+            };
+            run_on_complete(command, output_path);
+        }
+    }
+
     // We do this for nicer HRESULT printing when errors occur.
     if let Err(error) = result {
         error.code().unwrap();
     }
 }
 
-fn pause() {
-    println!("Press ENTER to stop recording...");
-    std::io::Read::read(&mut std::io::stdin(), &mut [0]).unwrap();
+// The indicator is a nicety, so not being able to show it only warns. It goes
+// on the display being recorded, which can change while recording (see
+// switch_to_next_display).
+fn create_indicator(
+    config: &RecorderConfig,
+    recorder: &Recorder,
+    display_index: usize,
+    show_hud: bool,
+) -> Option<RecordingIndicator> {
+    let result = recorded_display(config.source, display_index)
+        .ok_or_else(|| Error::new(E_INVALIDARG, "The display could not be found!".into()))
+        .and_then(get_display_info)
+        .and_then(|display| {
+            RecordingIndicator::new(
+                display.rect,
+                recorder.elapsed_time(),
+                recorder.pause_state(),
+                config.settings.live_stats.clone().filter(|_| show_hud),
+            )
+        });
+    match result {
+        Ok(indicator) => Some(indicator),
+        Err(error) => {
+            warn!(
+                "Unable to show the recording indicator: {}",
+                error.message()
+            );
+            None
+        }
+    }
 }
 
-fn enum_encoders() -> Result<()> {
-    let encoder_devices = VideoEncoderDevice::enumerate()?;
-    if encoder_devices.is_empty() {
-        exit_with_error("No hardware H264 encoders found!");
+// Where the recording is shown: the display being recorded, or the primary
+// display when recording the whole desktop.
+fn recorded_display(source: CaptureSource, display_index: usize) -> Option<HMONITOR> {
+    match source {
+        CaptureSource::Monitor => get_display_handle_from_index(display_index),
+        CaptureSource::Desktop => enumerate_display_info()
+            .ok()?
+            .into_iter()
+            .find(|display| display.is_primary)
+            .map(|display| display.handle),
     }
-    println!("Encoders ({}):", encoder_devices.len());
-    for (i, encoder_device) in encoder_devices.iter().enumerate() {
-        println!("  {} - {}", i, encoder_device.display_name());
+}
+
+fn print_stop_reason(reason: StopReason) {
+    match reason {
+        StopReason::UserRequested => info!("Recording stopped."),
+        StopReason::SourceClosed => {
+            warn!("Recording stopped because the display or window being captured went away.")
+        }
+        StopReason::MaxDurationReached => info!("Recording stopped after reaching the duration."),
+        StopReason::FrameLimitReached => {
+            info!("Recording stopped after reaching the frame limit.")
+        }
+        StopReason::NoFrames => {
+            error!("Recording stopped because no frames were received from the capture source.")
+        }
+        StopReason::Error => error!("Recording stopped because of an error."),
     }
-    Ok(())
 }
 
-fn create_encoding_session_factory(
-    backend: EncoderBackend,
-    encoder_index: usize,
-    verbose: bool,
-) -> Result<Box<dyn VideoEncoderSessionFactory>> {
-    Ok(match backend {
-        EncoderBackend::MediaFoundation => {
-            let encoder_devices = VideoEncoderDevice::enumerate()?;
-            if encoder_devices.is_empty() {
-                exit_with_error("No hardware H264 encoders found!");
-            }
-            if verbose {
-                println!("Encoders ({}):", encoder_devices.len());
-                for encoder_device in &encoder_devices {
-                    println!("  {}", encoder_device.display_name());
-                }
-            }
-            let encoder_device = if let Some(encoder_device) = encoder_devices.get(encoder_index) {
-                encoder_device
+// Holds the output path while the on-complete command runs.
+const ON_COMPLETE_FILE_VARIABLE: &str = "DISPLAYRECORDER_FILE";
+
+// The path reaches cmd through an environment variable inside quotes, so
+// spaces, & or | (or %) in it can't split or add to the command. Quotes the
+// user already put around {file} would undo ours.
+fn on_complete_command_line(command: &str) -> String {
+    command
+        .replace("\"{file}\"", "{file}")
+        .replace("{file}", &format!("\"%{}%\"", ON_COMPLETE_FILE_VARIABLE))
+}
+
+// Failing to run the command doesn't change how the recording went, so
+// this only reports what happened.
+fn run_on_complete(command: &str, output_path: &Path) {
+    let command = on_complete_command_line(command);
+    info!(
+        "Running \"{}\" with {} set to \"{}\"...",
+        command,
+        ON_COMPLETE_FILE_VARIABLE,
+        output_path.display()
+    );
+    match std::process::Command::new("cmd")
+        .arg("/C")
+        // Passed as is so that cmd sees the user's quoting
+        .raw_arg(&command)
+        .env(ON_COMPLETE_FILE_VARIABLE, output_path)
+        .status()
+    {
+        Ok(status) => {
+            if let Some(code) = status.code() {
+                info!("The on-complete command exited with code {}.", code);
             } else {
-                exit_with_error("Encoder index is out of bounds!");
-            };
-            if verbose {
-                println!("Using: {}", encoder_device.display_name());
+                warn!("The on-complete command was terminated.");
             }
-            Box::new(MFVideoEncodingSessionFactory::new(encoder_device.clone()))
         }
-        EncoderBackend::WindowsMediaTranscoding => Box::new(WMTVideoEncodingSessionFactory::new()),
-    })
+        Err(error) => error!("Unable to run the on-complete command: {}", error),
+    }
 }
 
-fn create_encoding_session(
-    d3d_device: ID3D11Device,
-    item: GraphicsCaptureItem,
-    borderless: bool,
-    factory: &Box<dyn VideoEncoderSessionFactory>,
-    resolution: SizeInt32,
-    bit_rate: u32,
-    frame_rate: u32,
-    stream: IRandomAccessStream,
-) -> Result<Box<dyn VideoEncodingSession>> {
-    let result = factory.create_session(
-        d3d_device, item, borderless, resolution, bit_rate, frame_rate, stream,
-    );
-    if result.is_err() {
-        println!("Error during encoder setup, try another set of encoding settings.");
+// Messages keep the plain look they had as prints, with RUST_LOG still able
+// to override the level.
+fn init_logger(verbose: bool) {
+    let level = if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .target(env_logger::Target::Stdout)
+        .format(|buf, record| match record.level() {
+            Level::Error => writeln!(buf, "ERROR: {}", record.args()),
+            Level::Warn => writeln!(buf, "WARNING: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
+
+fn pause() {
+    println!("Press ENTER to stop recording...");
+    wait_for_enter();
+}
+
+// Only asks when there's someone at a terminal to answer, anything else
+// leaves the file alone.
+fn confirm_overwrite(path: &str, quiet: bool) -> bool {
+    if quiet || !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return false;
+    }
+    eprint!("\"{}\" already exists, overwrite? [y/N] ", path);
+    let _ = std::io::stderr().flush();
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line).is_err() {
+        return false;
+    }
+    matches!(line.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn wait_for_enter() {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+}
+
+// Everything is captured as 8-bit sRGB (BGRA8), so HDR and wide gamut
+// displays lose whatever doesn't fit.
+fn check_display_colors(display_index: usize) {
+    let color_info = get_display_handle_from_index(display_index)
+        .and_then(|handle| get_display_color_info(handle).ok().flatten());
+    if let Some(color_info) = color_info {
+        if color_info.is_hdr() {
+            warn!("Display {} has HDR turned on, but recordings are SDR. Colors brighter or more saturated than SDR will be tonemapped or clipped.", display_index);
+        } else if color_info.is_wide_gamut() {
+            warn!("Display {} has a wide color gamut, but recordings are sRGB. Colors outside of sRGB will be clipped.", display_index);
+        }
+    }
+}
+
+// Warns about bit rates that are very unlikely to be what the user meant,
+// e.g. "18000" thinking in kbps when bare numbers are Mbps.
+fn check_bit_rate(bit_rate: u32, size: SizeInt32, frame_rate: u32) {
+    let pixels_per_second = size.Width as f64 * size.Height as f64 * frame_rate as f64;
+    if pixels_per_second <= 0.0 {
+        return;
+    }
+    let bits_per_pixel = bit_rate as f64 / pixels_per_second;
+    if bits_per_pixel < 0.01 {
+        warn!(
+            "A bit rate of {} kbps is very low for {}x{} at {} fps, expect a blurry recording.",
+            bit_rate / 1000,
+            size.Width,
+            size.Height,
+            frame_rate
+        );
+    } else if bits_per_pixel > 2.0 {
+        warn!("A bit rate of {} kbps is very high for {}x{} at {} fps, the encoder may not be able to reach it.",
+            bit_rate / 1000,
+            size.Width,
+            size.Height,
+            frame_rate
+        );
     }
-    result
+}
+
+fn enum_encoders(software: bool) -> Result<()> {
+    let encoder_devices = if software {
+        VideoEncoderDevice::enumerate_software()?
+    } else {
+        VideoEncoderDevice::enumerate()?
+    };
+    if encoder_devices.is_empty() {
+        if software {
+            exit_with_error("No software H264 encoders found!");
+        }
+        exit_with_error("No hardware H264 encoders found!");
+    }
+    println!("Encoders ({}):", encoder_devices.len());
+    for (i, encoder_device) in encoder_devices.iter().enumerate() {
+        println!(
+            "  {} - {} ({})",
+            i,
+            encoder_device.display_name(),
+            encoder_device.kind()
+        );
+    }
+    Ok(())
 }
 
 fn validate_path<P: AsRef<Path>>(path: P) -> bool {
@@ -325,11 +1220,56 @@ fn validate_path<P: AsRef<Path>>(path: P) -> bool {
     valid
 }
 
+// Any file name will do when the container was picked explicitly.
+fn validate_file_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().file_name().is_some()
+}
+
+// Raw recordings have no container, so we only accept extensions that
+// won't be mistaken for one.
+fn validate_raw_path<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    if let Some(extension) = path.extension() {
+        extension == "raw" || extension == "bgra" || extension == "nv12"
+    } else {
+        false
+    }
+}
+
+fn validate_png_path<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.extension()
+        .map_or(false, |extension| extension == "png")
+        && path.file_stem().map_or(false, |stem| !stem.is_empty())
+}
+
 fn exit_with_error(message: &str) -> ! {
-    println!("{}", message);
+    error!("{}", message);
     std::process::exit(1);
 }
 
+// Takes a process id, or the name of a running executable.
+fn resolve_audio_process(process: &str) -> u32 {
+    if !process_loopback_supported() {
+        exit_with_error(&format!(
+            "Recording a process's audio needs Windows build {} or later! Use --silent-audio instead.",
+            PROCESS_LOOPBACK_MIN_BUILD
+        ));
+    }
+    if let Ok(process_id) = process.parse() {
+        return process_id;
+    }
+    match find_process_id(process) {
+        Ok(Some(process_id)) => process_id,
+        Ok(None) => exit_with_error(&format!("No running process named \"{}\"!", process)),
+        Err(error) => exit_with_error(&format!(
+            "Couldn't look up the process \"{}\": {}",
+            process,
+            error.message()
+        )),
+    }
+}
+
 fn win32_programmatic_capture_supported() -> Result<bool> {
     ApiInformation::IsApiContractPresentByMajor(
         &HSTRING::from("Windows.Foundation.UniversalApiContract"),
@@ -337,6 +1277,14 @@ fn win32_programmatic_capture_supported() -> Result<bool> {
     )
 }
 
+fn borderless_capture_supported() -> bool {
+    ApiInformation::IsPropertyPresent(
+        &HSTRING::from(GraphicsCaptureSession::NAME),
+        h!("IsBorderRequired"),
+    )
+    .unwrap_or(false)
+}
+
 fn required_capture_features_supported() -> Result<bool> {
     let result = ApiInformation::IsTypePresent(&HSTRING::from(GraphicsCaptureSession::NAME))? && // Windows.Graphics.Capture is present
     GraphicsCaptureSession::IsSupported()? && // The CaptureService is available
@@ -344,14 +1292,161 @@ fn required_capture_features_supported() -> Result<bool> {
     Ok(result)
 }
 
-fn pump_messages<F: FnMut() -> Result<bool>>(mut hot_key_callback: F) -> Result<()> {
-    let _hot_key = HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x52 /* R */)?;
+fn snapshot_path(output_path: &Path, index: usize) -> PathBuf {
+    let stem = output_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "recording".to_owned());
+    output_path.with_file_name(format!("{}-snapshot-{}.png", stem, index))
+}
+
+// Reports progress in steps of 10%, remuxing long recordings takes a while.
+fn remux(output_path: &Path, remux_path: &Path) -> Result<()> {
+    info!("Remuxing to \"{}\"...", remux_path.display());
+    let mut last_step = 0;
+    remux_file(output_path, remux_path, Container::Mp4, |progress| {
+        let step = (progress * 10.0) as u32;
+        if step > last_step && step < 10 {
+            last_step = step;
+            info!("Remuxing... {}%", step * 10);
+        }
+    })?;
+    info!("Remuxed to \"{}\".", remux_path.display());
+    Ok(())
+}
+
+// A recording that can't be verified isn't necessarily broken, so this only
+// warns.
+fn verify_output(output_path: &Path) {
+    info!("Verifying the recording...");
+    match verify_recording(output_path) {
+        Ok(VerifyOutcome::Passed) => info!("The recording looks fine."),
+        Ok(VerifyOutcome::NoFrames) => warn!(
+            "No frames could be decoded from the recording, try another set of encoding settings."
+        ),
+        Ok(VerifyOutcome::Black) => warn!(
+            "The recording is all black. Unless the screen was too, try another set of encoding settings."
+        ),
+        Ok(VerifyOutcome::Uniform) => warn!(
+            "The recording is a single color, the encoder might be broken. Try another set of encoding settings."
+        ),
+        Err(error) => warn!("Couldn't verify the recording: {}", error.message()),
+    }
+}
+
+// Not being able to write the chapters doesn't change how the recording
+// went, so this only reports what happened.
+fn write_chapters(config: &RecorderConfig, chapters: &ChapterMarkers, end: Duration) {
+    let output_path = &config.output_path;
+    if is_null_output_path(output_path) || is_named_pipe_path(output_path) {
+        warn!("The recording wasn't written to a file, the chapter markers are dropped.");
+        return;
+    }
+    let path = chapters_path(output_path);
+    match chapters.write(&path, end) {
+        Ok(()) => info!("Chapters written to \"{}\".", path.display()),
+        Err(error) => error!("Unable to write the chapters: {}", error),
+    }
+}
+
+// Moves the recording over to the display after the current one, wrapping
+// around to the first. Returns the display that's recorded now, or None if
+// nothing changed.
+fn switch_to_next_display(
+    config: &RecorderConfig,
+    recorder: &mut Recorder,
+    display_index: usize,
+) -> Option<usize> {
+    if config.source != CaptureSource::Monitor || config.settings.crop.is_some() {
+        warn!("Only whole monitors can be switched between, ignoring...");
+        return None;
+    }
+    let displays = match enumerate_display_info() {
+        Ok(displays) => displays,
+        Err(error) => {
+            warn!("Unable to enumerate the displays: {}", error.message());
+            return None;
+        }
+    };
+    if displays.len() < 2 {
+        info!("There's only one display to record.");
+        return None;
+    }
+    let next_index = (display_index + 1) % displays.len();
+    match recorder.switch_display(next_index) {
+        Ok(()) => {
+            let names = get_display_names(&displays);
+            info!(
+                "Now recording display {}: {}",
+                next_index, names[next_index]
+            );
+            Some(next_index)
+        }
+        Err(error) => {
+            warn!("Unable to switch displays: {}", error.message());
+            None
+        }
+    }
+}
+
+enum HotKeyAction {
+    ToggleRecording,
+    Snapshot,
+    TogglePause,
+    MarkChapter,
+    NextDisplay,
+}
+
+struct HotKeys {
+    record: HotKey,
+    snapshot: HotKey,
+    pause: HotKey,
+    chapter: HotKey,
+    next_display: HotKey,
+}
+
+fn register_hot_keys() -> Result<HotKeys> {
+    Ok(HotKeys {
+        record: HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x52 /* R */)?,
+        snapshot: HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x53 /* S */)?,
+        pause: HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x50 /* P */)?,
+        chapter: HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x4D /* M */)?,
+        next_display: HotKey::new(MOD_SHIFT | MOD_CONTROL, 0x44 /* D */)?,
+    })
+}
+
+fn pump_messages<F: FnMut(HotKeyAction) -> Result<bool>>(
+    hot_keys: &HotKeys,
+    mut hot_key_callback: F,
+) -> Result<()> {
     println!("Press SHIFT+CTRL+R to start/stop the recording...");
+    println!("Press SHIFT+CTRL+S to save a snapshot while recording...");
+    println!("Press SHIFT+CTRL+P to pause/resume the recording...");
+    println!("Press SHIFT+CTRL+M to mark a chapter while recording...");
+    println!("Press SHIFT+CTRL+D to switch to the next display while recording...");
     unsafe {
         let mut message = MSG::default();
         while GetMessageW(&mut message, HWND(0), 0, 0).into() {
-            if message.message == WM_HOTKEY && hot_key_callback()? {
-                break;
+            if message.message == WM_HOTKEY {
+                let id = message.wParam.0 as i32;
+                let action = if id == hot_keys.record.id() {
+                    Some(HotKeyAction::ToggleRecording)
+                } else if id == hot_keys.snapshot.id() {
+                    Some(HotKeyAction::Snapshot)
+                } else if id == hot_keys.pause.id() {
+                    Some(HotKeyAction::TogglePause)
+                } else if id == hot_keys.chapter.id() {
+                    Some(HotKeyAction::MarkChapter)
+                } else if id == hot_keys.next_display.id() {
+                    Some(HotKeyAction::NextDisplay)
+                } else {
+                    None
+                };
+                if let Some(action) = action {
+                    if hot_key_callback(action)? {
+                        break;
+                    }
+                }
             }
             DispatchMessageW(&message);
         }
@@ -361,7 +1456,7 @@ fn pump_messages<F: FnMut() -> Result<bool>>(mut hot_key_callback: F) -> Result<
 
 #[cfg(test)]
 mod tests {
-    use crate::validate_path;
+    use crate::{on_complete_command_line, validate_path};
 
     #[test]
     fn path_parsing_test() {
@@ -377,4 +1472,17 @@ mod tests {
         assert!(!validate_path("mp4"));
         assert!(!validate_path("something.avi"));
     }
+
+    #[test]
+    fn on_complete_command_line_test() {
+        assert_eq!(
+            on_complete_command_line("notepad {file}"),
+            "notepad \"%DISPLAYRECORDER_FILE%\""
+        );
+        assert_eq!(
+            on_complete_command_line("notepad \"{file}\""),
+            "notepad \"%DISPLAYRECORDER_FILE%\""
+        );
+        assert_eq!(on_complete_command_line("notepad"), "notepad");
+    }
 }
@@ -1,14 +1,76 @@
 use windows::{
-    core::{Array, Result, GUID},
+    core::{Array, ComInterface, Result, GUID},
     Win32::Media::MediaFoundation::{
-        IMFActivate, IMFAttributes, MFTEnumEx, MFT_ENUM_FLAG, MFT_REGISTER_TYPE_INFO,
+        IMFActivate, IMFAttributes, MFCreateAttributes, MFEnumDeviceSources, MFTEnumEx,
+        MFT_ENUM_FLAG, MFT_ENUM_FLAG_ASYNCMFT, MFT_ENUM_FLAG_HARDWARE, MFT_ENUM_FLAG_LOCALMFT,
+        MFT_ENUM_FLAG_SORTANDFILTER, MFT_ENUM_FLAG_SYNCMFT, MFT_ENUM_FLAG_TRANSCODE_ONLY,
+        MFT_REGISTER_TYPE_INFO, MF_DEVSOURCE_ATTRIBUTE_FRIENDLY_NAME,
+        MF_DEVSOURCE_ATTRIBUTE_SOURCE_TYPE, MF_DEVSOURCE_ATTRIBUTE_SOURCE_TYPE_AUDCAP_GUID,
         MF_E_ATTRIBUTENOTFOUND,
     },
 };
 
+// Which kinds of MFTs to look for. The default finds hardware transforms
+// suitable for transcoding, which is what we use for encoding.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EnumOptions {
+    pub hardware: bool,
+    // Software MFTs, which can be synchronous or asynchronous.
+    pub synchronous: bool,
+    pub asynchronous: bool,
+    // Include MFTs registered in-process only.
+    pub local: bool,
+    pub transcode_only: bool,
+    // Orders the results by merit and drops anything blocked by policy.
+    pub sort_and_filter: bool,
+}
+
+impl EnumOptions {
+    // Only software MFTs, the inverse of the default.
+    pub fn software() -> Self {
+        Self {
+            hardware: false,
+            synchronous: true,
+            asynchronous: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn flags(&self) -> MFT_ENUM_FLAG {
+        let mut flags = MFT_ENUM_FLAG(0);
+        let options = [
+            (self.hardware, MFT_ENUM_FLAG_HARDWARE),
+            (self.synchronous, MFT_ENUM_FLAG_SYNCMFT),
+            (self.asynchronous, MFT_ENUM_FLAG_ASYNCMFT),
+            (self.local, MFT_ENUM_FLAG_LOCALMFT),
+            (self.transcode_only, MFT_ENUM_FLAG_TRANSCODE_ONLY),
+            (self.sort_and_filter, MFT_ENUM_FLAG_SORTANDFILTER),
+        ];
+        for (enabled, flag) in options {
+            if enabled {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+}
+
+impl Default for EnumOptions {
+    fn default() -> Self {
+        Self {
+            hardware: true,
+            synchronous: false,
+            asynchronous: false,
+            local: false,
+            transcode_only: true,
+            sort_and_filter: true,
+        }
+    }
+}
+
 pub fn enumerate_mfts(
     category: &GUID,
-    flags: MFT_ENUM_FLAG,
+    options: &EnumOptions,
     input_type: Option<&MFT_REGISTER_TYPE_INFO>,
     output_type: Option<&MFT_REGISTER_TYPE_INFO>,
 ) -> Result<Vec<IMFActivate>> {
@@ -18,14 +80,17 @@ pub fn enumerate_mfts(
         let mut len = 0;
         MFTEnumEx(
             *category,
-            flags,
+            options.flags(),
             input_type.map(|info| info as *const _),
             output_type.map(|info| info as *const _),
             &mut data,
             &mut len,
         )?;
+        // The Array takes ownership of both the CoTaskMemAlloc'd list and the
+        // reference each element holds, releasing them when it's dropped.
         Array::<IMFActivate>::from_raw_parts(data as _, len)
     };
+    // Cloning adds our own reference, so the activates outlive the list.
     if !mfactivate_list.is_empty() {
         for mfactivate in mfactivate_list.as_slice() {
             let transform_source = mfactivate.clone().unwrap();
@@ -35,6 +100,38 @@ pub fn enumerate_mfts(
     Ok(transform_sources)
 }
 
+// Returns the friendly names of the audio capture devices (e.g. microphones).
+pub fn enumerate_audio_capture_devices() -> Result<Vec<String>> {
+    let attributes = unsafe {
+        let mut attributes = None;
+        MFCreateAttributes(&mut attributes, 1)?;
+        attributes.unwrap()
+    };
+    unsafe {
+        attributes.SetGUID(
+            &MF_DEVSOURCE_ATTRIBUTE_SOURCE_TYPE,
+            &MF_DEVSOURCE_ATTRIBUTE_SOURCE_TYPE_AUDCAP_GUID,
+        )?;
+    }
+    let device_list = unsafe {
+        let mut data = std::ptr::null_mut();
+        let mut len = 0;
+        MFEnumDeviceSources(&attributes, &mut data, &mut len)?;
+        Array::<IMFActivate>::from_raw_parts(data as _, len)
+    };
+    let mut devices = Vec::new();
+    if !device_list.is_empty() {
+        for device in device_list.as_slice() {
+            let device = device.clone().unwrap();
+            let name =
+                get_string_attribute(&device.cast()?, &MF_DEVSOURCE_ATTRIBUTE_FRIENDLY_NAME)?
+                    .unwrap_or_else(|| "Unknown".to_owned());
+            devices.push(name);
+        }
+    }
+    Ok(devices)
+}
+
 pub fn get_string_attribute(
     attributes: &IMFAttributes,
     attribute_guid: &GUID,
@@ -0,0 +1,113 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use windows::{
+    Foundation::TimeSpan,
+    Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency},
+};
+
+// A clonable, thread-safe way to pause and resume a recording from anywhere.
+// Frames captured while paused are dropped, and the time spent paused is
+// taken out of the timeline so that the recording plays back without a gap.
+// Times are on the same QPC based clock as the frames' system relative time.
+// Besides the caller's pause, the recording can be held (e.g. while the
+// secure desktop is showing), and stays paused while either is in effect.
+#[derive(Clone, Default)]
+pub struct PauseState {
+    inner: Arc<Mutex<PauseStateInner>>,
+}
+
+#[derive(Default)]
+struct PauseStateInner {
+    paused: bool,
+    held: bool,
+    paused_since: Option<i64>,
+    // Every pause that has ended, in 100ns units.
+    paused_total: i64,
+}
+
+impl PauseState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // For arming a recording that only starts once it's resumed.
+    pub fn paused() -> Self {
+        let state = Self::default();
+        state.pause();
+        state
+    }
+
+    // Does nothing if already paused.
+    pub fn pause(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.paused = true;
+        inner.update();
+    }
+
+    // Does nothing if not paused. A hold keeps the recording paused until
+    // it's released.
+    pub fn resume(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.paused = false;
+        inner.update();
+    }
+
+    // Pauses independently of pause and resume, so that releasing the hold
+    // doesn't undo a pause the user asked for.
+    pub fn set_held(&self, held: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.held = held;
+        inner.update();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.lock().unwrap().paused_since.is_some()
+    }
+
+    // How long the recording has been paused in total, including the
+    // current pause if there is one.
+    pub fn paused_duration(&self) -> TimeSpan {
+        let inner = self.inner.lock().unwrap();
+        let current = inner
+            .paused_since
+            .map_or(0, |paused_since| now() - paused_since);
+        TimeSpan {
+            Duration: inner.paused_total + current,
+        }
+    }
+}
+
+impl PauseStateInner {
+    fn update(&mut self) {
+        let paused = self.paused || self.held;
+        if paused && self.paused_since.is_none() {
+            self.paused_since = Some(now());
+        } else if !paused {
+            if let Some(paused_since) = self.paused_since.take() {
+                self.paused_total += now() - paused_since;
+            }
+        }
+    }
+}
+
+impl Debug for PauseState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PauseState")
+            .field("paused", &self.is_paused())
+            .finish()
+    }
+}
+
+// The current QPC time in 100ns units.
+fn now() -> i64 {
+    let mut counter = 0;
+    let mut frequency = 0;
+    unsafe {
+        let _ = QueryPerformanceCounter(&mut counter);
+        let _ = QueryPerformanceFrequency(&mut frequency);
+    }
+    (counter as i128 * 10_000_000 / frequency.max(1) as i128) as i64
+}
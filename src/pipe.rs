@@ -0,0 +1,141 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use windows::{
+    core::{Error, Result, HSTRING},
+    Win32::{
+        Foundation::{
+            CloseHandle, ERROR_FILE_NOT_FOUND, ERROR_PIPE_CONNECTED, GENERIC_WRITE, HANDLE,
+            STG_E_INVALIDFUNCTION, STG_E_WRITEFAULT,
+        },
+        Media::MediaFoundation::IMFByteStream,
+        Storage::FileSystem::{
+            CreateFileW, WriteFile, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_NONE, OPEN_EXISTING,
+            PIPE_ACCESS_OUTBOUND,
+        },
+        System::{
+            Com::{STREAM_SEEK, STREAM_SEEK_CUR, STREAM_SEEK_SET},
+            Pipes::{ConnectNamedPipe, CreateNamedPipeW, PIPE_TYPE_BYTE, PIPE_WAIT},
+        },
+    },
+};
+
+use crate::stream::{create_byte_stream, StreamTarget};
+
+const PIPE_PREFIX: &str = r"\\.\pipe\";
+const PIPE_BUFFER_SIZE: u32 = 1024 * 1024;
+
+pub fn is_named_pipe_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_str()
+        .map_or(false, |path| path.to_lowercase().starts_with(PIPE_PREFIX))
+}
+
+// Connects to the named pipe if someone is already serving it, otherwise
+// creates it and waits for a reader to connect. The returned stream only
+// supports writing sequentially.
+pub fn open_named_pipe<P: AsRef<Path>>(path: P) -> Result<IMFByteStream> {
+    let name = HSTRING::from(path.as_ref().as_os_str());
+    let result = unsafe {
+        CreateFileW(
+            &name,
+            GENERIC_WRITE.0,
+            FILE_SHARE_NONE,
+            None,
+            OPEN_EXISTING,
+            FILE_ATTRIBUTE_NORMAL,
+            None,
+        )
+    };
+    let handle = match result {
+        Ok(handle) => handle,
+        Err(error) if error.code() == ERROR_FILE_NOT_FOUND.to_hresult() => unsafe {
+            let handle = CreateNamedPipeW(
+                &name,
+                PIPE_ACCESS_OUTBOUND,
+                PIPE_TYPE_BYTE | PIPE_WAIT,
+                1,
+                PIPE_BUFFER_SIZE,
+                0,
+                0,
+                None,
+            );
+            if handle.is_invalid() {
+                return Err(Error::from_win32());
+            }
+            // A reader that connected before we got here is fine too
+            if let Err(error) = ConnectNamedPipe(handle, None) {
+                if error.code() != ERROR_PIPE_CONNECTED.to_hresult() {
+                    let _ = CloseHandle(handle);
+                    return Err(error);
+                }
+            }
+            handle
+        },
+        Err(error) => return Err(error),
+    };
+    create_pipe_byte_stream(handle)
+}
+
+// Takes ownership of the handle, which is closed once the stream goes away.
+pub(crate) fn create_pipe_byte_stream(handle: HANDLE) -> Result<IMFByteStream> {
+    create_byte_stream(PipeTarget {
+        handle,
+        position: AtomicU64::new(0),
+    })
+}
+
+// Write-only, over a pipe handle. Pipes can't seek, so the only seeks
+// allowed are the ones that don't move (e.g. asking for the position).
+struct PipeTarget {
+    handle: HANDLE,
+    position: AtomicU64,
+}
+
+impl Drop for PipeTarget {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+impl StreamTarget for PipeTarget {
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let mut total = 0;
+        while total < data.len() {
+            let mut written = 0;
+            unsafe { WriteFile(self.handle, Some(&data[total..]), Some(&mut written), None)? };
+            // Would otherwise spin forever
+            if written == 0 {
+                return Err(Error::new(
+                    STG_E_WRITEFAULT,
+                    "The pipe stopped accepting data!".into(),
+                ));
+            }
+            total += written as usize;
+        }
+        self.position.fetch_add(total as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn seek(&self, offset: i64, origin: STREAM_SEEK) -> Result<u64> {
+        let position = self.position.load(Ordering::SeqCst);
+        let stays_put = match origin {
+            STREAM_SEEK_CUR => offset == 0,
+            STREAM_SEEK_SET => offset as u64 == position,
+            _ => false,
+        };
+        if !stays_put {
+            return Err(STG_E_INVALIDFUNCTION.into());
+        }
+        Ok(position)
+    }
+
+    // Everything written so far is all the size we know of
+    fn size(&self) -> Result<u64> {
+        Ok(self.position.load(Ordering::SeqCst))
+    }
+}
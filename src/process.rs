@@ -0,0 +1,55 @@
+use windows::{
+    core::Result,
+    Win32::{
+        Foundation::CloseHandle,
+        System::Diagnostics::ToolHelp::{
+            CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+            TH32CS_SNAPPROCESS,
+        },
+    },
+};
+
+pub struct ProcessInfo {
+    pub id: u32,
+    pub name: String,
+}
+
+pub fn enumerate_processes() -> Result<Vec<ProcessInfo>> {
+    let mut processes = Vec::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?;
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut result = Process32FirstW(snapshot, &mut entry);
+        while result.is_ok() {
+            let name_length = entry
+                .szExeFile
+                .iter()
+                .position(|c| *c == 0)
+                .unwrap_or(entry.szExeFile.len());
+            processes.push(ProcessInfo {
+                id: entry.th32ProcessID,
+                name: String::from_utf16_lossy(&entry.szExeFile[..name_length]),
+            });
+            result = Process32NextW(snapshot, &mut entry);
+        }
+        CloseHandle(snapshot)?;
+    }
+    Ok(processes)
+}
+
+// Matches the executable's name without caring about case or whether the
+// ".exe" is there. The first match wins when there are several.
+pub fn find_process_id(name: &str) -> Result<Option<u32>> {
+    let name = name.to_lowercase();
+    let name = name.strip_suffix(".exe").unwrap_or(&name);
+    Ok(enumerate_processes()?
+        .into_iter()
+        .find(|process| {
+            let process_name = process.name.to_lowercase();
+            process_name.strip_suffix(".exe").unwrap_or(&process_name) == name
+        })
+        .map(|process| process.id))
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::VecDeque,
+    mem::ManuallyDrop,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use log::error;
+use windows::{
+    core::{implement, w, ComInterface, Error, IUnknown, Result, HRESULT, PCWSTR},
+    Win32::{
+        Foundation::{CloseHandle, E_FAIL, HANDLE, WAIT_OBJECT_0},
+        Media::Audio::{
+            ActivateAudioInterfaceAsync, IActivateAudioInterfaceAsyncOperation,
+            IActivateAudioInterfaceCompletionHandler,
+            IActivateAudioInterfaceCompletionHandler_Impl, IAudioCaptureClient, IAudioClient,
+            AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+            AUDCLNT_STREAMFLAGS_LOOPBACK, AUDIOCLIENT_ACTIVATION_PARAMS,
+            AUDIOCLIENT_ACTIVATION_PARAMS_0, AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+            AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS, PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            WAVEFORMATEX, WAVE_FORMAT_PCM,
+        },
+        System::{
+            Com::{StructuredStorage::PROPVARIANT, BLOB},
+            Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ},
+            Threading::{CreateEventW, WaitForSingleObject},
+            Variant::VT_BLOB,
+        },
+    },
+};
+
+use crate::{apartment::ensure_apartment, pause::PauseState};
+
+// Per-process loopback showed up in this build.
+pub const PROCESS_LOOPBACK_MIN_BUILD: u32 = 20348;
+
+const VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK: PCWSTR = w!("VAD\\Process_Loopback");
+const BITS_PER_SAMPLE: u16 = 16;
+// How much audio the capture client buffers on its end: 200ms, in 100ns units.
+const BUFFER_DURATION: i64 = 2_000_000;
+// How long to wait on a packet before checking whether we should stop.
+const WAIT_TIMEOUT_MS: u32 = 100;
+
+pub fn process_loopback_supported() -> bool {
+    windows_build_number().map_or(false, |build| build >= PROCESS_LOOPBACK_MIN_BUILD)
+}
+
+// GetVersionEx lies to unmanifested apps, the registry doesn't.
+pub fn windows_build_number() -> Option<u32> {
+    let mut buffer = [0u16; 32];
+    let mut size = std::mem::size_of_val(&buffer) as u32;
+    unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            w!("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion"),
+            w!("CurrentBuildNumber"),
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr() as *mut _),
+            Some(&mut size),
+        )
+        .ok()?;
+    }
+    let length = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+    String::from_utf16_lossy(&buffer[..length]).parse().ok()
+}
+
+// Captures the audio a process (and its children) plays, and nothing else
+// on the system, as 16-bit PCM. Whatever's been captured is buffered until
+// read, up to a second of it, after which the oldest audio is dropped. The
+// capture starts right away, but nothing is kept while the recording is
+// paused.
+pub struct ProcessAudioCapture {
+    buffer: Arc<Mutex<CapturedAudio>>,
+    block_alignment: usize,
+    sample_rate: u32,
+    should_stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct CapturedAudio {
+    packets: VecDeque<AudioPacket>,
+    // In bytes, across all of the packets.
+    length: usize,
+}
+
+struct AudioPacket {
+    // When the first frame was captured, see ProcessAudioCapture::read.
+    time: i64,
+    data: Vec<u8>,
+}
+
+impl ProcessAudioCapture {
+    pub fn new(
+        process_id: u32,
+        sample_rate: u32,
+        channels: u16,
+        pause_state: PauseState,
+    ) -> Result<Self> {
+        let block_alignment = (channels * BITS_PER_SAMPLE / 8) as usize;
+        let max_buffered = sample_rate as usize * block_alignment;
+        let buffer = Arc::new(Mutex::new(CapturedAudio::default()));
+        let should_stop = Arc::new(AtomicBool::new(false));
+
+        // The audio client is created, used, and released on its own thread
+        let (sender, receiver) = channel();
+        let thread = std::thread::spawn({
+            let buffer = buffer.clone();
+            let should_stop = should_stop.clone();
+            move || {
+                let _apartment = match ensure_apartment() {
+                    Ok(apartment) => apartment,
+                    Err(error) => {
+                        let _ = sender.send(Err(error));
+                        return;
+                    }
+                };
+                let format = WAVEFORMATEX {
+                    wFormatTag: WAVE_FORMAT_PCM as u16,
+                    nChannels: channels,
+                    nSamplesPerSec: sample_rate,
+                    nAvgBytesPerSec: sample_rate * block_alignment as u32,
+                    nBlockAlign: block_alignment as u16,
+                    wBitsPerSample: BITS_PER_SAMPLE,
+                    cbSize: 0,
+                };
+                let (audio_client, capture_client, event) =
+                    match create_capture_client(process_id, &format) {
+                        Ok(clients) => clients,
+                        Err(error) => {
+                            let _ = sender.send(Err(error));
+                            return;
+                        }
+                    };
+                if let Err(error) = unsafe { audio_client.Start() } {
+                    let _ = sender.send(Err(error));
+                    return;
+                }
+                let _ = sender.send(Ok(()));
+
+                let result = capture_loop(
+                    &capture_client,
+                    event,
+                    &buffer,
+                    block_alignment,
+                    max_buffered,
+                    &pause_state,
+                    &should_stop,
+                );
+                if let Err(error) = result {
+                    error!(
+                        "Error while capturing process audio: {:?} - {}",
+                        error.code(),
+                        error.message()
+                    );
+                }
+                unsafe {
+                    let _ = audio_client.Stop();
+                    let _ = CloseHandle(event);
+                }
+            }
+        });
+        receiver.recv().unwrap()?;
+
+        Ok(Self {
+            buffer,
+            block_alignment,
+            sample_rate,
+            should_stop,
+            thread: Some(thread),
+        })
+    }
+
+    // Fills the destination with the audio captured from the given time on,
+    // leaving silence wherever nothing was captured. Times are QPC based, in
+    // 100ns units, the same as a frame's system relative time. Anything that
+    // was captured before the time is dropped, so the audio from before the
+    // first frame or from while the recording was paused never gets written.
+    pub fn read(&self, time: i64, destination: &mut [u8]) {
+        destination.fill(0);
+        let block_alignment = self.block_alignment;
+        let frames = destination.len() / block_alignment;
+        let end_time = time + self.frames_to_time(frames);
+        let mut buffer = self.buffer.lock().unwrap();
+        let CapturedAudio { packets, length } = &mut *buffer;
+        while let Some(packet) = packets.front_mut() {
+            let packet_frames = packet.data.len() / block_alignment;
+            if packet.time >= end_time {
+                break;
+            }
+            // Where the packet starts relative to the destination, in frames
+            let offset = self.time_to_frames(packet.time - time);
+            let skipped = ((-offset).max(0) as usize).min(packet_frames);
+            let start = (offset.max(0) as usize).min(frames);
+            let count = (packet_frames - skipped).min(frames - start);
+            destination[start * block_alignment..(start + count) * block_alignment]
+                .copy_from_slice(
+                    &packet.data[skipped * block_alignment..(skipped + count) * block_alignment],
+                );
+            let used = skipped + count;
+            if used < packet_frames {
+                // The rest comes after the destination
+                packet.data.drain(..used * block_alignment);
+                packet.time += self.frames_to_time(used);
+                *length -= used * block_alignment;
+                break;
+            }
+            *length -= packet.data.len();
+            packets.pop_front();
+        }
+    }
+
+    fn frames_to_time(&self, frames: usize) -> i64 {
+        (frames as i64 * 10_000_000) / self.sample_rate as i64
+    }
+
+    fn time_to_frames(&self, time: i64) -> i64 {
+        (time * self.sample_rate as i64).div_euclid(10_000_000)
+    }
+}
+
+impl Drop for ProcessAudioCapture {
+    fn drop(&mut self) {
+        self.should_stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn create_capture_client(
+    process_id: u32,
+    format: &WAVEFORMATEX,
+) -> Result<(IAudioClient, IAudioCaptureClient, HANDLE)> {
+    let audio_client = activate_process_loopback_client(process_id)?;
+    unsafe {
+        audio_client.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_LOOPBACK
+                | AUDCLNT_STREAMFLAGS_EVENTCALLBACK
+                | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+            BUFFER_DURATION,
+            0,
+            format,
+            None,
+        )?;
+        let event = CreateEventW(None, false, false, None)?;
+        audio_client.SetEventHandle(event)?;
+        let capture_client: IAudioCaptureClient = audio_client.GetService()?;
+        Ok((audio_client, capture_client, event))
+    }
+}
+
+fn activate_process_loopback_client(process_id: u32) -> Result<IAudioClient> {
+    let mut params = AUDIOCLIENT_ACTIVATION_PARAMS {
+        ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+        Anonymous: AUDIOCLIENT_ACTIVATION_PARAMS_0 {
+            ProcessLoopbackParams: AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS {
+                TargetProcessId: process_id,
+                ProcessLoopbackMode: PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            },
+        },
+    };
+    // The blob points at our params, so it must never be cleared
+    let activation_params = unsafe {
+        let mut activation_params = ManuallyDrop::new(PROPVARIANT::default());
+        (*activation_params.Anonymous.Anonymous).vt = VT_BLOB;
+        (*activation_params.Anonymous.Anonymous).Anonymous.blob = BLOB {
+            cbSize: std::mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>() as u32,
+            pBlobData: &mut params as *mut _ as *mut u8,
+        };
+        activation_params
+    };
+
+    let (sender, receiver) = channel();
+    let handler: IActivateAudioInterfaceCompletionHandler = ActivationCompletionHandler {
+        sender: Mutex::new(sender),
+    }
+    .into();
+    let operation = unsafe {
+        ActivateAudioInterfaceAsync(
+            VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+            &IAudioClient::IID,
+            Some(&*activation_params as *const _),
+            &handler,
+        )?
+    };
+    receiver.recv().unwrap();
+
+    let mut activate_result = HRESULT(0);
+    let mut activated_interface: Option<IUnknown> = None;
+    unsafe { operation.GetActivateResult(&mut activate_result, &mut activated_interface)? };
+    activate_result.ok()?;
+    activated_interface
+        .ok_or_else(|| {
+            Error::new(
+                E_FAIL,
+                "The process loopback audio client couldn't be activated!".into(),
+            )
+        })?
+        .cast()
+}
+
+fn capture_loop(
+    capture_client: &IAudioCaptureClient,
+    event: HANDLE,
+    buffer: &Mutex<CapturedAudio>,
+    block_alignment: usize,
+    max_buffered: usize,
+    pause_state: &PauseState,
+    should_stop: &AtomicBool,
+) -> Result<()> {
+    while !should_stop.load(Ordering::SeqCst) {
+        if unsafe { WaitForSingleObject(event, WAIT_TIMEOUT_MS) } != WAIT_OBJECT_0 {
+            continue;
+        }
+        while unsafe { capture_client.GetNextPacketSize()? } > 0 {
+            let mut data = std::ptr::null_mut();
+            let mut frames = 0;
+            let mut flags = 0;
+            let mut time = 0;
+            unsafe {
+                capture_client.GetBuffer(
+                    &mut data,
+                    &mut frames,
+                    &mut flags,
+                    None,
+                    Some(&mut time),
+                )?
+            };
+            let length = frames as usize * block_alignment;
+            if !pause_state.is_paused() {
+                let data = if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                    vec![0; length]
+                } else {
+                    unsafe { std::slice::from_raw_parts(data, length) }.to_vec()
+                };
+                let mut buffer = buffer.lock().unwrap();
+                buffer.length += length;
+                buffer.packets.push_back(AudioPacket {
+                    time: time as i64,
+                    data,
+                });
+                // Nobody's reading fast enough, keep the newest second
+                while buffer.length > max_buffered {
+                    let Some(packet) = buffer.packets.pop_front() else {
+                        break;
+                    };
+                    buffer.length -= packet.data.len();
+                }
+            }
+            unsafe { capture_client.ReleaseBuffer(frames)? };
+        }
+    }
+    Ok(())
+}
+
+#[implement(IActivateAudioInterfaceCompletionHandler)]
+struct ActivationCompletionHandler {
+    sender: Mutex<std::sync::mpsc::Sender<()>>,
+}
+
+impl IActivateAudioInterfaceCompletionHandler_Impl for ActivationCompletionHandler {
+    fn ActivateCompleted(
+        &self,
+        _operation: Option<&IActivateAudioInterfaceAsyncOperation>,
+    ) -> Result<()> {
+        let _ = self.sender.lock().unwrap().send(());
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::{io::Write, sync::Mutex, time::Duration};
+
+// A single console line that is rewritten in place (using carriage
+// returns) as a fixed-duration recording progresses.
+pub struct ProgressLine {
+    duration: Duration,
+    state: Mutex<ProgressLineState>,
+}
+
+#[derive(Default)]
+struct ProgressLineState {
+    last_percent: Option<u32>,
+    line_length: usize,
+    finished: bool,
+}
+
+impl ProgressLine {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            state: Mutex::new(ProgressLineState::default()),
+        }
+    }
+
+    pub fn update(&self, progress: f64) {
+        let mut state = self.state.lock().unwrap();
+        let percent = (progress * 100.0) as u32;
+        if state.finished || state.last_percent == Some(percent) {
+            return;
+        }
+        state.last_percent = Some(percent);
+
+        let elapsed = self.duration.mul_f64(progress);
+        let line = format!(
+            "Recording... {:>3}% ({} / {})",
+            percent,
+            format_time(elapsed),
+            format_time(self.duration)
+        );
+        // Pad with spaces in case the previous line was longer
+        let width = state.line_length.max(line.len());
+        print!("\r{:width$}", line, width = width);
+        let _ = std::io::stdout().flush();
+        state.line_length = line.len();
+    }
+
+    // Prints a message on its own line, the progress line gets redrawn
+    // on the next update.
+    pub fn message(&self, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.line_length > 0 {
+            print!("\r{:width$}\r", "", width = state.line_length);
+            state.line_length = 0;
+        }
+        println!("{}", message);
+        state.last_percent = None;
+    }
+
+    // Clears the line so that whatever gets printed next starts fresh.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return;
+        }
+        state.finished = true;
+        if state.line_length > 0 {
+            print!("\r{:width$}\r", "", width = state.line_length);
+            let _ = std::io::stdout().flush();
+        }
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use windows::{
+    core::Result,
+    Foundation::TimeSpan,
+    Graphics::{
+        Capture::{GraphicsCaptureItem, GraphicsCaptureSession},
+        SizeInt32,
+    },
+    Win32::Graphics::Direct3D11::{ID3D11Device, D3D11_BOX, D3D11_TEXTURE2D_DESC},
+};
+
+use crate::{
+    capture::{
+        get_capture_item_size, CaptureFrame, CaptureFrameGenerator, CaptureFrameGeneratorStopSignal,
+    },
+    d3d::{create_d3d_device, StagingReadback},
+};
+
+// A captured frame read back to the CPU as BGRA8. Rows are stride bytes
+// apart, which is usually more than width * 4 since staging textures pad
+// their rows, so use row() (or skip the padding yourself) rather than
+// treating data as tightly packed.
+pub struct RawFrame<'a> {
+    pub data: &'a [u8],
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    // Relative to the first frame read.
+    pub timestamp: Duration,
+}
+
+impl<'a> RawFrame<'a> {
+    // The pixels of one row, without the padding.
+    pub fn row(&self, y: u32) -> &'a [u8] {
+        let start = y as usize * self.stride as usize;
+        &self.data[start..start + self.width as usize * 4]
+    }
+}
+
+// Pulls frames from a capture item as raw pixels instead of encoding them,
+// for tools that do their own thing with the frames (remote desktop, etc.).
+// Like Recorder, this expects WinRT to be initialized on the calling thread.
+pub struct RawFrameReader {
+    d3d_device: ID3D11Device,
+    session: GraphicsCaptureSession,
+    frame_generator: CaptureFrameGenerator,
+    capture_size: SizeInt32,
+    staging: Option<StagingReadback>,
+    buffer: Vec<u8>,
+    first_timestamp: Option<TimeSpan>,
+    started: bool,
+}
+
+impl RawFrameReader {
+    pub fn new(item: GraphicsCaptureItem) -> Result<Self> {
+        let d3d_device = create_d3d_device()?;
+        let capture_size = get_capture_item_size(&item)?;
+        let frame_generator = CaptureFrameGenerator::new(d3d_device.clone(), item, capture_size)?;
+        // Only duplication has no session
+        let session = frame_generator.session().unwrap();
+        Ok(Self {
+            d3d_device,
+            session,
+            frame_generator,
+            capture_size,
+            staging: None,
+            buffer: Vec::new(),
+            first_timestamp: None,
+            started: false,
+        })
+    }
+
+    // The capture session, e.g. to turn off the border before the first
+    // frame is read.
+    pub fn session(&self) -> &GraphicsCaptureSession {
+        &self.session
+    }
+
+    // Makes a blocked (or the next) call to next_raw_frame return None. Can
+    // be used from another thread.
+    pub fn stop_signal(&self) -> CaptureFrameGeneratorStopSignal {
+        self.frame_generator.stop_signal()
+    }
+
+    // Blocks until the next frame arrives, starting the capture on the first
+    // call. Returns None once stopped or when the item goes away. The frame
+    // borrows the reader's buffer, so it's only valid until the next call.
+    pub fn next_raw_frame(&mut self) -> Result<Option<RawFrame<'_>>> {
+        if !self.started {
+            self.session.StartCapture()?;
+            self.started = true;
+        }
+        loop {
+            let frame = if let Some(frame) = self.frame_generator.try_get_next_frame()? {
+                frame
+            } else {
+                return Ok(None);
+            };
+            let frame_time = frame.system_relative_time();
+            // The frame goes back to the pool whether or not it could be read
+            let result = self.read_back(&frame);
+            frame.close()?;
+            // Nothing to show while minimized
+            let (size, stride) = if let Some(read) = result? {
+                read
+            } else {
+                continue;
+            };
+
+            let first_timestamp = *self.first_timestamp.get_or_insert(frame_time);
+            return Ok(Some(RawFrame {
+                data: &self.buffer,
+                width: size.Width as u32,
+                height: size.Height as u32,
+                stride,
+                timestamp: Duration::from(TimeSpan {
+                    Duration: frame_time.Duration - first_timestamp.Duration,
+                }),
+            }));
+        }
+    }
+
+    // Copies the frame's content into the buffer, returning its size and
+    // the buffer's stride.
+    fn read_back(&mut self, frame: &CaptureFrame) -> Result<Option<(SizeInt32, u32)>> {
+        let content_size = frame.content_size();
+        if content_size.Width <= 0 || content_size.Height <= 0 {
+            return Ok(None);
+        }
+        // Later frames come in at the new size, this one is clamped
+        if content_size != self.capture_size {
+            self.frame_generator.resize(content_size)?;
+            self.capture_size = content_size;
+        }
+
+        let texture = frame.texture();
+        let texture_desc = unsafe {
+            let mut desc = D3D11_TEXTURE2D_DESC::default();
+            texture.GetDesc(&mut desc);
+            desc
+        };
+        let size = SizeInt32 {
+            Width: content_size.Width.min(texture_desc.Width as i32),
+            Height: content_size.Height.min(texture_desc.Height as i32),
+        };
+        let region = D3D11_BOX {
+            left: 0,
+            top: 0,
+            front: 0,
+            right: size.Width as u32,
+            bottom: size.Height as u32,
+            back: 1,
+        };
+        self.ensure_staging(size)?;
+        let staging = self.staging.as_mut().unwrap();
+        staging.copy(texture, Some(&region))?;
+        // Only ever one copy in flight
+        let mapped = staging.read()?.unwrap();
+        // Copied as is, padding included
+        self.buffer.clear();
+        self.buffer.extend_from_slice(mapped.data());
+        let stride = mapped.row_pitch() as u32;
+        Ok(Some((size, stride)))
+    }
+
+    fn ensure_staging(&mut self, size: SizeInt32) -> Result<()> {
+        if self.staging.as_ref().map(|staging| staging.size()) != Some(size) {
+            self.staging = Some(StagingReadback::new(&self.d3d_device, size, 1)?);
+        }
+        Ok(())
+    }
+}
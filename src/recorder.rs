@@ -0,0 +1,816 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use log::{debug, error, info, log_enabled, warn, Level};
+use windows::{
+    core::{Error, Result, RuntimeType, HSTRING},
+    Foundation::{AsyncOperationCompletedHandler, IAsyncOperation},
+    Graphics::{Capture::GraphicsCaptureItem, SizeInt32},
+    Storage::{CreationCollisionOption, FileAccessMode, StorageFile, StorageFolder},
+    Win32::{
+        Foundation::{
+            ERROR_ALREADY_EXISTS, ERROR_FILE_EXISTS, ERROR_LOCK_VIOLATION, ERROR_SHARING_VIOLATION,
+            ERROR_TIMEOUT, E_FAIL, E_INVALIDARG, E_NOTIMPL, HWND, MAX_PATH,
+        },
+        Media::MediaFoundation::{
+            MFShutdown, MFStartup, MFVideoFormat_H264, MFSTARTUP_FULL, MF_E_TOPO_CODEC_NOT_FOUND,
+        },
+        Storage::FileSystem::GetFullPathNameW,
+    },
+};
+
+use crate::{
+    apartment::ensure_apartment,
+    cancellation::{CancellationToken, StopReason},
+    capture::{
+        create_capture_item_for_desktop, create_capture_item_for_monitor, get_capture_item_size,
+        ExcludedWindow,
+    },
+    capture_source::CaptureSource,
+    d3d::{create_d3d_device_with_options, D3DDeviceOptions},
+    displays::{get_display_handle_from_index, get_display_info},
+    duplication::get_duplicated_output_size,
+    file_handle::{is_seekable_file, open_file_handle},
+    media::MF_VERSION,
+    pause::PauseState,
+    pipe::{is_named_pipe_path, open_named_pipe},
+    video::{
+        backend::EncoderBackend,
+        bit_rate::BitRate,
+        encoding_session::{
+            require_capture_item, StopOutcome, VideoEncoderSessionFactory, VideoEncodingSession,
+            VideoEncodingSettings,
+        },
+        frame_sink::FrameSinkSession,
+        ladder::Rendition,
+        mf::{encoder_device::VideoEncoderDevice, encoding_session::MFVideoEncodingSessionFactory},
+        output_stream::{is_null_output_path, OutputStream},
+        png_sequence::PngSequenceFrameSink,
+        progress::ElapsedTime,
+        raw::RawVideoEncodingSessionFactory,
+        segments::{resume_segment_index, segment_path, SegmentOpener, SegmentSettings},
+        shared_memory::SharedMemoryFrameSink,
+        wmt::encoding_session::WMTVideoEncodingSessionFactory,
+    },
+};
+
+// How long to wait on the output's folder or file before giving up.
+const FILE_OPERATION_TIMEOUT: Duration = Duration::from_secs(30);
+// How often record_until checks whether the recording ended on its own.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct RecorderConfig {
+    pub source: CaptureSource,
+    // Only used when recording a monitor.
+    pub display_index: usize,
+    pub output_path: PathBuf,
+    pub backend: EncoderBackend,
+    pub encoder_index: usize,
+    // Picks from the software encoders instead of the hardware ones, for
+    // comparing the two. The encoder index is into the software list.
+    pub force_software: bool,
+    // Writes a PNG per frame (see video::png_sequence) instead of a video,
+    // since H.264 can't carry an alpha channel. The output path is used as
+    // the base name for the frames and the backend is ignored.
+    pub alpha: bool,
+    // Publishes the frames to the named shared memory section (see
+    // video::shared_memory) instead of encoding them. The output path and
+    // the backend are ignored.
+    pub shared_memory: Option<String>,
+    pub settings: VideoEncodingSettings,
+    // The D3D device used for capture and encoding.
+    pub device_options: D3DDeviceOptions,
+    // Extra renditions recorded from the same capture, each written next to
+    // the output (see Rendition::output_path). Only supported by the mf
+    // backend.
+    pub ladder: Vec<Rendition>,
+    // Windows hidden from the capture until the recording stops (see
+    // ExcludedWindow). They have to belong to this process.
+    pub excluded_windows: Vec<HWND>,
+    // Splits the recording into numbered files of about this length (see
+    // segment_path), each finalized as soon as the next one starts. Only
+    // supported by the mf backend.
+    pub segment_duration: Option<Duration>,
+    // Only warns when the recording ends without a single frame encoded,
+    // instead of failing to stop. Backends that don't count their frames
+    // never fail.
+    pub allow_empty: bool,
+    // Replaces an existing output file, otherwise creating the recording
+    // fails if there's one. The same goes for segments and renditions, a
+    // segment that already exists fails the recording once it's reached.
+    pub overwrite: bool,
+    // Continues a segmented recording after the segments already on disk
+    // (see resume_segment_index) instead of starting over at the first one.
+    pub resume: bool,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            source: CaptureSource::Monitor,
+            display_index: 0,
+            output_path: PathBuf::from("recording.mp4"),
+            backend: EncoderBackend::MediaFoundation,
+            encoder_index: 0,
+            force_software: false,
+            alpha: false,
+            shared_memory: None,
+            settings: VideoEncodingSettings::default(),
+            device_options: D3DDeviceOptions::default(),
+            ladder: Vec::new(),
+            excluded_windows: Vec::new(),
+            segment_duration: None,
+            allow_empty: false,
+            overwrite: true,
+            resume: false,
+        }
+    }
+}
+
+// Records a display to a file. The caller is responsible for initializing
+// WinRT (see ensure_apartment) and Media Foundation on the calling thread
+// beforehand. The process should also be per-monitor DPI aware, otherwise
+// monitor bounds are virtualized on scaled displays.
+pub struct Recorder {
+    session: Box<dyn VideoEncodingSession>,
+    cancellation_token: CancellationToken,
+    pause_state: PauseState,
+    excluded_windows: Vec<ExcludedWindow>,
+    allow_empty: bool,
+}
+
+impl Recorder {
+    pub fn new(config: &RecorderConfig) -> Result<Self> {
+        if config.shared_memory.is_some() {
+            if config.alpha || !config.ladder.is_empty() || config.segment_duration.is_some() {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "Shared memory can't be combined with alpha, renditions or segments!".into(),
+                ));
+            }
+            // Nothing is written to the output
+            return Self::create(config, OutputStream::null);
+        }
+        if !config.ladder.is_empty() {
+            if config.backend != EncoderBackend::MediaFoundation || config.alpha {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "Recording several renditions is only supported by the mf backend!".into(),
+                ));
+            }
+            if is_named_pipe_path(&config.output_path) {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "Several renditions can't be written to a named pipe!".into(),
+                ));
+            }
+        }
+        if let Some(segment_duration) = config.segment_duration {
+            return Self::new_segmented(config, segment_duration);
+        }
+        if config.resume {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "Only recordings split into segments can be resumed!".into(),
+            ));
+        }
+        if is_named_pipe_path(&config.output_path) {
+            // Pipes can't seek, which rules out anything that goes back to
+            // patch the file once it's done.
+            match config.backend {
+                EncoderBackend::WindowsMediaTranscoding => {
+                    return Err(Error::new(
+                        E_INVALIDARG,
+                        "The wmt backend can't write to a named pipe!".into(),
+                    ));
+                }
+                EncoderBackend::MediaFoundation if !config.settings.container.is_streamable() => {
+                    return Err(Error::new(
+                        E_INVALIDARG,
+                        "Writing to a named pipe requires a fragmented MP4!".into(),
+                    ));
+                }
+                _ => {}
+            }
+            return Self::create(config, || Ok(open_named_pipe(&config.output_path)?.into()));
+        }
+        if is_null_output_path(&config.output_path) {
+            if config.alpha {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "Alpha recordings can't be written to the null output!".into(),
+                ));
+            }
+            return Self::create(config, OutputStream::null);
+        }
+        Self::create(config, || {
+            open_output_file(&config.output_path, config.overwrite)
+        })
+    }
+
+    fn new_segmented(config: &RecorderConfig, segment_duration: Duration) -> Result<Self> {
+        if config.backend != EncoderBackend::MediaFoundation || config.alpha {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "Splitting the recording into segments is only supported by the mf backend!".into(),
+            ));
+        }
+        if !config.ladder.is_empty() {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "Several renditions can't be split into segments!".into(),
+            ));
+        }
+        if is_named_pipe_path(&config.output_path) {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "A named pipe can't be split into segments!".into(),
+            ));
+        }
+        if segment_duration.is_zero() {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "Segments have to be longer than zero!".into(),
+            ));
+        }
+
+        let first_index = if config.resume && !is_null_output_path(&config.output_path) {
+            let first_index = resume_segment_index(&config.output_path);
+            if first_index > 1 {
+                info!(
+                    "Resuming the recording at \"{}\"...",
+                    segment_path(&config.output_path, first_index).display()
+                );
+            }
+            first_index
+        } else {
+            1
+        };
+        let output_path = config.output_path.clone();
+        let overwrite = config.overwrite;
+        let opener = SegmentOpener::new(move |index| {
+            if is_null_output_path(&output_path) {
+                return OutputStream::null();
+            }
+            let path = segment_path(&output_path, index);
+            debug!("Writing segment {} to \"{}\".", index, path.display());
+            open_output_file(&path, overwrite)
+        });
+        let mut config = config.clone();
+        config.settings.segments = Some(SegmentSettings {
+            duration: segment_duration,
+            opener: opener.clone(),
+            first_index,
+        });
+        Self::create(&config, || opener.open(first_index))
+    }
+
+    // Records to the given stream instead of the config's output path. Not
+    // used with alpha, which always writes PNGs next to the output path.
+    pub fn with_output_stream(config: &RecorderConfig, stream: OutputStream) -> Result<Self> {
+        Self::create(config, || Ok(stream))
+    }
+
+    // Records to a file the caller opened itself, for hosts that keep the
+    // recorder away from the file system. A raw HANDLE can be passed with
+    // File::from_raw_handle. Files that can't seek (e.g. pipes) need a
+    // fragmented MP4, like named pipes do.
+    pub fn with_output_file(config: &RecorderConfig, file: File) -> Result<Self> {
+        if config.alpha
+            || config.shared_memory.is_some()
+            || !config.ladder.is_empty()
+            || config.segment_duration.is_some()
+        {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "An output file can't be combined with alpha, shared memory, renditions or segments!"
+                    .into(),
+            ));
+        }
+        if !is_seekable_file(&file) {
+            match config.backend {
+                EncoderBackend::WindowsMediaTranscoding => {
+                    return Err(Error::new(
+                        E_INVALIDARG,
+                        "The wmt backend can't write to a file that can't seek!".into(),
+                    ));
+                }
+                EncoderBackend::MediaFoundation if !config.settings.container.is_streamable() => {
+                    return Err(Error::new(
+                        E_INVALIDARG,
+                        "Writing to a file that can't seek requires a fragmented MP4!".into(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        let byte_stream = open_file_handle(file)?;
+        Self::create(config, || Ok(byte_stream.into()))
+    }
+
+    // The output is only opened once we know the capture target is valid,
+    // so that we don't leave empty files behind.
+    fn create<F: FnOnce() -> Result<OutputStream>>(
+        config: &RecorderConfig,
+        create_stream: F,
+    ) -> Result<Self> {
+        debug!(
+            "Using index \"{}\" and path \"{}\".",
+            config.display_index,
+            config.output_path.display()
+        );
+
+        let item = create_capture_item(config)?;
+        // Ending this recording shouldn't cancel the caller's token, which
+        // would stop the next recording made with the same config.
+        let mut settings = config.settings.clone();
+        settings.cancellation_token = config.settings.cancellation_token.child();
+
+        let excluded_windows = config
+            .excluded_windows
+            .iter()
+            .map(|window| ExcludedWindow::new(*window))
+            .collect::<Result<Vec<_>>>()?;
+
+        if config.alpha || config.shared_memory.is_some() {
+            let item = require_capture_item(item)?;
+            let item_size = get_capture_item_size(&item)?;
+            let d3d_device = create_d3d_device_with_options(&config.device_options)?;
+            let session: Box<dyn VideoEncodingSession> = if let Some(name) =
+                config.shared_memory.as_ref()
+            {
+                let sink = SharedMemoryFrameSink::new(&d3d_device, item_size, name)?;
+                Box::new(FrameSinkSession::new(d3d_device, item, &settings, sink)?)
+            } else {
+                let sink = PngSequenceFrameSink::new(&d3d_device, item_size, &config.output_path)?;
+                Box::new(FrameSinkSession::new(d3d_device, item, &settings, sink)?)
+            };
+            return Ok(Self {
+                session,
+                cancellation_token: settings.cancellation_token.clone(),
+                pause_state: config.settings.pause_state.clone(),
+                excluded_windows,
+                allow_empty: config.allow_empty,
+            });
+        }
+
+        let session_factory = create_encoding_session_factory(
+            config.backend,
+            config.encoder_index,
+            config.force_software,
+        )?;
+
+        // Resolve the bit rate here so that we can tell the user what we picked
+        if settings.bit_rate == BitRate::Auto {
+            let output_size = settings.output_size(get_source_size(config, item.as_ref())?);
+            let bit_rate =
+                settings
+                    .bit_rate
+                    .resolve(output_size, settings.frame_rate, &MFVideoFormat_H264);
+            debug!("Using an automatic bit rate of {} kbps.", bit_rate / 1000);
+            settings.bit_rate = BitRate::Fixed(bit_rate);
+        }
+
+        let stream = create_stream()?;
+        let d3d_device = create_d3d_device_with_options(&config.device_options)?;
+        let result = if config.ladder.is_empty() {
+            session_factory.create_session(d3d_device, item, &settings, stream)
+        } else {
+            let renditions = config
+                .ladder
+                .iter()
+                .map(|rendition| Ok((*rendition, open_rendition_output(config, rendition)?)))
+                .collect::<Result<Vec<_>>>()?;
+            session_factory.create_ladder_session(d3d_device, item, &settings, stream, renditions)
+        };
+        if result.is_err() {
+            error!("Error during encoder setup, try another set of encoding settings.");
+        }
+
+        Ok(Self {
+            session: result?,
+            cancellation_token: settings.cancellation_token.clone(),
+            pause_state: config.settings.pause_state.clone(),
+            excluded_windows,
+            allow_empty: config.allow_empty,
+        })
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.session.start()
+    }
+
+    pub fn stop(&mut self) -> Result<StopReason> {
+        self.cancellation_token.cancel();
+        let result = self.session.stop();
+        self.excluded_windows.clear();
+        result?;
+        self.check_frames_encoded()?;
+        Ok(self.stop_reason().unwrap())
+    }
+
+    // Gives up on draining the encoder after the timeout, see StopOutcome.
+    // The reason the recording ended is available from stop_reason.
+    pub fn stop_with_timeout(&mut self, timeout: Duration) -> Result<StopOutcome> {
+        self.cancellation_token.cancel();
+        let result = self.session.stop_with_timeout(timeout);
+        self.excluded_windows.clear();
+        let outcome = result?;
+        self.check_frames_encoded()?;
+        Ok(outcome)
+    }
+
+    // An empty file is still a valid video, which is easy to mistake for a
+    // successful recording (e.g. when the wrong display was captured).
+    fn check_frames_encoded(&self) -> Result<()> {
+        if self.session.frames_encoded() != Some(0) {
+            return Ok(());
+        }
+        if self.allow_empty {
+            warn!("No frames were recorded, the output is empty.");
+            Ok(())
+        } else {
+            Err(Error::new(
+                E_FAIL,
+                "No frames were recorded, the output is empty!".into(),
+            ))
+        }
+    }
+
+    // Frames are dropped until resume is called, and the paused time is left
+    // out of the recording. Only supported by the mf backend.
+    pub fn pause(&self) -> Result<()> {
+        if !self.session.supports_pause() {
+            return Err(Error::new(
+                E_NOTIMPL,
+                "Pausing is only supported by the mf backend!".into(),
+            ));
+        }
+        self.pause_state.pause();
+        Ok(())
+    }
+
+    pub fn resume(&self) {
+        self.pause_state.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause_state.is_paused()
+    }
+
+    // The token this recording stops on, a child of the config's. Unlike
+    // the config's, it's also cancelled when the recording ends on its own.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation_token.clone()
+    }
+
+    // Why the recording ended, None while it's still going.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.cancellation_token.stop_reason()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.session.elapsed()
+    }
+
+    // For keeping track of the elapsed time from another thread.
+    pub fn elapsed_time(&self) -> ElapsedTime {
+        self.session.elapsed_time()
+    }
+
+    pub fn pause_state(&self) -> PauseState {
+        self.pause_state.clone()
+    }
+
+    // Only known with the mf backend.
+    pub fn frames_encoded(&self) -> Option<u64> {
+        self.session.frames_encoded()
+    }
+
+    pub fn snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.session.snapshot(path.as_ref().to_owned())
+    }
+
+    // Moves the capture over to another display while recording, keeping
+    // the same output. Displays of a different size are scaled to fit.
+    // Only supported by the mf backend.
+    pub fn switch_display(&mut self, display_index: usize) -> Result<()> {
+        let item = create_monitor_capture_item(display_index)?;
+        self.session
+            .retarget(item, get_display_handle_from_index(display_index))
+    }
+}
+
+// Records a display for the given duration and finalizes the file before
+// returning. Unlike Recorder, this takes care of initializing WinRT and
+// Media Foundation. The rest of the config (encoder, bit rate, etc.) is
+// used as is, and its cancellation token can still end the recording early.
+pub fn record_display_for<P: AsRef<Path>>(
+    display_index: usize,
+    output_path: P,
+    duration: Duration,
+    config: &RecorderConfig,
+) -> Result<()> {
+    let config = {
+        let mut config = config.clone();
+        config.source = CaptureSource::Monitor;
+        config.display_index = display_index;
+        config.output_path = output_path.as_ref().to_owned();
+        config.settings.duration = Some(duration);
+        config
+    };
+
+    let _apartment = ensure_apartment()?;
+    unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? };
+    let result = record_until_cancelled(&config, duration);
+    unsafe { MFShutdown()? };
+    result
+}
+
+fn record_until_cancelled(config: &RecorderConfig, duration: Duration) -> Result<()> {
+    let mut recorder = Recorder::new(config)?;
+    let cancellation_token = recorder.cancellation_token();
+    let (sender, receiver) = channel();
+    cancellation_token.on_cancelled(move || {
+        let _ = sender.send(());
+    });
+
+    recorder.start()?;
+    // The duration is measured using frame times, which won't advance if
+    // nothing on screen changes. Fall back to the wall clock in that case.
+    if receiver
+        .recv_timeout(duration + Duration::from_secs(1))
+        .is_err()
+    {
+        cancellation_token.cancel_with_reason(StopReason::MaxDurationReached);
+    }
+    recorder.stop()?;
+    Ok(())
+}
+
+// What a finished recording ended up with, see record_until.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RecordingStats {
+    pub stop_reason: StopReason,
+    // The length of the recording, without any time spent paused.
+    pub duration: Duration,
+    // Only known with the mf backend.
+    pub frames_encoded: Option<u64>,
+}
+
+// Records with the given config until something is sent on stop (or its
+// sender is dropped), then finalizes the output and returns how it went. The
+// recording can also end on its own (duration, frame limit, the source
+// closing, or the config's cancellation token), which returns early.
+//
+// This blocks the calling thread for the whole recording, so keep the
+// Sender on another thread (e.g. a UI or signal handler) and send () on it to
+// stop. Like record_display_for, this takes care of initializing WinRT and
+// Media Foundation.
+pub fn record_until(config: &RecorderConfig, stop: Receiver<()>) -> Result<RecordingStats> {
+    let _apartment = ensure_apartment()?;
+    unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? };
+    let result = record_until_stopped(config, stop);
+    unsafe { MFShutdown()? };
+    result
+}
+
+fn record_until_stopped(config: &RecorderConfig, stop: Receiver<()>) -> Result<RecordingStats> {
+    let mut recorder = Recorder::new(config)?;
+    let cancellation_token = recorder.cancellation_token();
+    recorder.start()?;
+    while !cancellation_token.is_cancelled() {
+        match stop.recv_timeout(STOP_POLL_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    let stop_reason = recorder.stop()?;
+    Ok(RecordingStats {
+        stop_reason,
+        duration: recorder.elapsed(),
+        frames_encoded: recorder.frames_encoded(),
+    })
+}
+
+// What the config records, before any crop. There's no item when the
+// display is captured with desktop duplication, which doesn't use
+// Windows.Graphics.Capture at all.
+pub fn create_capture_item(config: &RecorderConfig) -> Result<Option<GraphicsCaptureItem>> {
+    if uses_desktop_duplication(config) {
+        return Ok(None);
+    }
+    let item = match config.source {
+        CaptureSource::Monitor => create_monitor_capture_item(config.display_index)?,
+        CaptureSource::Desktop => create_capture_item_for_desktop()?,
+    };
+    Ok(Some(item))
+}
+
+// The size of what the config records, before any crop. The item is the one
+// create_capture_item made for the config.
+pub fn get_source_size(
+    config: &RecorderConfig,
+    item: Option<&GraphicsCaptureItem>,
+) -> Result<SizeInt32> {
+    match (item, config.settings.desktop_duplication) {
+        (Some(item), _) => get_capture_item_size(item),
+        (None, Some(display_handle)) => get_duplicated_output_size(display_handle),
+        (None, None) => Err(Error::new(
+            E_INVALIDARG,
+            "The config isn't recorded with desktop duplication, it needs a capture item!".into(),
+        )),
+    }
+}
+
+// Only the mf backend duplicates, and frame sinks always capture an item.
+fn uses_desktop_duplication(config: &RecorderConfig) -> bool {
+    config.settings.desktop_duplication.is_some()
+        && config.backend == EncoderBackend::MediaFoundation
+        && !config.alpha
+        && config.shared_memory.is_none()
+}
+
+fn create_monitor_capture_item(display_index: usize) -> Result<GraphicsCaptureItem> {
+    // Get the display handle using the provided index
+    let display_handle = if let Some(display_handle) = get_display_handle_from_index(display_index)
+    {
+        display_handle
+    } else {
+        return Err(Error::new(
+            E_INVALIDARG,
+            "The provided display index was out of bounds!".into(),
+        ));
+    };
+    let item = create_capture_item_for_monitor(display_handle)?;
+    if log_enabled!(Level::Debug) {
+        // The capture item is always in physical pixels, if the monitor
+        // bounds disagree then we aren't DPI aware.
+        let item_size = item.Size()?;
+        let display_info = get_display_info(display_handle)?;
+        let rect = display_info.rect;
+        let (width, height) = (rect.right - rect.left, rect.bottom - rect.top);
+        debug!(
+            "Capture item size: {}x{}, monitor bounds: {}x{}",
+            item_size.Width, item_size.Height, width, height
+        );
+        if width != item_size.Width || height != item_size.Height {
+            warn!(
+                "The capture item size doesn't match the monitor bounds, is the process DPI aware?"
+            );
+        }
+    }
+    Ok(item)
+}
+
+fn create_encoding_session_factory(
+    backend: EncoderBackend,
+    encoder_index: usize,
+    force_software: bool,
+) -> Result<Box<dyn VideoEncoderSessionFactory>> {
+    Ok(match backend {
+        EncoderBackend::MediaFoundation => {
+            let (encoder_devices, kind) = if force_software {
+                (VideoEncoderDevice::enumerate_software()?, "software")
+            } else {
+                (VideoEncoderDevice::enumerate()?, "hardware")
+            };
+            if encoder_devices.is_empty() {
+                return Err(Error::new(
+                    MF_E_TOPO_CODEC_NOT_FOUND,
+                    format!("No {} H264 encoders found!", kind).into(),
+                ));
+            }
+            debug!("Encoders ({}):", encoder_devices.len());
+            for encoder_device in &encoder_devices {
+                debug!("  {}", encoder_device.display_name());
+            }
+            let encoder_device = if let Some(encoder_device) = encoder_devices.get(encoder_index) {
+                encoder_device
+            } else {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "Encoder index is out of bounds!".into(),
+                ));
+            };
+            info!(
+                "Using the {} encoder: {}",
+                encoder_device.kind(),
+                encoder_device.display_name()
+            );
+            Box::new(MFVideoEncodingSessionFactory::new(encoder_device.clone()))
+        }
+        EncoderBackend::WindowsMediaTranscoding => Box::new(WMTVideoEncodingSessionFactory::new()),
+        EncoderBackend::Raw => Box::new(RawVideoEncodingSessionFactory::new()),
+    })
+}
+
+fn open_output_file(output_path: &Path, overwrite: bool) -> Result<OutputStream> {
+    let file = create_file(output_path, overwrite)?;
+    let stream = wait_for_file_operation(
+        file.OpenAsync(FileAccessMode::ReadWrite)?,
+        "open",
+        output_path,
+    )?;
+    Ok(stream.into())
+}
+
+// Renditions of a null recording aren't written anywhere either.
+fn open_rendition_output(config: &RecorderConfig, rendition: &Rendition) -> Result<OutputStream> {
+    if is_null_output_path(&config.output_path) {
+        OutputStream::null()
+    } else {
+        let path = rendition.output_path(&config.output_path);
+        debug!(
+            "Writing the {}p rendition to \"{}\".",
+            rendition.size.Height,
+            path.display()
+        );
+        open_output_file(&path, config.overwrite)
+    }
+}
+
+fn create_file(output_path: &Path, overwrite: bool) -> Result<StorageFile> {
+    let path = unsafe {
+        let mut new_path = vec![0u16; MAX_PATH as usize];
+        let length = GetFullPathNameW(
+            &HSTRING::from(output_path.as_os_str().to_str().unwrap()),
+            Some(&mut new_path),
+            None,
+        );
+        new_path.resize(length as usize, 0);
+        String::from_utf16(&new_path).unwrap()
+    };
+    let path = Path::new(&path);
+    let parent_folder_path = path.parent().unwrap();
+    let parent_folder = wait_for_file_operation(
+        StorageFolder::GetFolderFromPathAsync(&HSTRING::from(
+            parent_folder_path.as_os_str().to_str().unwrap(),
+        ))?,
+        "open the folder",
+        parent_folder_path,
+    )?;
+    let file_name = path.file_name().unwrap();
+    wait_for_file_operation(
+        parent_folder.CreateFileAsync(
+            &HSTRING::from(file_name.to_str().unwrap()),
+            if overwrite {
+                CreationCollisionOption::ReplaceExisting
+            } else {
+                CreationCollisionOption::FailIfExists
+            },
+        )?,
+        "create",
+        path,
+    )
+}
+
+// Like get(), but gives up after FILE_OPERATION_TIMEOUT instead of hanging
+// on an unresponsive network drive, and says which operation on which path
+// failed.
+fn wait_for_file_operation<T: RuntimeType + 'static>(
+    operation: IAsyncOperation<T>,
+    action: &str,
+    path: &Path,
+) -> Result<T> {
+    let (sender, receiver) = channel();
+    operation.SetCompleted(&AsyncOperationCompletedHandler::new(move |_, _| {
+        let _ = sender.send(());
+        Ok(())
+    }))?;
+    if receiver.recv_timeout(FILE_OPERATION_TIMEOUT).is_err() {
+        let _ = operation.Cancel();
+        return Err(Error::new(
+            ERROR_TIMEOUT.to_hresult(),
+            format!("Timed out trying to {} \"{}\"!", action, path.display()).into(),
+        ));
+    }
+    operation.GetResults().map_err(|error| {
+        let code = error.code();
+        let message = if code == ERROR_SHARING_VIOLATION.to_hresult()
+            || code == ERROR_LOCK_VIOLATION.to_hresult()
+        {
+            format!(
+                "The output file \"{}\" is in use by another program!",
+                path.display()
+            )
+        } else if code == ERROR_ALREADY_EXISTS.to_hresult()
+            || code == ERROR_FILE_EXISTS.to_hresult()
+        {
+            format!("The output file \"{}\" already exists!", path.display())
+        } else {
+            format!(
+                "Unable to {} \"{}\": {}",
+                action,
+                path.display(),
+                error.message()
+            )
+        };
+        Error::new(code, message.into())
+    })
+}
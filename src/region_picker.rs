@@ -0,0 +1,253 @@
+use std::cell::RefCell;
+
+use displayrecorder::{
+    capture::exclude_window_from_capture,
+    displays::{get_display_index_from_handle, get_display_info},
+};
+use windows::{
+    core::{w, Error, Result},
+    Graphics::RectInt32,
+    Win32::{
+        Foundation::{COLORREF, E_FAIL, HWND, LPARAM, LRESULT, POINT, RECT, WPARAM},
+        Graphics::Gdi::{
+            BeginPaint, EndPaint, FillRect, GetStockObject, InvalidateRect, MonitorFromRect,
+            BLACK_BRUSH, HBRUSH, MONITOR_DEFAULTTONEAREST, PAINTSTRUCT, WHITE_BRUSH,
+        },
+        System::LibraryLoader::GetModuleHandleW,
+        UI::{
+            Input::KeyboardAndMouse::{ReleaseCapture, SetCapture, VK_ESCAPE},
+            WindowsAndMessaging::{
+                CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetClientRect,
+                GetMessageW, GetSystemMetrics, LoadCursorW, PostQuitMessage, RegisterClassW,
+                SetForegroundWindow, SetLayeredWindowAttributes, TranslateMessage, IDC_CROSS,
+                LWA_ALPHA, MSG, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN,
+                SM_YVIRTUALSCREEN, WM_DESTROY, WM_KEYDOWN, WM_LBUTTONDOWN, WM_LBUTTONUP,
+                WM_MOUSEMOVE, WM_PAINT, WNDCLASSW, WS_EX_LAYERED, WS_EX_TOOLWINDOW, WS_EX_TOPMOST,
+                WS_POPUP, WS_VISIBLE,
+            },
+        },
+    },
+};
+
+// How opaque the overlay is, out of 255.
+const OVERLAY_ALPHA: u8 = 96;
+
+// A region picked on screen, as a crop of the display it's on.
+pub struct SelectedRegion {
+    pub display_index: usize,
+    pub crop: RectInt32,
+}
+
+#[derive(Default)]
+struct SelectionState {
+    // Where the overlay's client area starts on the virtual screen.
+    origin: POINT,
+    anchor: Option<POINT>,
+    current: POINT,
+    selection: Option<RECT>,
+}
+
+thread_local! {
+    static SELECTION_STATE: RefCell<SelectionState> = RefCell::new(SelectionState::default());
+}
+
+// Covers every display with a translucent overlay and lets the user drag
+// out a rectangle. Returns None if they pressed Esc instead. Selections that
+// span displays are clamped to the display with most of the selection on it,
+// since only one display can be captured at a time.
+pub fn select_region() -> Result<Option<SelectedRegion>> {
+    let selection = if let Some(selection) = pick_rect()? {
+        selection
+    } else {
+        return Ok(None);
+    };
+
+    let display_handle = unsafe { MonitorFromRect(&selection, MONITOR_DEFAULTTONEAREST) };
+    let display_index = get_display_index_from_handle(display_handle).ok_or_else(|| {
+        Error::new(
+            E_FAIL,
+            "The display the region is on couldn't be found!".into(),
+        )
+    })?;
+    let display_rect = get_display_info(display_handle)?.rect;
+    let left = selection.left.max(display_rect.left);
+    let top = selection.top.max(display_rect.top);
+    let right = selection.right.min(display_rect.right);
+    let bottom = selection.bottom.min(display_rect.bottom);
+    Ok(Some(SelectedRegion {
+        display_index,
+        crop: RectInt32 {
+            X: left - display_rect.left,
+            Y: top - display_rect.top,
+            Width: right - left,
+            Height: bottom - top,
+        },
+    }))
+}
+
+fn pick_rect() -> Result<Option<RECT>> {
+    let instance = unsafe { GetModuleHandleW(None)? };
+    let class_name = w!("displayrecorder.RegionPicker");
+    let class = WNDCLASSW {
+        hCursor: unsafe { LoadCursorW(None, IDC_CROSS)? },
+        hInstance: instance.into(),
+        lpszClassName: class_name,
+        lpfnWndProc: Some(wndproc),
+        ..Default::default()
+    };
+    if unsafe { RegisterClassW(&class) } == 0 {
+        return Err(Error::from_win32());
+    }
+
+    // The process is per-monitor DPI aware, so this is in physical pixels
+    let (x, y, width, height) = unsafe {
+        (
+            GetSystemMetrics(SM_XVIRTUALSCREEN),
+            GetSystemMetrics(SM_YVIRTUALSCREEN),
+            GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            GetSystemMetrics(SM_CYVIRTUALSCREEN),
+        )
+    };
+    SELECTION_STATE.with(|state| {
+        *state.borrow_mut() = SelectionState {
+            origin: POINT { x, y },
+            ..Default::default()
+        }
+    });
+
+    unsafe {
+        let window = CreateWindowExW(
+            WS_EX_LAYERED | WS_EX_TOPMOST | WS_EX_TOOLWINDOW,
+            class_name,
+            w!("Select a region to record"),
+            WS_POPUP | WS_VISIBLE,
+            x,
+            y,
+            width,
+            height,
+            None,
+            None,
+            instance,
+            None,
+        );
+        if window.0 == 0 {
+            return Err(Error::from_win32());
+        }
+        SetLayeredWindowAttributes(window, COLORREF(0), OVERLAY_ALPHA, LWA_ALPHA)?;
+        // Keeps the overlay out of anything already capturing the screen.
+        // Older builds just show it, which is no worse than before.
+        let _ = exclude_window_from_capture(window);
+        // Esc only reaches us if we have focus
+        SetForegroundWindow(window);
+
+        let mut message = MSG::default();
+        while GetMessageW(&mut message, None, 0, 0).into() {
+            TranslateMessage(&message);
+            DispatchMessageW(&message);
+        }
+    }
+
+    Ok(SELECTION_STATE.with(|state| state.borrow_mut().selection.take()))
+}
+
+extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match message {
+        WM_LBUTTONDOWN => {
+            let point = point_from_lparam(lparam);
+            SELECTION_STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                state.anchor = Some(point);
+                state.current = point;
+            });
+            unsafe {
+                SetCapture(window);
+            }
+        }
+        WM_MOUSEMOVE => {
+            let dragging = SELECTION_STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                state.current = point_from_lparam(lparam);
+                state.anchor.is_some()
+            });
+            if dragging {
+                unsafe {
+                    InvalidateRect(window, None, false);
+                }
+            }
+        }
+        WM_LBUTTONUP => {
+            unsafe {
+                let _ = ReleaseCapture();
+            }
+            let selection = SELECTION_STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let anchor = state.anchor.take()?;
+                let rect = normalize_rect(anchor, point_from_lparam(lparam));
+                // A click without a drag doesn't select anything
+                if rect.right - rect.left < 2 || rect.bottom - rect.top < 2 {
+                    return None;
+                }
+                let origin = state.origin;
+                Some(RECT {
+                    left: rect.left + origin.x,
+                    top: rect.top + origin.y,
+                    right: rect.right + origin.x,
+                    bottom: rect.bottom + origin.y,
+                })
+            });
+            if let Some(selection) = selection {
+                SELECTION_STATE.with(|state| state.borrow_mut().selection = Some(selection));
+                unsafe {
+                    let _ = DestroyWindow(window);
+                }
+            } else {
+                unsafe {
+                    InvalidateRect(window, None, false);
+                }
+            }
+        }
+        WM_KEYDOWN if wparam.0 == VK_ESCAPE.0 as usize => unsafe {
+            let _ = DestroyWindow(window);
+        },
+        WM_PAINT => unsafe {
+            let mut paint = PAINTSTRUCT::default();
+            let hdc = BeginPaint(window, &mut paint);
+            let mut client_rect = RECT::default();
+            let _ = GetClientRect(window, &mut client_rect);
+            FillRect(hdc, &client_rect, HBRUSH(GetStockObject(BLACK_BRUSH).0));
+            // The selection shows up lighter than the rest of the overlay
+            let selection = SELECTION_STATE.with(|state| {
+                let state = state.borrow();
+                state
+                    .anchor
+                    .map(|anchor| normalize_rect(anchor, state.current))
+            });
+            if let Some(selection) = selection {
+                FillRect(hdc, &selection, HBRUSH(GetStockObject(WHITE_BRUSH).0));
+            }
+            EndPaint(window, &paint);
+        },
+        WM_DESTROY => unsafe {
+            PostQuitMessage(0);
+        },
+        _ => return unsafe { DefWindowProcW(window, message, wparam, lparam) },
+    }
+    LRESULT(0)
+}
+
+fn point_from_lparam(lparam: LPARAM) -> POINT {
+    // Coordinates are signed, and can be negative while the mouse is captured
+    POINT {
+        x: (lparam.0 & 0xffff) as i16 as i32,
+        y: ((lparam.0 >> 16) & 0xffff) as i16 as i32,
+    }
+}
+
+fn normalize_rect(a: POINT, b: POINT) -> RECT {
+    RECT {
+        left: a.x.min(b.x),
+        top: a.y.min(b.y),
+        right: a.x.max(b.x),
+        bottom: a.y.max(b.y),
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{info, warn};
+use windows::Win32::{
+    Foundation::HANDLE,
+    System::{
+        StationsAndDesktops::{
+            CloseDesktop, GetThreadDesktop, GetUserObjectInformationW, OpenInputDesktop,
+            DESKTOP_CONTROL_FLAGS, DESKTOP_READOBJECTS, HDESK, UOI_NAME,
+        },
+        Threading::GetCurrentThreadId,
+    },
+};
+
+use crate::pause::PauseState;
+
+// How often the input desktop is checked. A UAC prompt takes a moment to
+// come up anyway.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Whether input is going to a desktop other than ours, i.e. the secure
+// desktop used by UAC prompts, the lock screen, and CTRL+ALT+DEL. We aren't
+// allowed to open the secure desktop, so this returns None when the input
+// desktop can't be opened. That also happens when we never could (e.g. a
+// service, or a locked down session), which is why the watcher only takes it
+// to mean the secure desktop once it's been able to open ours.
+pub fn secure_desktop_active() -> Option<bool> {
+    unsafe {
+        let input_desktop =
+            OpenInputDesktop(DESKTOP_CONTROL_FLAGS(0), false, DESKTOP_READOBJECTS).ok()?;
+        let input_name = desktop_name(input_desktop);
+        let _ = CloseDesktop(input_desktop);
+        let our_name = GetThreadDesktop(GetCurrentThreadId())
+            .ok()
+            .and_then(desktop_name);
+        Some(input_name != our_name)
+    }
+}
+
+// Holds the recording (see PauseState::set_held) for as long as the secure
+// desktop is showing. Graphics Capture can't see it, so without this the
+// recording would jump over the time it was up.
+pub struct SecureDesktopWatcher {
+    sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SecureDesktopWatcher {
+    pub fn new(pause_state: PauseState) -> Self {
+        let (sender, receiver) = channel();
+        let thread = std::thread::spawn(move || {
+            let mut held = false;
+            let mut opened = false;
+            let mut warned = false;
+            // Runs until the watcher is dropped
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(POLL_INTERVAL) {
+                let active = match secure_desktop_active() {
+                    Some(active) => {
+                        opened = true;
+                        active
+                    }
+                    None if opened => true,
+                    None => {
+                        if !warned {
+                            warn!("Unable to open the input desktop, the recording won't pause for the secure desktop...");
+                            warned = true;
+                        }
+                        false
+                    }
+                };
+                if active != held {
+                    if active {
+                        info!("The secure desktop is showing (e.g. a UAC prompt), pausing until it's gone...");
+                    } else {
+                        info!("The secure desktop is gone, resuming...");
+                    }
+                    pause_state.set_held(active);
+                    held = active;
+                }
+            }
+            if held {
+                pause_state.set_held(false);
+            }
+        });
+        Self {
+            sender,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for SecureDesktopWatcher {
+    fn drop(&mut self) {
+        let _ = self.sender.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn desktop_name(desktop: HDESK) -> Option<String> {
+    let mut name = [0u16; 256];
+    unsafe {
+        GetUserObjectInformationW(
+            HANDLE(desktop.0),
+            UOI_NAME,
+            Some(name.as_mut_ptr() as *mut _),
+            std::mem::size_of_val(&name) as u32,
+            None,
+        )
+        .ok()?;
+    }
+    let length = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    Some(String::from_utf16_lossy(&name[..length]))
+}
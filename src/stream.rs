@@ -0,0 +1,131 @@
+use std::ffi::c_void;
+
+use windows::{
+    core::{implement, Result, HRESULT},
+    Win32::{
+        Foundation::{E_NOTIMPL, STG_E_INVALIDFUNCTION, S_OK},
+        Media::MediaFoundation::{IMFByteStream, MFCreateMFByteStreamOnStream},
+        System::Com::{
+            ISequentialStream_Impl, IStream, IStream_Impl, LOCKTYPE, STATFLAG, STATSTG, STGC,
+            STGTY_STREAM, STREAM_SEEK,
+        },
+    },
+};
+
+// What a byte stream needs from wherever its bytes go. Everything else about
+// being an IStream is the same for all of them, see create_byte_stream.
+pub(crate) trait StreamTarget: 'static {
+    // Returns how much was read, which is 0 once the end has been reached.
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    // Either all of the data gets written or this fails.
+    fn write(&self, data: &[u8]) -> Result<()>;
+
+    // Returns the new position.
+    fn seek(&self, offset: i64, origin: STREAM_SEEK) -> Result<u64>;
+
+    fn set_size(&self, _size: u64) -> Result<()> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    fn size(&self) -> Result<u64>;
+}
+
+pub(crate) fn create_byte_stream<T: StreamTarget>(target: T) -> Result<IMFByteStream> {
+    let stream: IStream = TargetStream { target }.into();
+    unsafe { MFCreateMFByteStreamOnStream(&stream) }
+}
+
+#[implement(IStream)]
+struct TargetStream<T>
+where
+    T: StreamTarget,
+{
+    target: T,
+}
+
+impl<T: StreamTarget> ISequentialStream_Impl for TargetStream<T> {
+    fn Read(&self, pv: *mut c_void, cb: u32, pcbread: *mut u32) -> HRESULT {
+        let buffer = unsafe { std::slice::from_raw_parts_mut(pv as *mut u8, cb as usize) };
+        match self.target.read(buffer) {
+            Ok(read) => {
+                if !pcbread.is_null() {
+                    unsafe { *pcbread = read as u32 };
+                }
+                S_OK
+            }
+            Err(error) => error.code(),
+        }
+    }
+
+    fn Write(&self, pv: *const c_void, cb: u32, pcbwritten: *mut u32) -> HRESULT {
+        let data = unsafe { std::slice::from_raw_parts(pv as *const u8, cb as usize) };
+        if let Err(error) = self.target.write(data) {
+            return error.code();
+        }
+        if !pcbwritten.is_null() {
+            unsafe { *pcbwritten = cb };
+        }
+        S_OK
+    }
+}
+
+impl<T: StreamTarget> IStream_Impl for TargetStream<T> {
+    fn Seek(&self, dlibmove: i64, dworigin: STREAM_SEEK, plibnewposition: *mut u64) -> Result<()> {
+        let position = self.target.seek(dlibmove, dworigin)?;
+        if !plibnewposition.is_null() {
+            unsafe { *plibnewposition = position };
+        }
+        Ok(())
+    }
+
+    fn SetSize(&self, libnewsize: u64) -> Result<()> {
+        self.target.set_size(libnewsize)
+    }
+
+    fn CopyTo(
+        &self,
+        _pstm: Option<&IStream>,
+        _cb: u64,
+        _pcbread: *mut u64,
+        _pcbwritten: *mut u64,
+    ) -> Result<()> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    fn Commit(&self, _grfcommitflags: &STGC) -> Result<()> {
+        Ok(())
+    }
+
+    fn Revert(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn LockRegion(&self, _liboffset: u64, _cb: u64, _dwlocktype: &LOCKTYPE) -> Result<()> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    fn UnlockRegion(&self, _liboffset: u64, _cb: u64, _dwlocktype: u32) -> Result<()> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    fn Stat(&self, pstatstg: *mut STATSTG, _grfstatflag: &STATFLAG) -> Result<()> {
+        let size = self.target.size()?;
+        if !pstatstg.is_null() {
+            unsafe {
+                *pstatstg = STATSTG {
+                    r#type: STGTY_STREAM.0 as u32,
+                    cbSize: size,
+                    ..Default::default()
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn Clone(&self) -> Result<IStream> {
+        Err(E_NOTIMPL.into())
+    }
+}
@@ -0,0 +1,68 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::{
+    core::GUID,
+    Win32::Media::MediaFoundation::{MFAudioFormat_AAC, MFAudioFormat_FLAC, MFAudioFormat_MP3},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AudioCodec {
+    Aac,
+    Mp3,
+    Flac,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseAudioCodecError(&'static str);
+
+impl AudioCodec {
+    pub fn subtype(&self) -> GUID {
+        match self {
+            AudioCodec::Aac => MFAudioFormat_AAC,
+            AudioCodec::Mp3 => MFAudioFormat_MP3,
+            AudioCodec::Flac => MFAudioFormat_FLAC,
+        }
+    }
+
+    // Whether the MP4 sink can hold this codec, which is the only container
+    // we write.
+    pub fn supported_in_mp4(&self) -> bool {
+        match self {
+            AudioCodec::Aac | AudioCodec::Mp3 => true,
+            AudioCodec::Flac => false,
+        }
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = ParseAudioCodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aac" => Ok(AudioCodec::Aac),
+            "mp3" => Ok(AudioCodec::Mp3),
+            "flac" => Ok(AudioCodec::Flac),
+            _ => Err(ParseAudioCodecError(
+                "Invalid audio codec value! Expecting: aac, mp3, or flac.",
+            )),
+        }
+    }
+}
+
+impl Display for AudioCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::Flac => "flac",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseAudioCodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseAudioCodecError {}
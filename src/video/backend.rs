@@ -4,6 +4,8 @@ use std::{fmt::Display, str::FromStr};
 pub enum EncoderBackend {
     MediaFoundation,
     WindowsMediaTranscoding,
+    // Uncompressed frames, see video::raw
+    Raw,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -16,8 +18,9 @@ impl FromStr for EncoderBackend {
         match s.to_lowercase().as_str() {
             "mf" => Ok(EncoderBackend::MediaFoundation),
             "wmt" => Ok(EncoderBackend::WindowsMediaTranscoding),
+            "raw" => Ok(EncoderBackend::Raw),
             _ => Err(ParseEncoderBackendError(
-                "Invalid encoder backend value! Expecting: 'mf' (MediaFoundation), or 'wmt' (Windows.Media.Transcoding).",
+                "Invalid encoder backend value! Expecting: 'mf' (MediaFoundation), 'wmt' (Windows.Media.Transcoding), or 'raw' (uncompressed frames).",
             )),
         }
     }
@@ -28,6 +31,7 @@ impl Display for EncoderBackend {
         let string = match self {
             EncoderBackend::MediaFoundation => "mf",
             EncoderBackend::WindowsMediaTranscoding => "wmt",
+            EncoderBackend::Raw => "raw",
         };
         write!(f, "{}", string)
     }
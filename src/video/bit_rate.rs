@@ -0,0 +1,42 @@
+use windows::{core::GUID, Graphics::SizeInt32, Win32::Media::MediaFoundation::MFVideoFormat_HEVC};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BitRate {
+    // Picked from the output size and frame rate
+    Auto,
+    // In bits per second
+    Fixed(u32),
+}
+
+impl Default for BitRate {
+    fn default() -> Self {
+        BitRate::Fixed(18000000)
+    }
+}
+
+impl BitRate {
+    pub fn resolve(&self, size: SizeInt32, frame_rate: u32, codec: &GUID) -> u32 {
+        match self {
+            BitRate::Auto => auto_bit_rate(size, frame_rate, codec),
+            BitRate::Fixed(bit_rate) => *bit_rate,
+        }
+    }
+}
+
+// How many bits each pixel of each frame gets. Newer codecs need less for
+// the same quality.
+fn bits_per_pixel(codec: &GUID) -> f64 {
+    if *codec == MFVideoFormat_HEVC {
+        0.06
+    } else {
+        // H264, and anything we don't know better about
+        0.1
+    }
+}
+
+pub fn auto_bit_rate(size: SizeInt32, frame_rate: u32, codec: &GUID) -> u32 {
+    let pixels_per_second = size.Width as f64 * size.Height as f64 * frame_rate as f64;
+    let bit_rate = pixels_per_second * bits_per_pixel(codec);
+    // Keep tiny captures from looking awful
+    bit_rate.clamp(500_000.0, u32::MAX as f64) as u32
+}
@@ -0,0 +1,77 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::{
+    core::GUID,
+    Win32::{
+        Graphics::Dxgi::Common::{DXGI_FORMAT, DXGI_FORMAT_AYUV, DXGI_FORMAT_NV12},
+        Media::MediaFoundation::{MFVideoFormat_AYUV, MFVideoFormat_NV12},
+    },
+};
+
+// The chroma subsampling of the encoded video. 4:2:0 is what every encoder
+// and player supports, 4:4:4 keeps colored text sharp but needs the H.264
+// High 4:4:4 profile, which few hardware encoders implement.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Chroma {
+    Yuv420,
+    Yuv444,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseChromaError(&'static str);
+
+impl Chroma {
+    pub fn input_subtype(&self) -> GUID {
+        match self {
+            Chroma::Yuv420 => MFVideoFormat_NV12,
+            Chroma::Yuv444 => MFVideoFormat_AYUV,
+        }
+    }
+
+    pub fn dxgi_format(&self) -> DXGI_FORMAT {
+        match self {
+            Chroma::Yuv420 => DXGI_FORMAT_NV12,
+            Chroma::Yuv444 => DXGI_FORMAT_AYUV,
+        }
+    }
+
+    // The H.264 profile (eAVEncH264VProfile) needed, if the encoder's
+    // default won't do.
+    pub fn h264_profile(&self) -> Option<u32> {
+        match self {
+            Chroma::Yuv420 => None,
+            Chroma::Yuv444 => Some(244),
+        }
+    }
+}
+
+impl FromStr for Chroma {
+    type Err = ParseChromaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "420" => Ok(Chroma::Yuv420),
+            "444" => Ok(Chroma::Yuv444),
+            _ => Err(ParseChromaError(
+                "Invalid chroma value! Expecting: 420, or 444.",
+            )),
+        }
+    }
+}
+
+impl Display for Chroma {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Chroma::Yuv420 => "420",
+            Chroma::Yuv444 => "444",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseChromaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseChromaError {}
@@ -0,0 +1,80 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use windows::{
+    core::GUID,
+    Win32::Media::MediaFoundation::{
+        MFTranscodeContainerType_FMPEG4, MFTranscodeContainerType_MPEG4,
+    },
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Container {
+    Mp4,
+    // Never seeks back, so it can go to streams that can't seek (e.g. pipes)
+    // and still plays back if the recording is cut short.
+    FragmentedMp4,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseContainerError(&'static str);
+
+impl Container {
+    // The container implied by a file's extension, if we write that kind of
+    // file. Fragmented MP4s share the extension, so it's never inferred.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?;
+        if extension.eq_ignore_ascii_case("mp4") {
+            Some(Container::Mp4)
+        } else {
+            None
+        }
+    }
+
+    pub fn container_type(&self) -> GUID {
+        match self {
+            Container::Mp4 => MFTranscodeContainerType_MPEG4,
+            Container::FragmentedMp4 => MFTranscodeContainerType_FMPEG4,
+        }
+    }
+
+    pub fn is_streamable(&self) -> bool {
+        match self {
+            Container::Mp4 => false,
+            Container::FragmentedMp4 => true,
+        }
+    }
+}
+
+impl FromStr for Container {
+    type Err = ParseContainerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(Container::Mp4),
+            "fmp4" => Ok(Container::FragmentedMp4),
+            "mkv" => Err(ParseContainerError(
+                "MKV isn't supported, Media Foundation has no Matroska sink! Expecting: mp4, or fmp4.",
+            )),
+            _ => Err(ParseContainerError(
+                "Invalid container value! Expecting: mp4, or fmp4.",
+            )),
+        }
+    }
+}
+
+impl Display for Container {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Container::Mp4 => "mp4",
+            Container::FragmentedMp4 => "fmp4",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseContainerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseContainerError {}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use windows::{
+    core::Result,
+    Graphics::SizeInt32,
+    Win32::Graphics::{
+        Direct3D11::{
+            ID3D11Device, ID3D11DeviceContext, ID3D11ShaderResourceView, ID3D11Texture2D,
+            D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE,
+            D3D11_RESOURCE_MISC_GENERATE_MIPS, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+        },
+        Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC},
+    },
+};
+
+use crate::d3d::StagingReadback;
+
+// The largest dimension of the thumbnail we compare frames with.
+const FINGERPRINT_SIZE: i32 = 64;
+
+// Detects (near) identical consecutive frames by letting the GPU reduce each
+// frame down to a tiny thumbnail with GenerateMips, and only reading that
+// thumbnail back to compare against the last frame that was encoded. Changes
+// smaller than a thumbnail pixel get averaged away, so any threshold above
+// 0.0 is approximate. A threshold of 0.0 compares whole frames instead.
+pub struct DuplicateFrameDetector {
+    d3d_context: ID3D11DeviceContext,
+
+    mip_texture: ID3D11Texture2D,
+    mip_view: ID3D11ShaderResourceView,
+    mip_level: u32,
+    staging: StagingReadback,
+
+    threshold: f32,
+    max_duration: Duration,
+
+    current: Vec<u8>,
+    reference: Option<Vec<u8>>,
+}
+
+impl DuplicateFrameDetector {
+    pub fn new(
+        d3d_device: &ID3D11Device,
+        size: SizeInt32,
+        threshold: f32,
+        max_duration: Duration,
+    ) -> Result<Self> {
+        let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+
+        let mut mip_level = 0;
+        while threshold > 0.0
+            && ((size.Width >> mip_level) > FINGERPRINT_SIZE
+                || (size.Height >> mip_level) > FINGERPRINT_SIZE)
+        {
+            mip_level += 1;
+        }
+        let fingerprint_size = SizeInt32 {
+            Width: (size.Width >> mip_level).max(1),
+            Height: (size.Height >> mip_level).max(1),
+        };
+
+        let texture_desc = D3D11_TEXTURE2D_DESC {
+            Width: size.Width as u32,
+            Height: size.Height as u32,
+            ArraySize: 1,
+            MipLevels: 0,
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                ..Default::default()
+            },
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_SHADER_RESOURCE.0) as u32,
+            MiscFlags: D3D11_RESOURCE_MISC_GENERATE_MIPS.0 as u32,
+            ..Default::default()
+        };
+        let mip_texture = unsafe {
+            let mut texture = None;
+            d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+            texture.unwrap()
+        };
+        let mip_view = unsafe {
+            let mut view = None;
+            d3d_device.CreateShaderResourceView(&mip_texture, None, Some(&mut view))?;
+            view.unwrap()
+        };
+
+        let staging = StagingReadback::new(d3d_device, fingerprint_size, 1)?;
+
+        Ok(Self {
+            d3d_context,
+
+            mip_texture,
+            mip_view,
+            mip_level,
+            staging,
+
+            threshold,
+            max_duration,
+
+            current: Vec::new(),
+            reference: None,
+        })
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        self.max_duration
+    }
+
+    // Returns true if the texture matches the last committed frame within
+    // the threshold. The texture must match the size we were created with.
+    pub fn is_duplicate(&mut self, texture: &ID3D11Texture2D) -> Result<bool> {
+        self.current = self.fingerprint(texture)?;
+        let is_duplicate = if let Some(reference) = self.reference.as_ref() {
+            difference(reference, &self.current) <= self.threshold
+        } else {
+            false
+        };
+        Ok(is_duplicate)
+    }
+
+    // Marks the last checked frame as the one that was encoded.
+    pub fn commit(&mut self) {
+        self.reference = Some(std::mem::take(&mut self.current));
+    }
+
+    fn fingerprint(&mut self, texture: &ID3D11Texture2D) -> Result<Vec<u8>> {
+        let size = self.staging.size();
+        let row_size = size.Width as usize * 4;
+        let mut fingerprint = vec![0u8; row_size * size.Height as usize];
+        unsafe {
+            self.d3d_context
+                .CopySubresourceRegion(&self.mip_texture, 0, 0, 0, 0, texture, 0, None);
+            self.d3d_context.GenerateMips(&self.mip_view);
+        }
+        self.staging
+            .copy_subresource(&self.mip_texture, self.mip_level, None)?;
+        // Only ever one copy in flight
+        let mapped = self.staging.read()?.unwrap();
+        for (y, row) in fingerprint.chunks_exact_mut(row_size).enumerate() {
+            row.copy_from_slice(mapped.row(y));
+        }
+        drop(mapped);
+        Ok(fingerprint)
+    }
+}
+
+// The mean absolute difference between two fingerprints, from 0.0 (identical)
+// to 1.0 (every channel is as different as it can be).
+fn difference(a: &[u8], b: &[u8]) -> f32 {
+    let total: u64 = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs() as u64)
+        .sum();
+    total as f32 / (a.len().max(1) as f32 * 255.0)
+}
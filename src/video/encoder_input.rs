@@ -0,0 +1,114 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::{
+    core::GUID,
+    Win32::{
+        Graphics::Dxgi::Common::{
+            DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_B8G8R8A8_UNORM_SRGB,
+            DXGI_FORMAT_NV12, DXGI_FORMAT_P010,
+        },
+        Media::MediaFoundation::{MFVideoFormat_ARGB32, MFVideoFormat_NV12, MFVideoFormat_P010},
+    },
+};
+
+// The format 4:2:0 frames are handed to the encoder in. NV12 is what every
+// encoder takes, P010 keeps 10 bits for encoders that can make use of them,
+// and ARGB leaves the conversion to YUV up to the encoder.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EncoderInput {
+    Nv12,
+    P010,
+    Argb,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseEncoderInputError(&'static str);
+
+impl EncoderInput {
+    // The formats to try, best first, for frames captured in this format.
+    pub fn preferred_for_format(capture_format: DXGI_FORMAT) -> &'static [EncoderInput] {
+        if capture_format == DXGI_FORMAT_B8G8R8A8_UNORM
+            || capture_format == DXGI_FORMAT_B8G8R8A8_UNORM_SRGB
+        {
+            &[EncoderInput::Nv12, EncoderInput::Argb]
+        } else {
+            &[EncoderInput::P010, EncoderInput::Nv12]
+        }
+    }
+
+    pub fn input_subtype(&self) -> GUID {
+        match self {
+            EncoderInput::Nv12 => MFVideoFormat_NV12,
+            EncoderInput::P010 => MFVideoFormat_P010,
+            EncoderInput::Argb => MFVideoFormat_ARGB32,
+        }
+    }
+
+    pub fn dxgi_format(&self) -> DXGI_FORMAT {
+        match self {
+            EncoderInput::Nv12 => DXGI_FORMAT_NV12,
+            EncoderInput::P010 => DXGI_FORMAT_P010,
+            EncoderInput::Argb => DXGI_FORMAT_B8G8R8A8_UNORM,
+        }
+    }
+}
+
+impl FromStr for EncoderInput {
+    type Err = ParseEncoderInputError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nv12" => Ok(EncoderInput::Nv12),
+            "p010" => Ok(EncoderInput::P010),
+            "argb" => Ok(EncoderInput::Argb),
+            _ => Err(ParseEncoderInputError(
+                "Invalid encoder input value! Expecting: nv12, p010, or argb.",
+            )),
+        }
+    }
+}
+
+impl Display for EncoderInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            EncoderInput::Nv12 => "nv12",
+            EncoderInput::P010 => "p010",
+            EncoderInput::Argb => "argb",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseEncoderInputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseEncoderInputError {}
+
+#[cfg(test)]
+mod tests {
+    use super::EncoderInput;
+
+    #[test]
+    fn encoder_input_parsing_test() {
+        assert_eq!("nv12".parse(), Ok(EncoderInput::Nv12));
+        assert_eq!("NV12".parse(), Ok(EncoderInput::Nv12));
+        assert_eq!("p010".parse(), Ok(EncoderInput::P010));
+        assert_eq!("P010".parse(), Ok(EncoderInput::P010));
+        assert_eq!("argb".parse(), Ok(EncoderInput::Argb));
+        assert_eq!("Argb".parse(), Ok(EncoderInput::Argb));
+
+        assert!("".parse::<EncoderInput>().is_err());
+        assert!("yuy2".parse::<EncoderInput>().is_err());
+        assert!(" nv12".parse::<EncoderInput>().is_err());
+    }
+
+    #[test]
+    fn encoder_input_round_trip_test() {
+        for input in [EncoderInput::Nv12, EncoderInput::P010, EncoderInput::Argb] {
+            assert_eq!(input.to_string().parse(), Ok(input));
+        }
+        assert_eq!(EncoderInput::P010.to_string(), "p010");
+    }
+}
@@ -1,24 +1,393 @@
+use std::{path::PathBuf, time::Duration};
+
 use windows::{
-    core::Result,
-    Graphics::{Capture::GraphicsCaptureItem, SizeInt32},
-    Storage::Streams::IRandomAccessStream,
-    Win32::Graphics::Direct3D11::ID3D11Device,
+    core::{Error, Result},
+    Foundation::TimeSpan,
+    Graphics::{
+        Capture::GraphicsCaptureItem, DirectX::Direct3D11::IDirect3DSurface, RectInt32, SizeInt32,
+    },
+    Win32::{
+        Foundation::{E_INVALIDARG, E_NOTIMPL},
+        Graphics::{Direct3D11::ID3D11Device, Gdi::HMONITOR},
+    },
+};
+
+use crate::{
+    cancellation::CancellationToken, capture::FramePoolMode, capture_bit_depth::CaptureBitDepth,
+    desktop::Desktop, pause::PauseState,
+};
+
+use super::{
+    audio_codec::AudioCodec,
+    bit_rate::BitRate,
+    chroma::Chroma,
+    container::Container,
+    encoder_input::EncoderInput,
+    fit::Fit,
+    hud::LiveStats,
+    input_color_space::InputColorSpace,
+    ladder::Rendition,
+    output_stream::OutputStream,
+    pixel_aspect_ratio::PixelAspectRatio,
+    preset::Preset,
+    progress::{ElapsedTime, FirstFrameCallback, ProgressCallback},
+    raw_format::RawFormat,
+    scaler::Scaler,
+    segments::SegmentSettings,
+    timestamp_base::TimestampBase,
+    watermark::WatermarkSettings,
 };
 
+#[derive(Clone, Debug)]
+pub struct VideoEncodingSettings {
+    pub borderless: bool,
+    // None records at the size of the capture item (or the crop).
+    pub resolution: Option<SizeInt32>,
+    // How the content is fit into the resolution when their aspect ratios
+    // differ, see Fit.
+    pub fit: Fit,
+    // Only records this part of the capture item, in its coordinates. Has to
+    // fit inside the item.
+    pub crop: Option<RectInt32>,
+    pub bit_rate: BitRate,
+    // The output frame rate, signaled to the encoder.
+    pub frame_rate: u32,
+    // When set, frames are taken from the capture at this rate and then
+    // decimated down to frame_rate before encoding. When None, every
+    // captured frame is encoded.
+    pub capture_frame_rate: Option<u32>,
+    pub pixel_aspect_ratio: PixelAspectRatio,
+    pub scaler: Scaler,
+    pub timestamp_base: TimestampBase,
+    // Multiplies the time between frames in the output, below 1 for a
+    // timelapse and above 1 for slow motion. Capturing and pacing still
+    // happen in real time, see playback_frame_rate. Only supported by the mf
+    // backend.
+    pub speed: f64,
+    pub variable_frame_rate: bool,
+    pub dedup_threshold: Option<f32>,
+    pub dedup_max_duration: Duration,
+    // Stops the recording on its own once this much time has been captured.
+    pub duration: Option<Duration>,
+    // Stops the recording after exactly this many frames have been encoded.
+    pub max_frames: Option<u64>,
+    // Gives up on a capture that hasn't delivered its first frame after this
+    // long. Later gaps are only warned about, since the screen may simply
+    // not be changing. Only supported by the mf backend.
+    pub frame_timeout: Option<Duration>,
+    // Boosts the encoder thread's priority to reduce dropped frames.
+    pub high_priority: bool,
+    // Fails encoder setup if the encoder didn't accept the requested bit
+    // rate, profile, or frame rate as is. Otherwise differences are logged.
+    pub strict: bool,
+    // The muxer used by the mf backend, regardless of the output's name.
+    pub container: Container,
+    // Adds an AAC track of silence, for tools that reject video-only files.
+    pub silent_audio: bool,
+    // The codec used for the audio track.
+    pub audio_codec: AudioCodec,
+    // Records the audio this process (and its children) plays instead of
+    // silence. Needs process loopback support, see process_audio.
+    pub audio_process: Option<u32>,
+    // Forces a keyframe at every multiple of this period on the output
+    // timeline, on top of whatever keyframes the encoder places itself.
+    pub keyframe_period: Option<Duration>,
+    // Rolls the output over to a new file at each interval, see
+    // SegmentSettings. Only supported by the mf backend.
+    pub segments: Option<SegmentSettings>,
+    // Drawn over every frame before it's scaled (see WatermarkSettings).
+    // Only supported by the mf backend.
+    pub watermark: Option<WatermarkSettings>,
+    // Switches the capture to whichever window has focus as it changes. The
+    // configured source is only used until the first switch.
+    pub follow_foreground: bool,
+    // Tunes capture and encoding for live use: a single capture buffer, only
+    // the newest frame handed to the encoder, the encoder's low latency
+    // mode, and no B-frames. The target itself is only compared against the
+    // nominal latency (see video::latency).
+    pub target_latency: Option<Duration>,
+    // Falls back to 4:2:0 if the encoder doesn't support it.
+    pub chroma: Chroma,
+    // The format 4:2:0 frames are handed to the encoder in. None picks the
+    // best one the encoder takes for the capture format. Renditions always
+    // use NV12. Only supported by the mf backend.
+    pub encoder_input: Option<EncoderInput>,
+    // None leaves it up to the encoder, which is also what happens if it
+    // doesn't support presets.
+    pub preset: Option<Preset>,
+    // Caps the threads a software encoder uses, None lets it use one per
+    // core. Hardware encoders ignore it.
+    pub encoder_threads: Option<u32>,
+    // The pixel format written by the raw backend.
+    pub raw_format: RawFormat,
+    pub progress_callback: Option<ProgressCallback>,
+    pub first_frame_callback: Option<FirstFrameCallback>,
+    pub cancellation_token: CancellationToken,
+    // Pausing is only supported by the mf backend, the others keep
+    // recording.
+    pub pause_state: PauseState,
+    // Pauses while the secure desktop (e.g. a UAC prompt) is showing, which
+    // the capture can't see. Otherwise the recording jumps over that time.
+    // Only supported by the mf backend.
+    pub pause_on_secure_desktop: bool,
+    // Writes the output on a thread of its own, behind a queue of this many
+    // samples, so that disk stalls don't hold up encoding. Only supported by
+    // the mf backend.
+    pub write_queue_length: Option<usize>,
+    // Has the sink writer block while the output is behind, instead of
+    // buffering samples without limit. Only supported by the mf backend.
+    pub sink_writer_throttling: bool,
+    // Which thread the capture's FrameArrived handler runs on, see
+    // FramePoolMode. Frames are consumed on our own threads either way.
+    pub frame_pool_mode: FramePoolMode,
+    // Logs the frame type, size and QP of every encoded frame of the main
+    // output to this CSV file. Only supported by the mf backend.
+    pub stats_path: Option<PathBuf>,
+    // Collects the frame and bit rates the HUD shows while recording. Only
+    // supported by the mf backend.
+    pub live_stats: Option<LiveStats>,
+    // Draws the HUD into the recording itself. Needs live_stats.
+    pub hud_burn_in: bool,
+    // Takes a frame once per refresh of this display (or every few, to get
+    // close to the frame rate) with evenly spaced timestamps, repeating the
+    // last one when nothing changed, for constant frame rate output without
+    // jitter. Replaces the capture frame rate pacing. Only supported by the
+    // mf backend.
+    pub vsync_pace: Option<HMONITOR>,
+    // Takes a single frame from the capture every this long, and encodes
+    // them one frame apart at frame_rate. Replaces the capture frame rate
+    // pacing. Only supported by the mf backend.
+    pub timelapse_interval: Option<Duration>,
+    // Captures this display with DXGI desktop duplication instead of
+    // capturing an item with Windows.Graphics.Capture, the session is created
+    // without one. The output size is based on the display's desktop. Only
+    // supported by the mf backend.
+    pub desktop_duplication: Option<HMONITOR>,
+    // The desktop the duplication thread attaches to, see Desktop. The
+    // thread creating the session has to be attached to it as well.
+    pub desktop: Desktop,
+    // The precision of the captured frames. Anything above 8 bits is encoded
+    // as 8 bit unless the encoder takes P010, values outside of SDR's range
+    // are clipped either way. Only supported by the mf backend with
+    // Windows.Graphics.Capture and the auto scaler.
+    pub capture_bit_depth: CaptureBitDepth,
+    // The transfer function the video processor assumes the frames are in.
+    // None goes by the capture format, linear for 16 bit and sRGB otherwise.
+    // Only supported by the mf backend with the auto scaler.
+    pub input_color_space: Option<InputColorSpace>,
+}
+
+impl Default for VideoEncodingSettings {
+    fn default() -> Self {
+        Self {
+            borderless: false,
+            resolution: None,
+            fit: Fit::Letterbox,
+            crop: None,
+            bit_rate: BitRate::default(),
+            frame_rate: 60,
+            capture_frame_rate: None,
+            pixel_aspect_ratio: PixelAspectRatio::SQUARE,
+            scaler: Scaler::Auto,
+            timestamp_base: TimestampBase::Zero,
+            speed: 1.0,
+            variable_frame_rate: false,
+            dedup_threshold: None,
+            dedup_max_duration: Duration::from_secs(1),
+            duration: None,
+            max_frames: None,
+            frame_timeout: None,
+            high_priority: false,
+            strict: false,
+            container: Container::Mp4,
+            silent_audio: false,
+            audio_codec: AudioCodec::Aac,
+            audio_process: None,
+            keyframe_period: None,
+            segments: None,
+            watermark: None,
+            follow_foreground: false,
+            target_latency: None,
+            chroma: Chroma::Yuv420,
+            encoder_input: None,
+            preset: None,
+            encoder_threads: None,
+            raw_format: RawFormat::Bgra,
+            progress_callback: None,
+            first_frame_callback: None,
+            cancellation_token: CancellationToken::new(),
+            pause_state: PauseState::new(),
+            pause_on_secure_desktop: false,
+            write_queue_length: None,
+            sink_writer_throttling: true,
+            frame_pool_mode: FramePoolMode::FreeThreaded,
+            stats_path: None,
+            live_stats: None,
+            hud_burn_in: false,
+            vsync_pace: None,
+            timelapse_interval: None,
+            desktop_duplication: None,
+            desktop: Desktop::Default,
+            capture_bit_depth: CaptureBitDepth::Eight,
+            input_color_space: None,
+        }
+    }
+}
+
+impl VideoEncodingSettings {
+    // The frame rate the output plays back at, which is what gets signaled
+    // to the encoder: frame_rate frames of real time end up spread over
+    // 1/speed seconds.
+    pub fn playback_frame_rate(&self) -> u32 {
+        ((self.frame_rate as f64 / self.speed).round() as u32).max(1)
+    }
+
+    // How much output time each second of recording turns into: the speed,
+    // times one output frame per interval for a timelapse.
+    pub fn playback_rate(&self) -> f64 {
+        match self.timelapse_interval {
+            Some(interval) => self.speed / (interval.as_secs_f64() * self.frame_rate as f64),
+            None => self.speed,
+        }
+    }
+
+    // The size to encode content of the given size at, before rounding.
+    pub fn output_size(&self, content_size: SizeInt32) -> SizeInt32 {
+        self.resolution.map_or(content_size, |resolution| {
+            self.fit.output_size(resolution, content_size)
+        })
+    }
+}
+
+// Sessions are only created without an item when they capture with desktop
+// duplication, everything else needs one.
+pub(crate) fn require_capture_item(
+    item: Option<GraphicsCaptureItem>,
+) -> Result<GraphicsCaptureItem> {
+    item.ok_or_else(|| {
+        Error::new(
+            E_INVALIDARG,
+            "Only desktop duplication can record without a capture item!".into(),
+        )
+    })
+}
+
 pub trait VideoEncoderSessionFactory {
+    // The item is None when the settings capture with desktop duplication.
     fn create_session(
         &self,
         d3d_device: ID3D11Device,
-        item: GraphicsCaptureItem,
-        borderless: bool,
-        resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
-        stream: IRandomAccessStream,
+        item: Option<GraphicsCaptureItem>,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
     ) -> Result<Box<dyn VideoEncodingSession>>;
+
+    // Also records each rendition to its own stream, from the same capture.
+    // The main output still follows the settings.
+    fn create_ladder_session(
+        &self,
+        _d3d_device: ID3D11Device,
+        _item: Option<GraphicsCaptureItem>,
+        _settings: &VideoEncodingSettings,
+        _stream: OutputStream,
+        _renditions: Vec<(Rendition, OutputStream)>,
+    ) -> Result<Box<dyn VideoEncodingSession>> {
+        Err(Error::new(
+            E_NOTIMPL,
+            "Recording several renditions is only supported by the mf backend!".into(),
+        ))
+    }
+
+    // Encodes frames handed to the session's submit_frame instead of
+    // capturing them. The frames have to be BGRA8 and input_size, and are
+    // scaled to the settings' resolution.
+    fn create_feed_session(
+        &self,
+        _d3d_device: ID3D11Device,
+        _input_size: SizeInt32,
+        _settings: &VideoEncodingSettings,
+        _stream: OutputStream,
+    ) -> Result<Box<dyn VideoEncodingSession>> {
+        Err(Error::new(
+            E_NOTIMPL,
+            "Encoding submitted frames is only supported by the mf backend!".into(),
+        ))
+    }
+}
+
+// How a stop with a timeout went.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopOutcome {
+    // Everything captured made it into the output.
+    Drained,
+    // The encoder didn't finish in time, so the output was finalized
+    // without the frames it was still holding on to.
+    PendingFramesDropped(u64),
 }
 
 pub trait VideoEncodingSession {
     fn start(&mut self) -> Result<()>;
     fn stop(&mut self) -> Result<()>;
+
+    // Like stop, but won't wait on the encoder for longer than the timeout.
+    // Backends that can't give up on their encoder just stop.
+    fn stop_with_timeout(&mut self, _timeout: Duration) -> Result<StopOutcome> {
+        self.stop()?;
+        Ok(StopOutcome::Drained)
+    }
+
+    // How much has been recorded so far, measured using frame times. Can be
+    // called while the recording is running.
+    fn elapsed(&self) -> Duration {
+        self.elapsed_time().get()
+    }
+
+    // The same as elapsed, as a handle that can be read from other threads.
+    fn elapsed_time(&self) -> ElapsedTime;
+
+    // How many frames the encoder has produced so far, for backends that
+    // drive the encoder themselves.
+    fn frames_encoded(&self) -> Option<u64> {
+        None
+    }
+
+    // Whether the session follows the settings' pause state.
+    fn supports_pause(&self) -> bool {
+        false
+    }
+
+    // Saves the next captured frame to a PNG without interrupting the
+    // recording. The file is written in the background.
+    fn snapshot(&mut self, _path: PathBuf) -> Result<()> {
+        Err(Error::new(
+            E_NOTIMPL,
+            "Snapshots are only supported by the mf backend.".into(),
+        ))
+    }
+
+    // Switches the capture over to another item without interrupting the
+    // recording. Items of a different size are scaled to fit the output,
+    // the same as when the captured item changes size. The display is where
+    // the item is shown, which vsync pacing moves over to.
+    fn retarget(
+        &mut self,
+        _item: GraphicsCaptureItem,
+        _display_handle: Option<HMONITOR>,
+    ) -> Result<()> {
+        Err(Error::new(
+            E_NOTIMPL,
+            "Switching the capture target is only supported by the mf backend.".into(),
+        ))
+    }
+
+    // Hands the session a frame to encode, for sessions made with
+    // create_feed_session. Timestamps are in 100ns units and have to
+    // increase, the output starts at the first one. Blocks while the encoder
+    // is behind.
+    fn submit_frame(&mut self, _surface: &IDirect3DSurface, _timestamp: TimeSpan) -> Result<()> {
+        Err(Error::new(
+            E_NOTIMPL,
+            "Only feed sessions accept submitted frames.".into(),
+        ))
+    }
 }
@@ -0,0 +1,88 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::Graphics::SizeInt32;
+
+// How the capture is fit into a preset resolution whose aspect ratio
+// doesn't match, e.g. 1080p on a 3440x1440 ultrawide.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fit {
+    // Keeps the preset's size and pads the content with black bars.
+    Letterbox,
+    // Keeps the preset's height and the content's aspect ratio, so 1080p on
+    // an ultrawide becomes 2580x1080.
+    Height,
+    // Keeps the preset's width and the content's aspect ratio.
+    Width,
+    // Keeps the preset's size and distorts the content to fill it.
+    Stretch,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseFitError(&'static str);
+
+impl Fit {
+    // The size to encode at when recording content of the given size at a
+    // preset resolution. Encoders want even sizes, callers still round.
+    pub fn output_size(&self, resolution: SizeInt32, content_size: SizeInt32) -> SizeInt32 {
+        if content_size.Width <= 0 || content_size.Height <= 0 {
+            return resolution;
+        }
+        match self {
+            Fit::Letterbox | Fit::Stretch => resolution,
+            Fit::Height => SizeInt32 {
+                Width: scale(resolution.Height, content_size.Width, content_size.Height),
+                Height: resolution.Height,
+            },
+            Fit::Width => SizeInt32 {
+                Width: resolution.Width,
+                Height: scale(resolution.Width, content_size.Height, content_size.Width),
+            },
+        }
+    }
+
+    pub fn preserves_aspect_ratio(&self) -> bool {
+        *self != Fit::Stretch
+    }
+}
+
+// value * numerator / denominator, rounded to the nearest even number
+fn scale(value: i32, numerator: i32, denominator: i32) -> i32 {
+    let scaled = value as i64 * numerator as i64;
+    let rounded = (scaled + denominator as i64) / (denominator as i64 * 2) * 2;
+    rounded.max(2) as i32
+}
+
+impl FromStr for Fit {
+    type Err = ParseFitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "letterbox" => Ok(Fit::Letterbox),
+            "height" => Ok(Fit::Height),
+            "width" => Ok(Fit::Width),
+            "stretch" => Ok(Fit::Stretch),
+            _ => Err(ParseFitError(
+                "Invalid fit value! Expecting: letterbox, height, width, or stretch.",
+            )),
+        }
+    }
+}
+
+impl Display for Fit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Fit::Letterbox => "letterbox",
+            Fit::Height => "height",
+            Fit::Width => "width",
+            Fit::Stretch => "stretch",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseFitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseFitError {}
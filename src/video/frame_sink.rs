@@ -0,0 +1,789 @@
+use std::{
+    collections::VecDeque,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use windows::{
+    core::{Error, Result, HSTRING},
+    Foundation::TimeSpan,
+    Graphics::{
+        Capture::{GraphicsCaptureItem, GraphicsCaptureSession},
+        DirectX::Direct3D11::IDirect3DSurface,
+        SizeInt32,
+    },
+    Win32::{
+        Foundation::E_UNEXPECTED,
+        Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D, D3D11_BOX},
+    },
+};
+
+use crate::{
+    cancellation::{CancellationToken, StopReason},
+    capture::{
+        get_capture_item_size, CaptureFrame, CaptureFrameGenerator,
+        CaptureFrameGeneratorStopSignal, DEFAULT_BUFFER_COUNT,
+    },
+    d3d::{get_d3d_interface_from_object, StagingReadback},
+    pause::PauseState,
+};
+
+use super::{
+    encoding_session::{VideoEncodingSession, VideoEncodingSettings},
+    hud::LiveStats,
+    pacing::{TimelapseClock, VsyncClock, VsyncRetargeter},
+    progress::{DurationTracker, ElapsedTime, FirstFrameCallback},
+    timestamp_base::TimestampBase,
+};
+
+// Something that consumes captured frames as they arrive. Implementing this is
+// all it takes to add a new kind of output, CaptureLoop takes care of
+// driving the capture. The mf backend's encoder is one (see its
+// SampleGenerator), the readback sinks (PNG sequences, raw frames, shared
+// memory) run under FrameSinkSession.
+pub trait FrameSink: Send {
+    // The size is the part of the surface that has content. It can be
+    // smaller than the surface, or larger right after the capture grows
+    // until the frame pool catches up (see surface_content_size). Returns
+    // false if the sink skipped the frame, e.g. because it was a duplicate,
+    // which doesn't count towards the frame limit.
+    fn write_frame(
+        &mut self,
+        surface: &IDirect3DSurface,
+        size: SizeInt32,
+        timestamp: TimeSpan,
+    ) -> Result<bool>;
+
+    // Paced captures (vsync, timelapse) repeat the last frame at the given
+    // time when nothing new arrived in time. Only called once a frame has
+    // been written.
+    fn repeat_frame(&mut self, _timestamp: TimeSpan) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Called right before the first frame, and the first one after every
+    // pause, with that frame's timestamp and its system relative time. Sinks
+    // that record something on the capture's clock (the mf backend's audio)
+    // line it up with the frames from these.
+    fn start_span(&mut self, _timestamp: TimeSpan, _system_time: TimeSpan) {}
+
+    // The size the sink wants frames captured at, for sinks that follow the
+    // capture when its content changes size. The frame pool is recreated
+    // whenever this changes.
+    fn capture_size(&self) -> Option<SizeInt32> {
+        None
+    }
+
+    // Called once after the last frame, whether the capture ended on its
+    // own, was stopped or failed.
+    fn finalize(&mut self) -> Result<()>;
+}
+
+// The part of a frame's surface that has content, or None if there's nothing
+// to show (e.g. a minimized window).
+pub fn surface_content_size(
+    surface: &IDirect3DSurface,
+    content_size: SizeInt32,
+) -> Result<Option<SizeInt32>> {
+    let description = surface.Description()?;
+    let size = SizeInt32 {
+        Width: content_size.Width.clamp(0, description.Width),
+        Height: content_size.Height.clamp(0, description.Height),
+    };
+    Ok((size.Width > 0 && size.Height > 0).then_some(size))
+}
+
+// Pulls frames from the capture and pushes them into a sink, taking care of
+// everything that doesn't depend on what the frames become: pausing, the
+// duration and frame limits, pacing to vsync or a timelapse, and noticing
+// that the capture went quiet or that its source closed. FrameSinkSession
+// runs it on a thread of its own, the mf backend steps it whenever its
+// encoder asks for another frame.
+pub struct CaptureLoop<S: FrameSink> {
+    frame_generator: CaptureFrameGenerator,
+    capture_size: SizeInt32,
+    sink: S,
+    finished: bool,
+
+    cancellation_token: CancellationToken,
+    pause_state: PauseState,
+    // How long the recording had been paused before the first frame, which
+    // the timeline doesn't need to make up for.
+    paused_before_first_frame: Option<i64>,
+    // How long the recording had been paused when the sink was last told
+    // about a span, see FrameSink::start_span.
+    span_paused_duration: Option<i64>,
+    duration_tracker: DurationTracker,
+    max_frames: Option<u64>,
+    frame_timeout: Option<Duration>,
+    // When tuned for latency, frames that queued up behind a newer one are
+    // dropped, see LOW_LATENCY_QUEUE_LENGTH.
+    newest_frame_only: bool,
+    received_frame: bool,
+    warned_about_stall: bool,
+    frames_written: u64,
+    live_stats: Option<LiveStats>,
+    // Each of these takes frames on a clock of its own rather than as they
+    // arrive.
+    vsync_clock: Option<VsyncClock>,
+    last_paced_time: Option<TimeSpan>,
+    timelapse_clock: Option<TimelapseClock>,
+    // The time of the first frame of the timelapse, which the others are
+    // stamped relative to.
+    timelapse_start: Option<TimeSpan>,
+    timestamp_base: TimestampBase,
+    first_frame_time: Option<TimeSpan>,
+}
+
+// The frame pool is free threaded, and the loop is only ever used by one
+// thread at a time.
+unsafe impl<S: FrameSink> Send for CaptureLoop<S> {}
+
+impl<S: FrameSink> CaptureLoop<S> {
+    // The capture size is what the frame generator was created with.
+    pub fn new(
+        frame_generator: CaptureFrameGenerator,
+        capture_size: SizeInt32,
+        sink: S,
+        settings: &VideoEncodingSettings,
+    ) -> Result<Self> {
+        let vsync_clock = settings
+            .vsync_pace
+            .map(|display_handle| VsyncClock::new(display_handle, settings.frame_rate))
+            .transpose()?;
+        let timelapse_clock = settings
+            .timelapse_interval
+            .map(|interval| TimelapseClock::new(interval, settings.frame_rate));
+        Ok(Self {
+            frame_generator,
+            capture_size,
+            sink,
+            finished: false,
+
+            cancellation_token: settings.cancellation_token.clone(),
+            pause_state: settings.pause_state.clone(),
+            paused_before_first_frame: None,
+            span_paused_duration: None,
+            duration_tracker: DurationTracker::new(settings),
+            max_frames: settings.max_frames,
+            frame_timeout: settings.frame_timeout,
+            newest_frame_only: settings.target_latency.is_some(),
+            received_frame: false,
+            warned_about_stall: false,
+            frames_written: 0,
+            live_stats: settings.live_stats.clone(),
+            vsync_clock,
+            last_paced_time: None,
+            timelapse_clock,
+            timelapse_start: None,
+            timestamp_base: settings.timestamp_base,
+            first_frame_time: None,
+        })
+    }
+
+    pub fn frame_generator(&self) -> &CaptureFrameGenerator {
+        &self.frame_generator
+    }
+
+    // Only when pacing to vsync.
+    pub fn vsync_retargeter(&self) -> Option<VsyncRetargeter> {
+        self.vsync_clock.as_ref().map(VsyncClock::retargeter)
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn elapsed_time(&self) -> ElapsedTime {
+        self.duration_tracker.elapsed_time()
+    }
+
+    // Runs until the sink takes a frame. Returns false once the capture has
+    // ended instead, by which point the sink has been finalized.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        match self.next() {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                self.finish()?;
+                Ok(false)
+            }
+            Err(error) => {
+                // Whatever made it into the sink is still worth keeping, but
+                // the original error is the one worth reporting.
+                let _ = self.finish();
+                Err(error)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finished = true;
+        let stop_result = self.frame_generator.stop_capture();
+        let finalize_result = self.sink.finalize();
+        stop_result.and(finalize_result)
+    }
+
+    fn next(&mut self) -> Result<bool> {
+        // The last frame has already been handed to the sink, ending here
+        // lets it finish up before we finalize.
+        if let Some(max_frames) = self.max_frames {
+            if self.frames_written >= max_frames {
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::FrameLimitReached);
+                return Ok(false);
+            }
+        }
+        if self.vsync_clock.is_some() {
+            return self.next_vsync_paced();
+        }
+        if self.timelapse_clock.is_some() {
+            return self.next_timelapse();
+        }
+
+        while let Some(frame) = self.next_frame()? {
+            let frame = if self.newest_frame_only {
+                match self.skip_to_newest(frame)? {
+                    Some(frame) => frame,
+                    None => break,
+                }
+            } else {
+                frame
+            };
+            if self.cancellation_token.is_cancelled() {
+                break;
+            }
+            if self.pause_state.is_paused() {
+                frame.close()?;
+                continue;
+            }
+            if let Some(live_stats) = self.live_stats.as_ref() {
+                live_stats.record_captured();
+            }
+            let frame_time = self.unpaused_time(frame.system_relative_time());
+            // Once we've reached the requested duration, end the recording
+            // as if it had been cancelled.
+            if !self.duration_tracker.update(frame_time) {
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::MaxDurationReached);
+                break;
+            }
+
+            let result = self.write_frame(&frame, frame_time);
+            match self.handle_written(result)? {
+                Some(true) => return Ok(true),
+                // The frame was skipped, wait for the next one
+                Some(false) => {}
+                None => return Ok(false),
+            }
+        }
+        self.end_stream()
+    }
+
+    // Takes the newest frame on every tick of the vsync clock, repeating the
+    // last one if nothing new arrived, and stamps it with the tick's time
+    // rather than the frame's, so the output is evenly spaced. Ticks while
+    // paused are skipped along with their time.
+    fn next_vsync_paced(&mut self) -> Result<bool> {
+        loop {
+            let interval = {
+                let vsync_clock = self.vsync_clock.as_mut().unwrap();
+                vsync_clock.wait()?;
+                vsync_clock.interval()
+            };
+            if self.cancellation_token.is_cancelled() {
+                break;
+            }
+            // Only the newest frame matters, the rest go back to the pool
+            let mut latest: Option<CaptureFrame> = None;
+            let mut stopped = false;
+            while let Some(frame) = self
+                .frame_generator
+                .try_get_next_frame_with_timeout(Duration::ZERO)?
+            {
+                if let Some(frame) = frame {
+                    if let Some(older) = latest.replace(frame) {
+                        older.close()?;
+                    }
+                } else {
+                    stopped = true;
+                    break;
+                }
+            }
+            if stopped || self.pause_state.is_paused() {
+                if let Some(frame) = latest {
+                    frame.close()?;
+                }
+                if stopped {
+                    break;
+                }
+                continue;
+            }
+
+            // The clock starts with the first frame
+            let frame_time = match (self.last_paced_time, latest.as_ref()) {
+                (Some(last_paced_time), _) => TimeSpan {
+                    Duration: last_paced_time.Duration + interval,
+                },
+                (None, Some(frame)) => frame.system_relative_time(),
+                (None, None) => continue,
+            };
+            if !self.duration_tracker.update(frame_time) {
+                if let Some(frame) = latest {
+                    frame.close()?;
+                }
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::MaxDurationReached);
+                break;
+            }
+            self.last_paced_time = Some(frame_time);
+
+            let result = if let Some(frame) = latest {
+                self.write_frame(&frame, frame_time)
+            } else if self.frames_written > 0 {
+                let timestamp = self.timestamp(frame_time);
+                self.sink.repeat_frame(timestamp)
+            } else {
+                Ok(false)
+            };
+            match self.handle_written(result)? {
+                Some(true) => return Ok(true),
+                Some(false) => {}
+                None => return Ok(false),
+            }
+        }
+        self.end_stream()
+    }
+
+    // Takes the newest frame once every interval, repeating the last one if
+    // nothing new arrived, and stamps them one output frame apart. Frames in
+    // between are closed as soon as they arrive, so that the capture never
+    // backs up however long the interval is. Ticks while paused are skipped.
+    fn next_timelapse(&mut self) -> Result<bool> {
+        loop {
+            let mut stopped = false;
+            let latest = if let Some(next_tick) = self.timelapse_clock.as_ref().unwrap().next_tick()
+            {
+                let mut latest: Option<CaptureFrame> = None;
+                loop {
+                    let timeout = next_tick.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        break;
+                    }
+                    match self
+                        .frame_generator
+                        .try_get_next_frame_with_timeout(timeout)?
+                    {
+                        Some(Some(frame)) => {
+                            if let Some(older) = latest.replace(frame) {
+                                older.close()?;
+                            }
+                        }
+                        Some(None) => {
+                            stopped = true;
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                latest
+            } else {
+                // The first frame is taken as soon as it arrives
+                let frame = self.next_frame()?;
+                stopped = frame.is_none();
+                frame
+            };
+            if stopped || self.cancellation_token.is_cancelled() {
+                if let Some(frame) = latest {
+                    frame.close()?;
+                }
+                break;
+            }
+            self.timelapse_clock.as_mut().unwrap().tick();
+            if self.pause_state.is_paused() {
+                if let Some(frame) = latest {
+                    frame.close()?;
+                }
+                continue;
+            }
+
+            let start = if let Some(start) = self.timelapse_start {
+                start
+            } else if let Some(frame) = latest.as_ref() {
+                let start = self.unpaused_time(frame.system_relative_time());
+                self.timelapse_start = Some(start);
+                start
+            } else {
+                continue;
+            };
+            let (real_time, output_time) = self.timelapse_clock.as_mut().unwrap().take_frame();
+            // The duration is measured in real time
+            if !self.duration_tracker.update(TimeSpan {
+                Duration: start.Duration + real_time.Duration,
+            }) {
+                if let Some(frame) = latest {
+                    frame.close()?;
+                }
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::MaxDurationReached);
+                break;
+            }
+
+            let frame_time = TimeSpan {
+                Duration: start.Duration + output_time.Duration,
+            };
+            let result = if let Some(frame) = latest {
+                self.write_frame(&frame, frame_time)
+            } else {
+                let timestamp = self.timestamp(frame_time);
+                self.sink.repeat_frame(timestamp)
+            };
+            match self.handle_written(result)? {
+                Some(true) => return Ok(true),
+                Some(false) => {}
+                None => return Ok(false),
+            }
+        }
+        self.end_stream()
+    }
+
+    // Hands the frame to the sink, and gives it back to the pool right
+    // after. If the sink follows the content's size, the pool is resized
+    // to whatever it asks for.
+    fn write_frame(&mut self, frame: &CaptureFrame, frame_time: TimeSpan) -> Result<bool> {
+        let timestamp = self.timestamp(frame_time);
+        let paused_duration = self.pause_state.paused_duration().Duration;
+        if self.span_paused_duration != Some(paused_duration) {
+            self.span_paused_duration = Some(paused_duration);
+            self.sink
+                .start_span(timestamp, frame.system_relative_time());
+        }
+        let result = self
+            .sink
+            .write_frame(frame.surface(), frame.content_size(), timestamp);
+        frame.close()?;
+        let written = result?;
+        if let Some(capture_size) = self.sink.capture_size() {
+            if capture_size != self.capture_size {
+                self.frame_generator.resize(capture_size)?;
+                self.capture_size = capture_size;
+            }
+        }
+        Ok(written)
+    }
+
+    // Counts frames the sink took. None means the sink failed, in which
+    // case the recording ends as if the capture had.
+    fn handle_written(&mut self, result: Result<bool>) -> Result<Option<bool>> {
+        match result {
+            Ok(true) => {
+                self.frames_written += 1;
+                Ok(Some(true))
+            }
+            Ok(false) => Ok(Some(false)),
+            Err(error) => {
+                error!(
+                    "Error while handling a captured frame: {:?} - {}",
+                    error.code(),
+                    error.message()
+                );
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::Error);
+                Ok(None)
+            }
+        }
+    }
+
+    fn end_stream(&mut self) -> Result<bool> {
+        if self.frame_generator.source_closed() {
+            self.cancellation_token
+                .cancel_with_reason(StopReason::SourceClosed);
+        } else if self.frame_generator.failed() {
+            self.cancellation_token
+                .cancel_with_reason(StopReason::Error);
+        }
+        Ok(false)
+    }
+
+    // Waits on the capture, watching for it going quiet when there's a frame
+    // timeout. The capture always delivers a frame right after it starts, so
+    // not getting one means it's broken. After that a gap can't be told
+    // apart from nothing on screen changing, so it's only warned about.
+    fn next_frame(&mut self) -> Result<Option<CaptureFrame>> {
+        let timeout = if let Some(timeout) = self.frame_timeout {
+            timeout
+        } else {
+            return self.frame_generator.try_get_next_frame();
+        };
+        loop {
+            if let Some(frame) = self
+                .frame_generator
+                .try_get_next_frame_with_timeout(timeout)?
+            {
+                if frame.is_some() {
+                    if self.warned_about_stall {
+                        info!("Frames are arriving again.");
+                    }
+                    self.received_frame = true;
+                    self.warned_about_stall = false;
+                }
+                return Ok(frame);
+            }
+            if !self.received_frame {
+                error!(
+                    "No frames received from the capture source after {}s!",
+                    timeout.as_secs_f64()
+                );
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::NoFrames);
+                return Ok(None);
+            }
+            // Nothing arrives while the secure desktop is up
+            if !self.warned_about_stall && !self.pause_state.is_paused() {
+                warn!("No new frames for {}s. This is expected if nothing on screen is changing, but the capture also goes quiet while a UAC prompt or the lock screen is showing...", timeout.as_secs_f64());
+                self.warned_about_stall = true;
+            }
+        }
+    }
+
+    // Closes the frame if newer ones are already waiting, returning the
+    // newest instead. Returns None if the capture stopped meanwhile.
+    fn skip_to_newest(&mut self, frame: CaptureFrame) -> Result<Option<CaptureFrame>> {
+        let mut newest = frame;
+        while let Some(next) = self
+            .frame_generator
+            .try_get_next_frame_with_timeout(Duration::ZERO)?
+        {
+            if let Some(next) = next {
+                std::mem::replace(&mut newest, next).close()?;
+            } else {
+                newest.close()?;
+                return Ok(None);
+            }
+        }
+        Ok(Some(newest))
+    }
+
+    // Takes the time spent paused out of the frame's time, so that the
+    // frames on either side of a pause end up next to each other.
+    fn unpaused_time(&mut self, frame_time: TimeSpan) -> TimeSpan {
+        let paused = self.pause_state.paused_duration().Duration;
+        let paused_before_first_frame = *self.paused_before_first_frame.get_or_insert(paused);
+        TimeSpan {
+            Duration: frame_time.Duration - (paused - paused_before_first_frame),
+        }
+    }
+
+    // Either rebases the timeline so that the first frame is at zero, or
+    // keeps the original QPC based system relative time.
+    fn timestamp(&mut self, frame_time: TimeSpan) -> TimeSpan {
+        let first_frame_time = *self.first_frame_time.get_or_insert(frame_time);
+        match self.timestamp_base {
+            TimestampBase::Zero => TimeSpan {
+                Duration: frame_time.Duration - first_frame_time.Duration,
+            },
+            TimestampBase::Qpc => frame_time,
+        }
+    }
+}
+
+// Captures an item and pushes every frame into a FrameSink on its own thread.
+pub struct FrameSinkSession<S: FrameSink + 'static> {
+    capture_session: GraphicsCaptureSession,
+    stop_signal: CaptureFrameGeneratorStopSignal,
+    cancellation_token: CancellationToken,
+    first_frame_callback: Option<FirstFrameCallback>,
+    capture_loop: Option<CaptureLoop<S>>,
+    capture_thread: Option<JoinHandle<Result<()>>>,
+    elapsed_time: ElapsedTime,
+}
+
+impl<S: FrameSink + 'static> FrameSinkSession<S> {
+    pub fn new(
+        d3d_device: ID3D11Device,
+        item: GraphicsCaptureItem,
+        settings: &VideoEncodingSettings,
+        sink: S,
+    ) -> Result<Self> {
+        let item_size = get_capture_item_size(&item)?;
+        let frame_generator = CaptureFrameGenerator::with_frame_pool_mode(
+            d3d_device,
+            item,
+            item_size,
+            DEFAULT_BUFFER_COUNT,
+            settings.frame_pool_mode,
+        )?;
+        // Only duplication has no session
+        let capture_session = frame_generator.session().unwrap();
+        if settings.borderless {
+            capture_session.SetIsBorderRequired(false)?;
+        }
+        let stop_signal = frame_generator.stop_signal();
+        settings.cancellation_token.on_cancelled({
+            let stop_signal = frame_generator.stop_signal();
+            move || stop_signal.signal()
+        });
+
+        let capture_loop = CaptureLoop::new(frame_generator, item_size, sink, settings)?;
+        let elapsed_time = capture_loop.elapsed_time();
+
+        Ok(Self {
+            capture_session,
+            stop_signal,
+            cancellation_token: settings.cancellation_token.clone(),
+            first_frame_callback: settings.first_frame_callback.clone(),
+            capture_loop: Some(capture_loop),
+            capture_thread: None,
+            elapsed_time,
+        })
+    }
+}
+
+impl<S: FrameSink + 'static> VideoEncodingSession for FrameSinkSession<S> {
+    fn start(&mut self) -> Result<()> {
+        if let Some(mut capture_loop) = self.capture_loop.take() {
+            self.capture_session.StartCapture()?;
+            let cancellation_token = self.cancellation_token.clone();
+            let mut first_frame_callback = self.first_frame_callback.take();
+            let result = std::thread::Builder::new()
+                .name("Capture Thread".to_owned())
+                .spawn(move || -> Result<()> {
+                    let result = (|| -> Result<()> {
+                        while capture_loop.step()? {
+                            if let Some(first_frame_callback) = first_frame_callback.take() {
+                                first_frame_callback.notify();
+                            }
+                        }
+                        Ok(())
+                    })();
+                    if result.is_err() {
+                        error!("Recording stopped unexpectedly!");
+                        cancellation_token.cancel_with_reason(StopReason::Error);
+                    }
+                    result
+                });
+            match result {
+                Ok(handle) => self.capture_thread = Some(handle),
+                Err(_) => {
+                    return Err(Error::new(
+                        E_UNEXPECTED,
+                        HSTRING::from("Unable to create the capture thread!"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(capture_thread) = self.capture_thread.take() {
+            self.stop_signal.signal();
+            match capture_thread.join() {
+                Ok(result) => result,
+                Err(_) => Err(Error::new(
+                    E_UNEXPECTED,
+                    HSTRING::from("Capture thread failed unexpectedly!"),
+                )),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    fn elapsed_time(&self) -> ElapsedTime {
+        self.elapsed_time.clone()
+    }
+
+    fn supports_pause(&self) -> bool {
+        true
+    }
+}
+
+// How many frames a pipelined FrameReadback keeps in flight. With two the CPU
+// reads the previous frame while the GPU copies the current one.
+pub const READBACK_TEXTURES: usize = 2;
+
+// Copies frames back to the CPU as tightly packed BGRA8 (alpha included) at a
+// fixed size. Anything outside of the captured content is left as zeros,
+// which is transparent black. With more than one texture frames come back
+// late (see submit), so call flush once the last frame has been submitted.
+// With one each frame comes back as it's submitted.
+pub struct FrameReadback {
+    staging: StagingReadback,
+    // The content size and timestamp of every frame in staging, oldest first.
+    pending: VecDeque<(SizeInt32, TimeSpan)>,
+    buffer: Vec<u8>,
+}
+
+impl FrameReadback {
+    pub fn new(d3d_device: &ID3D11Device, size: SizeInt32, textures: usize) -> Result<Self> {
+        Ok(Self {
+            staging: StagingReadback::new(d3d_device, size, textures)?,
+            pending: VecDeque::new(),
+            buffer: vec![0u8; size.Width as usize * 4 * size.Height as usize],
+        })
+    }
+
+    // Starts copying the frame and returns the oldest one still in flight,
+    // once every staging texture is in use.
+    pub fn submit(
+        &mut self,
+        surface: &IDirect3DSurface,
+        content_size: SizeInt32,
+        timestamp: TimeSpan,
+    ) -> Result<Option<(&[u8], TimeSpan)>> {
+        let texture: ID3D11Texture2D = get_d3d_interface_from_object(surface)?;
+        let size = self.staging.size();
+        let content_size = SizeInt32 {
+            Width: content_size.Width.min(size.Width),
+            Height: content_size.Height.min(size.Height),
+        };
+        let region = D3D11_BOX {
+            left: 0,
+            top: 0,
+            front: 0,
+            right: content_size.Width as u32,
+            bottom: content_size.Height as u32,
+            back: 1,
+        };
+        self.staging.copy(&texture, Some(&region))?;
+        self.pending.push_back((content_size, timestamp));
+        let timestamp = if self.staging.is_full() {
+            self.read_pending()?
+        } else {
+            None
+        };
+        Ok(timestamp.map(|timestamp| (self.buffer.as_slice(), timestamp)))
+    }
+
+    // Returns the frames still in flight one at a time, blocking until the
+    // GPU is done with them.
+    pub fn flush(&mut self) -> Result<Option<(&[u8], TimeSpan)>> {
+        let timestamp = self.read_pending()?;
+        Ok(timestamp.map(|timestamp| (self.buffer.as_slice(), timestamp)))
+    }
+
+    fn read_pending(&mut self) -> Result<Option<TimeSpan>> {
+        let Some((content_size, timestamp)) = self.pending.pop_front() else {
+            return Ok(None);
+        };
+        let row_size = self.staging.size().Width as usize * 4;
+        // The two queues are kept in step
+        let frame = self.staging.read()?.unwrap();
+        let width = content_size.Width as usize;
+        let height = content_size.Height as usize;
+        self.buffer.fill(0);
+        for (y, row) in self
+            .buffer
+            .chunks_exact_mut(row_size)
+            .take(height)
+            .enumerate()
+        {
+            row[..width * 4].copy_from_slice(&frame.row(y)[..width * 4]);
+        }
+        Ok(Some(timestamp))
+    }
+}
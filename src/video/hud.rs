@@ -0,0 +1,103 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// How often the HUD's numbers are recomputed.
+pub const HUD_REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+// Running totals kept by the mf backend while recording, for the HUD. Cheap
+// to clone and safe to read from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct LiveStats {
+    counters: Arc<LiveStatsCounters>,
+}
+
+#[derive(Debug, Default)]
+struct LiveStatsCounters {
+    frames_captured: AtomicU64,
+    frames_encoded: AtomicU64,
+    bytes_encoded: AtomicU64,
+}
+
+impl LiveStats {
+    // A frame arrived from the capture while recording (not paused).
+    pub fn record_captured(&self) {
+        self.counters
+            .frames_captured
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_encoded(&self, bytes: u64) {
+        self.counters.frames_encoded.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_encoded
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn frames_encoded(&self) -> u64 {
+        self.counters.frames_encoded.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_encoded(&self) -> u64 {
+        self.counters.bytes_encoded.load(Ordering::Relaxed)
+    }
+
+    // Frames that were captured but never encoded, whether the encoder fell
+    // behind or they were over the frame rate or duplicates. Frames still in
+    // the encoder count too, until they come out.
+    pub fn frames_dropped(&self) -> u64 {
+        self.counters
+            .frames_captured
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.frames_encoded())
+    }
+}
+
+// Turns the totals into the HUD's line of text, with the frame and bit rates
+// measured over the last refresh interval.
+pub struct HudMeter {
+    stats: LiveStats,
+    last_update: Option<(Instant, u64, u64)>,
+    text: String,
+}
+
+impl HudMeter {
+    pub fn new(stats: LiveStats) -> Self {
+        Self {
+            stats,
+            last_update: None,
+            text: format_hud_text(0.0, 0, 0.0),
+        }
+    }
+
+    // Only recomputes the text once the refresh interval has passed.
+    pub fn text(&mut self) -> &str {
+        let now = Instant::now();
+        let frames = self.stats.frames_encoded();
+        let bytes = self.stats.bytes_encoded();
+        match self.last_update {
+            Some((last_time, last_frames, last_bytes)) => {
+                let elapsed = now.duration_since(last_time);
+                if elapsed >= HUD_REFRESH_INTERVAL {
+                    let seconds = elapsed.as_secs_f64();
+                    self.text = format_hud_text(
+                        (frames - last_frames) as f64 / seconds,
+                        self.stats.frames_dropped(),
+                        (bytes - last_bytes) as f64 * 8.0 / seconds / 1_000_000.0,
+                    );
+                    self.last_update = Some((now, frames, bytes));
+                }
+            }
+            None => self.last_update = Some((now, frames, bytes)),
+        }
+        &self.text
+    }
+}
+
+fn format_hud_text(fps: f64, dropped: u64, mbps: f64) -> String {
+    format!("{:.1} fps  {} dropped  {:.1} Mbps", fps, dropped, mbps)
+}
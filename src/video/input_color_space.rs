@@ -0,0 +1,94 @@
+use std::{fmt::Display, str::FromStr};
+
+use windows::Win32::Graphics::Dxgi::Common::{
+    DXGI_COLOR_SPACE_RGB_FULL_G10_NONE_P709, DXGI_COLOR_SPACE_RGB_FULL_G22_NONE_P709,
+    DXGI_COLOR_SPACE_TYPE, DXGI_FORMAT, DXGI_FORMAT_R16G16B16A16_FLOAT,
+};
+
+// The transfer function of the captured frames, which the video processor
+// has to undo before converting them to NV12. Getting it wrong washes out
+// (linear treated as sRGB) or crushes (the other way around) the output.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputColorSpace {
+    // Gamma 2.2, what 8 and 10 bit captures are in.
+    Srgb,
+    // Gamma 1.0 (scRGB), what FP16 captures are in.
+    Linear,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseInputColorSpaceError(&'static str);
+
+impl InputColorSpace {
+    // What the capture delivers frames of this format in.
+    pub fn for_format(format: DXGI_FORMAT) -> Self {
+        if format == DXGI_FORMAT_R16G16B16A16_FLOAT {
+            InputColorSpace::Linear
+        } else {
+            InputColorSpace::Srgb
+        }
+    }
+
+    pub fn dxgi_color_space(&self) -> DXGI_COLOR_SPACE_TYPE {
+        match self {
+            InputColorSpace::Srgb => DXGI_COLOR_SPACE_RGB_FULL_G22_NONE_P709,
+            InputColorSpace::Linear => DXGI_COLOR_SPACE_RGB_FULL_G10_NONE_P709,
+        }
+    }
+}
+
+impl FromStr for InputColorSpace {
+    type Err = ParseInputColorSpaceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(InputColorSpace::Srgb),
+            "linear" => Ok(InputColorSpace::Linear),
+            _ => Err(ParseInputColorSpaceError(
+                "Invalid input colorspace value! Expecting: srgb, or linear.",
+            )),
+        }
+    }
+}
+
+impl Display for InputColorSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            InputColorSpace::Srgb => "srgb",
+            InputColorSpace::Linear => "linear",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseInputColorSpaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseInputColorSpaceError {}
+
+#[cfg(test)]
+mod tests {
+    use super::InputColorSpace;
+
+    #[test]
+    fn input_color_space_parsing_test() {
+        assert_eq!("srgb".parse(), Ok(InputColorSpace::Srgb));
+        assert_eq!("sRGB".parse(), Ok(InputColorSpace::Srgb));
+        assert_eq!("linear".parse(), Ok(InputColorSpace::Linear));
+        assert_eq!("Linear".parse(), Ok(InputColorSpace::Linear));
+
+        assert!("".parse::<InputColorSpace>().is_err());
+        assert!("scrgb".parse::<InputColorSpace>().is_err());
+        assert!("hdr".parse::<InputColorSpace>().is_err());
+    }
+
+    #[test]
+    fn input_color_space_round_trip_test() {
+        for color_space in [InputColorSpace::Srgb, InputColorSpace::Linear] {
+            assert_eq!(color_space.to_string().parse(), Ok(color_space));
+        }
+        assert_eq!(InputColorSpace::Srgb.to_string(), "srgb");
+    }
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use windows::Foundation::TimeSpan;
+
+// Decides when to force a keyframe so that one lands at the start of every
+// period on the output timeline (e.g. at 0s, 2s, 4s, ...), no matter how
+// irregularly frames arrive. Useful when the output gets cut into segments
+// downstream.
+pub struct KeyframeScheduler {
+    period: i64,
+    last_boundary: Option<i64>,
+}
+
+impl KeyframeScheduler {
+    pub fn new(period: Duration) -> Self {
+        let period = TimeSpan::from(period).Duration.max(1);
+        Self {
+            period,
+            last_boundary: None,
+        }
+    }
+
+    // Returns true for the first frame at or past each boundary.
+    pub fn should_force(&mut self, timestamp: TimeSpan) -> bool {
+        let boundary = timestamp.Duration.div_euclid(self.period);
+        let force = self.last_boundary.map_or(true, |last| boundary > last);
+        self.last_boundary = Some(boundary);
+        force
+    }
+}
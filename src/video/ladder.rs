@@ -0,0 +1,33 @@
+use std::path::{Path, PathBuf};
+
+use windows::Graphics::SizeInt32;
+
+// One extra quality level recorded alongside the main output, from the same
+// capture. Each rendition gets its own encoder and file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rendition {
+    pub size: SizeInt32,
+    pub bit_rate: u32,
+}
+
+impl Rendition {
+    // Renditions are written next to the main output and named after their
+    // height, e.g. recording.mp4 gets recording-720p.mp4.
+    pub fn output_path(&self, main_output_path: &Path) -> PathBuf {
+        let stem = main_output_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "recording".to_owned());
+        let file_name = if let Some(extension) = main_output_path.extension() {
+            format!(
+                "{}-{}p.{}",
+                stem,
+                self.size.Height,
+                extension.to_string_lossy()
+            )
+        } else {
+            format!("{}-{}p", stem, self.size.Height)
+        };
+        main_output_path.with_file_name(file_name)
+    }
+}
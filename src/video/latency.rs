@@ -0,0 +1,21 @@
+use std::time::Duration;
+
+// Capture buffers used when tuning for latency. With a single buffer the
+// frame pool can't get ahead of the encoder, so frames never queue up
+// waiting for it.
+pub const LOW_LATENCY_BUFFER_COUNT: i32 = 1;
+
+// How many captured frames can wait for the encoder when tuning for latency.
+// Anything that arrived before the newest frame is dropped rather than
+// encoded late, see CaptureLoop.
+pub const LOW_LATENCY_QUEUE_LENGTH: usize = 1;
+
+// What a frame should take from being presented to coming out of the
+// encoder when tuned for latency, worked out from the frame rate alone
+// rather than measured: up to a frame interval waiting for the capture, one
+// for each buffered or queued frame, and one in the encoder. A slow encoder
+// or a busy GPU adds to this.
+pub fn nominal_pipeline_latency(frame_rate: u32) -> Duration {
+    let frame_interval = Duration::from_secs(1) / frame_rate.max(1);
+    frame_interval * (LOW_LATENCY_BUFFER_COUNT as u32 + LOW_LATENCY_QUEUE_LENGTH as u32 + 1)
+}
@@ -0,0 +1,292 @@
+use std::sync::{Arc, Mutex};
+
+use windows::{
+    core::{Error, Result},
+    Win32::Media::MediaFoundation::{
+        IMFAttributes, IMFMediaType, IMFSinkWriter, MFAudioFormat_PCM, MFCreateMediaType,
+        MFCreateMemoryBuffer, MFCreateSample, MFMediaType_Audio, MFT_CATEGORY_AUDIO_ENCODER,
+        MFT_REGISTER_TYPE_INFO, MF_E_INVALIDMEDIATYPE, MF_E_TOPO_CODEC_NOT_FOUND,
+        MF_MT_AUDIO_AVG_BYTES_PER_SECOND, MF_MT_AUDIO_BITS_PER_SAMPLE, MF_MT_AUDIO_BLOCK_ALIGNMENT,
+        MF_MT_AUDIO_NUM_CHANNELS, MF_MT_AUDIO_SAMPLES_PER_SECOND, MF_MT_MAJOR_TYPE, MF_MT_SUBTYPE,
+    },
+};
+
+use crate::{
+    media::{enumerate_mfts, EnumOptions},
+    pause::PauseState,
+    process_audio::ProcessAudioCapture,
+    video::audio_codec::AudioCodec,
+};
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: u32 = 2;
+const BITS_PER_SAMPLE: u32 = 16;
+const BLOCK_ALIGNMENT: u32 = CHANNELS * BITS_PER_SAMPLE / 8;
+// 128 kbps, one of the few rates the AAC encoder accepts. The MP3 encoder
+// takes it as well.
+const ENCODED_BYTES_PER_SECOND: u32 = 16000;
+// How much audio goes into a single sample at most: 100ms
+const MAX_FRAMES_PER_SAMPLE: u64 = SAMPLE_RATE as u64 / 10;
+// How far the audio trails the video when it's being captured, so that the
+// audio for a frame has arrived by the time we write it: 100ms
+const CAPTURE_DELAY: i64 = 1_000_000;
+
+// What an audio track records instead of silence: the audio a process plays.
+// One capture serves every segment of a recording, each one picking up where
+// the one before it left off.
+#[derive(Clone)]
+pub struct AudioSource {
+    capture: Arc<ProcessAudioCapture>,
+    timeline: AudioTimeline,
+}
+
+impl AudioSource {
+    // Starts capturing right away, see ProcessAudioCapture.
+    pub fn new(process_id: u32, pause_state: PauseState) -> Result<Self> {
+        Ok(Self {
+            capture: Arc::new(ProcessAudioCapture::new(
+                process_id,
+                SAMPLE_RATE,
+                CHANNELS as u16,
+                pause_state,
+            )?),
+            timeline: AudioTimeline::default(),
+        })
+    }
+
+    pub fn timeline(&self) -> &AudioTimeline {
+        &self.timeline
+    }
+
+    // The same source for a stream whose times are the given amount behind
+    // the video's, see AudioTimeline::shifted.
+    pub fn shifted(&self, shift: i64) -> Self {
+        Self {
+            capture: self.capture.clone(),
+            timeline: self.timeline.shifted(shift),
+        }
+    }
+}
+
+// Where the video's timeline sits on the clock the audio is captured on. The
+// two drift apart at every pause, since the video leaves the time spent
+// paused out, so each stretch between pauses gets a span of its own. The
+// capture starts one with the first frame and another with the first frame
+// after every pause (see FrameSink::start_span).
+#[derive(Clone, Default)]
+pub struct AudioTimeline {
+    spans: Arc<Mutex<Vec<TimelineSpan>>>,
+    // Segments start their timeline over, see shifted.
+    shift: i64,
+}
+
+struct TimelineSpan {
+    // Where the span starts on the video's timeline.
+    start: i64,
+    // What to add to a time on the video's timeline to get the capture time.
+    offset: i64,
+}
+
+impl AudioTimeline {
+    // Both times belong to the same frame, on the video's timeline and as
+    // its system relative time.
+    pub fn start_span(&self, time: i64, system_time: i64) {
+        self.spans.lock().unwrap().push(TimelineSpan {
+            start: time,
+            offset: system_time - time,
+        });
+    }
+
+    // A view of the same timeline for a stream whose times are the given
+    // amount behind the video's.
+    pub fn shifted(&self, shift: i64) -> Self {
+        Self {
+            spans: self.spans.clone(),
+            shift: self.shift + shift,
+        }
+    }
+
+    // Returns the capture time for the time and when the span it's in ends,
+    // or None before the first frame.
+    fn locate(&self, time: i64) -> Option<(i64, Option<i64>)> {
+        let time = time + self.shift;
+        let spans = self.spans.lock().unwrap();
+        let index = spans.iter().rposition(|span| span.start <= time)?;
+        let end = spans.get(index + 1).map(|span| span.start - self.shift);
+        Some((time + spans[index].offset, end))
+    }
+}
+
+// An audio track kept in step with the video, holding either silence or the
+// audio a process is playing. The sink writer encodes the PCM we hand it.
+// Any gaps in the captured audio are filled with silence.
+pub struct AudioTrack {
+    stream_index: u32,
+    source: Option<AudioSource>,
+    // The time of the first video sample, which is where the audio starts.
+    start_time: Option<i64>,
+    end_time: i64,
+    frames_written: u64,
+}
+
+impl AudioTrack {
+    // Has to be called before the sink writer starts writing.
+    pub fn new(
+        sink_writer: &IMFSinkWriter,
+        encoding_parameters: &IMFAttributes,
+        audio_codec: AudioCodec,
+        audio_source: Option<AudioSource>,
+    ) -> Result<Self> {
+        if !audio_codec.supported_in_mp4() {
+            return Err(Error::new(
+                MF_E_INVALIDMEDIATYPE,
+                format!("The {} audio codec can't be stored in an MP4!", audio_codec).into(),
+            ));
+        }
+        ensure_audio_encoder(audio_codec)?;
+
+        let stream_index = unsafe {
+            let output_type = create_audio_type(Some(audio_codec))?;
+            let stream_index = sink_writer.AddStream(&output_type)?;
+            let input_type = create_audio_type(None)?;
+            sink_writer.SetInputMediaType(stream_index, &input_type, encoding_parameters)?;
+            stream_index
+        };
+        Ok(Self {
+            stream_index,
+            source: audio_source,
+            start_time: None,
+            end_time: 0,
+            frames_written: 0,
+        })
+    }
+
+    // Writes audio until it covers everything up to the end of a video
+    // sample (in 100ns units, on the same timeline), less the capture delay
+    // if there's a source.
+    pub fn fill_until(
+        &mut self,
+        sink_writer: &IMFSinkWriter,
+        sample_time: i64,
+        time: i64,
+    ) -> Result<()> {
+        self.start_time.get_or_insert(sample_time);
+        self.end_time = time;
+        let delay = if self.source.is_some() {
+            CAPTURE_DELAY
+        } else {
+            0
+        };
+        self.write_until(sink_writer, time - delay)
+    }
+
+    // Catches the audio up with the last video sample, so that both tracks
+    // end together when we finalize.
+    pub fn finish(&mut self, sink_writer: &IMFSinkWriter) -> Result<()> {
+        self.write_until(sink_writer, self.end_time)
+    }
+
+    fn write_until(&mut self, sink_writer: &IMFSinkWriter, time: i64) -> Result<()> {
+        let start_time = *self.start_time.get_or_insert(self.end_time);
+        let target_frames = ((time - start_time).max(0) as u64 * SAMPLE_RATE as u64) / 10_000_000;
+        while self.frames_written < target_frames {
+            let mut frames = (target_frames - self.frames_written).min(MAX_FRAMES_PER_SAMPLE);
+            let sample_time = start_time + frames_to_time(self.frames_written);
+            // A sample never spans a pause, the audio on either side of it
+            // was captured at times that aren't next to each other.
+            let capture_time = self.source.as_ref().and_then(|source| {
+                let (capture_time, span_end) = source.timeline.locate(sample_time)?;
+                if let Some(span_end) = span_end {
+                    let span_frames = time_to_frames(span_end - start_time);
+                    frames = frames.min(span_frames.saturating_sub(self.frames_written).max(1));
+                }
+                Some(capture_time)
+            });
+            let duration =
+                frames_to_time(self.frames_written + frames) - frames_to_time(self.frames_written);
+            let length = frames as u32 * BLOCK_ALIGNMENT;
+            unsafe {
+                let buffer = MFCreateMemoryBuffer(length)?;
+                let mut data = std::ptr::null_mut();
+                buffer.Lock(&mut data, None, None)?;
+                let data = std::slice::from_raw_parts_mut(data, length as usize);
+                match (self.source.as_ref(), capture_time) {
+                    (Some(source), Some(capture_time)) => source.capture.read(capture_time, data),
+                    _ => data.fill(0),
+                }
+                buffer.Unlock()?;
+                buffer.SetCurrentLength(length)?;
+
+                let sample = MFCreateSample()?;
+                sample.AddBuffer(&buffer)?;
+                sample.SetSampleTime(sample_time)?;
+                sample.SetSampleDuration(duration)?;
+                sink_writer.WriteSample(self.stream_index, &sample)?;
+            }
+            self.frames_written += frames;
+        }
+        Ok(())
+    }
+}
+
+fn frames_to_time(frames: u64) -> i64 {
+    (frames * 10_000_000 / SAMPLE_RATE as u64) as i64
+}
+
+// Rounds up, so that the frame it lands on is at or after the time.
+fn time_to_frames(time: i64) -> u64 {
+    (time.max(0) as u64 * SAMPLE_RATE as u64).div_ceil(10_000_000)
+}
+
+// The sink writer finds the encoder itself, but it only reports a generic
+// failure when there isn't one.
+fn ensure_audio_encoder(audio_codec: AudioCodec) -> Result<()> {
+    let input_info = MFT_REGISTER_TYPE_INFO {
+        guidMajorType: MFMediaType_Audio,
+        guidSubtype: MFAudioFormat_PCM,
+    };
+    let output_info = MFT_REGISTER_TYPE_INFO {
+        guidMajorType: MFMediaType_Audio,
+        guidSubtype: audio_codec.subtype(),
+    };
+    let encoders = enumerate_mfts(
+        &MFT_CATEGORY_AUDIO_ENCODER,
+        &EnumOptions {
+            hardware: false,
+            synchronous: true,
+            ..Default::default()
+        },
+        Some(&input_info),
+        Some(&output_info),
+    )?;
+    if encoders.is_empty() {
+        return Err(Error::new(
+            MF_E_TOPO_CODEC_NOT_FOUND,
+            format!("No {} audio encoder found!", audio_codec).into(),
+        ));
+    }
+    Ok(())
+}
+
+// None creates the PCM type we feed the sink writer.
+fn create_audio_type(audio_codec: Option<AudioCodec>) -> Result<IMFMediaType> {
+    unsafe {
+        let media_type = MFCreateMediaType()?;
+        media_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Audio)?;
+        media_type.SetUINT32(&MF_MT_AUDIO_BITS_PER_SAMPLE, BITS_PER_SAMPLE)?;
+        media_type.SetUINT32(&MF_MT_AUDIO_SAMPLES_PER_SECOND, SAMPLE_RATE)?;
+        media_type.SetUINT32(&MF_MT_AUDIO_NUM_CHANNELS, CHANNELS)?;
+        if let Some(audio_codec) = audio_codec {
+            media_type.SetGUID(&MF_MT_SUBTYPE, &audio_codec.subtype())?;
+            media_type.SetUINT32(&MF_MT_AUDIO_AVG_BYTES_PER_SECOND, ENCODED_BYTES_PER_SECOND)?;
+        } else {
+            media_type.SetGUID(&MF_MT_SUBTYPE, &MFAudioFormat_PCM)?;
+            media_type.SetUINT32(&MF_MT_AUDIO_BLOCK_ALIGNMENT, BLOCK_ALIGNMENT)?;
+            media_type.SetUINT32(
+                &MF_MT_AUDIO_AVG_BYTES_PER_SECOND,
+                SAMPLE_RATE * BLOCK_ALIGNMENT,
+            )?;
+        }
+        Ok(media_type)
+    }
+}
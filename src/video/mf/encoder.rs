@@ -1,47 +1,120 @@
 use std::{
+    mem::ManuallyDrop,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
     },
     thread::JoinHandle,
+    time::Duration,
 };
 
+use log::{debug, error, info, warn};
 use windows::{
-    core::{ComInterface, Error, Result},
+    core::{implement, w, ComInterface, Error, Result, GUID},
     Foundation::TimeSpan,
     Graphics::SizeInt32,
     Win32::{
-        Foundation::E_NOTIMPL,
-        Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D},
+        Foundation::{E_NOTIMPL, HANDLE, VARIANT_TRUE},
+        Graphics::{
+            Direct3D11::{ID3D11Device, ID3D11Texture2D},
+            Dxgi::Common::DXGI_FORMAT,
+        },
         Media::MediaFoundation::{
-            IMFAttributes, IMFDXGIDeviceManager, IMFMediaEventGenerator, IMFMediaType, IMFSample,
-            IMFTransform, METransformHaveOutput, METransformNeedInput, MFCreateDXGIDeviceManager,
-            MFCreateDXGISurfaceBuffer, MFCreateMediaType, MFCreateSample, MFMediaType_Video,
-            MFStartup, MFVideoFormat_H264, MFVideoFormat_NV12, MFVideoInterlace_Progressive,
-            MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS, MFSTARTUP_FULL, MFT_MESSAGE_COMMAND_FLUSH,
-            MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, MFT_MESSAGE_NOTIFY_END_OF_STREAM,
-            MFT_MESSAGE_NOTIFY_END_STREAMING, MFT_MESSAGE_NOTIFY_START_OF_STREAM,
-            MFT_MESSAGE_SET_D3D_MANAGER, MFT_OUTPUT_DATA_BUFFER, MFT_SET_TYPE_TEST_ONLY,
-            MF_EVENT_TYPE, MF_E_INVALIDMEDIATYPE, MF_E_NO_MORE_TYPES, MF_E_TRANSFORM_TYPE_NOT_SET,
+            CODECAPI_AVEncCommonQualityVsSpeed, CODECAPI_AVEncMPVDefaultBPictureCount,
+            CODECAPI_AVEncNumWorkerThreads, CODECAPI_AVEncVideoForceKeyFrame,
+            CODECAPI_AVLowLatencyMode, ICodecAPI, IMFAsyncCallback, IMFAsyncCallback_Impl,
+            IMFAsyncResult, IMFAttributes, IMFDXGIDeviceManager, IMFMediaEventGenerator,
+            IMFMediaType, IMFSample, IMFTransform, METransformDrainComplete, METransformHaveOutput,
+            METransformNeedInput, MFCreateDXGIDeviceManager, MFCreateDXGISurfaceBuffer,
+            MFCreateMediaType, MFCreateMemoryBuffer, MFCreateSample, MFCreateTrackedSample,
+            MFMediaType_Video, MFStartup, MFVideoFormat_H264, MFVideoInterlace_Progressive,
+            MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS, MFSTARTUP_FULL, MFT_MESSAGE_COMMAND_DRAIN,
+            MFT_MESSAGE_COMMAND_FLUSH, MFT_MESSAGE_NOTIFY_BEGIN_STREAMING,
+            MFT_MESSAGE_NOTIFY_END_OF_STREAM, MFT_MESSAGE_NOTIFY_END_STREAMING,
+            MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_MESSAGE_SET_D3D_MANAGER,
+            MFT_OUTPUT_DATA_BUFFER, MFT_OUTPUT_STREAM_CAN_PROVIDE_SAMPLES,
+            MFT_OUTPUT_STREAM_PROVIDES_SAMPLES, MFT_SET_TYPE_TEST_ONLY, MF_EVENT_TYPE,
+            MF_E_INVALIDMEDIATYPE, MF_E_NO_MORE_TYPES, MF_E_TRANSFORM_NEED_MORE_INPUT,
+            MF_E_TRANSFORM_STREAM_CHANGE, MF_E_TRANSFORM_TYPE_NOT_SET,
             MF_MT_ALL_SAMPLES_INDEPENDENT, MF_MT_AVG_BITRATE, MF_MT_FRAME_RATE, MF_MT_FRAME_SIZE,
-            MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE, MF_MT_PIXEL_ASPECT_RATIO, MF_MT_SUBTYPE,
-            MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS, MF_TRANSFORM_ASYNC_UNLOCK,
+            MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE, MF_MT_MPEG2_PROFILE, MF_MT_PIXEL_ASPECT_RATIO,
+            MF_MT_SUBTYPE, MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS, MF_TRANSFORM_ASYNC_UNLOCK,
+        },
+        System::Threading::{
+            AvRevertMmThreadCharacteristics, AvSetMmThreadCharacteristicsW, GetCurrentThread,
+            SetThreadPriority, THREAD_PRIORITY_TIME_CRITICAL,
         },
+        System::Variant::{VARIANT, VT_BOOL, VT_UI4},
     },
 };
 
-use crate::media::{MFSetAttributeRatio, MFSetAttributeSize, MF_VERSION};
+use crate::{
+    media::{MFSetAttributeRatio, MFSetAttributeSize, MF_VERSION},
+    video::{chroma::Chroma, encoder_input::EncoderInput, pixel_aspect_ratio::PixelAspectRatio},
+};
 
 use super::encoder_device::VideoEncoderDevice;
 
+type ReleaseCallback = Box<dyn Send + FnOnce()>;
+
 pub struct VideoEncoderInputSample {
     timestamp: TimeSpan,
     texture: ID3D11Texture2D,
+    force_keyframe: bool,
+    release_callback: Option<ReleaseCallback>,
 }
 
 impl VideoEncoderInputSample {
     pub fn new(timestamp: TimeSpan, texture: ID3D11Texture2D) -> Self {
-        Self { timestamp, texture }
+        Self {
+            timestamp,
+            texture,
+            force_keyframe: false,
+            release_callback: None,
+        }
+    }
+
+    // Asks the encoder to make this frame a keyframe (IDR).
+    pub fn with_forced_keyframe(mut self) -> Self {
+        self.force_keyframe = true;
+        self
+    }
+
+    // Called once nothing references the texture anymore: when the encoder
+    // releases the sample it was given, or right away if this never makes it
+    // to the encoder (e.g. a rendition dropped it).
+    pub fn with_release_callback<F: 'static + Send + FnOnce()>(mut self, callback: F) -> Self {
+        self.release_callback = Some(Box::new(callback));
+        self
+    }
+}
+
+impl Drop for VideoEncoderInputSample {
+    fn drop(&mut self) {
+        if let Some(callback) = self.release_callback.take() {
+            callback();
+        }
+    }
+}
+
+// Runs a sample's release callback when the tracked sample it was attached
+// to is released by everyone, which MFT encoders may take a few frames to do.
+#[implement(IMFAsyncCallback)]
+struct SampleReleasedCallback {
+    callback: Mutex<Option<ReleaseCallback>>,
+}
+
+impl IMFAsyncCallback_Impl for SampleReleasedCallback {
+    fn GetParameters(&self, _flags: *mut u32, _queue: *mut u32) -> Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Invoke(&self, _result: Option<&IMFAsyncResult>) -> Result<()> {
+        if let Some(callback) = self.callback.lock().unwrap().take() {
+            callback();
+        }
+        Ok(())
     }
 }
 
@@ -55,12 +128,48 @@ impl VideoEncoderOutputSample {
     }
 }
 
+// What a VideoEncoder gets set up with.
+pub struct VideoEncoderSettings<'a> {
+    pub input_resolution: SizeInt32,
+    pub output_resolution: SizeInt32,
+    pub bit_rate: u32,
+    pub frame_rate: u32,
+    pub pixel_aspect_ratio: PixelAspectRatio,
+    pub chroma: Chroma,
+    // In order of preference, the first one the encoder takes is used.
+    pub input_formats: &'a [EncoderInput],
+    // Asks for the encoder's low latency settings, see set_low_latency.
+    pub low_latency: bool,
+    // Caps how many threads a software encoder spreads the work over.
+    // Hardware encoders don't run on the CPU, so they ignore it.
+    pub worker_threads: Option<u32>,
+}
+
 pub struct VideoEncoder {
     inner: Option<VideoEncoderInner>,
     output_type: IMFMediaType,
+    negotiated_output_type: IMFMediaType,
+    input_format: DXGI_FORMAT,
     started: AtomicBool,
     should_stop: Arc<AtomicBool>,
     encoder_thread_handle: Option<JoinHandle<Result<()>>>,
+    encoder_thread_done: Option<Receiver<()>>,
+    frame_counts: Arc<FrameCounts>,
+}
+
+// How many frames went into the encoder and how many came back out, so that
+// we know how many were lost if we give up on a drain.
+#[derive(Default)]
+struct FrameCounts {
+    input: AtomicU64,
+    output: AtomicU64,
+}
+
+impl FrameCounts {
+    fn pending(&self) -> u64 {
+        let output = self.output.load(Ordering::SeqCst);
+        self.input.load(Ordering::SeqCst).saturating_sub(output)
+    }
 }
 
 struct VideoEncoderInner {
@@ -69,26 +178,41 @@ struct VideoEncoderInner {
     _device_manager_reset_token: u32,
 
     transform: IMFTransform,
-    event_generator: IMFMediaEventGenerator,
+    // Not every encoder exposes this, we only need it to force keyframes.
+    codec_api: Option<ICodecAPI>,
+    // None for synchronous MFTs, which we drive ourselves.
+    event_generator: Option<IMFMediaEventGenerator>,
     input_stream_id: u32,
     output_stream_id: u32,
 
     sample_requested_callback:
         Option<Box<dyn Send + FnMut() -> Result<Option<VideoEncoderInputSample>>>>,
     sample_rendered_callback: Option<Box<dyn Send + FnMut(VideoEncoderOutputSample) -> Result<()>>>,
+    output_type_changed_callback: Option<Box<dyn Send + FnMut(&IMFMediaType) -> Result<()>>>,
+    stream_ended_callback: Option<Box<dyn Send + FnMut() -> Result<()>>>,
 
     should_stop: Arc<AtomicBool>,
+    high_priority: bool,
+    frame_counts: Arc<FrameCounts>,
 }
 
 impl VideoEncoder {
     pub fn new(
         encoder_device: &VideoEncoderDevice,
         d3d_device: ID3D11Device,
-        input_resolution: SizeInt32,
-        output_resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
+        settings: VideoEncoderSettings,
     ) -> Result<Self> {
+        let VideoEncoderSettings {
+            input_resolution,
+            output_resolution,
+            bit_rate,
+            frame_rate,
+            pixel_aspect_ratio,
+            chroma,
+            input_formats,
+            low_latency,
+            worker_threads,
+        } = settings;
         let transform = encoder_device.create_transform()?;
 
         // Create MF device manager
@@ -106,12 +230,26 @@ impl VideoEncoder {
         unsafe { media_device_manager.ResetDevice(&d3d_device, device_manager_reset_token)? };
 
         // Setup MFTransform
-        let event_generator: IMFMediaEventGenerator = transform.cast()?;
-        let attributes = unsafe { transform.GetAttributes()? };
-        unsafe {
-            attributes.SetUINT32(&MF_TRANSFORM_ASYNC_UNLOCK, 1)?;
-            attributes.SetUINT32(&MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS, 1)?;
-        };
+        // Only asynchronous MFTs send events, synchronous ones are driven
+        // by the encode loop directly.
+        let event_generator: Option<IMFMediaEventGenerator> = transform.cast().ok();
+        if event_generator.is_none() {
+            info!(
+                "The {} encoder is synchronous, driving it directly...",
+                encoder_device.display_name()
+            );
+        }
+        // Synchronous MFTs don't have to have attributes at all
+        match unsafe { transform.GetAttributes() } {
+            Ok(attributes) => unsafe {
+                if event_generator.is_some() {
+                    attributes.SetUINT32(&MF_TRANSFORM_ASYNC_UNLOCK, 1)?;
+                }
+                attributes.SetUINT32(&MF_READWRITE_ENABLE_HARDWARE_TRANSFORMS, 1)?;
+            },
+            Err(error) if event_generator.is_some() => return Err(error),
+            Err(_) => {}
+        }
 
         let mut number_of_input_streams = 0;
         let mut number_of_output_streams = 0;
@@ -151,98 +289,170 @@ impl VideoEncoder {
         let output_stream_id = output_stream_ids[0];
 
         // TOOD: Avoid this AddRef?
-        unsafe {
+        let result = unsafe {
             let temp = media_device_manager.clone();
-            transform.ProcessMessage(MFT_MESSAGE_SET_D3D_MANAGER, std::mem::transmute(temp))?;
+            transform.ProcessMessage(MFT_MESSAGE_SET_D3D_MANAGER, std::mem::transmute(temp))
         };
+        match result {
+            Ok(()) => {}
+            // Software encoders read the frames back through the buffers
+            // instead
+            Err(error) if event_generator.is_none() && error.code() == E_NOTIMPL => {
+                debug!("The encoder isn't D3D aware, frames will be read back to the CPU...");
+            }
+            Err(error) => return Err(error),
+        }
 
-        let output_type = unsafe {
-            let output_type = MFCreateMediaType()?;
-            let attributes: IMFAttributes = output_type.cast()?;
-            output_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
-            output_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_H264)?;
-            output_type.SetUINT32(&MF_MT_AVG_BITRATE, bit_rate)?;
-            MFSetAttributeSize(
-                &attributes,
-                &MF_MT_FRAME_SIZE,
-                output_resolution.Width as u32,
-                output_resolution.Height as u32,
-            )?;
-            MFSetAttributeRatio(&attributes, &MF_MT_FRAME_RATE, frame_rate, 1)?;
-            MFSetAttributeRatio(&attributes, &MF_MT_PIXEL_ASPECT_RATIO, 1, 1)?;
-            output_type.SetUINT32(&MF_MT_INTERLACE_MODE, MFVideoInterlace_Progressive.0 as u32)?;
-            output_type.SetUINT32(&MF_MT_ALL_SAMPLES_INDEPENDENT, 1)?;
-            transform.SetOutputType(output_stream_id, &output_type, 0)?;
-            output_type
-        };
-        let input_type: Option<IMFMediaType> = unsafe {
-            let mut count = 0;
-            loop {
-                let result = transform.GetInputAvailableType(input_stream_id, count);
-                if let Err(error) = &result {
-                    if error.code() == MF_E_NO_MORE_TYPES {
-                        break None;
-                    }
-                }
+        // Encoders only take these into account while negotiating types
+        let codec_api: Option<ICodecAPI> = transform.cast().ok();
+        if low_latency && !codec_api.as_ref().map_or(false, set_low_latency) {
+            warn!("The encoder doesn't support all of the low latency settings, the latency may be higher than estimated...");
+        }
+        if let Some(threads) = worker_threads {
+            if encoder_device.is_hardware() {
+                warn!("The encoder threads option only applies to software encoders, ignoring...");
+            } else if !codec_api.as_ref().map_or(false, |codec_api| {
+                set_codec_value(codec_api, &CODECAPI_AVEncNumWorkerThreads, threads)
+            }) {
+                warn!("The encoder doesn't support limiting its threads, ignoring...");
+            }
+        }
 
-                let input_type = result?;
-                let attributes: IMFAttributes = input_type.cast()?;
-                input_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
-                input_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_NV12)?;
+        let create_output_type = |profile: Option<u32>| -> Result<IMFMediaType> {
+            unsafe {
+                let output_type = MFCreateMediaType()?;
+                let attributes: IMFAttributes = output_type.cast()?;
+                output_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+                output_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_H264)?;
+                output_type.SetUINT32(&MF_MT_AVG_BITRATE, bit_rate)?;
                 MFSetAttributeSize(
                     &attributes,
                     &MF_MT_FRAME_SIZE,
-                    input_resolution.Width as u32,
-                    input_resolution.Height as u32,
+                    output_resolution.Width as u32,
+                    output_resolution.Height as u32,
                 )?;
-                MFSetAttributeRatio(&attributes, &MF_MT_FRAME_RATE, 60, 1)?;
-                let result = transform.SetInputType(
+                MFSetAttributeRatio(&attributes, &MF_MT_FRAME_RATE, frame_rate, 1)?;
+                MFSetAttributeRatio(
+                    &attributes,
+                    &MF_MT_PIXEL_ASPECT_RATIO,
+                    pixel_aspect_ratio.numerator,
+                    pixel_aspect_ratio.denominator,
+                )?;
+                output_type
+                    .SetUINT32(&MF_MT_INTERLACE_MODE, MFVideoInterlace_Progressive.0 as u32)?;
+                output_type.SetUINT32(&MF_MT_ALL_SAMPLES_INDEPENDENT, 1)?;
+                if let Some(profile) = profile {
+                    output_type.SetUINT32(&MF_MT_MPEG2_PROFILE, profile)?;
+                }
+                Ok(output_type)
+            }
+        };
+
+        // Anything other than 4:2:0 is best effort, if the encoder turns
+        // down either the profile or the input format we fall back.
+        let negotiated = if chroma != Chroma::Yuv420 {
+            let output_type = create_output_type(chroma.h264_profile())?;
+            let result = unsafe { transform.SetOutputType(output_stream_id, &output_type, 0) };
+            if result.is_ok() {
+                find_input_type(
+                    &transform,
                     input_stream_id,
-                    &input_type,
-                    MFT_SET_TYPE_TEST_ONLY.0 as u32,
+                    &chroma.input_subtype(),
+                    input_resolution,
+                )?
+                .map(|input_type| (output_type, input_type, chroma.dxgi_format()))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        let (output_type, input_type, input_format) = if let Some(negotiated) = negotiated {
+            negotiated
+        } else {
+            if chroma != Chroma::Yuv420 {
+                warn!(
+                    "The encoder doesn't support {} chroma subsampling, falling back to 420...",
+                    chroma
                 );
-                if let Err(error) = &result {
-                    if error.code() == MF_E_INVALIDMEDIATYPE {
-                        count += 1;
-                        continue;
-                    }
+            }
+            let output_type = create_output_type(None)?;
+            unsafe { transform.SetOutputType(output_stream_id, &output_type, 0)? };
+            // The first of the formats the encoder takes
+            let mut negotiated_input = None;
+            for input_format in input_formats {
+                let input_type = find_input_type(
+                    &transform,
+                    input_stream_id,
+                    &input_format.input_subtype(),
+                    input_resolution,
+                )?;
+                if let Some(input_type) = input_type {
+                    negotiated_input = Some((input_type, *input_format));
+                    break;
+                }
+            }
+            match negotiated_input {
+                Some((input_type, input_format)) => {
+                    debug!("Encoding from {} input.", input_format);
+                    (output_type, input_type, input_format.dxgi_format())
+                }
+                None if input_formats.len() == 1 => {
+                    return Err(Error::new(
+                        MF_E_TRANSFORM_TYPE_NOT_SET,
+                        format!(
+                            "The encoder doesn't support {} input! Try a different set of encoding settings.",
+                            input_formats[0]
+                        )
+                        .into(),
+                    ));
+                }
+                None => {
+                    return Err(Error::new(
+                        MF_E_TRANSFORM_TYPE_NOT_SET,
+                        "No suitable input type found! Try a different set of encoding settings."
+                            .into(),
+                    ));
                 }
-                result?;
-                break Some(input_type);
             }
         };
-        if let Some(input_type) = input_type {
-            unsafe { transform.SetInputType(input_stream_id, &input_type, 0)? };
-        } else {
-            return Err(Error::new(
-                MF_E_TRANSFORM_TYPE_NOT_SET,
-                "No suitable input type found! Try a different set of encoding settings.".into(),
-            ));
-        }
+        unsafe { transform.SetInputType(input_stream_id, &input_type, 0)? };
+        // Hardware encoders can quietly clamp what we asked for
+        let negotiated_output_type = unsafe { transform.GetOutputCurrentType(output_stream_id)? };
 
         let should_stop = Arc::new(AtomicBool::new(false));
+        let frame_counts = Arc::new(FrameCounts::default());
         let inner = VideoEncoderInner {
             _d3d_device: d3d_device,
             _media_device_manager: media_device_manager,
             _device_manager_reset_token: device_manager_reset_token,
 
             transform,
+            codec_api,
             event_generator,
             input_stream_id,
             output_stream_id,
 
             sample_requested_callback: None,
             sample_rendered_callback: None,
+            output_type_changed_callback: None,
+            stream_ended_callback: None,
 
             should_stop: should_stop.clone(),
+            high_priority: false,
+            frame_counts: frame_counts.clone(),
         };
 
         Ok(Self {
             inner: Some(inner),
             output_type,
+            negotiated_output_type,
+            input_format,
             started: AtomicBool::new(false),
             should_stop,
             encoder_thread_handle: None,
+            encoder_thread_done: None,
+            frame_counts,
         })
     }
 
@@ -262,12 +472,20 @@ impl VideoEncoder {
             }
 
             // Start a seperate thread to drive the transform
+            let (done_sender, done_receiver) = channel();
+            self.encoder_thread_done = Some(done_receiver);
             self.encoder_thread_handle = Some(std::thread::spawn(move || -> Result<()> {
                 unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL)? }
+                let _priority_boost = if inner.high_priority {
+                    Some(ThreadPriorityBoost::new())
+                } else {
+                    None
+                };
                 let result = inner.encode();
                 if result.is_err() {
-                    println!("Recording stopped unexpectedly!");
+                    error!("Recording stopped unexpectedly!");
                 }
+                let _ = done_sender.send(());
                 result
             }));
             result = true;
@@ -276,7 +494,8 @@ impl VideoEncoder {
     }
 
     pub fn stop(&mut self) -> Result<()> {
-        if self.started.load(Ordering::SeqCst) {
+        // Nothing to do if stop_with_timeout already took care of it
+        if self.started.load(Ordering::SeqCst) && self.encoder_thread_handle.is_some() {
             assert!(self
                 .should_stop
                 .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
@@ -286,6 +505,31 @@ impl VideoEncoder {
         Ok(())
     }
 
+    // Like stop, but gives up on the encoder if it hasn't drained within the
+    // timeout. Returns how many frames were still in the encoder in that
+    // case. The encoder thread is left to finish (or hang) on its own.
+    pub fn stop_with_timeout(&mut self, timeout: Duration) -> Result<Option<u64>> {
+        if self.started.load(Ordering::SeqCst) && self.encoder_thread_handle.is_some() {
+            self.should_stop.store(true, Ordering::SeqCst);
+            let done = self.encoder_thread_done.as_ref().unwrap();
+            if done.recv_timeout(timeout).is_err() {
+                self.encoder_thread_handle.take();
+                return Ok(Some(self.frame_counts.pending()));
+            }
+            self.wait_for_completion()?;
+        }
+        Ok(None)
+    }
+
+    // Waits for the encoder to run out of samples and drain on its own,
+    // without asking it to stop.
+    pub fn wait_for_end(&mut self) -> Result<()> {
+        if self.encoder_thread_handle.is_some() {
+            self.wait_for_completion()?;
+        }
+        Ok(())
+    }
+
     fn wait_for_completion(&mut self) -> Result<()> {
         let handle = self.encoder_thread_handle.take().unwrap();
         handle.join().unwrap()
@@ -309,9 +553,174 @@ impl VideoEncoder {
         self.inner.as_mut().unwrap().sample_rendered_callback = Some(Box::new(callback));
     }
 
+    // Called on the encoder thread when the encoder switches to a new output
+    // type mid stream, before any samples of the new type are rendered.
+    pub fn set_output_type_changed_callback<
+        F: 'static + Send + FnMut(&IMFMediaType) -> Result<()>,
+    >(
+        &mut self,
+        callback: F,
+    ) {
+        self.inner.as_mut().unwrap().output_type_changed_callback = Some(Box::new(callback));
+    }
+
+    pub fn set_stream_ended_callback<F: 'static + Send + FnMut() -> Result<()>>(
+        &mut self,
+        callback: F,
+    ) {
+        self.inner.as_mut().unwrap().stream_ended_callback = Some(Box::new(callback));
+    }
+
+    // Runs the encoder thread at time critical priority. This can starve
+    // other work on the machine, so it's off unless asked for.
+    pub fn set_high_priority(&mut self, high_priority: bool) {
+        self.inner.as_mut().unwrap().high_priority = high_priority;
+    }
+
     pub fn output_type(&self) -> &IMFMediaType {
         &self.output_type
     }
+
+    // Returns false if the encoder doesn't support the setting.
+    pub fn set_quality_vs_speed(&self, value: u32) -> bool {
+        self.set_codec_value(&CODECAPI_AVEncCommonQualityVsSpeed, value)
+    }
+
+    fn set_codec_value(&self, api: &GUID, value: u32) -> bool {
+        self.inner
+            .as_ref()
+            .and_then(|inner| inner.codec_api.as_ref())
+            .map_or(false, |codec_api| set_codec_value(codec_api, api, value))
+    }
+
+    // Has to be asked before the encoder is started.
+    pub fn supports_forced_keyframes(&self) -> bool {
+        self.inner
+            .as_ref()
+            .map_or(false, |inner| inner.codec_api.is_some())
+    }
+
+    // How many encoded samples have come out of the encoder so far.
+    pub fn frames_encoded(&self) -> u64 {
+        self.frame_counts.output.load(Ordering::SeqCst)
+    }
+
+    // The format input samples have to be in, which follows from both the
+    // chroma subsampling and the input formats the encoder was given.
+    pub fn input_format(&self) -> DXGI_FORMAT {
+        self.input_format
+    }
+
+    // The output type the encoder settled on once the input type was set,
+    // which isn't necessarily what output_type asked for.
+    pub fn negotiated_output_type(&self) -> &IMFMediaType {
+        &self.negotiated_output_type
+    }
+}
+
+// Turns on the encoder's low latency mode and turns off B-frames, which
+// would otherwise hold frames back until a later one arrives. Has to happen
+// before the types are set. Returns false if the encoder turned any of it
+// down.
+fn set_low_latency(codec_api: &ICodecAPI) -> bool {
+    unsafe {
+        let mut low_latency = VARIANT::default();
+        (*low_latency.Anonymous.Anonymous).vt = VT_BOOL;
+        (*low_latency.Anonymous.Anonymous).Anonymous.boolVal = VARIANT_TRUE;
+        let mut b_frames = VARIANT::default();
+        (*b_frames.Anonymous.Anonymous).vt = VT_UI4;
+        (*b_frames.Anonymous.Anonymous).Anonymous.ulVal = 0;
+        let low_latency = codec_api.SetValue(&CODECAPI_AVLowLatencyMode, &low_latency);
+        let b_frames = codec_api.SetValue(&CODECAPI_AVEncMPVDefaultBPictureCount, &b_frames);
+        low_latency.is_ok() && b_frames.is_ok()
+    }
+}
+
+// Returns false if the encoder doesn't support the setting.
+fn set_codec_value(codec_api: &ICodecAPI, api: &GUID, value: u32) -> bool {
+    unsafe {
+        if codec_api.IsSupported(api).is_err() {
+            return false;
+        }
+        let mut variant = VARIANT::default();
+        (*variant.Anonymous.Anonymous).vt = VT_UI4;
+        (*variant.Anonymous.Anonymous).Anonymous.ulVal = value;
+        codec_api.SetValue(api, &variant).is_ok()
+    }
+}
+
+// Looks for an input type of the given subtype that the encoder accepts
+// with its current output type.
+fn find_input_type(
+    transform: &IMFTransform,
+    input_stream_id: u32,
+    subtype: &GUID,
+    input_resolution: SizeInt32,
+) -> Result<Option<IMFMediaType>> {
+    unsafe {
+        let mut count = 0;
+        loop {
+            let result = transform.GetInputAvailableType(input_stream_id, count);
+            if let Err(error) = &result {
+                if error.code() == MF_E_NO_MORE_TYPES {
+                    break Ok(None);
+                }
+            }
+
+            let input_type = result?;
+            let attributes: IMFAttributes = input_type.cast()?;
+            input_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+            input_type.SetGUID(&MF_MT_SUBTYPE, subtype)?;
+            MFSetAttributeSize(
+                &attributes,
+                &MF_MT_FRAME_SIZE,
+                input_resolution.Width as u32,
+                input_resolution.Height as u32,
+            )?;
+            MFSetAttributeRatio(&attributes, &MF_MT_FRAME_RATE, 60, 1)?;
+            let result = transform.SetInputType(
+                input_stream_id,
+                &input_type,
+                MFT_SET_TYPE_TEST_ONLY.0 as u32,
+            );
+            if let Err(error) = &result {
+                if error.code() == MF_E_INVALIDMEDIATYPE {
+                    count += 1;
+                    continue;
+                }
+            }
+            result?;
+            break Ok(Some(input_type));
+        }
+    }
+}
+
+// Raises the priority of the calling thread until dropped. The thread is
+// also registered with MMCSS as a capture task, if that fails we still keep
+// the raised priority.
+struct ThreadPriorityBoost {
+    mmcss_handle: Option<HANDLE>,
+}
+
+impl ThreadPriorityBoost {
+    fn new() -> Self {
+        unsafe {
+            let _ = SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL);
+            let mut task_index = 0;
+            let mmcss_handle = AvSetMmThreadCharacteristicsW(w!("Capture"), &mut task_index).ok();
+            Self { mmcss_handle }
+        }
+    }
+}
+
+impl Drop for ThreadPriorityBoost {
+    fn drop(&mut self) {
+        if let Some(mmcss_handle) = self.mmcss_handle.take() {
+            unsafe {
+                let _ = AvRevertMmThreadCharacteristics(mmcss_handle);
+            }
+        }
+    }
 }
 
 unsafe impl Send for VideoEncoderInner {}
@@ -325,6 +734,7 @@ unsafe impl Send for VideoEncoderInner {}
 //        = note: `#[warn(non_upper_case_globals)]` on by default
 const MEDIA_ENGINE_TRANFORM_NEED_INPUT: MF_EVENT_TYPE = METransformNeedInput;
 const MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT: MF_EVENT_TYPE = METransformHaveOutput;
+const MEDIA_ENGINE_TRANFORM_DRAIN_COMPLETE: MF_EVENT_TYPE = METransformDrainComplete;
 impl VideoEncoderInner {
     fn encode(&mut self) -> Result<()> {
         unsafe {
@@ -334,12 +744,33 @@ impl VideoEncoderInner {
                 .ProcessMessage(MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, 0)?;
             self.transform
                 .ProcessMessage(MFT_MESSAGE_NOTIFY_START_OF_STREAM, 0)?;
+        }
+
+        if let Some(event_generator) = self.event_generator.clone() {
+            self.encode_async(&event_generator)?;
+        } else {
+            self.encode_sync()?;
+        }
 
+        unsafe {
+            self.transform
+                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_STREAMING, 0)?;
+            self.transform
+                .ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0)?;
+        }
+        if let Some(callback) = self.stream_ended_callback.as_mut() {
+            callback()?;
+        }
+        Ok(())
+    }
+
+    // Asynchronous MFTs tell us when they want input and when they have
+    // output, so they can work on several frames at once.
+    fn encode_async(&mut self, event_generator: &IMFMediaEventGenerator) -> Result<()> {
+        unsafe {
             let mut should_exit = false;
             while !should_exit {
-                let event = self
-                    .event_generator
-                    .GetEvent(MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS(0))?;
+                let event = event_generator.GetEvent(MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS(0))?;
 
                 let event_type = MF_EVENT_TYPE(event.GetType()? as i32);
                 match event_type {
@@ -347,7 +778,7 @@ impl VideoEncoderInner {
                         should_exit = self.on_transform_input_requested()?;
                     }
                     MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT => {
-                        self.on_transform_output_ready()?;
+                        self.process_output()?;
                     }
                     _ => {
                         panic!("Unknown media event type: {}", event_type.0);
@@ -357,50 +788,167 @@ impl VideoEncoderInner {
 
             self.transform
                 .ProcessMessage(MFT_MESSAGE_NOTIFY_END_OF_STREAM, 0)?;
+
+            // Drain the encoder so that every frame we've given it ends up
+            // in the output, not just the ones it happened to be done with.
             self.transform
-                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_STREAMING, 0)?;
+                .ProcessMessage(MFT_MESSAGE_COMMAND_DRAIN, 0)?;
+            loop {
+                let event = event_generator.GetEvent(MEDIA_EVENT_GENERATOR_GET_EVENT_FLAGS(0))?;
+
+                let event_type = MF_EVENT_TYPE(event.GetType()? as i32);
+                match event_type {
+                    MEDIA_ENGINE_TRANFORM_HAVE_OUTPUT => {
+                        self.process_output()?;
+                    }
+                    MEDIA_ENGINE_TRANFORM_DRAIN_COMPLETE => break,
+                    // We don't have any more input to give
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Synchronous MFTs (mostly software encoders) get one frame at a time,
+    // and then give back whatever output they have for it.
+    fn encode_sync(&mut self) -> Result<()> {
+        while !self.on_transform_input_requested()? {
+            while self.process_output()? {}
+        }
+
+        unsafe {
             self.transform
-                .ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0)?;
+                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_OF_STREAM, 0)?;
+            self.transform
+                .ProcessMessage(MFT_MESSAGE_COMMAND_DRAIN, 0)?;
         }
+        while self.process_output()? {}
         Ok(())
     }
 
     fn on_transform_input_requested(&mut self) -> Result<bool> {
         let mut should_exit = true;
         if !self.should_stop.load(Ordering::SeqCst) {
-            if let Some(sample) = self.sample_requested_callback.as_mut().unwrap()()? {
+            if let Some(mut sample) = self.sample_requested_callback.as_mut().unwrap()()? {
                 let input_buffer = unsafe {
                     MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &sample.texture, 0, false)?
                 };
-                let mf_sample = unsafe { MFCreateSample()? };
+                if sample.force_keyframe {
+                    self.force_keyframe()?;
+                }
+                let mf_sample = if let Some(callback) = sample.release_callback.take() {
+                    let tracked_sample = unsafe { MFCreateTrackedSample()? };
+                    let callback: IMFAsyncCallback = SampleReleasedCallback {
+                        callback: Mutex::new(Some(callback)),
+                    }
+                    .into();
+                    unsafe { tracked_sample.SetAllocator(&callback, None)? };
+                    tracked_sample.cast()?
+                } else {
+                    unsafe { MFCreateSample()? }
+                };
                 unsafe {
                     mf_sample.AddBuffer(&input_buffer)?;
                     mf_sample.SetSampleTime(sample.timestamp.Duration)?;
                     self.transform
                         .ProcessInput(self.input_stream_id, &mf_sample, 0)?;
                 };
+                self.frame_counts.input.fetch_add(1, Ordering::SeqCst);
                 should_exit = false;
             }
         }
         Ok(should_exit)
     }
 
-    fn on_transform_output_ready(&mut self) -> Result<()> {
+    fn force_keyframe(&self) -> Result<()> {
+        if let Some(codec_api) = self.codec_api.as_ref() {
+            unsafe {
+                let mut value = VARIANT::default();
+                (*value.Anonymous.Anonymous).vt = VT_UI4;
+                (*value.Anonymous.Anonymous).Anonymous.ulVal = 1;
+                codec_api.SetValue(&CODECAPI_AVEncVideoForceKeyFrame, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false once the encoder needs more input before it can produce
+    // anything else.
+    fn process_output(&mut self) -> Result<bool> {
         let mut status = 0;
         let output_buffer = MFT_OUTPUT_DATA_BUFFER {
             dwStreamID: self.output_stream_id,
+            pSample: ManuallyDrop::new(self.allocate_output_sample()?),
             ..Default::default()
         };
 
         let sample = unsafe {
             let mut output_buffers = [output_buffer];
-            self.transform
-                .ProcessOutput(0, &mut output_buffers, &mut status)?;
-            output_buffers[0].pSample.as_ref().unwrap().clone()
+            let result = self
+                .transform
+                .ProcessOutput(0, &mut output_buffers, &mut status);
+            let sample = ManuallyDrop::take(&mut output_buffers[0].pSample);
+            let _events = ManuallyDrop::take(&mut output_buffers[0].pEvents);
+            match result {
+                Ok(()) => {}
+                Err(error) if error.code() == MF_E_TRANSFORM_NEED_MORE_INPUT => {
+                    return Ok(false);
+                }
+                // Some hardware encoders renegotiate their output type mid
+                // stream. The sample comes once we've accepted the new type,
+                // with the next output event for asynchronous MFTs.
+                Err(error) if error.code() == MF_E_TRANSFORM_STREAM_CHANGE => {
+                    self.on_output_type_changed()?;
+                    return Ok(true);
+                }
+                Err(error) => return Err(error),
+            }
+            sample.unwrap()
         };
 
+        self.frame_counts.output.fetch_add(1, Ordering::SeqCst);
         let output_sample = VideoEncoderOutputSample { sample };
         self.sample_rendered_callback.as_mut().unwrap()(output_sample)?;
+        Ok(true)
+    }
+
+    // Hardware encoders allocate their own samples, software ones usually
+    // need one from us, sized for the current output type.
+    fn allocate_output_sample(&self) -> Result<Option<IMFSample>> {
+        unsafe {
+            let stream_info = self.transform.GetOutputStreamInfo(self.output_stream_id)?;
+            let provides_samples = (MFT_OUTPUT_STREAM_PROVIDES_SAMPLES.0
+                | MFT_OUTPUT_STREAM_CAN_PROVIDE_SAMPLES.0)
+                as u32;
+            if (stream_info.dwFlags & provides_samples) != 0 {
+                return Ok(None);
+            }
+            let buffer = MFCreateMemoryBuffer(stream_info.cbSize)?;
+            let sample = MFCreateSample()?;
+            sample.AddBuffer(&buffer)?;
+            Ok(Some(sample))
+        }
+    }
+
+    fn on_output_type_changed(&mut self) -> Result<()> {
+        let output_type = unsafe {
+            let output_type = self
+                .transform
+                .GetOutputAvailableType(self.output_stream_id, 0)?;
+            self.transform
+                .SetOutputType(self.output_stream_id, &output_type, 0)?;
+            output_type
+        };
+        let frame_size = unsafe { output_type.GetUINT64(&MF_MT_FRAME_SIZE)? };
+        info!(
+            "The encoder changed its output type mid stream (now {}x{}), renegotiating...",
+            frame_size >> 32,
+            frame_size as u32
+        );
+        if let Some(callback) = self.output_type_changed_callback.as_mut() {
+            callback(&output_type)?;
+        }
         Ok(())
     }
 }
@@ -1,29 +1,39 @@
 use windows::{
     core::{ComInterface, Result},
     Win32::Media::MediaFoundation::{
-        IMFActivate, IMFTransform, MFMediaType_Video, MFT_FRIENDLY_NAME_Attribute,
-        MFVideoFormat_H264, MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG_HARDWARE,
-        MFT_ENUM_FLAG_SORTANDFILTER, MFT_ENUM_FLAG_TRANSCODE_ONLY, MFT_REGISTER_TYPE_INFO,
+        IMFActivate, IMFTransform, MFMediaType_Video, MFT_ENUM_HARDWARE_URL_Attribute,
+        MFT_ENUM_HARDWARE_VENDOR_ID_Attribute, MFT_FRIENDLY_NAME_Attribute, MFVideoFormat_H264,
+        MFT_CATEGORY_VIDEO_ENCODER, MFT_REGISTER_TYPE_INFO,
     },
 };
 
-use crate::media::{enumerate_mfts, get_string_attribute};
+use crate::media::{enumerate_mfts, get_string_attribute, EnumOptions};
 
 #[derive(Clone)]
 pub struct VideoEncoderDevice {
     source: IMFActivate,
     display_name: String,
+    vendor_id: Option<String>,
+    hardware: bool,
 }
 
 impl VideoEncoderDevice {
     pub fn enumerate() -> Result<Vec<VideoEncoderDevice>> {
+        Self::enumerate_with_options(&EnumOptions::default())
+    }
+
+    pub fn enumerate_software() -> Result<Vec<VideoEncoderDevice>> {
+        Self::enumerate_with_options(&EnumOptions::software())
+    }
+
+    pub fn enumerate_with_options(options: &EnumOptions) -> Result<Vec<VideoEncoderDevice>> {
         let output_info = MFT_REGISTER_TYPE_INFO {
             guidMajorType: MFMediaType_Video,
             guidSubtype: MFVideoFormat_H264,
         };
         let encoders = enumerate_mfts(
             &MFT_CATEGORY_VIDEO_ENCODER,
-            MFT_ENUM_FLAG_HARDWARE | MFT_ENUM_FLAG_TRANSCODE_ONLY | MFT_ENUM_FLAG_SORTANDFILTER,
+            options,
             None,
             Some(&output_info),
         )?;
@@ -36,9 +46,16 @@ impl VideoEncoderDevice {
             } else {
                 "Unknown".to_owned()
             };
+            let vendor_id =
+                get_string_attribute(&encoder.cast()?, &MFT_ENUM_HARDWARE_VENDOR_ID_Attribute)?;
+            // Only hardware MFTs have a hardware URL
+            let hardware =
+                get_string_attribute(&encoder.cast()?, &MFT_ENUM_HARDWARE_URL_Attribute)?.is_some();
             let encoder_device = VideoEncoderDevice {
                 source: encoder,
                 display_name,
+                vendor_id,
+                hardware,
             };
             encoder_devices.push(encoder_device);
         }
@@ -49,6 +66,23 @@ impl VideoEncoderDevice {
         &self.display_name
     }
 
+    // The PCI vendor of the hardware encoder, e.g. "VEN_10DE".
+    pub fn vendor_id(&self) -> Option<&str> {
+        self.vendor_id.as_deref()
+    }
+
+    pub fn is_hardware(&self) -> bool {
+        self.hardware
+    }
+
+    pub fn kind(&self) -> &'static str {
+        if self.hardware {
+            "hardware"
+        } else {
+            "software"
+        }
+    }
+
     pub fn create_transform(&self) -> Result<IMFTransform> {
         unsafe { self.source.ActivateObject() }
     }
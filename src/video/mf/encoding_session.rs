@@ -1,132 +1,867 @@
-use std::sync::Arc;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use log::{debug, info, warn};
 use windows::{
-    core::{Result, HSTRING},
+    core::{Error, Result, GUID, HSTRING},
     Foundation::TimeSpan,
     Graphics::{
-        Capture::{Direct3D11CaptureFrame, GraphicsCaptureItem, GraphicsCaptureSession},
-        SizeInt32,
+        Capture::GraphicsCaptureItem, DirectX::Direct3D11::IDirect3DSurface, RectInt32, SizeInt32,
     },
-    Storage::Streams::IRandomAccessStream,
     Win32::{
+        Foundation::E_INVALIDARG,
         Graphics::{
             Direct3D11::{
                 ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Texture2D,
-                D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX,
-                D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+                D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_TEXTURE2D_DESC,
+                D3D11_USAGE_DEFAULT,
             },
-            Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_NV12, DXGI_SAMPLE_DESC},
+            Dxgi::Common::{DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC},
+            Gdi::HMONITOR,
         },
         Media::MediaFoundation::{
-            IMFMediaType, IMFSample, IMFSinkWriter, MFCreateAttributes,
-            MFCreateMFByteStreamOnStreamEx, MFCreateSinkWriterFromURL,
+            IMFMediaType, IMFSample, IMFSinkWriter, MFCreateAttributes, MFCreateSinkWriterFromURL,
+            MFNominalRange_16_235, MFSampleExtension_CleanPoint, MFVideoFormat_H264,
+            MFVideoPrimaries_BT709, MFVideoTransFunc_709, MFVideoTransferMatrix_BT601,
+            MF_E_INVALIDMEDIATYPE, MF_MT_AVG_BITRATE, MF_MT_FRAME_RATE, MF_MT_MPEG2_PROFILE,
+            MF_MT_TRANSFER_FUNCTION, MF_MT_VIDEO_NOMINAL_RANGE, MF_MT_VIDEO_PRIMARIES,
+            MF_MT_YUV_MATRIX, MF_SINK_WRITER_DISABLE_THROTTLING, MF_SINK_WRITER_STATISTICS,
+            MF_TRANSCODE_CONTAINERTYPE, MF_VIDEO_PROCESSOR_ALGORITHM_DEFAULT,
+            MF_VIDEO_PROCESSOR_ALGORITHM_MRF_CRF_444,
         },
     },
 };
 
 use crate::{
-    capture::CaptureFrameGenerator,
+    capture::{
+        create_capture_item_for_window, get_capture_item_size, CaptureFrameGenerator,
+        CaptureRetargeter, CaptureStarter, DEFAULT_BUFFER_COUNT,
+    },
+    capture_bit_depth::CaptureBitDepth,
     d3d::get_d3d_interface_from_object,
+    duplication::get_duplicated_output_size,
+    foreground::{foreground_window, ForegroundWatcher},
+    pause::PauseState,
+    secure_desktop::SecureDesktopWatcher,
     video::{
-        encoding_session::{VideoEncoderSessionFactory, VideoEncodingSession},
-        util::ensure_even_size,
+        audio_codec::AudioCodec,
+        chroma::Chroma,
+        container::Container,
+        dedup::DuplicateFrameDetector,
+        encoder_input::EncoderInput,
+        encoding_session::{
+            require_capture_item, StopOutcome, VideoEncoderSessionFactory, VideoEncodingSession,
+            VideoEncodingSettings,
+        },
+        frame_sink::{CaptureLoop, FrameSink},
+        hud::HudMeter,
+        input_color_space::InputColorSpace,
+        keyframes::KeyframeScheduler,
+        ladder::Rendition,
+        latency::LOW_LATENCY_BUFFER_COUNT,
+        output_stream::OutputStream,
+        overlay::{HudRenderer, WatermarkRenderer},
+        pacing::{FramePacer, VsyncRetargeter},
+        preset::Preset,
+        progress::{ElapsedTime, FirstFrameCallback},
+        scaler::Scaler,
+        segments::{SegmentClock, SegmentSettings},
+        snapshot::{SnapshotRequester, SnapshotTaker},
+        util::{content_region, crop_region, ensure_even_size},
         CLEAR_COLOR,
     },
 };
 
 use super::{
-    encoder::{VideoEncoder, VideoEncoderInputSample},
+    audio_track::{AudioSource, AudioTimeline, AudioTrack},
+    encoder::{VideoEncoder, VideoEncoderInputSample, VideoEncoderSettings},
     encoder_device::VideoEncoderDevice,
-    processor::VideoProcessor,
+    feed::MFFrameFeedSession,
+    frame_stats::FrameStatsWriter,
+    mft_processor::MFTVideoProcessor,
+    processor::{TextureProcessor, VideoProcessor},
+    texture_pool::TexturePool,
+    write_queue::WriteQueue,
 };
 
 struct MFVideoEncodingSession {
     video_encoder: VideoEncoder,
-    capture_session: GraphicsCaptureSession,
+    capture_starter: CaptureStarter,
+    sample_writer: Arc<SegmentedSampleWriter>,
+    write_queue: Option<Arc<WriteQueue>>,
+    frame_stats: Option<Arc<FrameStatsWriter>>,
+    snapshot_requester: SnapshotRequester,
+    elapsed_time: ElapsedTime,
+    // None with desktop duplication, which can't be retargeted. The
+    // foreground watcher is only created when following the foreground
+    // window, once the recording starts.
+    retargeter: Option<CaptureRetargeter>,
+    vsync_retargeter: Option<VsyncRetargeter>,
+    follow_foreground: bool,
+    foreground_watcher: Option<ForegroundWatcher>,
+    // Only set when pausing on the secure desktop, from the start of the
+    // recording.
+    pause_state: Option<PauseState>,
+    secure_desktop_watcher: Option<SecureDesktopWatcher>,
+    renditions: Vec<RenditionEncoder>,
+    rendition_feeders: Arc<Mutex<Vec<RenditionFeeder>>>,
+}
+
+// How many frames a rendition's encoder can fall behind the capture before
+// it starts dropping them, so that a slow rendition can't hold up the rest.
+const RENDITION_QUEUE_LENGTH: usize = 4;
+
+// Segmented recordings always write through a queue (this long unless
+// --write-queue asks for another length), so that finalizing one segment and
+// opening the next happens on the queue's thread while the encoder carries
+// on: two seconds at 60fps.
+const SEGMENT_WRITE_QUEUE_LENGTH: usize = 120;
+// How much the sink writer can have queued before we warn about it.
+const SINK_WRITER_BACKLOG_WARNING: usize = 64 * 1024 * 1024;
+// How long the capture has to keep a new size before everything gets
+// rebuilt for it, so that dragging a window's border doesn't rebuild on
+// every frame.
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// The encoder side of an extra rendition. Its samples come from the
+// matching RenditionFeeder, and its stream ends when the feeder goes away.
+struct RenditionEncoder {
+    video_encoder: VideoEncoder,
     sample_writer: Arc<SampleWriter>,
 }
 
+// Scales each composed frame to a rendition's size and queues it up for
+// that rendition's encoder. Runs on the main encoder's thread as part of
+// sample generation.
+struct RenditionFeeder {
+    size: SizeInt32,
+    video_processor: Box<dyn TextureProcessor>,
+    texture_pool: TexturePool,
+    sender: SyncSender<VideoEncoderInputSample>,
+}
+// Only ever used by whichever thread is generating samples.
+unsafe impl Send for RenditionFeeder {}
+
 struct SampleGenerator {
     d3d_device: ID3D11Device,
     d3d_context: ID3D11DeviceContext,
 
-    video_processor: VideoProcessor,
+    video_processor: Box<dyn TextureProcessor>,
+    // What the encoder's input is copied into, see TexturePool.
+    texture_pool: TexturePool,
     compose_texture: ID3D11Texture2D,
     render_target_view: ID3D11RenderTargetView,
+    // The format of the captured frames, and so of the compose texture.
+    capture_format: DXGI_FORMAT,
+    input_color_space: Option<InputColorSpace>,
+    // The size of the compose texture and the frame pool. This follows the
+    // capture if it changes size, while output_size stays what the encoder
+    // was set up with.
+    input_size: SizeInt32,
+    output_size: SizeInt32,
+    // A size the capture changed to and the frame time it was first seen,
+    // see handle_content_size.
+    pending_size: Option<(SizeInt32, TimeSpan)>,
+    crop: Option<RectInt32>,
+    // What the encoder takes its input in, see VideoEncoder::input_format.
+    encoder_format: DXGI_FORMAT,
+    scaler: Scaler,
+    // Renditions always keep the content's aspect ratio.
+    preserve_aspect_ratio: bool,
+    dedup_threshold: Option<f32>,
+    dedup_max_duration: Duration,
+    duplicate_detector: Option<DuplicateFrameDetector>,
+    snapshot_taker: SnapshotTaker,
+    watermark_renderer: Option<WatermarkRenderer>,
+    hud_renderer: Option<(HudRenderer, HudMeter)>,
+    capture_pacer: Option<FramePacer>,
+    output_pacer: Option<FramePacer>,
 
-    frame_generator: CaptureFrameGenerator,
-
+    keyframe_scheduler: Option<KeyframeScheduler>,
+    // Lines keyframes up with the segment boundaries, measured from the
+    // first frame.
+    segment_scheduler: Option<KeyframeScheduler>,
+    speed: f64,
     seen_first_time_stamp: bool,
     first_timestamp: TimeSpan,
+    last_sample_time: Option<TimeSpan>,
+    // Shared with the session, which drops them if the main encoder can't
+    // be stopped, so that the renditions still get to the end of their
+    // streams.
+    rendition_feeders: Arc<Mutex<Vec<RenditionFeeder>>>,
+    // Shared with the audio track when it records a process. Audio can't be
+    // sped up, so the timestamps here are the samples' times as they are.
+    audio_timeline: Option<AudioTimeline>,
+    sample: Option<VideoEncoderInputSample>,
 }
 
-struct SampleWriter {
-    _stream: IRandomAccessStream,
+// How a SampleWriter writes its output, the same for every segment.
+#[derive(Copy, Clone)]
+pub(super) struct SampleWriterSettings {
+    pub variable_frame_rate: bool,
+    pub container: Container,
+    pub throttling: bool,
+    // Adds an audio track, which is silent without an audio source.
+    pub audio_codec: Option<AudioCodec>,
+}
+
+impl SampleWriterSettings {
+    pub fn new(settings: &VideoEncodingSettings, audio_codec: Option<AudioCodec>) -> Self {
+        Self {
+            variable_frame_rate: settings.variable_frame_rate,
+            container: settings.container,
+            throttling: settings.sink_writer_throttling,
+            audio_codec,
+        }
+    }
+}
+
+pub(super) struct SampleWriter {
+    _stream: OutputStream,
     sink_writer: IMFSinkWriter,
     sink_writer_stream_index: u32,
+
+    variable_frame_rate: bool,
+    pending_sample: Mutex<Option<IMFSample>>,
+    // Held for as long as a sample is being written or the file finalized,
+    // so that the two never overlap.
+    finalized: Mutex<bool>,
+    backlog_warned: AtomicBool,
+
+    first_frame_callback: Mutex<Option<FirstFrameCallback>>,
+    audio_track: Option<Mutex<AudioTrack>>,
+}
+
+// Writes the main output, finalizing it and moving on to a new segment on
+// the first keyframe past each boundary. Every segment gets a writer of its
+// own that's dropped once it's finalized, so nothing builds up over a long
+// recording. Without segments this is just the one SampleWriter.
+struct SegmentedSampleWriter {
+    state: Mutex<SegmentState>,
+    segments: Option<SegmentSettings>,
+
+    writer_settings: SampleWriterSettings,
+    audio_source: Option<AudioSource>,
+}
+
+struct SegmentState {
+    sample_writer: SampleWriter,
+    // What the next segment is opened with, the encoder can change it.
+    output_type: IMFMediaType,
+    index: u32,
+    clock: Option<SegmentClock>,
 }
 
 impl MFVideoEncodingSession {
     pub fn new(
         d3d_device: ID3D11Device,
-        item: GraphicsCaptureItem,
-        borderless: bool,
+        item: Option<GraphicsCaptureItem>,
         encoder_device: &VideoEncoderDevice,
-        resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
-        stream: IRandomAccessStream,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
+        renditions: Vec<(Rendition, OutputStream)>,
     ) -> Result<Self> {
-        let item_size = item.Size()?;
-        let input_size = ensure_even_size(item_size);
-        let output_size = ensure_even_size(resolution);
+        // Duplication captures the display's desktop, whatever the item is
+        let item_size = match settings.desktop_duplication {
+            Some(display_handle) => get_duplicated_output_size(display_handle)?,
+            None => get_capture_item_size(&require_capture_item(item.clone())?)?,
+        };
+        let content_size = if let Some(crop) = settings.crop {
+            if crop.X < 0
+                || crop.Y < 0
+                || crop.Width <= 0
+                || crop.Height <= 0
+                || crop.X + crop.Width > item_size.Width
+                || crop.Y + crop.Height > item_size.Height
+            {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "The crop region doesn't fit inside the capture!".into(),
+                ));
+            }
+            SizeInt32 {
+                Width: crop.Width,
+                Height: crop.Height,
+            }
+        } else {
+            item_size
+        };
+        let input_size = ensure_even_size(content_size);
+        let output_size = ensure_even_size(settings.output_size(content_size));
 
+        // The MFT scalers only produce NV12
+        let chroma = if settings.chroma != Chroma::Yuv420 && settings.scaler != Scaler::Auto {
+            warn!(
+                "The {} scaler only supports 420 chroma subsampling, falling back to 420...",
+                settings.scaler
+            );
+            Chroma::Yuv420
+        } else {
+            settings.chroma
+        };
         let mut video_encoder = VideoEncoder::new(
             encoder_device,
             d3d_device.clone(),
-            output_size,
-            output_size,
-            bit_rate,
-            frame_rate,
+            VideoEncoderSettings {
+                input_resolution: output_size,
+                output_resolution: output_size,
+                bit_rate: settings.bit_rate.resolve(
+                    output_size,
+                    settings.playback_frame_rate(),
+                    &MFVideoFormat_H264,
+                ),
+                frame_rate: settings.playback_frame_rate(),
+                pixel_aspect_ratio: settings.pixel_aspect_ratio,
+                chroma,
+                input_formats: &encoder_inputs(settings, settings.capture_bit_depth.dxgi_format()),
+                low_latency: settings.target_latency.is_some(),
+                worker_threads: settings.encoder_threads,
+            },
         )?;
         let output_type = video_encoder.output_type().clone();
+        check_output_type(
+            &output_type,
+            video_encoder.negotiated_output_type(),
+            settings.strict,
+        )?;
+        if settings.scaler == Scaler::Auto {
+            set_color_attributes(&output_type)?;
+        }
+        video_encoder.set_high_priority(settings.high_priority);
+        apply_preset(&video_encoder, settings.preset);
+        if (settings.keyframe_period.is_some() || settings.segments.is_some())
+            && !video_encoder.supports_forced_keyframes()
+        {
+            warn!("The encoder doesn't support forcing keyframes, they'll only be placed where the encoder wants...");
+        }
+
+        let mut rendition_encoders = Vec::new();
+        let mut rendition_feeders = Vec::new();
+        for (rendition, rendition_stream) in renditions {
+            let (rendition_encoder, rendition_feeder) = create_rendition(
+                &d3d_device,
+                encoder_device,
+                settings,
+                input_size,
+                rendition,
+                rendition_stream,
+            )?;
+            rendition_encoders.push(rendition_encoder);
+            rendition_feeders.push(rendition_feeder);
+        }
 
-        let mut sample_generator = SampleGenerator::new(d3d_device, item, input_size, output_size)?;
-        let capture_session = sample_generator.capture_session().clone();
-        if borderless {
+        let snapshot_requester = SnapshotRequester::default();
+        let mut sample_generator = SampleGenerator::new(
+            d3d_device.clone(),
+            input_size,
+            output_size,
+            video_encoder.input_format(),
+            settings,
+            snapshot_requester.clone(),
+        )?;
+        let rendition_feeders = Arc::new(Mutex::new(rendition_feeders));
+        sample_generator.set_rendition_feeders(rendition_feeders.clone());
+        let audio_source = settings
+            .audio_process
+            .map(|process_id| AudioSource::new(process_id, settings.pause_state.clone()))
+            .transpose()?;
+        if let Some(audio_source) = audio_source.as_ref() {
+            sample_generator.set_audio_timeline(audio_source.timeline().clone());
+        }
+        // A crop still needs the whole item captured
+        let capture_size = if settings.crop.is_some() {
+            item_size
+        } else {
+            input_size
+        };
+        let frame_generator = create_frame_generator(d3d_device, item, capture_size, settings)?;
+        let mut capture_loop =
+            CaptureLoop::new(frame_generator, capture_size, sample_generator, settings)?;
+        let frame_generator = capture_loop.frame_generator();
+        let capture_starter = frame_generator.starter();
+        let retargeter = frame_generator.retargeter();
+        let vsync_retargeter = capture_loop.vsync_retargeter();
+        let elapsed_time = capture_loop.elapsed_time();
+        settings.cancellation_token.on_cancelled({
+            let stop_signal = frame_generator.stop_signal();
+            move || stop_signal.signal()
+        });
+        // Duplication never draws a border
+        if let Some(capture_session) = frame_generator.session().filter(|_| settings.borderless) {
             capture_session.SetIsBorderRequired(false)?;
         }
         video_encoder.set_sample_requested_callback(
-            move || -> Result<Option<VideoEncoderInputSample>> { sample_generator.generate() },
+            move || -> Result<Option<VideoEncoderInputSample>> {
+                Ok(if capture_loop.step()? {
+                    capture_loop.sink_mut().take_sample()
+                } else {
+                    None
+                })
+            },
         );
 
-        let sample_writer = Arc::new(SampleWriter::new(stream, &output_type)?);
+        let sample_writer = Arc::new(SegmentedSampleWriter::new(
+            stream,
+            &output_type,
+            settings,
+            audio_source,
+        )?);
+        let write_queue_length = settings.write_queue_length.or(settings
+            .segments
+            .as_ref()
+            .map(|_| SEGMENT_WRITE_QUEUE_LENGTH));
+        let write_queue = write_queue_length.map(|length| {
+            let sample_writer = sample_writer.clone();
+            let type_writer = sample_writer.clone();
+            Arc::new(WriteQueue::new(
+                length,
+                move |sample| sample_writer.write(sample),
+                move |output_type| type_writer.set_output_type(output_type),
+            ))
+        });
+        let frame_stats = settings
+            .stats_path
+            .as_ref()
+            .map(FrameStatsWriter::new)
+            .transpose()?
+            .map(Arc::new);
         video_encoder.set_sample_rendered_callback({
             let sample_writer = sample_writer.clone();
-            move |sample| -> Result<()> { sample_writer.write(sample.sample()) }
+            let write_queue = write_queue.clone();
+            let frame_stats = frame_stats.clone();
+            let live_stats = settings.live_stats.clone();
+            move |sample| -> Result<()> {
+                if let Some(frame_stats) = frame_stats.as_ref() {
+                    frame_stats.record(sample.sample())?;
+                }
+                if let Some(live_stats) = live_stats.as_ref() {
+                    live_stats.record_encoded(unsafe { sample.sample().GetTotalLength()? } as u64);
+                }
+                if let Some(write_queue) = write_queue.as_ref() {
+                    write_queue.push(sample.sample())
+                } else {
+                    sample_writer.write(sample.sample())
+                }
+            }
+        });
+        video_encoder.set_output_type_changed_callback({
+            let sample_writer = sample_writer.clone();
+            let write_queue = write_queue.clone();
+            move |output_type| -> Result<()> {
+                if let Some(write_queue) = write_queue.as_ref() {
+                    write_queue.push_output_type(output_type)
+                } else {
+                    sample_writer.set_output_type(output_type)
+                }
+            }
+        });
+        // If the capture ends on its own (e.g. the recording was cancelled),
+        // finalize the file as soon as the encoder is done with it.
+        video_encoder.set_stream_ended_callback({
+            let sample_writer = sample_writer.clone();
+            let write_queue = write_queue.clone();
+            let frame_stats = frame_stats.clone();
+            move || -> Result<()> {
+                if let Some(write_queue) = write_queue.as_ref() {
+                    write_queue.flush()?;
+                }
+                if let Some(frame_stats) = frame_stats.as_ref() {
+                    frame_stats.flush()?;
+                }
+                sample_writer.stop()
+            }
         });
 
         Ok(Self {
             video_encoder,
-            capture_session,
+            capture_starter,
             sample_writer,
+            write_queue,
+            frame_stats,
+            snapshot_requester,
+            elapsed_time,
+            retargeter,
+            vsync_retargeter,
+            follow_foreground: settings.follow_foreground,
+            foreground_watcher: None,
+            pause_state: settings
+                .pause_on_secure_desktop
+                .then(|| settings.pause_state.clone()),
+            secure_desktop_watcher: None,
+            renditions: rendition_encoders,
+            rendition_feeders,
         })
     }
 }
 
+impl MFVideoEncodingSession {
+    // Stops the main encoder and then the renditions, all within the one
+    // deadline if there is one. Every output gets finalized whatever
+    // happened to the others, the first error is the one returned.
+    fn shut_down(&mut self, deadline: Option<Instant>) -> Result<StopOutcome> {
+        let remaining = |deadline: Instant| deadline.saturating_duration_since(Instant::now());
+        self.foreground_watcher.take();
+        self.secure_desktop_watcher.take();
+        let mut results = Vec::new();
+
+        let mut pending_frames = match deadline {
+            Some(deadline) => self.video_encoder.stop_with_timeout(remaining(deadline)),
+            None => self.video_encoder.stop().map(|_| None),
+        }
+        .unwrap_or_else(|error| {
+            results.push(Err(error));
+            None
+        });
+        // Normally the main encoder's thread has already dropped the feeders
+        // on its way out. If it's stuck, or failed, the renditions would
+        // otherwise wait on it forever.
+        self.rendition_feeders.lock().unwrap().clear();
+
+        // If the encoder is still running, whatever it produces from here on
+        // is ignored by the flushed queue and the finalized writer.
+        if let Some(write_queue) = self.write_queue.as_ref() {
+            results.push(write_queue.flush());
+        }
+        if let Some(frame_stats) = self.frame_stats.as_ref() {
+            results.push(frame_stats.flush());
+        }
+        results.push(self.sample_writer.stop());
+
+        for rendition in &mut self.renditions {
+            match deadline {
+                Some(deadline) => match rendition
+                    .video_encoder
+                    .stop_with_timeout(remaining(deadline))
+                {
+                    Ok(Some(rendition_pending_frames)) => {
+                        *pending_frames.get_or_insert(0) += rendition_pending_frames;
+                    }
+                    Ok(None) => {}
+                    Err(error) => results.push(Err(error)),
+                },
+                // Every rendition gets to the end of its queue and drains
+                None => results.push(rendition.video_encoder.wait_for_end()),
+            }
+            results.push(rendition.sample_writer.stop());
+        }
+
+        results.into_iter().collect::<Result<Vec<_>>>()?;
+        Ok(match pending_frames {
+            None => StopOutcome::Drained,
+            Some(pending_frames) => StopOutcome::PendingFramesDropped(pending_frames),
+        })
+    }
+}
+
+fn create_frame_generator(
+    d3d_device: ID3D11Device,
+    item: Option<GraphicsCaptureItem>,
+    capture_size: SizeInt32,
+    settings: &VideoEncodingSettings,
+) -> Result<CaptureFrameGenerator> {
+    if let Some(display_handle) = settings.desktop_duplication {
+        return CaptureFrameGenerator::for_output_duplication(
+            d3d_device,
+            display_handle,
+            settings.desktop,
+        );
+    }
+    let buffer_count = if settings.target_latency.is_some() {
+        LOW_LATENCY_BUFFER_COUNT
+    } else {
+        DEFAULT_BUFFER_COUNT
+    };
+    CaptureFrameGenerator::with_pixel_format(
+        d3d_device,
+        require_capture_item(item)?,
+        capture_size,
+        buffer_count,
+        settings.frame_pool_mode,
+        settings.capture_bit_depth.pixel_format(),
+    )
+    .map_err(|error| {
+        if settings.capture_bit_depth != CaptureBitDepth::Eight {
+            Error::new(
+                error.code(),
+                format!(
+                    "Unable to capture at {} bits, this version of Windows may not support it! {}",
+                    settings.capture_bit_depth,
+                    error.message()
+                )
+                .into(),
+            )
+        } else {
+            error
+        }
+    })
+}
+
+fn create_rendition(
+    d3d_device: &ID3D11Device,
+    encoder_device: &VideoEncoderDevice,
+    settings: &VideoEncodingSettings,
+    input_size: SizeInt32,
+    rendition: Rendition,
+    stream: OutputStream,
+) -> Result<(RenditionEncoder, RenditionFeeder)> {
+    let size = ensure_even_size(rendition.size);
+    info!(
+        "Adding a {}x{} rendition at {} kbps...",
+        size.Width,
+        size.Height,
+        rendition.bit_rate / 1000
+    );
+    // Renditions always use 4:2:0, they're meant for playback everywhere
+    let mut video_encoder = VideoEncoder::new(
+        encoder_device,
+        d3d_device.clone(),
+        VideoEncoderSettings {
+            input_resolution: size,
+            output_resolution: size,
+            bit_rate: rendition.bit_rate,
+            frame_rate: settings.playback_frame_rate(),
+            pixel_aspect_ratio: settings.pixel_aspect_ratio,
+            chroma: Chroma::Yuv420,
+            input_formats: &[EncoderInput::Nv12],
+            low_latency: settings.target_latency.is_some(),
+            // The main encoder already warned about hardware encoders
+            worker_threads: settings
+                .encoder_threads
+                .filter(|_| !encoder_device.is_hardware()),
+        },
+    )?;
+    let output_type = video_encoder.output_type().clone();
+    check_output_type(
+        &output_type,
+        video_encoder.negotiated_output_type(),
+        settings.strict,
+    )?;
+    if settings.scaler == Scaler::Auto {
+        set_color_attributes(&output_type)?;
+    }
+    video_encoder.set_high_priority(settings.high_priority);
+    // The main encoder already warned if presets aren't supported
+    if let Some(preset) = settings.preset {
+        video_encoder.set_quality_vs_speed(preset.quality_vs_speed());
+    }
+
+    let (sender, receiver) = sync_channel(RENDITION_QUEUE_LENGTH);
+    video_encoder.set_sample_requested_callback(
+        move || -> Result<Option<VideoEncoderInputSample>> { Ok(receiver.recv().ok()) },
+    );
+    let sample_writer = Arc::new(SampleWriter::new(
+        stream,
+        &output_type,
+        SampleWriterSettings::new(settings, None),
+        None,
+        None,
+    )?);
+    video_encoder.set_sample_rendered_callback({
+        let sample_writer = sample_writer.clone();
+        move |sample| -> Result<()> { sample_writer.write(sample.sample()) }
+    });
+    video_encoder.set_output_type_changed_callback({
+        let sample_writer = sample_writer.clone();
+        move |output_type| -> Result<()> { sample_writer.set_output_type(output_type) }
+    });
+    video_encoder.set_stream_ended_callback({
+        let sample_writer = sample_writer.clone();
+        move || -> Result<()> { sample_writer.stop() }
+    });
+
+    let video_processor = create_texture_processor(
+        d3d_device,
+        TextureConversion {
+            scaler: settings.scaler,
+            input_format: settings.capture_bit_depth.dxgi_format(),
+            input_color_space: settings.input_color_space,
+            input_size,
+            output_format: Chroma::Yuv420.dxgi_format(),
+            output_size: size,
+            preserve_aspect_ratio: true,
+        },
+    )?;
+    Ok((
+        RenditionEncoder {
+            video_encoder,
+            sample_writer,
+        },
+        RenditionFeeder {
+            size,
+            video_processor,
+            texture_pool: TexturePool::new(d3d_device)?,
+            sender,
+        },
+    ))
+}
+
 impl VideoEncodingSession for MFVideoEncodingSession {
     fn start(&mut self) -> Result<()> {
+        // Renditions wait on the capture, so they have to be ready first
+        for rendition in &mut self.renditions {
+            rendition.sample_writer.start()?;
+            assert!(rendition.video_encoder.try_start()?);
+        }
         self.sample_writer.start()?;
-        self.capture_session.StartCapture()?;
+        self.capture_starter.start()?;
         assert!(self.video_encoder.try_start()?);
+        if let Some(retargeter) = self.retargeter.as_ref().filter(|_| self.follow_foreground) {
+            self.foreground_watcher = Some(follow_foreground(retargeter.clone())?);
+        }
+        if let Some(pause_state) = self.pause_state.as_ref() {
+            self.secure_desktop_watcher = Some(SecureDesktopWatcher::new(pause_state.clone()));
+        }
         Ok(())
     }
 
     fn stop(&mut self) -> Result<()> {
-        self.video_encoder.stop()?;
-        self.sample_writer.stop()?;
+        self.shut_down(None).map(|_| ())
+    }
+
+    fn stop_with_timeout(&mut self, timeout: Duration) -> Result<StopOutcome> {
+        self.shut_down(Some(Instant::now() + timeout))
+    }
+
+    fn elapsed_time(&self) -> ElapsedTime {
+        self.elapsed_time.clone()
+    }
+
+    fn frames_encoded(&self) -> Option<u64> {
+        Some(self.video_encoder.frames_encoded())
+    }
+
+    fn supports_pause(&self) -> bool {
+        true
+    }
+
+    fn snapshot(&mut self, path: PathBuf) -> Result<()> {
+        self.snapshot_requester.request(path);
         Ok(())
     }
+
+    fn retarget(
+        &mut self,
+        item: GraphicsCaptureItem,
+        display_handle: Option<HMONITOR>,
+    ) -> Result<()> {
+        if self.follow_foreground {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "The capture is already following the foreground window!".into(),
+            ));
+        }
+        let retargeter = self.retargeter.as_ref().ok_or_else(|| {
+            Error::new(
+                E_INVALIDARG,
+                "Desktop duplication can't switch to another display!".into(),
+            )
+        })?;
+        retargeter.retarget(item)?;
+        if let (Some(vsync_retargeter), Some(display_handle)) =
+            (self.vsync_retargeter.as_ref(), display_handle)
+        {
+            vsync_retargeter.retarget(display_handle);
+        }
+        Ok(())
+    }
+}
+
+// Tags the stream with the colors the video processor produces (see
+// VideoProcessor), so that players don't have to guess: sRGB content with
+// BT.709 primaries, converted with the BT.601 matrix into studio range. The
+// MFT scalers pick their own matrix, so their output is left untagged.
+pub(super) fn set_color_attributes(media_type: &IMFMediaType) -> Result<()> {
+    unsafe {
+        media_type.SetUINT32(&MF_MT_VIDEO_PRIMARIES, MFVideoPrimaries_BT709.0 as u32)?;
+        media_type.SetUINT32(&MF_MT_TRANSFER_FUNCTION, MFVideoTransFunc_709.0 as u32)?;
+        media_type.SetUINT32(&MF_MT_YUV_MATRIX, MFVideoTransferMatrix_BT601.0 as u32)?;
+        media_type.SetUINT32(&MF_MT_VIDEO_NOMINAL_RANGE, MFNominalRange_16_235.0 as u32)?;
+    }
+    Ok(())
+}
+
+pub(super) fn apply_preset(video_encoder: &VideoEncoder, preset: Option<Preset>) {
+    if let Some(preset) = preset {
+        if !video_encoder.set_quality_vs_speed(preset.quality_vs_speed()) {
+            warn!(
+                "The encoder doesn't support the {} preset, ignoring...",
+                preset
+            );
+        }
+    }
+}
+
+// Compares what we asked the encoder for with what it ended up using.
+// Attributes we didn't set aren't compared.
+pub(super) fn check_output_type(
+    requested: &IMFMediaType,
+    negotiated: &IMFMediaType,
+    strict: bool,
+) -> Result<()> {
+    let attributes: [(&str, &GUID, bool); 3] = [
+        ("bit rate", &MF_MT_AVG_BITRATE, false),
+        ("profile", &MF_MT_MPEG2_PROFILE, false),
+        ("frame rate", &MF_MT_FRAME_RATE, true),
+    ];
+    let mut mismatched = false;
+    for (name, key, is_ratio) in attributes {
+        let (requested_value, negotiated_value) = unsafe {
+            if is_ratio {
+                let requested_value = match requested.GetUINT64(key) {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+                (
+                    format_ratio(requested_value),
+                    negotiated.GetUINT64(key).ok().map(format_ratio),
+                )
+            } else {
+                let requested_value = match requested.GetUINT32(key) {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+                (
+                    requested_value.to_string(),
+                    negotiated
+                        .GetUINT32(key)
+                        .ok()
+                        .map(|value| value.to_string()),
+                )
+            }
+        };
+        let negotiated_value = negotiated_value.unwrap_or_else(|| "unset".to_owned());
+        if requested_value != negotiated_value {
+            warn!(
+                "The encoder changed the {} from {} to {}.",
+                name, requested_value, negotiated_value
+            );
+            mismatched = true;
+        }
+    }
+    if mismatched && strict {
+        return Err(Error::new(
+            MF_E_INVALIDMEDIATYPE,
+            "The encoder didn't accept the requested settings as is!".into(),
+        ));
+    }
+    Ok(())
+}
+
+fn format_ratio(value: u64) -> String {
+    let numerator = (value >> 32) as u32;
+    let denominator = value as u32;
+    if denominator == 1 {
+        numerator.to_string()
+    } else {
+        format!("{}/{}", numerator, denominator)
+    }
 }
 
 pub struct MFVideoEncodingSessionFactory {
@@ -143,21 +878,52 @@ impl VideoEncoderSessionFactory for MFVideoEncodingSessionFactory {
     fn create_session(
         &self,
         d3d_device: ID3D11Device,
-        item: GraphicsCaptureItem,
-        borderless: bool,
-        resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
-        stream: IRandomAccessStream,
+        item: Option<GraphicsCaptureItem>,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
     ) -> Result<Box<dyn VideoEncodingSession>> {
         let session = Box::new(MFVideoEncodingSession::new(
             d3d_device,
             item,
-            borderless,
             &self.encoder_device,
-            resolution,
-            bit_rate,
-            frame_rate,
+            settings,
+            stream,
+            Vec::new(),
+        )?);
+        Ok(session)
+    }
+
+    fn create_ladder_session(
+        &self,
+        d3d_device: ID3D11Device,
+        item: Option<GraphicsCaptureItem>,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
+        renditions: Vec<(Rendition, OutputStream)>,
+    ) -> Result<Box<dyn VideoEncodingSession>> {
+        let session = Box::new(MFVideoEncodingSession::new(
+            d3d_device,
+            item,
+            &self.encoder_device,
+            settings,
+            stream,
+            renditions,
+        )?);
+        Ok(session)
+    }
+
+    fn create_feed_session(
+        &self,
+        d3d_device: ID3D11Device,
+        input_size: SizeInt32,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
+    ) -> Result<Box<dyn VideoEncodingSession>> {
+        let session = Box::new(MFFrameFeedSession::new(
+            d3d_device,
+            &self.encoder_device,
+            input_size,
+            settings,
             stream,
         )?);
         Ok(session)
@@ -168,127 +934,253 @@ unsafe impl Send for SampleGenerator {}
 impl SampleGenerator {
     pub fn new(
         d3d_device: ID3D11Device,
-        item: GraphicsCaptureItem,
         input_size: SizeInt32,
         output_size: SizeInt32,
+        encoder_format: DXGI_FORMAT,
+        settings: &VideoEncodingSettings,
+        snapshot_requester: SnapshotRequester,
     ) -> Result<Self> {
         let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
 
-        let video_processor = VideoProcessor::new(
-            d3d_device.clone(),
-            DXGI_FORMAT_B8G8R8A8_UNORM,
+        let capture_format = settings.capture_bit_depth.dxgi_format();
+        let video_processor = create_texture_processor(
+            &d3d_device,
+            TextureConversion {
+                scaler: settings.scaler,
+                input_format: capture_format,
+                input_color_space: settings.input_color_space,
+                input_size,
+                output_format: encoder_format,
+                output_size,
+                preserve_aspect_ratio: settings.fit.preserves_aspect_ratio(),
+            },
+        )?;
+        let (compose_texture, render_target_view) =
+            create_compose_texture(&d3d_device, capture_format, input_size)?;
+        let duplicate_detector = create_duplicate_detector(
+            &d3d_device,
             input_size,
-            DXGI_FORMAT_NV12,
-            output_size,
+            settings.dedup_threshold,
+            settings.dedup_max_duration,
         )?;
 
-        let texture_desc = D3D11_TEXTURE2D_DESC {
-            Width: input_size.Width as u32,
-            Height: input_size.Height as u32,
-            ArraySize: 1,
-            MipLevels: 1,
-            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
-            SampleDesc: DXGI_SAMPLE_DESC {
-                Count: 1,
-                ..Default::default()
-            },
-            Usage: D3D11_USAGE_DEFAULT,
-            BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_SHADER_RESOURCE.0) as u32,
-            ..Default::default()
-        };
-        let compose_texture = unsafe {
-            let mut texture = None;
-            d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
-            texture.unwrap()
-        };
-        let render_target_view = unsafe {
-            let mut rtv = None;
-            d3d_device.CreateRenderTargetView(&compose_texture, None, Some(&mut rtv))?;
-            rtv.unwrap()
-        };
-
-        let frame_generator = CaptureFrameGenerator::new(d3d_device.clone(), item, input_size)?;
+        let snapshot_taker = SnapshotTaker::new(d3d_device.clone(), snapshot_requester)?;
+        let watermark_renderer = settings
+            .watermark
+            .as_ref()
+            .map(|watermark| WatermarkRenderer::new(&d3d_device, watermark))
+            .transpose()?;
+        let hud_renderer = settings
+            .live_stats
+            .as_ref()
+            .filter(|_| settings.hud_burn_in)
+            .map(|live_stats| -> Result<_> {
+                Ok((
+                    HudRenderer::new(&d3d_device)?,
+                    HudMeter::new(live_stats.clone()),
+                ))
+            })
+            .transpose()?;
+        // Vsync pacing and timelapses replace both pacers, see CaptureLoop
+        let paced = settings.vsync_pace.is_some() || settings.timelapse_interval.is_some();
 
         Ok(Self {
+            texture_pool: TexturePool::new(&d3d_device)?,
             d3d_device,
             d3d_context,
 
             video_processor,
             compose_texture,
             render_target_view,
+            capture_format,
+            input_color_space: settings.input_color_space,
+            input_size,
+            output_size,
+            pending_size: None,
+            crop: settings.crop,
+            encoder_format,
+            scaler: settings.scaler,
+            preserve_aspect_ratio: settings.fit.preserves_aspect_ratio(),
+            dedup_threshold: settings.dedup_threshold,
+            dedup_max_duration: settings.dedup_max_duration,
+            duplicate_detector,
+            snapshot_taker,
+            watermark_renderer,
+            hud_renderer,
+            capture_pacer: settings
+                .capture_frame_rate
+                .filter(|_| !paced)
+                .map(FramePacer::new),
+            output_pacer: settings
+                .capture_frame_rate
+                .filter(|_| !paced)
+                .map(|_| FramePacer::new(settings.frame_rate)),
 
-            frame_generator,
-
+            keyframe_scheduler: settings.keyframe_period.map(KeyframeScheduler::new),
+            segment_scheduler: settings
+                .segments
+                .as_ref()
+                .map(|segments| KeyframeScheduler::new(segments.duration)),
+            speed: settings.speed,
             seen_first_time_stamp: false,
+            last_sample_time: None,
             first_timestamp: TimeSpan::default(),
+            rendition_feeders: Arc::new(Mutex::new(Vec::new())),
+            audio_timeline: None,
+            sample: None,
         })
     }
 
-    pub fn capture_session(&self) -> &GraphicsCaptureSession {
-        self.frame_generator.session()
+    fn set_rendition_feeders(&mut self, rendition_feeders: Arc<Mutex<Vec<RenditionFeeder>>>) {
+        self.rendition_feeders = rendition_feeders;
     }
 
-    pub fn generate(&mut self) -> Result<Option<VideoEncoderInputSample>> {
-        if let Some(frame) = self.frame_generator.try_get_next_frame()? {
-            let result = self.generate_from_frame(&frame);
-            match result {
-                Ok(sample) => Ok(Some(sample)),
-                Err(error) => {
-                    eprintln!(
-                        "Error during input sample generation: {:?} - {}",
-                        error.code(),
-                        error.message()
-                    );
-                    self.stop_capture()?;
-                    Ok(None)
+    fn set_audio_timeline(&mut self, audio_timeline: AudioTimeline) {
+        self.audio_timeline = Some(audio_timeline);
+    }
+
+    // The sample for the frame CaptureLoop::step last wrote.
+    pub fn take_sample(&mut self) -> Option<VideoEncoderInputSample> {
+        self.sample.take()
+    }
+
+    // The encoder can't change size mid-stream, so when the capture does
+    // (e.g. the display's resolution changed) everything up to the video
+    // processor is rebuilt at the new size. The processor then scales the
+    // content to fit the original output, letterboxing if the aspect ratio
+    // changed. Until the new size has held for RESIZE_DEBOUNCE, frames are
+    // cropped or padded into the compose texture we already have.
+    fn handle_content_size(&mut self, content_size: SizeInt32, frame_time: TimeSpan) -> Result<()> {
+        // Minimized windows report 0x0, keep what we have until they're back.
+        // A crop stays where it is, whatever it no longer covers is black.
+        if content_size.Width <= 0 || content_size.Height <= 0 || self.crop.is_some() {
+            return Ok(());
+        }
+        let input_size = ensure_even_size(content_size);
+        if input_size == self.input_size {
+            self.pending_size = None;
+            return Ok(());
+        }
+        match self.pending_size {
+            Some((pending_size, since)) if pending_size == input_size => {
+                let held = Duration::from(TimeSpan {
+                    Duration: frame_time.Duration - since.Duration,
+                });
+                if held < RESIZE_DEBOUNCE {
+                    return Ok(());
                 }
             }
-        } else {
-            self.stop_capture()?;
-            Ok(None)
+            _ => {
+                self.pending_size = Some((input_size, frame_time));
+                return Ok(());
+            }
+        }
+        self.pending_size = None;
+        info!(
+            "The capture changed size from {}x{} to {}x{}, scaling it to fit the recording...",
+            self.input_size.Width, self.input_size.Height, input_size.Width, input_size.Height
+        );
+
+        self.video_processor = create_texture_processor(
+            &self.d3d_device,
+            TextureConversion {
+                scaler: self.scaler,
+                input_format: self.capture_format,
+                input_color_space: self.input_color_space,
+                input_size,
+                output_format: self.encoder_format,
+                output_size: self.output_size,
+                preserve_aspect_ratio: self.preserve_aspect_ratio,
+            },
+        )?;
+        for rendition_feeder in self.rendition_feeders.lock().unwrap().iter_mut() {
+            rendition_feeder.video_processor = create_texture_processor(
+                &self.d3d_device,
+                TextureConversion {
+                    scaler: self.scaler,
+                    input_format: self.capture_format,
+                    input_color_space: self.input_color_space,
+                    input_size,
+                    output_format: Chroma::Yuv420.dxgi_format(),
+                    output_size: rendition_feeder.size,
+                    preserve_aspect_ratio: true,
+                },
+            )?;
         }
+        let (compose_texture, render_target_view) =
+            create_compose_texture(&self.d3d_device, self.capture_format, input_size)?;
+        self.compose_texture = compose_texture;
+        self.render_target_view = render_target_view;
+        // Frames of different sizes never match, start comparing from scratch
+        self.duplicate_detector = create_duplicate_detector(
+            &self.d3d_device,
+            input_size,
+            self.dedup_threshold,
+            self.dedup_max_duration,
+        )?;
+        self.input_size = input_size;
+        Ok(())
     }
 
-    fn stop_capture(&mut self) -> Result<()> {
-        self.frame_generator.stop_capture()
+    // Each rendition scales the compose texture on its own, the same way the
+    // main output does.
+    fn feed_renditions(&mut self, timestamp: TimeSpan, force_keyframe: bool) -> Result<()> {
+        for rendition_feeder in self.rendition_feeders.lock().unwrap().iter_mut() {
+            rendition_feeder
+                .video_processor
+                .process_texture(&self.compose_texture)?;
+            let sample = rendition_feeder
+                .texture_pool
+                .create_sample(rendition_feeder.video_processor.output_texture(), timestamp)?;
+            let sample = if force_keyframe {
+                sample.with_forced_keyframe()
+            } else {
+                sample
+            };
+            match rendition_feeder.sender.try_send(sample) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => debug!(
+                    "The {}p rendition fell behind, dropping a frame...",
+                    rendition_feeder.size.Height
+                ),
+                // The rendition's encoder failed, which it has already reported
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+        Ok(())
     }
 
+    // The frame time is what CaptureLoop stamped the frame with, before
+    // any change of speed.
     fn generate_from_frame(
         &mut self,
-        frame: &Direct3D11CaptureFrame,
-    ) -> Result<VideoEncoderInputSample> {
-        let frame_time = frame.SystemRelativeTime()?;
-
-        if !self.seen_first_time_stamp {
-            self.first_timestamp = frame_time;
-            self.seen_first_time_stamp = true;
+        frame_texture: &ID3D11Texture2D,
+        content_size: SizeInt32,
+        frame_time: TimeSpan,
+    ) -> Result<Option<VideoEncoderInputSample>> {
+        // Drop frames that came in faster than the capture rate before doing
+        // any work on them.
+        if let Some(capture_pacer) = self.capture_pacer.as_mut() {
+            if !capture_pacer.should_take(frame_time) {
+                return Ok(None);
+            }
         }
 
-        let timestamp = TimeSpan {
-            Duration: frame_time.Duration - self.first_timestamp.Duration,
-        };
-        let content_size = frame.ContentSize()?;
-        let frame_texture: ID3D11Texture2D = get_d3d_interface_from_object(&frame.Surface()?)?;
-        let desc = unsafe {
-            let mut desc = D3D11_TEXTURE2D_DESC::default();
-            frame_texture.GetDesc(&mut desc);
-            desc
-        };
+        let timestamp = self.timestamp(frame_time);
+        self.handle_content_size(content_size, frame_time)?;
 
-        // In order to support window resizing, we need to only copy out the part of
-        // the buffer that contains the window. If the window is smaller than the buffer,
-        // then it's a straight forward copy using the ContentSize. If the window is larger,
-        // we need to clamp to the size of the buffer. For simplicity, we always clamp.
-        let width = content_size.Width.clamp(0, desc.Width as i32) as u32;
-        let height = content_size.Height.clamp(0, desc.Height as i32) as u32;
-
-        let region = D3D11_BOX {
-            left: 0,
-            right: width,
-            top: 0,
-            bottom: height,
-            back: 1,
-            front: 0,
+        // Only the part of the frame with content is copied, see content_region.
+        // A minimized window has nothing to show, so hold off on encoding
+        // until it's restored. The previous frame stays on screen meanwhile.
+        let region = if let Some(crop) = self.crop {
+            crop_region(crop, content_size, frame_texture, &self.compose_texture)
+        } else {
+            content_region(content_size, frame_texture, &self.compose_texture)
+        };
+        let region = if let Some(region) = region {
+            region
+        } else {
+            return Ok(None);
         };
 
         unsafe {
@@ -300,53 +1192,361 @@ impl SampleGenerator {
                 0,
                 0,
                 0,
-                &frame_texture,
+                frame_texture,
                 0,
                 Some(&region),
             );
+            // Drawn before anything looks at the frame, so that snapshots
+            // and renditions get it too.
+            if let Some(watermark_renderer) = self.watermark_renderer.as_mut() {
+                watermark_renderer.draw(&self.compose_texture)?;
+            }
+            if let Some((hud_renderer, hud_meter)) = self.hud_renderer.as_mut() {
+                hud_renderer.draw(&self.compose_texture, hud_meter.text())?;
+            }
+
+            // A snapshot that can't be taken shouldn't end the recording
+            if let Err(error) = self.snapshot_taker.on_frame(&self.compose_texture) {
+                warn!(
+                    "Unable to take a snapshot: {:?} - {}",
+                    error.code(),
+                    error.message()
+                );
+            }
+
+            // Identical frames are dropped so that the previous frame's duration
+            // gets extended instead, but only up to the configured maximum.
+            if let Some(duplicate_detector) = self.duplicate_detector.as_mut() {
+                let is_duplicate = duplicate_detector.is_duplicate(&self.compose_texture)?;
+                let stretched = if let Some(last_sample_time) = self.last_sample_time {
+                    Duration::from(TimeSpan {
+                        Duration: frame_time.Duration - last_sample_time.Duration,
+                    })
+                } else {
+                    Duration::ZERO
+                };
+                if is_duplicate && stretched < duplicate_detector.max_duration() {
+                    return Ok(None);
+                }
+            }
+
+            // Decimate down to the output frame rate. This has to happen before
+            // we commit to the frame so that the duplicate detector keeps
+            // comparing against what was actually encoded.
+            if let Some(output_pacer) = self.output_pacer.as_mut() {
+                if !output_pacer.should_take(frame_time) {
+                    return Ok(None);
+                }
+            }
+            if let Some(duplicate_detector) = self.duplicate_detector.as_mut() {
+                duplicate_detector.commit();
+            }
+            self.last_sample_time = Some(frame_time);
 
             // Process our back buffer
             self.video_processor
                 .process_texture(&self.compose_texture)?;
 
-            // Get our NV12 texture
-            let video_output_texture = self.video_processor.output_texture();
+            let sample = self.create_sample(timestamp, frame_time)?;
+            Ok(Some(sample))
+        }
+    }
 
-            // Make a copy for the sample
-            let desc = {
-                let mut desc = D3D11_TEXTURE2D_DESC::default();
-                video_output_texture.GetDesc(&mut desc);
-                desc
-            };
-            let sample_texture = {
-                let mut texture = None;
-                self.d3d_device
-                    .CreateTexture2D(&desc, None, Some(&mut texture))?;
-                texture.unwrap()
-            };
-            self.d3d_context
-                .CopyResource(&sample_texture, video_output_texture);
+    // CaptureLoop has already applied the timestamp base, all that's left
+    // is scaling the time since the first frame by the speed factor.
+    fn timestamp(&mut self, frame_time: TimeSpan) -> TimeSpan {
+        if !self.seen_first_time_stamp {
+            self.first_timestamp = frame_time;
+            self.seen_first_time_stamp = true;
+        }
+        let elapsed = frame_time.Duration - self.first_timestamp.Duration;
+        let elapsed = (elapsed as f64 * self.speed).round() as i64;
+        TimeSpan {
+            Duration: self.first_timestamp.Duration + elapsed,
+        }
+    }
+
+    // Wraps whatever the video processor last produced in a sample, and
+    // feeds the renditions the compose texture along with it.
+    fn create_sample(
+        &mut self,
+        timestamp: TimeSpan,
+        frame_time: TimeSpan,
+    ) -> Result<VideoEncoderInputSample> {
+        // Get our NV12 texture, and copy it into one for the sample
+        let sample = self
+            .texture_pool
+            .create_sample(self.video_processor.output_texture(), timestamp)?;
+
+        // The periods start at the first frame, whichever timestamp base the
+        // output uses.
+        let force_keyframe = self.keyframe_scheduler.as_mut().map_or(false, |scheduler| {
+            scheduler.should_force(TimeSpan {
+                Duration: timestamp.Duration - self.first_timestamp.Duration,
+            })
+        });
+        let segment_keyframe = self.segment_scheduler.as_mut().map_or(false, |scheduler| {
+            scheduler.should_force(TimeSpan {
+                Duration: frame_time.Duration - self.first_timestamp.Duration,
+            })
+        });
+        let force_keyframe = force_keyframe || segment_keyframe;
+        self.feed_renditions(timestamp, force_keyframe)?;
+
+        if force_keyframe {
+            Ok(sample.with_forced_keyframe())
+        } else {
+            Ok(sample)
+        }
+    }
+}
+
+impl FrameSink for SampleGenerator {
+    fn write_frame(
+        &mut self,
+        surface: &IDirect3DSurface,
+        size: SizeInt32,
+        timestamp: TimeSpan,
+    ) -> Result<bool> {
+        let frame_texture: ID3D11Texture2D = get_d3d_interface_from_object(surface)?;
+        self.sample = self.generate_from_frame(&frame_texture, size, timestamp)?;
+        Ok(self.sample.is_some())
+    }
+
+    fn repeat_frame(&mut self, timestamp: TimeSpan) -> Result<bool> {
+        let frame_time = timestamp;
+        let timestamp = self.timestamp(frame_time);
+        self.sample = Some(self.create_sample(timestamp, frame_time)?);
+        Ok(true)
+    }
+
+    fn start_span(&mut self, timestamp: TimeSpan, system_time: TimeSpan) {
+        if let Some(audio_timeline) = self.audio_timeline.as_ref() {
+            audio_timeline.start_span(timestamp.Duration, system_time.Duration);
+        }
+    }
 
-            // Release the frame back to the frame pool
-            frame.Close()?;
+    // A crop still needs the whole item captured
+    fn capture_size(&self) -> Option<SizeInt32> {
+        self.crop.is_none().then_some(self.input_size)
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        let last_frame = self
+            .last_sample_time
+            .is_some()
+            .then_some(&self.compose_texture);
+        if let Err(error) = self.snapshot_taker.finish(last_frame) {
+            warn!(
+                "Unable to take a snapshot: {:?} - {}",
+                error.code(),
+                error.message()
+            );
+        }
+        // Ends the renditions' streams
+        self.rendition_feeders.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+// How long focus has to stay on a window before the capture switches to it.
+const FOREGROUND_DEBOUNCE: Duration = Duration::from_millis(300);
 
-            Ok(VideoEncoderInputSample::new(timestamp, sample_texture))
+// Moves the capture to whatever window has focus, starting with the current
+// one. The output keeps the size it was set up with, windows get scaled to
+// fit (see SampleGenerator::handle_content_size).
+fn follow_foreground(retargeter: CaptureRetargeter) -> Result<ForegroundWatcher> {
+    let retarget = move |window| {
+        let result =
+            create_capture_item_for_window(window).and_then(|item| retargeter.retarget(item));
+        // Some windows can't be captured (e.g. elevated ones), stay where we are
+        if let Err(error) = result {
+            warn!(
+                "Unable to follow the foreground window: {:?} - {}",
+                error.code(),
+                error.message()
+            );
         }
+    };
+    if let Some(window) = foreground_window() {
+        retarget(window);
+    }
+    ForegroundWatcher::new(FOREGROUND_DEBOUNCE, retarget)
+}
+
+// The input formats the main encoder is offered, best first. The MFT
+// scalers only produce NV12.
+pub(super) fn encoder_inputs(
+    settings: &VideoEncodingSettings,
+    capture_format: DXGI_FORMAT,
+) -> Vec<EncoderInput> {
+    match settings.encoder_input {
+        Some(encoder_input) => vec![encoder_input],
+        None if settings.scaler != Scaler::Auto => vec![EncoderInput::Nv12],
+        None => EncoderInput::preferred_for_format(capture_format).to_vec(),
     }
 }
 
+// What create_texture_processor converts from and to.
+pub(super) struct TextureConversion {
+    pub scaler: Scaler,
+    pub input_format: DXGI_FORMAT,
+    // None follows whatever the input format is in.
+    pub input_color_space: Option<InputColorSpace>,
+    pub input_size: SizeInt32,
+    pub output_format: DXGI_FORMAT,
+    pub output_size: SizeInt32,
+    pub preserve_aspect_ratio: bool,
+}
+
+pub(super) fn create_texture_processor(
+    d3d_device: &ID3D11Device,
+    conversion: TextureConversion,
+) -> Result<Box<dyn TextureProcessor>> {
+    let TextureConversion {
+        scaler,
+        input_format,
+        input_color_space,
+        input_size,
+        output_format,
+        output_size,
+        preserve_aspect_ratio,
+    } = conversion;
+    // The MFT scalers are set up for BGRA8 input
+    if scaler != Scaler::Auto && input_format != DXGI_FORMAT_B8G8R8A8_UNORM {
+        return Err(Error::new(
+            E_INVALIDARG,
+            format!("The {} scaler only supports 8 bit captures!", scaler).into(),
+        ));
+    }
+    // They also only produce NV12
+    if scaler != Scaler::Auto && output_format != Chroma::Yuv420.dxgi_format() {
+        return Err(Error::new(
+            E_INVALIDARG,
+            format!("The {} scaler only supports NV12 encoder input!", scaler).into(),
+        ));
+    }
+    let input_color_space =
+        input_color_space.unwrap_or_else(|| InputColorSpace::for_format(input_format));
+    // And treat everything as sRGB
+    if scaler != Scaler::Auto && input_color_space != InputColorSpace::Srgb {
+        return Err(Error::new(
+            E_INVALIDARG,
+            format!("The {} scaler only supports sRGB input!", scaler).into(),
+        ));
+    }
+    Ok(match scaler {
+        Scaler::Auto => Box::new(VideoProcessor::new(
+            d3d_device.clone(),
+            input_format,
+            input_color_space,
+            input_size,
+            output_format,
+            output_size,
+            preserve_aspect_ratio,
+        )?),
+        Scaler::Bilinear => Box::new(MFTVideoProcessor::new(
+            d3d_device.clone(),
+            input_size,
+            output_size,
+            MF_VIDEO_PROCESSOR_ALGORITHM_DEFAULT,
+            preserve_aspect_ratio,
+        )?),
+        Scaler::HighQuality => Box::new(MFTVideoProcessor::new(
+            d3d_device.clone(),
+            input_size,
+            output_size,
+            MF_VIDEO_PROCESSOR_ALGORITHM_MRF_CRF_444,
+            preserve_aspect_ratio,
+        )?),
+    })
+}
+
+fn create_compose_texture(
+    d3d_device: &ID3D11Device,
+    format: DXGI_FORMAT,
+    size: SizeInt32,
+) -> Result<(ID3D11Texture2D, ID3D11RenderTargetView)> {
+    let texture_desc = D3D11_TEXTURE2D_DESC {
+        Width: size.Width as u32,
+        Height: size.Height as u32,
+        ArraySize: 1,
+        MipLevels: 1,
+        Format: format,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            ..Default::default()
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_SHADER_RESOURCE.0) as u32,
+        ..Default::default()
+    };
+    let compose_texture = unsafe {
+        let mut texture = None;
+        d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+        texture.unwrap()
+    };
+    let render_target_view = unsafe {
+        let mut rtv = None;
+        d3d_device.CreateRenderTargetView(&compose_texture, None, Some(&mut rtv))?;
+        rtv.unwrap()
+    };
+    Ok((compose_texture, render_target_view))
+}
+
+fn create_duplicate_detector(
+    d3d_device: &ID3D11Device,
+    size: SizeInt32,
+    threshold: Option<f32>,
+    max_duration: Duration,
+) -> Result<Option<DuplicateFrameDetector>> {
+    Ok(if let Some(threshold) = threshold {
+        Some(DuplicateFrameDetector::new(
+            d3d_device,
+            size,
+            threshold,
+            max_duration,
+        )?)
+    } else {
+        None
+    })
+}
+
 unsafe impl Send for SampleWriter {}
 unsafe impl Sync for SampleWriter {}
 impl SampleWriter {
-    pub fn new(stream: IRandomAccessStream, output_type: &IMFMediaType) -> Result<Self> {
+    pub fn new(
+        stream: OutputStream,
+        output_type: &IMFMediaType,
+        settings: SampleWriterSettings,
+        audio_source: Option<AudioSource>,
+        first_frame_callback: Option<FirstFrameCallback>,
+    ) -> Result<Self> {
+        let SampleWriterSettings {
+            variable_frame_rate,
+            container,
+            throttling,
+            audio_codec,
+        } = settings;
         let empty_attributes = unsafe {
             let mut attributes = None;
             MFCreateAttributes(&mut attributes, 0)?;
             attributes.unwrap()
         };
+        let sink_attributes = unsafe {
+            let mut attributes = None;
+            MFCreateAttributes(&mut attributes, 2)?;
+            let attributes = attributes.unwrap();
+            attributes.SetGUID(&MF_TRANSCODE_CONTAINERTYPE, &container.container_type())?;
+            // With throttling, WriteSample blocks while the sink is behind,
+            // which holds up the encoder and in turn the capture. Without
+            // it, samples pile up in memory instead.
+            attributes.SetUINT32(&MF_SINK_WRITER_DISABLE_THROTTLING, (!throttling).into())?;
+            attributes
+        };
         let sink_writer = unsafe {
-            let byte_stream = MFCreateMFByteStreamOnStreamEx(&stream)?;
-            MFCreateSinkWriterFromURL(&HSTRING::from(".mp4"), &byte_stream, &empty_attributes)?
+            let byte_stream = stream.to_byte_stream()?;
+            MFCreateSinkWriterFromURL(&HSTRING::from(".mp4"), &byte_stream, &sink_attributes)?
         };
         let sink_writer_stream_index = unsafe { sink_writer.AddStream(output_type)? };
         unsafe {
@@ -356,11 +1556,29 @@ impl SampleWriter {
                 &empty_attributes,
             )?
         };
+        let audio_track = if let Some(audio_codec) = audio_codec {
+            Some(Mutex::new(AudioTrack::new(
+                &sink_writer,
+                &empty_attributes,
+                audio_codec,
+                audio_source,
+            )?))
+        } else {
+            None
+        };
 
         Ok(Self {
             _stream: stream,
             sink_writer,
             sink_writer_stream_index,
+
+            variable_frame_rate,
+            pending_sample: Mutex::new(None),
+            finalized: Mutex::new(false),
+            backlog_warned: AtomicBool::new(false),
+
+            first_frame_callback: Mutex::new(first_frame_callback),
+            audio_track,
         })
     }
 
@@ -368,14 +1586,238 @@ impl SampleWriter {
         unsafe { self.sink_writer.BeginWriting() }
     }
 
+    // Follows the encoder to a new output type mid stream. Samples written
+    // from here on have to be of the new type.
+    pub fn set_output_type(&self, output_type: &IMFMediaType) -> Result<()> {
+        let finalized = self.finalized.lock().unwrap();
+        if *finalized {
+            return Ok(());
+        }
+        // Whatever we were holding on to is of the old type
+        if let Some(sample) = self.pending_sample.lock().unwrap().take() {
+            self.write_sample(&sample)?;
+        }
+        let empty_attributes = unsafe {
+            let mut attributes = None;
+            MFCreateAttributes(&mut attributes, 0)?;
+            attributes.unwrap()
+        };
+        unsafe {
+            self.sink_writer.SetInputMediaType(
+                self.sink_writer_stream_index,
+                output_type,
+                &empty_attributes,
+            )
+        }
+        .map_err(|error| {
+            Error::new(
+                error.code(),
+                format!(
+                    "The output can't follow the encoder's new output type: {}",
+                    error.message()
+                )
+                .into(),
+            )
+        })
+    }
+
+    // Safe to call more than once, only the first call finalizes the file.
+    // Later calls wait for it to be done.
     pub fn stop(&self) -> Result<()> {
+        let mut finalized = self.finalized.lock().unwrap();
+        if *finalized {
+            return Ok(());
+        }
+        *finalized = true;
+
+        // The last sample has nothing after it to measure against, so
+        // it keeps whatever duration the encoder gave it.
+        if let Some(sample) = self.pending_sample.lock().unwrap().take() {
+            self.write_sample(&sample)?;
+        }
+        if let Some(audio_track) = self.audio_track.as_ref() {
+            audio_track.lock().unwrap().finish(&self.sink_writer)?;
+        }
         unsafe { self.sink_writer.Finalize() }
     }
 
     pub fn write(&self, sample: &IMFSample) -> Result<()> {
+        let finalized = self.finalized.lock().unwrap();
+        if *finalized {
+            return Ok(());
+        }
+        if !self.variable_frame_rate {
+            return self.write_sample(sample);
+        }
+
+        // In VFR mode we hold on to each sample until the next one arrives,
+        // so that its duration covers the real gap between the two frames
+        // instead of assuming 1/fps.
+        let mut pending_sample = self.pending_sample.lock().unwrap();
+        if let Some(previous_sample) = pending_sample.take() {
+            unsafe {
+                let previous_time = previous_sample.GetSampleTime()?;
+                let time = sample.GetSampleTime()?;
+                if time > previous_time {
+                    previous_sample.SetSampleDuration(time - previous_time)?;
+                }
+            }
+            self.write_sample(&previous_sample)?;
+        }
+        *pending_sample = Some(sample.clone());
+        Ok(())
+    }
+
+    fn write_sample(&self, sample: &IMFSample) -> Result<()> {
         unsafe {
             self.sink_writer
-                .WriteSample(self.sink_writer_stream_index, sample)
+                .WriteSample(self.sink_writer_stream_index, sample)?;
+        }
+        self.check_backlog()?;
+        // Keep the audio caught up with the end of each frame
+        if let Some(audio_track) = self.audio_track.as_ref() {
+            let (sample_time, end_time) = unsafe {
+                let sample_time = sample.GetSampleTime()?;
+                (
+                    sample_time,
+                    sample_time + sample.GetSampleDuration().unwrap_or(0),
+                )
+            };
+            audio_track
+                .lock()
+                .unwrap()
+                .fill_until(&self.sink_writer, sample_time, end_time)?;
+        }
+        if let Some(first_frame_callback) = self.first_frame_callback.lock().unwrap().take() {
+            first_frame_callback.notify();
+        }
+        Ok(())
+    }
+
+    // Warns once if the sink writer is holding on to a lot of samples, which
+    // only happens without throttling (or with a sink that ignores it).
+    fn check_backlog(&self) -> Result<()> {
+        if self.backlog_warned.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let queued = unsafe {
+            let mut statistics = MF_SINK_WRITER_STATISTICS {
+                cb: std::mem::size_of::<MF_SINK_WRITER_STATISTICS>() as u32,
+                ..Default::default()
+            };
+            self.sink_writer
+                .GetStatistics(self.sink_writer_stream_index, &mut statistics)?;
+            statistics.dwByteCountQueued as usize
+        };
+        if queued > SINK_WRITER_BACKLOG_WARNING {
+            warn!(
+                "The sink writer has {} MB queued, the output isn't keeping up with the encoder...",
+                queued / (1024 * 1024)
+            );
+            self.backlog_warned.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+unsafe impl Send for SegmentedSampleWriter {}
+unsafe impl Sync for SegmentedSampleWriter {}
+impl SegmentedSampleWriter {
+    pub fn new(
+        stream: OutputStream,
+        output_type: &IMFMediaType,
+        settings: &VideoEncodingSettings,
+        audio_source: Option<AudioSource>,
+    ) -> Result<Self> {
+        let audio_codec =
+            (settings.silent_audio || audio_source.is_some()).then_some(settings.audio_codec);
+        let writer_settings = SampleWriterSettings::new(settings, audio_codec);
+        let sample_writer = SampleWriter::new(
+            stream,
+            output_type,
+            writer_settings,
+            audio_source.clone(),
+            settings.first_frame_callback.clone(),
+        )?;
+        Ok(Self {
+            state: Mutex::new(SegmentState {
+                sample_writer,
+                output_type: output_type.clone(),
+                index: settings
+                    .segments
+                    .as_ref()
+                    .map_or(1, |segments| segments.first_index),
+                clock: settings
+                    .segments
+                    .as_ref()
+                    .map(|segments| SegmentClock::new(segments.duration)),
+            }),
+            segments: settings.segments.clone(),
+
+            writer_settings,
+            audio_source,
+        })
+    }
+
+    pub fn start(&self) -> Result<()> {
+        self.state.lock().unwrap().sample_writer.start()
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.state.lock().unwrap().sample_writer.stop()
+    }
+
+    pub fn set_output_type(&self, output_type: &IMFMediaType) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.sample_writer.set_output_type(output_type)?;
+        state.output_type = output_type.clone();
+        Ok(())
+    }
+
+    pub fn write(&self, sample: &IMFSample) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let (Some(segments), Some(clock)) = (self.segments.as_ref(), state.clock.as_mut()) {
+            let time = unsafe { sample.GetSampleTime()? };
+            let is_keyframe =
+                unsafe { sample.GetUINT32(&MFSampleExtension_CleanPoint).unwrap_or(0) != 0 };
+            let starts_segment = clock.place(time, is_keyframe);
+            let time_offset = clock.time_offset();
+            if starts_segment {
+                self.roll_over(&mut state, segments, time_offset)?;
+            }
+            if time_offset != 0 {
+                unsafe { sample.SetSampleTime(time - time_offset)? };
+            }
         }
+        state.sample_writer.write(sample)
+    }
+
+    // The current segment is finalized before the next one is opened, so
+    // there's only ever one file being written. This happens on the write
+    // queue's thread, see SEGMENT_WRITE_QUEUE_LENGTH.
+    fn roll_over(
+        &self,
+        state: &mut SegmentState,
+        segments: &SegmentSettings,
+        time_offset: i64,
+    ) -> Result<()> {
+        state.sample_writer.stop()?;
+        state.index += 1;
+        info!("Starting segment {}...", state.index);
+        let stream = segments.opener.open(state.index)?;
+        let sample_writer = SampleWriter::new(
+            stream,
+            &state.output_type,
+            self.writer_settings,
+            // The segment's audio follows its video back to the start
+            self.audio_source
+                .as_ref()
+                .map(|audio_source| audio_source.shifted(time_offset)),
+            None,
+        )?;
+        sample_writer.start()?;
+        // Releases the finished segment's sink writer and stream
+        state.sample_writer = sample_writer;
+        Ok(())
     }
 }
@@ -0,0 +1,286 @@
+use std::sync::{
+    mpsc::{sync_channel, SyncSender},
+    Arc,
+};
+
+use log::warn;
+use windows::{
+    core::{ComInterface, Error, Result},
+    Foundation::TimeSpan,
+    Graphics::{DirectX::Direct3D11::IDirect3DSurface, SizeInt32},
+    Win32::{
+        Foundation::{E_FAIL, E_ILLEGAL_METHOD_CALL, E_INVALIDARG},
+        Graphics::{
+            Direct3D11::{ID3D11Device, ID3D11Multithread, ID3D11Texture2D, D3D11_TEXTURE2D_DESC},
+            Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM,
+        },
+        Media::MediaFoundation::MFVideoFormat_H264,
+    },
+};
+
+use crate::{
+    cancellation::{CancellationToken, StopReason},
+    d3d::get_d3d_interface_from_object,
+    video::{
+        chroma::Chroma,
+        encoding_session::{VideoEncodingSession, VideoEncodingSettings},
+        output_stream::OutputStream,
+        progress::{DurationTracker, ElapsedTime},
+        scaler::Scaler,
+        util::ensure_even_size,
+    },
+};
+
+use super::{
+    encoder::{VideoEncoder, VideoEncoderInputSample, VideoEncoderSettings},
+    encoder_device::VideoEncoderDevice,
+    encoding_session::{
+        apply_preset, check_output_type, create_texture_processor, encoder_inputs,
+        set_color_attributes, SampleWriter, SampleWriterSettings, TextureConversion,
+    },
+    processor::TextureProcessor,
+    texture_pool::TexturePool,
+};
+
+// How many submitted frames can wait on the encoder before submit_frame
+// blocks.
+const FEED_QUEUE_LENGTH: usize = 4;
+
+// Encodes frames the caller renders itself instead of captured ones. Frames
+// have to be BGRA8 textures of the size the session was created with, made
+// on the same device. Only the output related settings apply, the capture
+// ones (crop, cursor, watermark, etc.) are ignored.
+pub struct MFFrameFeedSession {
+    video_encoder: VideoEncoder,
+    sample_writer: Arc<SampleWriter>,
+    // None once the session has been stopped.
+    feeder: Option<FrameFeeder>,
+    duration_tracker: DurationTracker,
+    cancellation_token: CancellationToken,
+}
+
+struct FrameFeeder {
+    input_size: SizeInt32,
+    video_processor: Box<dyn TextureProcessor>,
+    texture_pool: TexturePool,
+    sender: SyncSender<VideoEncoderInputSample>,
+    first_timestamp: Option<TimeSpan>,
+    last_timestamp: Option<TimeSpan>,
+}
+
+impl MFFrameFeedSession {
+    pub fn new(
+        d3d_device: ID3D11Device,
+        encoder_device: &VideoEncoderDevice,
+        input_size: SizeInt32,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
+    ) -> Result<Self> {
+        if input_size.Width <= 0 || input_size.Height <= 0 {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "The frame size has to be positive!".into(),
+            ));
+        }
+        let output_size = ensure_even_size(settings.output_size(input_size));
+
+        // Frames are converted on the caller's thread while the encoder
+        // uses the same device from its own
+        let multithread: ID3D11Multithread = d3d_device.cast()?;
+        unsafe {
+            let _ = multithread.SetMultithreadProtected(true);
+        }
+
+        // The MFT scalers only produce NV12
+        let chroma = if settings.chroma != Chroma::Yuv420 && settings.scaler != Scaler::Auto {
+            warn!(
+                "The {} scaler only supports 420 chroma subsampling, falling back to 420...",
+                settings.scaler
+            );
+            Chroma::Yuv420
+        } else {
+            settings.chroma
+        };
+        let mut video_encoder = VideoEncoder::new(
+            encoder_device,
+            d3d_device.clone(),
+            VideoEncoderSettings {
+                input_resolution: output_size,
+                output_resolution: output_size,
+                bit_rate: settings.bit_rate.resolve(
+                    output_size,
+                    settings.playback_frame_rate(),
+                    &MFVideoFormat_H264,
+                ),
+                frame_rate: settings.playback_frame_rate(),
+                pixel_aspect_ratio: settings.pixel_aspect_ratio,
+                chroma,
+                input_formats: &encoder_inputs(settings, DXGI_FORMAT_B8G8R8A8_UNORM),
+                low_latency: settings.target_latency.is_some(),
+                worker_threads: settings.encoder_threads,
+            },
+        )?;
+        let output_type = video_encoder.output_type().clone();
+        check_output_type(
+            &output_type,
+            video_encoder.negotiated_output_type(),
+            settings.strict,
+        )?;
+        if settings.scaler == Scaler::Auto {
+            set_color_attributes(&output_type)?;
+        }
+        video_encoder.set_high_priority(settings.high_priority);
+        apply_preset(&video_encoder, settings.preset);
+
+        let (sender, receiver) = sync_channel(FEED_QUEUE_LENGTH);
+        video_encoder.set_sample_requested_callback(
+            move || -> Result<Option<VideoEncoderInputSample>> { Ok(receiver.recv().ok()) },
+        );
+        let sample_writer = Arc::new(SampleWriter::new(
+            stream,
+            &output_type,
+            SampleWriterSettings::new(settings, None),
+            None,
+            settings.first_frame_callback.clone(),
+        )?);
+        video_encoder.set_sample_rendered_callback({
+            let sample_writer = sample_writer.clone();
+            move |sample| -> Result<()> { sample_writer.write(sample.sample()) }
+        });
+        video_encoder.set_output_type_changed_callback({
+            let sample_writer = sample_writer.clone();
+            move |output_type| -> Result<()> { sample_writer.set_output_type(output_type) }
+        });
+        video_encoder.set_stream_ended_callback({
+            let sample_writer = sample_writer.clone();
+            move || -> Result<()> { sample_writer.stop() }
+        });
+
+        let video_processor = create_texture_processor(
+            &d3d_device,
+            TextureConversion {
+                scaler: settings.scaler,
+                input_format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                input_color_space: settings.input_color_space,
+                input_size,
+                output_format: video_encoder.input_format(),
+                output_size,
+                preserve_aspect_ratio: true,
+            },
+        )?;
+        Ok(Self {
+            video_encoder,
+            sample_writer,
+            feeder: Some(FrameFeeder {
+                input_size,
+                video_processor,
+                texture_pool: TexturePool::new(&d3d_device)?,
+                sender,
+                first_timestamp: None,
+                last_timestamp: None,
+            }),
+            duration_tracker: DurationTracker::new(settings),
+            cancellation_token: settings.cancellation_token.clone(),
+        })
+    }
+}
+
+impl FrameFeeder {
+    fn check_frame(
+        &self,
+        surface: &IDirect3DSurface,
+        timestamp: TimeSpan,
+    ) -> Result<ID3D11Texture2D> {
+        let texture: ID3D11Texture2D = get_d3d_interface_from_object(surface)?;
+        let desc = unsafe {
+            let mut desc = D3D11_TEXTURE2D_DESC::default();
+            texture.GetDesc(&mut desc);
+            desc
+        };
+        if desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM {
+            return Err(Error::new(
+                E_INVALIDARG,
+                "Submitted frames have to be BGRA8!".into(),
+            ));
+        }
+        if desc.Width as i32 != self.input_size.Width
+            || desc.Height as i32 != self.input_size.Height
+        {
+            return Err(Error::new(
+                E_INVALIDARG,
+                format!(
+                    "Submitted frames have to be {}x{}, got {}x{}!",
+                    self.input_size.Width, self.input_size.Height, desc.Width, desc.Height
+                )
+                .into(),
+            ));
+        }
+        if let Some(last_timestamp) = self.last_timestamp {
+            if timestamp.Duration <= last_timestamp.Duration {
+                return Err(Error::new(
+                    E_INVALIDARG,
+                    "Submitted frames have to have increasing timestamps!".into(),
+                ));
+            }
+        }
+        Ok(texture)
+    }
+
+    fn submit(&mut self, texture: &ID3D11Texture2D, timestamp: TimeSpan) -> Result<()> {
+        self.last_timestamp = Some(timestamp);
+
+        // The output starts at the first frame
+        let first_timestamp = *self.first_timestamp.get_or_insert(timestamp);
+        let timestamp = TimeSpan {
+            Duration: timestamp.Duration - first_timestamp.Duration,
+        };
+        self.video_processor.process_texture(texture)?;
+        let sample = self
+            .texture_pool
+            .create_sample(self.video_processor.output_texture(), timestamp)?;
+        // Blocks while the encoder is behind
+        self.sender
+            .send(sample)
+            .map_err(|_| Error::new(E_FAIL, "The encoder stopped unexpectedly!".into()))
+    }
+}
+
+impl VideoEncodingSession for MFFrameFeedSession {
+    fn start(&mut self) -> Result<()> {
+        self.sample_writer.start()?;
+        assert!(self.video_encoder.try_start()?);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        // Ends the stream once the encoder has drained what's queued
+        self.feeder.take();
+        self.video_encoder.wait_for_end()?;
+        self.sample_writer.stop()
+    }
+
+    fn elapsed_time(&self) -> ElapsedTime {
+        self.duration_tracker.elapsed_time()
+    }
+
+    fn frames_encoded(&self) -> Option<u64> {
+        Some(self.video_encoder.frames_encoded())
+    }
+
+    fn submit_frame(&mut self, surface: &IDirect3DSurface, timestamp: TimeSpan) -> Result<()> {
+        let feeder = self.feeder.as_mut().ok_or_else(|| {
+            Error::new(
+                E_ILLEGAL_METHOD_CALL,
+                "The session has already been stopped!".into(),
+            )
+        })?;
+        let texture = feeder.check_frame(surface, timestamp)?;
+        // Frames past the duration are dropped, like captured ones
+        if !self.duration_tracker.update(timestamp) {
+            self.cancellation_token
+                .cancel_with_reason(StopReason::MaxDurationReached);
+            return Ok(());
+        }
+        feeder.submit(&texture, timestamp)
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use windows::{
+    core::{Error, Result},
+    Win32::{
+        Foundation::E_FAIL,
+        Media::MediaFoundation::{
+            IMFSample, MFSampleExtension_CleanPoint, MFSampleExtension_VideoEncodePictureType,
+            MFSampleExtension_VideoEncodeQP,
+        },
+    },
+};
+
+// Logs every encoded sample to a CSV file: its index, timestamp (in
+// seconds, before any segment rebasing), frame type, size in bytes and QP.
+//
+// Only the size and whether it's a keyframe (MFSampleExtension_CleanPoint)
+// are reported by every encoder. The frame type and QP come from
+// MFSampleExtension_VideoEncodePictureType and MFSampleExtension_VideoEncodeQP,
+// which the Microsoft software encoder sets but hardware encoders are free to
+// leave out. Columns the encoder didn't fill in are left empty, and without a
+// picture type non-keyframes are logged as "?" since they could be P or B.
+pub struct FrameStatsWriter {
+    state: Mutex<FrameStatsState>,
+}
+
+struct FrameStatsState {
+    writer: BufWriter<File>,
+    frame_count: u64,
+}
+
+impl FrameStatsWriter {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path.as_ref()).map_err(|error| io_error(&error))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "frame,timestamp,type,size,qp").map_err(|error| io_error(&error))?;
+        Ok(Self {
+            state: Mutex::new(FrameStatsState {
+                writer,
+                frame_count: 0,
+            }),
+        })
+    }
+
+    pub fn record(&self, sample: &IMFSample) -> Result<()> {
+        let (time, size, is_keyframe, picture_type, qp) = unsafe {
+            (
+                sample.GetSampleTime()?,
+                sample.GetTotalLength()?,
+                sample.GetUINT32(&MFSampleExtension_CleanPoint).unwrap_or(0) != 0,
+                sample
+                    .GetUINT32(&MFSampleExtension_VideoEncodePictureType)
+                    .ok(),
+                sample.GetUINT64(&MFSampleExtension_VideoEncodeQP).ok(),
+            )
+        };
+        // Values from eAVEncH264PictureType
+        let frame_type = match picture_type {
+            Some(0) => "I",
+            Some(1) => "P",
+            Some(2) => "B",
+            _ if is_keyframe => "I",
+            _ => "?",
+        };
+        // The QP for the frame is in the low 16 bits
+        let qp = qp.map_or_else(String::new, |qp| (qp & 0xffff).to_string());
+
+        let mut state = self.state.lock().unwrap();
+        let frame = state.frame_count;
+        state.frame_count += 1;
+        writeln!(
+            state.writer,
+            "{},{:.6},{},{},{}",
+            frame,
+            time as f64 / 10_000_000.0,
+            frame_type,
+            size,
+            qp
+        )
+        .map_err(|error| io_error(&error))
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .writer
+            .flush()
+            .map_err(|error| io_error(&error))
+    }
+}
+
+fn io_error(error: &std::io::Error) -> Error {
+    Error::new(
+        E_FAIL,
+        format!("Unable to write the frame stats: {}", error).into(),
+    )
+}
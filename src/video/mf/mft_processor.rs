@@ -0,0 +1,274 @@
+use std::mem::ManuallyDrop;
+
+use windows::{
+    core::{ComInterface, Error, Interface, Result, GUID},
+    Graphics::SizeInt32,
+    Win32::{
+        Foundation::RECT,
+        Graphics::{
+            Direct3D11::{
+                ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BIND_RENDER_TARGET,
+                D3D11_BIND_VIDEO_ENCODER, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+            },
+            Dxgi::Common::{DXGI_FORMAT_NV12, DXGI_SAMPLE_DESC},
+        },
+        Media::MediaFoundation::{
+            IMFAttributes, IMFDXGIBuffer, IMFDXGIDeviceManager, IMFMediaType, IMFSample,
+            IMFTransform, IMFVideoProcessorControl, MFCreateDXGIDeviceManager,
+            MFCreateDXGISurfaceBuffer, MFCreateMediaType, MFCreateSample, MFMediaType_Video,
+            MFVideoFormat_ARGB32, MFVideoFormat_NV12, MFVideoInterlace_Progressive, MFARGB,
+            MFT_CATEGORY_VIDEO_PROCESSOR, MFT_MESSAGE_NOTIFY_BEGIN_STREAMING,
+            MFT_MESSAGE_NOTIFY_END_OF_STREAM, MFT_MESSAGE_NOTIFY_END_STREAMING,
+            MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_MESSAGE_SET_D3D_MANAGER,
+            MFT_OUTPUT_DATA_BUFFER, MFT_OUTPUT_STREAM_PROVIDES_SAMPLES, MFT_REGISTER_TYPE_INFO,
+            MF_E_TOPO_CODEC_NOT_FOUND, MF_MT_FRAME_SIZE, MF_MT_INTERLACE_MODE, MF_MT_MAJOR_TYPE,
+            MF_MT_PIXEL_ASPECT_RATIO, MF_MT_SUBTYPE, MF_VIDEO_PROCESSOR_ALGORITHM,
+            MF_VIDEO_PROCESSOR_ALGORITHM_TYPE,
+        },
+    },
+};
+
+use crate::media::{enumerate_mfts, EnumOptions, MFSetAttributeRatio, MFSetAttributeSize};
+
+use super::processor::{compute_dest_rect, TextureProcessor};
+
+// Scales and converts BGRA textures to NV12 using the Video Processor MFT
+// instead of driving the D3D11 video processor directly. This lets us pick
+// the scaling algorithm explicitly.
+pub struct MFTVideoProcessor {
+    _d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    _media_device_manager: IMFDXGIDeviceManager,
+
+    transform: IMFTransform,
+    provides_samples: bool,
+    video_output_texture: ID3D11Texture2D,
+    output_sample: IMFSample,
+}
+
+impl MFTVideoProcessor {
+    pub fn new(
+        d3d_device: ID3D11Device,
+        input_size: SizeInt32,
+        output_size: SizeInt32,
+        algorithm: MF_VIDEO_PROCESSOR_ALGORITHM_TYPE,
+        preserve_aspect_ratio: bool,
+    ) -> Result<Self> {
+        let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+
+        let input_info = MFT_REGISTER_TYPE_INFO {
+            guidMajorType: MFMediaType_Video,
+            guidSubtype: MFVideoFormat_ARGB32,
+        };
+        let output_info = MFT_REGISTER_TYPE_INFO {
+            guidMajorType: MFMediaType_Video,
+            guidSubtype: MFVideoFormat_NV12,
+        };
+        let transforms = enumerate_mfts(
+            &MFT_CATEGORY_VIDEO_PROCESSOR,
+            &EnumOptions {
+                hardware: false,
+                synchronous: true,
+                local: true,
+                transcode_only: false,
+                ..Default::default()
+            },
+            Some(&input_info),
+            Some(&output_info),
+        )?;
+        let transform: IMFTransform = if let Some(transform) = transforms.first() {
+            unsafe { transform.ActivateObject()? }
+        } else {
+            return Err(Error::new(
+                MF_E_TOPO_CODEC_NOT_FOUND,
+                "No video processor MFT found!".into(),
+            ));
+        };
+
+        // The algorithm must be chosen before the device manager is set
+        let attributes = unsafe { transform.GetAttributes()? };
+        unsafe { attributes.SetUINT32(&MF_VIDEO_PROCESSOR_ALGORITHM, algorithm.0 as u32)? };
+
+        // Create MF device manager
+        let mut device_manager_reset_token: u32 = 0;
+        let media_device_manager = {
+            let mut media_device_manager = None;
+            unsafe {
+                MFCreateDXGIDeviceManager(
+                    &mut device_manager_reset_token,
+                    &mut media_device_manager,
+                )?
+            };
+            media_device_manager.unwrap()
+        };
+        unsafe { media_device_manager.ResetDevice(&d3d_device, device_manager_reset_token)? };
+        unsafe {
+            let temp = media_device_manager.clone();
+            transform.ProcessMessage(MFT_MESSAGE_SET_D3D_MANAGER, std::mem::transmute(temp))?;
+        };
+
+        unsafe {
+            let input_type = create_video_type(&MFVideoFormat_ARGB32, input_size)?;
+            transform.SetInputType(0, &input_type, 0)?;
+            let output_type = create_video_type(&MFVideoFormat_NV12, output_size)?;
+            transform.SetOutputType(0, &output_type, 0)?;
+        }
+
+        // Preserve the aspect ratio when scaling, the same way the
+        // D3D11 video processor path does.
+        let control: IMFVideoProcessorControl = transform.cast()?;
+        unsafe {
+            control.SetBorderColor(Some(&MFARGB {
+                rgbBlue: 0,
+                rgbGreen: 0,
+                rgbRed: 0,
+                rgbAlpha: 255,
+            }))?;
+        }
+        if preserve_aspect_ratio && input_size != output_size {
+            let dest_rect = compute_dest_rect(&output_size, &input_size);
+            let rect = RECT {
+                left: dest_rect.X,
+                top: dest_rect.Y,
+                right: dest_rect.X + dest_rect.Width,
+                bottom: dest_rect.Y + dest_rect.Height,
+            };
+            unsafe { control.SetDestinationRectangle(Some(&rect))? };
+        }
+
+        let stream_info = unsafe { transform.GetOutputStreamInfo(0)? };
+        let provides_samples =
+            (stream_info.dwFlags & MFT_OUTPUT_STREAM_PROVIDES_SAMPLES.0 as u32) != 0;
+
+        let texture_desc = D3D11_TEXTURE2D_DESC {
+            Width: output_size.Width as u32,
+            Height: output_size.Height as u32,
+            ArraySize: 1,
+            MipLevels: 1,
+            Format: DXGI_FORMAT_NV12,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                ..Default::default()
+            },
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_VIDEO_ENCODER.0) as u32,
+            ..Default::default()
+        };
+        let video_output_texture = unsafe {
+            let mut texture = None;
+            d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+            texture.unwrap()
+        };
+        let output_sample = unsafe {
+            let output_buffer =
+                MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &video_output_texture, 0, false)?;
+            let output_sample = MFCreateSample()?;
+            output_sample.AddBuffer(&output_buffer)?;
+            output_sample
+        };
+
+        unsafe {
+            transform.ProcessMessage(MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, 0)?;
+            transform.ProcessMessage(MFT_MESSAGE_NOTIFY_START_OF_STREAM, 0)?;
+        }
+
+        Ok(Self {
+            _d3d_device: d3d_device,
+            d3d_context,
+            _media_device_manager: media_device_manager,
+
+            transform,
+            provides_samples,
+            video_output_texture,
+            output_sample,
+        })
+    }
+}
+
+impl TextureProcessor for MFTVideoProcessor {
+    fn output_texture(&self) -> &ID3D11Texture2D {
+        &self.video_output_texture
+    }
+
+    fn process_texture(&mut self, input_texture: &ID3D11Texture2D) -> Result<()> {
+        unsafe {
+            let input_buffer =
+                MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, input_texture, 0, false)?;
+            let input_sample = MFCreateSample()?;
+            input_sample.AddBuffer(&input_buffer)?;
+            input_sample.SetSampleTime(0)?;
+            self.transform.ProcessInput(0, &input_sample, 0)?;
+
+            // If the MFT doesn't allocate its own samples, it renders
+            // directly into our output texture.
+            let sample = if self.provides_samples {
+                None
+            } else {
+                Some(self.output_sample.clone())
+            };
+            let mut output_buffers = [MFT_OUTPUT_DATA_BUFFER {
+                dwStreamID: 0,
+                pSample: ManuallyDrop::new(sample),
+                ..Default::default()
+            }];
+            let mut status = 0;
+            let result = self
+                .transform
+                .ProcessOutput(0, &mut output_buffers, &mut status);
+            let sample = ManuallyDrop::take(&mut output_buffers[0].pSample);
+            let _events = ManuallyDrop::take(&mut output_buffers[0].pEvents);
+            result?;
+
+            if self.provides_samples {
+                let sample = sample.unwrap();
+                let buffer: IMFDXGIBuffer = sample.GetBufferByIndex(0)?.cast()?;
+                let texture = {
+                    let mut texture = std::ptr::null_mut();
+                    buffer.GetResource(&ID3D11Texture2D::IID, &mut texture)?;
+                    ID3D11Texture2D::from_raw(texture)
+                };
+                let subresource = buffer.GetSubresourceIndex()?;
+                self.d3d_context.CopySubresourceRegion(
+                    &self.video_output_texture,
+                    0,
+                    0,
+                    0,
+                    0,
+                    &texture,
+                    subresource,
+                    None,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MFTVideoProcessor {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self
+                .transform
+                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_OF_STREAM, 0);
+            let _ = self
+                .transform
+                .ProcessMessage(MFT_MESSAGE_NOTIFY_END_STREAMING, 0);
+        }
+    }
+}
+
+unsafe fn create_video_type(subtype: &GUID, size: SizeInt32) -> Result<IMFMediaType> {
+    let media_type = MFCreateMediaType()?;
+    let attributes: IMFAttributes = media_type.cast()?;
+    media_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+    media_type.SetGUID(&MF_MT_SUBTYPE, subtype)?;
+    MFSetAttributeSize(
+        &attributes,
+        &MF_MT_FRAME_SIZE,
+        size.Width as u32,
+        size.Height as u32,
+    )?;
+    MFSetAttributeRatio(&attributes, &MF_MT_PIXEL_ASPECT_RATIO, 1, 1)?;
+    media_type.SetUINT32(&MF_MT_INTERLACE_MODE, MFVideoInterlace_Progressive.0 as u32)?;
+    Ok(media_type)
+}
@@ -1,4 +1,13 @@
+mod audio_track;
 mod encoder;
 pub mod encoder_device;
 pub mod encoding_session;
-mod processor;
+mod feed;
+mod frame_stats;
+mod mft_processor;
+pub mod remux;
+// Also used by the raw backend to convert to NV12
+pub(super) mod processor;
+mod texture_pool;
+pub mod verify;
+mod write_queue;
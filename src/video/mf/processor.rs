@@ -1,28 +1,35 @@
 use windows::{
-    core::{ComInterface, Result},
+    core::{ComInterface, Error, Result},
     Foundation::Numerics::Vector2,
     Graphics::{RectInt32, SizeInt32},
     Win32::{
-        Foundation::RECT,
+        Foundation::{E_INVALIDARG, RECT},
         Graphics::{
             Direct3D11::{
                 ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, ID3D11VideoContext,
-                ID3D11VideoDevice, ID3D11VideoProcessor, ID3D11VideoProcessorInputView,
+                ID3D11VideoContext1, ID3D11VideoDevice, ID3D11VideoProcessor,
+                ID3D11VideoProcessorEnumerator1, ID3D11VideoProcessorInputView,
                 ID3D11VideoProcessorOutputView, D3D11_BIND_RENDER_TARGET,
                 D3D11_BIND_SHADER_RESOURCE, D3D11_BIND_VIDEO_ENCODER, D3D11_TEX2D_VPIV,
                 D3D11_TEX2D_VPOV, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
                 D3D11_VIDEO_FRAME_FORMAT_PROGRESSIVE, D3D11_VIDEO_PROCESSOR_COLOR_SPACE,
-                D3D11_VIDEO_PROCESSOR_CONTENT_DESC, D3D11_VIDEO_PROCESSOR_INPUT_VIEW_DESC,
-                D3D11_VIDEO_PROCESSOR_INPUT_VIEW_DESC_0, D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC,
-                D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC_0, D3D11_VIDEO_PROCESSOR_STREAM,
-                D3D11_VIDEO_USAGE_OPTIMAL_QUALITY, D3D11_VPIV_DIMENSION_TEXTURE2D,
-                D3D11_VPOV_DIMENSION_TEXTURE2D,
+                D3D11_VIDEO_PROCESSOR_CONTENT_DESC, D3D11_VIDEO_PROCESSOR_FORMAT_SUPPORT_INPUT,
+                D3D11_VIDEO_PROCESSOR_INPUT_VIEW_DESC, D3D11_VIDEO_PROCESSOR_INPUT_VIEW_DESC_0,
+                D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC, D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC_0,
+                D3D11_VIDEO_PROCESSOR_STREAM, D3D11_VIDEO_USAGE_OPTIMAL_QUALITY,
+                D3D11_VPIV_DIMENSION_TEXTURE2D, D3D11_VPOV_DIMENSION_TEXTURE2D,
+            },
+            Dxgi::Common::{
+                DXGI_COLOR_SPACE_RGB_FULL_G22_NONE_P709, DXGI_COLOR_SPACE_TYPE,
+                DXGI_COLOR_SPACE_YCBCR_STUDIO_G22_LEFT_P601, DXGI_FORMAT, DXGI_FORMAT_NV12,
+                DXGI_FORMAT_P010, DXGI_RATIONAL, DXGI_SAMPLE_DESC,
             },
-            Dxgi::Common::{DXGI_FORMAT, DXGI_RATIONAL, DXGI_SAMPLE_DESC},
         },
     },
 };
 
+use crate::video::input_color_space::InputColorSpace;
+
 pub struct VideoProcessor {
     _d3d_device: ID3D11Device,
     d3d_context: ID3D11DeviceContext,
@@ -40,9 +47,11 @@ impl VideoProcessor {
     pub fn new(
         d3d_device: ID3D11Device,
         input_format: DXGI_FORMAT,
+        input_color_space: InputColorSpace,
         input_size: SizeInt32,
         output_format: DXGI_FORMAT,
         output_size: SizeInt32,
+        preserve_aspect_ratio: bool,
     ) -> Result<Self> {
         let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
 
@@ -67,21 +76,74 @@ impl VideoProcessor {
             Usage: D3D11_VIDEO_USAGE_OPTIMAL_QUALITY,
         };
         let video_enum = unsafe { video_device.CreateVideoProcessorEnumerator(&video_desc)? };
+        let format_support = unsafe { video_enum.CheckVideoProcessorFormat(input_format)? };
+        if format_support & D3D11_VIDEO_PROCESSOR_FORMAT_SUPPORT_INPUT.0 as u32 == 0 {
+            return Err(Error::new(
+                E_INVALIDARG,
+                format!(
+                    "The video processor doesn't support {:?} input!",
+                    input_format
+                )
+                .into(),
+            ));
+        }
 
         let video_processor = unsafe { video_device.CreateVideoProcessor(&video_enum, 0)? };
 
+        let output_color_space = output_color_space(output_format);
         let mut color_space = D3D11_VIDEO_PROCESSOR_COLOR_SPACE {
-            _bitfield: 17, // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_16_235
+            _bitfield: if output_color_space == DXGI_COLOR_SPACE_RGB_FULL_G22_NONE_P709 {
+                33 // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_0_255
+            } else {
+                17 // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_16_235
+            },
         };
         unsafe { video_context.VideoProcessorSetOutputColorSpace(&video_processor, &color_space) };
         color_space._bitfield = 33; // Usage: 1 (Video processing), Nominal_Range: D3D11_VIDEO_PROCESSOR_NOMINAL_RANGE_0_255
         unsafe {
             video_context.VideoProcessorSetStreamColorSpace(&video_processor, 0, &color_space)
         };
+        // The legacy color space above is sRGB. Linear input (e.g. FP16
+        // scRGB) needs the newer API, and there's no tonemapping, anything
+        // brighter than SDR white gets clipped.
+        if input_color_space == InputColorSpace::Linear {
+            // Drivers can turn down conversions they don't implement
+            if let Ok(video_enum) = video_enum.cast::<ID3D11VideoProcessorEnumerator1>() {
+                let supported = unsafe {
+                    video_enum.CheckVideoProcessorFormatConversion(
+                        input_format,
+                        input_color_space.dxgi_color_space(),
+                        output_format,
+                        output_color_space,
+                    )?
+                };
+                if !supported.as_bool() {
+                    return Err(Error::new(
+                        E_INVALIDARG,
+                        format!(
+                            "The video processor can't convert linear {:?} input!",
+                            input_format
+                        )
+                        .into(),
+                    ));
+                }
+            }
+            let video_context: ID3D11VideoContext1 = video_context.cast()?;
+            unsafe {
+                video_context.VideoProcessorSetStreamColorSpace1(
+                    &video_processor,
+                    0,
+                    input_color_space.dxgi_color_space(),
+                );
+                video_context
+                    .VideoProcessorSetOutputColorSpace1(&video_processor, output_color_space);
+            };
+        }
 
         // If the input and output resolutions don't match, setup the
         // video processor to preserve the aspect ratio when scaling.
-        if input_size.Width != output_size.Width || input_size.Height != output_size.Height {
+        // Otherwise the content is stretched over the whole output.
+        if preserve_aspect_ratio && input_size != output_size {
             let dest_rect = compute_dest_rect(&output_size, &input_size);
             let rect = RECT {
                 left: dest_rect.X,
@@ -180,12 +242,14 @@ impl VideoProcessor {
             video_input,
         })
     }
+}
 
-    pub fn output_texture(&self) -> &ID3D11Texture2D {
+impl TextureProcessor for VideoProcessor {
+    fn output_texture(&self) -> &ID3D11Texture2D {
         &self.video_output_texture
     }
 
-    pub fn process_texture(&mut self, input_texture: &ID3D11Texture2D) -> Result<()> {
+    fn process_texture(&mut self, input_texture: &ID3D11Texture2D) -> Result<()> {
         // The caller is responsible for making sure they give us a
         // texture that matches the input size we were initialized with.
 
@@ -224,7 +288,7 @@ fn compute_scale_factor(output_size: Vector2, input_size: Vector2) -> f32 {
     scale_factor
 }
 
-fn compute_dest_rect(output_size: &SizeInt32, input_size: &SizeInt32) -> RectInt32 {
+pub fn compute_dest_rect(output_size: &SizeInt32, input_size: &SizeInt32) -> RectInt32 {
     let scale = compute_scale_factor(
         Vector2 {
             X: output_size.Width as f32,
@@ -254,3 +318,18 @@ fn compute_dest_rect(output_size: &SizeInt32, input_size: &SizeInt32) -> RectInt
         Height: new_size.Height,
     }
 }
+
+// YUV output is studio range, like the legacy color space set on the
+// processor. Anything else is left to the encoder in full range RGB.
+fn output_color_space(output_format: DXGI_FORMAT) -> DXGI_COLOR_SPACE_TYPE {
+    if output_format == DXGI_FORMAT_NV12 || output_format == DXGI_FORMAT_P010 {
+        DXGI_COLOR_SPACE_YCBCR_STUDIO_G22_LEFT_P601
+    } else {
+        DXGI_COLOR_SPACE_RGB_FULL_G22_NONE_P709
+    }
+}
+
+pub trait TextureProcessor {
+    fn output_texture(&self) -> &ID3D11Texture2D;
+    fn process_texture(&mut self, input_texture: &ID3D11Texture2D) -> Result<()>;
+}
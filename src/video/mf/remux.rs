@@ -0,0 +1,117 @@
+use std::path::{Path, PathBuf};
+
+use windows::{
+    core::{Result, HSTRING},
+    Win32::{
+        Media::MediaFoundation::{
+            IMFSample, MFCreateAttributes, MFCreateSinkWriterFromURL, MFCreateSourceReaderFromURL,
+            MF_E_INVALIDSTREAMNUMBER, MF_PD_DURATION, MF_SOURCE_READERF_ENDOFSTREAM,
+            MF_SOURCE_READERF_STREAMTICK, MF_SOURCE_READER_ANY_STREAM,
+            MF_SOURCE_READER_MEDIASOURCE, MF_TRANSCODE_CONTAINERTYPE,
+        },
+        System::Com::StructuredStorage::PropVariantToUInt64,
+    },
+};
+
+use crate::video::container::Container;
+
+// Copies every stream of the input into a new file with the given container,
+// without decoding or re-encoding anything. progress is called with how far
+// along we are, from 0.0 to 1.0, as samples are copied. Media Foundation has
+// to be started on the calling thread.
+pub fn remux_file<F: FnMut(f64)>(
+    input_path: &Path,
+    output_path: &Path,
+    container: Container,
+    mut progress: F,
+) -> Result<()> {
+    let source_reader = unsafe {
+        MFCreateSourceReaderFromURL(&HSTRING::from(absolute_path(input_path).as_os_str()), None)?
+    };
+    let sink_attributes = unsafe {
+        let mut attributes = None;
+        MFCreateAttributes(&mut attributes, 1)?;
+        let attributes = attributes.unwrap();
+        attributes.SetGUID(&MF_TRANSCODE_CONTAINERTYPE, &container.container_type())?;
+        attributes
+    };
+    let sink_writer = unsafe {
+        MFCreateSinkWriterFromURL(
+            &HSTRING::from(absolute_path(output_path).as_os_str()),
+            None,
+            &sink_attributes,
+        )?
+    };
+
+    // Without an output type set, the reader hands out the samples as they
+    // are stored in the file
+    let mut sink_stream_indices = Vec::new();
+    loop {
+        let stream_index = sink_stream_indices.len() as u32;
+        let media_type = match unsafe { source_reader.GetNativeMediaType(stream_index, 0) } {
+            Ok(media_type) => media_type,
+            Err(error) if error.code() == MF_E_INVALIDSTREAMNUMBER => break,
+            Err(error) => return Err(error),
+        };
+        unsafe {
+            source_reader.SetStreamSelection(stream_index, true)?;
+            let sink_stream_index = sink_writer.AddStream(&media_type)?;
+            sink_writer.SetInputMediaType(sink_stream_index, &media_type, None)?;
+            sink_stream_indices.push(sink_stream_index);
+        }
+    }
+
+    // 100ns units, only used for the progress
+    let duration = unsafe {
+        source_reader
+            .GetPresentationAttribute(MF_SOURCE_READER_MEDIASOURCE.0 as u32, &MF_PD_DURATION)
+            .and_then(|duration| PropVariantToUInt64(&duration))
+            .unwrap_or(0)
+    };
+
+    unsafe { sink_writer.BeginWriting()? };
+    let mut streams_ended = 0;
+    while streams_ended < sink_stream_indices.len() {
+        let mut stream_index = 0;
+        let mut flags = 0;
+        let mut timestamp = 0;
+        let mut sample: Option<IMFSample> = None;
+        unsafe {
+            source_reader.ReadSample(
+                MF_SOURCE_READER_ANY_STREAM.0 as u32,
+                0,
+                Some(&mut stream_index),
+                Some(&mut flags),
+                Some(&mut timestamp),
+                Some(&mut sample),
+            )?;
+        }
+        let sink_stream_index = sink_stream_indices[stream_index as usize];
+        if let Some(sample) = sample {
+            unsafe { sink_writer.WriteSample(sink_stream_index, &sample)? };
+            if duration > 0 {
+                progress((timestamp as f64 / duration as f64).clamp(0.0, 1.0));
+            }
+        }
+        if flags & MF_SOURCE_READERF_STREAMTICK.0 as u32 != 0 {
+            unsafe { sink_writer.SendStreamTick(sink_stream_index, timestamp)? };
+        }
+        if flags & MF_SOURCE_READERF_ENDOFSTREAM.0 as u32 != 0 {
+            streams_ended += 1;
+        }
+    }
+    unsafe { sink_writer.Finalize()? };
+    progress(1.0);
+    Ok(())
+}
+
+// Media Foundation's URLs don't resolve relative paths against the current
+// directory.
+pub(super) fn absolute_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_owned();
+    }
+    std::env::current_dir()
+        .map(|directory| directory.join(path))
+        .unwrap_or_else(|_| path.to_owned())
+}
@@ -0,0 +1,93 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use windows::{
+    core::Result,
+    Foundation::TimeSpan,
+    Win32::Graphics::Direct3D11::{
+        ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_TEXTURE2D_DESC,
+    },
+};
+
+use super::encoder::VideoEncoderInputSample;
+
+// Textures are only ever touched by one thread at a time, and D3D11
+// resources are free threaded anyway.
+struct ReturnedTexture(ID3D11Texture2D);
+unsafe impl Send for ReturnedTexture {}
+
+// The textures processed frames are copied into for the encoder. Rather than
+// allocating one per frame, each goes back into the pool once the encoder is
+// done with the sample wrapping it, so a texture the encoder still holds is
+// never written to. Encoders hold on to a few frames (e.g. for B-frames or
+// their own queue), so the pool grows until it covers that and then stays
+// put. It never waits for a texture to come back, since an encoder can hold
+// its input until it gets more.
+pub struct TexturePool {
+    d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    desc: Option<D3D11_TEXTURE2D_DESC>,
+    sender: Sender<ReturnedTexture>,
+    receiver: Receiver<ReturnedTexture>,
+}
+
+impl TexturePool {
+    pub fn new(d3d_device: &ID3D11Device) -> Result<Self> {
+        let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+        let (sender, receiver) = channel();
+        Ok(Self {
+            d3d_device: d3d_device.clone(),
+            d3d_context,
+            desc: None,
+            sender,
+            receiver,
+        })
+    }
+
+    // Copies the texture into a free one from the pool and wraps it in a
+    // sample that returns it once released.
+    pub fn create_sample(
+        &mut self,
+        texture: &ID3D11Texture2D,
+        timestamp: TimeSpan,
+    ) -> Result<VideoEncoderInputSample> {
+        let desc = unsafe {
+            let mut desc = D3D11_TEXTURE2D_DESC::default();
+            texture.GetDesc(&mut desc);
+            desc
+        };
+        // Textures of another size or format can't be reused, forget them
+        // as they come back.
+        if self
+            .desc
+            .map_or(true, |pool_desc| !same_texture(&pool_desc, &desc))
+        {
+            let (sender, receiver) = channel();
+            self.sender = sender;
+            self.receiver = receiver;
+            self.desc = Some(desc);
+        }
+
+        let pooled = match self.receiver.try_recv() {
+            Ok(ReturnedTexture(pooled)) => pooled,
+            Err(_) => unsafe {
+                let mut pooled = None;
+                self.d3d_device
+                    .CreateTexture2D(&desc, None, Some(&mut pooled))?;
+                pooled.unwrap()
+            },
+        };
+        unsafe { self.d3d_context.CopyResource(&pooled, texture) };
+
+        let sender = self.sender.clone();
+        let returned = ReturnedTexture(pooled.clone());
+        Ok(
+            VideoEncoderInputSample::new(timestamp, pooled).with_release_callback(move || {
+                let _ = sender.send(returned);
+            }),
+        )
+    }
+}
+
+fn same_texture(a: &D3D11_TEXTURE2D_DESC, b: &D3D11_TEXTURE2D_DESC) -> bool {
+    a.Width == b.Width && a.Height == b.Height && a.Format == b.Format
+}
@@ -0,0 +1,129 @@
+use std::path::Path;
+
+use windows::{
+    core::{Result, HSTRING},
+    Win32::Media::MediaFoundation::{
+        IMFSample, MFCreateAttributes, MFCreateMediaType, MFCreateSourceReaderFromURL,
+        MFMediaType_Video, MFVideoFormat_NV12, MF_MT_MAJOR_TYPE, MF_MT_SUBTYPE,
+        MF_SOURCE_READERF_ENDOFSTREAM, MF_SOURCE_READER_ALL_STREAMS,
+        MF_SOURCE_READER_ENABLE_VIDEO_PROCESSING, MF_SOURCE_READER_FIRST_VIDEO_STREAM,
+    },
+};
+
+use super::remux::absolute_path;
+
+// Encoders can take a moment to settle, so frames this close to the start
+// (in 100ns units) are only looked at if the recording isn't any longer.
+const VERIFY_WARM_UP: i64 = 10_000_000;
+// How many frames after the warm-up are decoded.
+const VERIFY_FRAME_COUNT: usize = 30;
+// Studio swing black is 16, anything this dark all over is black.
+const BLACK_LUMA_THRESHOLD: u8 = 32;
+// Frames whose brightest and darkest pixels are this close are a single
+// color, which real content never is.
+const UNIFORM_LUMA_RANGE: u8 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VerifyOutcome {
+    Passed,
+    // Nothing in the file could be decoded.
+    NoFrames,
+    // Every frame we looked at was black.
+    Black,
+    // Every frame we looked at was a single color (e.g. all green).
+    Uniform,
+}
+
+// Decodes a handful of frames of a finished recording and checks that they
+// aren't all black or a single color, which is what some driver and format
+// combinations produce while every API call succeeds. Media Foundation has
+// to be started on the calling thread.
+pub fn verify_recording(path: &Path) -> Result<VerifyOutcome> {
+    let attributes = unsafe {
+        let mut attributes = None;
+        MFCreateAttributes(&mut attributes, 1)?;
+        let attributes = attributes.unwrap();
+        attributes.SetUINT32(&MF_SOURCE_READER_ENABLE_VIDEO_PROCESSING, 1)?;
+        attributes
+    };
+    let source_reader = unsafe {
+        MFCreateSourceReaderFromURL(&HSTRING::from(absolute_path(path).as_os_str()), &attributes)?
+    };
+    let video_stream = MF_SOURCE_READER_FIRST_VIDEO_STREAM.0 as u32;
+    unsafe {
+        source_reader.SetStreamSelection(MF_SOURCE_READER_ALL_STREAMS.0 as u32, false)?;
+        source_reader.SetStreamSelection(video_stream, true)?;
+        let media_type = MFCreateMediaType()?;
+        media_type.SetGUID(&MF_MT_MAJOR_TYPE, &MFMediaType_Video)?;
+        media_type.SetGUID(&MF_MT_SUBTYPE, &MFVideoFormat_NV12)?;
+        source_reader.SetCurrentMediaType(video_stream, None, &media_type)?;
+    }
+
+    // The (darkest, brightest) luma of each frame
+    let mut early_frames = Vec::new();
+    let mut frames = Vec::new();
+    while frames.len() < VERIFY_FRAME_COUNT {
+        let mut flags = 0;
+        let mut timestamp = 0;
+        let mut sample: Option<IMFSample> = None;
+        unsafe {
+            source_reader.ReadSample(
+                video_stream,
+                0,
+                None,
+                Some(&mut flags),
+                Some(&mut timestamp),
+                Some(&mut sample),
+            )?;
+        }
+        if let Some(sample) = sample {
+            let range = luma_range(&sample)?;
+            if timestamp < VERIFY_WARM_UP {
+                early_frames.push(range);
+            } else {
+                frames.push(range);
+            }
+        }
+        if flags & MF_SOURCE_READERF_ENDOFSTREAM.0 as u32 != 0 {
+            break;
+        }
+    }
+    if frames.is_empty() {
+        frames = early_frames;
+    }
+
+    Ok(if frames.is_empty() {
+        VerifyOutcome::NoFrames
+    } else if frames
+        .iter()
+        .all(|(_, brightest)| *brightest <= BLACK_LUMA_THRESHOLD)
+    {
+        VerifyOutcome::Black
+    } else if frames
+        .iter()
+        .all(|(darkest, brightest)| brightest.saturating_sub(*darkest) <= UNIFORM_LUMA_RANGE)
+    {
+        VerifyOutcome::Uniform
+    } else {
+        VerifyOutcome::Passed
+    })
+}
+
+// NV12 starts with the luma plane, which takes up two thirds of the frame.
+// Any row padding only makes a frame look less uniform, never more.
+fn luma_range(sample: &IMFSample) -> Result<(u8, u8)> {
+    unsafe {
+        let buffer = sample.ConvertToContiguousBuffer()?;
+        let mut data = std::ptr::null_mut();
+        let mut length = 0;
+        buffer.Lock(&mut data, None, Some(&mut length))?;
+        let luma = std::slice::from_raw_parts(data, length as usize * 2 / 3);
+        let range = luma
+            .iter()
+            .fold((u8::MAX, u8::MIN), |(darkest, brightest), value| {
+                (darkest.min(*value), brightest.max(*value))
+            });
+        buffer.Unlock()?;
+        Ok(range)
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use log::{debug, warn};
+use windows::{
+    core::{Error, Result},
+    Win32::{
+        Foundation::E_UNEXPECTED,
+        Media::MediaFoundation::{IMFMediaType, IMFSample},
+    },
+};
+
+// Encoded samples are only ever touched by one thread at a time. Output type
+// changes go through the queue too, so that they land between the samples
+// of the old type and the new one.
+enum QueuedItem {
+    Sample(IMFSample),
+    OutputType(IMFMediaType),
+}
+unsafe impl Send for QueuedItem {}
+
+// Writes samples on a thread of its own, so that a slow disk holds up the
+// queue instead of the encoder. Once the queue is full, pushing waits for
+// room rather than dropping samples, since losing an encoded frame would
+// corrupt the video until the next keyframe.
+pub struct WriteQueue {
+    sender: Mutex<Option<SyncSender<QueuedItem>>>,
+    thread: Mutex<Option<JoinHandle<Result<()>>>>,
+    warned: Mutex<bool>,
+}
+
+impl WriteQueue {
+    pub fn new<
+        F: 'static + Send + FnMut(&IMFSample) -> Result<()>,
+        T: 'static + Send + FnMut(&IMFMediaType) -> Result<()>,
+    >(
+        length: usize,
+        mut write: F,
+        mut set_output_type: T,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<QueuedItem>(length);
+        let thread = std::thread::spawn(move || -> Result<()> {
+            // Returning early drops the receiver, which fails the next push
+            for item in receiver {
+                match item {
+                    QueuedItem::Sample(sample) => write(&sample)?,
+                    QueuedItem::OutputType(output_type) => set_output_type(&output_type)?,
+                }
+            }
+            Ok(())
+        });
+        Self {
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+            warned: Mutex::new(false),
+        }
+    }
+
+    // Samples pushed after a flush are ignored.
+    pub fn push(&self, sample: &IMFSample) -> Result<()> {
+        self.push_item(QueuedItem::Sample(sample.clone()))
+    }
+
+    pub fn push_output_type(&self, output_type: &IMFMediaType) -> Result<()> {
+        self.push_item(QueuedItem::OutputType(output_type.clone()))
+    }
+
+    fn push_item(&self, item: QueuedItem) -> Result<()> {
+        // A clone, so that waiting for room doesn't keep flush from taking
+        // the sender
+        let sender = if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            sender.clone()
+        } else {
+            return Ok(());
+        };
+        let item = match sender.try_send(item) {
+            Ok(_) => return Ok(()),
+            Err(TrySendError::Full(item)) => item,
+            // The writer failed, get its error
+            Err(TrySendError::Disconnected(_)) => {
+                drop(sender);
+                return self.flush();
+            }
+        };
+        let mut warned = self.warned.lock().unwrap();
+        if !*warned {
+            warn!("The write queue is full, the disk isn't keeping up with the encoder...");
+            *warned = true;
+        } else {
+            debug!("The write queue is full, waiting...");
+        }
+        // Also fails if the writer fails while we wait, flush reports that
+        let _ = sender.send(item);
+        Ok(())
+    }
+
+    // Waits for everything queued so far to be written. Safe to call more
+    // than once, only the first call reports the writer's error.
+    pub fn flush(&self) -> Result<()> {
+        self.sender.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().map_err(|_| {
+                Error::new(E_UNEXPECTED, "The write queue's thread panicked!".into())
+            })??;
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,36 @@
+pub mod audio_codec;
 pub mod backend;
+pub mod bit_rate;
+pub mod chroma;
+pub mod container;
+mod dedup;
+pub mod encoder_input;
 pub mod encoding_session;
+pub mod fit;
+pub mod frame_sink;
+pub mod hud;
+pub mod input_color_space;
+mod keyframes;
+pub mod ladder;
+pub mod latency;
 pub mod mf;
+mod null_stream;
+pub mod output_stream;
+mod overlay;
+mod pacing;
+pub mod pixel_aspect_ratio;
+pub mod png_sequence;
+pub mod preset;
+pub mod progress;
+pub mod raw;
+pub mod raw_format;
+pub mod scaler;
+pub mod segments;
+pub mod shared_memory;
+mod snapshot;
+pub mod timestamp_base;
 mod util;
+pub mod watermark;
 pub mod wmt;
 
 const CLEAR_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
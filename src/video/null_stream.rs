@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+use windows::{
+    core::Result,
+    Win32::{
+        Foundation::STG_E_INVALIDFUNCTION,
+        Media::MediaFoundation::IMFByteStream,
+        System::Com::{STREAM_SEEK, STREAM_SEEK_CUR, STREAM_SEEK_END, STREAM_SEEK_SET},
+    },
+};
+
+use crate::stream::{create_byte_stream, StreamTarget};
+
+// A byte stream that throws away everything written to it. Positions and the
+// size are still tracked, so the sink writer can seek around and finalize
+// the file as usual, which means the encoder is drained like it normally is.
+pub fn create_null_byte_stream() -> Result<IMFByteStream> {
+    create_byte_stream(NullTarget {
+        state: Mutex::new(NullTargetState::default()),
+    })
+}
+
+#[derive(Default)]
+struct NullTargetState {
+    position: u64,
+    size: u64,
+}
+
+struct NullTarget {
+    state: Mutex<NullTargetState>,
+}
+
+impl StreamTarget for NullTarget {
+    // Nothing was kept, so anything read back is zeros.
+    fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let read = state
+            .size
+            .saturating_sub(state.position)
+            .min(buffer.len() as u64) as usize;
+        buffer[..read].fill(0);
+        state.position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.position += data.len() as u64;
+        state.size = state.size.max(state.position);
+        Ok(())
+    }
+
+    fn seek(&self, offset: i64, origin: STREAM_SEEK) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let base = match origin {
+            STREAM_SEEK_SET => 0,
+            STREAM_SEEK_CUR => state.position as i64,
+            STREAM_SEEK_END => state.size as i64,
+            _ => return Err(STG_E_INVALIDFUNCTION.into()),
+        };
+        let position = base + offset;
+        if position < 0 {
+            return Err(STG_E_INVALIDFUNCTION.into());
+        }
+        state.position = position as u64;
+        Ok(state.position)
+    }
+
+    fn set_size(&self, size: u64) -> Result<()> {
+        self.state.lock().unwrap().size = size;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.state.lock().unwrap().size)
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use windows::{
+    core::{ComInterface, Interface, Result},
+    Storage::Streams::IRandomAccessStream,
+    Win32::Media::MediaFoundation::{
+        IMFByteStream, MFCreateMFByteStreamOnStreamEx, MFCreateStreamOnMFByteStreamEx,
+    },
+};
+
+use super::null_stream::create_null_byte_stream;
+
+// Passing this as the output path records without writing anything.
+pub const NULL_OUTPUT_PATH: &str = "null";
+
+pub fn is_null_output_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_str()
+        .map_or(false, |path| path.eq_ignore_ascii_case(NULL_OUTPUT_PATH))
+}
+
+// Where the encoded output goes. Files and in-memory streams come in as
+// WinRT streams, anything else (pipes, network sinks, etc.) can implement
+// IMFByteStream directly.
+#[derive(Clone)]
+pub enum OutputStream {
+    RandomAccessStream(IRandomAccessStream),
+    ByteStream(IMFByteStream),
+}
+
+impl OutputStream {
+    // Discards the output while still going through the whole pipeline,
+    // which is useful for measuring capture and encode throughput.
+    pub fn null() -> Result<Self> {
+        Ok(OutputStream::ByteStream(create_null_byte_stream()?))
+    }
+
+    pub fn to_byte_stream(&self) -> Result<IMFByteStream> {
+        match self {
+            OutputStream::RandomAccessStream(stream) => unsafe {
+                MFCreateMFByteStreamOnStreamEx(stream)
+            },
+            OutputStream::ByteStream(byte_stream) => Ok(byte_stream.clone()),
+        }
+    }
+
+    pub fn to_random_access_stream(&self) -> Result<IRandomAccessStream> {
+        match self {
+            OutputStream::RandomAccessStream(stream) => Ok(stream.clone()),
+            // Asks for the WinRT stream directly, which saves wrapping an
+            // IStream ourselves
+            OutputStream::ByteStream(byte_stream) => unsafe {
+                let mut stream = std::ptr::null_mut();
+                MFCreateStreamOnMFByteStreamEx(
+                    byte_stream,
+                    &IRandomAccessStream::IID,
+                    &mut stream,
+                )?;
+                Ok(IRandomAccessStream::from_raw(stream))
+            },
+        }
+    }
+}
+
+impl From<IRandomAccessStream> for OutputStream {
+    fn from(stream: IRandomAccessStream) -> Self {
+        OutputStream::RandomAccessStream(stream)
+    }
+}
+
+impl From<IMFByteStream> for OutputStream {
+    fn from(byte_stream: IMFByteStream) -> Self {
+        OutputStream::ByteStream(byte_stream)
+    }
+}
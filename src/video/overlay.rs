@@ -0,0 +1,310 @@
+use std::path::Path;
+
+use windows::{
+    core::{w, ComInterface, Result, HSTRING},
+    Win32::{
+        Foundation::GENERIC_READ,
+        Graphics::{
+            Direct2D::{
+                Common::{
+                    D2D1_ALPHA_MODE_PREMULTIPLIED, D2D1_COLOR_F, D2D1_PIXEL_FORMAT, D2D_RECT_F,
+                },
+                D2D1CreateFactory, ID2D1Bitmap1, ID2D1DeviceContext, ID2D1Factory1,
+                ID2D1SolidColorBrush, D2D1_BITMAP_INTERPOLATION_MODE_LINEAR, D2D1_BITMAP_OPTIONS,
+                D2D1_BITMAP_OPTIONS_CANNOT_DRAW, D2D1_BITMAP_OPTIONS_NONE,
+                D2D1_BITMAP_OPTIONS_TARGET, D2D1_BITMAP_PROPERTIES1,
+                D2D1_DEVICE_CONTEXT_OPTIONS_NONE, D2D1_DRAW_TEXT_OPTIONS_NONE,
+                D2D1_FACTORY_TYPE_MULTI_THREADED,
+            },
+            Direct3D11::{ID3D11Device, ID3D11Texture2D, D3D11_TEXTURE2D_DESC},
+            DirectWrite::{
+                DWriteCreateFactory, IDWriteFactory, IDWriteTextFormat, DWRITE_FACTORY_TYPE_SHARED,
+                DWRITE_FONT_STRETCH_NORMAL, DWRITE_FONT_STYLE_NORMAL, DWRITE_FONT_WEIGHT_NORMAL,
+                DWRITE_MEASURING_MODE_NATURAL,
+            },
+            Dxgi::{Common::DXGI_FORMAT_B8G8R8A8_UNORM, IDXGIDevice, IDXGISurface},
+            Imaging::{
+                CLSID_WICImagingFactory, GUID_WICPixelFormat32bppPBGRA, IWICImagingFactory,
+                WICBitmapDitherTypeNone, WICBitmapPaletteTypeMedianCut,
+                WICDecodeMetadataCacheOnDemand,
+            },
+        },
+        System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER},
+    },
+};
+
+use super::watermark::{WatermarkPosition, WatermarkSettings};
+
+// How far the watermark sits from the frame's edges, as a fraction of the
+// frame's shorter side.
+const MARGIN: f32 = 0.02;
+// The HUD's text height, as a fraction of the frame's height.
+const HUD_FONT_SIZE: f32 = 0.025;
+const HUD_MIN_FONT_SIZE: f32 = 12.0;
+// Roughly how wide a Consolas character is, relative to the font size.
+const HUD_CHARACTER_WIDTH: f32 = 0.55;
+const HUD_TEXT_COLOR: D2D1_COLOR_F = D2D1_COLOR_F {
+    r: 1.0,
+    g: 1.0,
+    b: 1.0,
+    a: 1.0,
+};
+const HUD_BACKGROUND_COLOR: D2D1_COLOR_F = D2D1_COLOR_F {
+    r: 0.0,
+    g: 0.0,
+    b: 0.0,
+    a: 0.6,
+};
+
+// Draws the watermark onto frames with Direct2D. The image is decoded and
+// uploaded once, and the target bitmap is only recreated when the frame's
+// texture changes, so each frame costs a single draw.
+pub struct WatermarkRenderer {
+    d2d_context: ID2D1DeviceContext,
+    bitmap: ID2D1Bitmap1,
+    bitmap_size: (f32, f32),
+    position: WatermarkPosition,
+    opacity: f32,
+    scale: Option<f32>,
+    target: Option<(ID3D11Texture2D, ID2D1Bitmap1)>,
+}
+
+impl WatermarkRenderer {
+    // The device needs BGRA support, see create_d3d_device.
+    pub fn new(d3d_device: &ID3D11Device, settings: &WatermarkSettings) -> Result<Self> {
+        let d2d_context = create_d2d_context(d3d_device)?;
+        let bitmap = load_bitmap(&d2d_context, &settings.path)?;
+        let size = unsafe { bitmap.GetPixelSize() };
+        Ok(Self {
+            d2d_context,
+            bitmap,
+            bitmap_size: (size.width as f32, size.height as f32),
+            position: settings.position,
+            opacity: settings.opacity.clamp(0.0, 1.0),
+            scale: settings.scale,
+            target: None,
+        })
+    }
+
+    // The texture has to be BGRA and bindable as a render target.
+    pub fn draw(&mut self, texture: &ID3D11Texture2D) -> Result<()> {
+        let target = match self.target.as_ref() {
+            Some((target_texture, target)) if target_texture == texture => target.clone(),
+            _ => {
+                let target = create_target(&self.d2d_context, texture)?;
+                self.target = Some((texture.clone(), target.clone()));
+                target
+            }
+        };
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { texture.GetDesc(&mut desc) };
+        let rect = self.watermark_rect(desc.Width as f32, desc.Height as f32);
+        unsafe {
+            self.d2d_context.SetTarget(&target);
+            self.d2d_context.BeginDraw();
+            self.d2d_context.DrawBitmap(
+                &self.bitmap,
+                Some(&rect),
+                self.opacity,
+                D2D1_BITMAP_INTERPOLATION_MODE_LINEAR,
+                None,
+            );
+            self.d2d_context.EndDraw(None, None)
+        }
+    }
+
+    fn watermark_rect(&self, frame_width: f32, frame_height: f32) -> D2D_RECT_F {
+        let (image_width, image_height) = self.bitmap_size;
+        let (width, height) = if let Some(scale) = self.scale {
+            let width = frame_width * scale;
+            (width, width * image_height / image_width)
+        } else {
+            (image_width, image_height)
+        };
+        let margin = frame_width.min(frame_height) * MARGIN;
+        let left = match self.position {
+            WatermarkPosition::TopLeft | WatermarkPosition::BottomLeft => margin,
+            WatermarkPosition::TopRight | WatermarkPosition::BottomRight => {
+                frame_width - margin - width
+            }
+        };
+        let top = match self.position {
+            WatermarkPosition::TopLeft | WatermarkPosition::TopRight => margin,
+            WatermarkPosition::BottomLeft | WatermarkPosition::BottomRight => {
+                frame_height - margin - height
+            }
+        };
+        D2D_RECT_F {
+            left,
+            top,
+            right: left + width,
+            bottom: top + height,
+        }
+    }
+}
+
+// Draws the HUD's line of text (see HudMeter) in the top left corner of
+// frames, on a translucent background so it stays readable.
+pub struct HudRenderer {
+    d2d_context: ID2D1DeviceContext,
+    dwrite_factory: IDWriteFactory,
+    text_brush: ID2D1SolidColorBrush,
+    background_brush: ID2D1SolidColorBrush,
+    // Recreated when the frame height changes, along with the font size.
+    text_format: Option<(u32, f32, IDWriteTextFormat)>,
+    target: Option<(ID3D11Texture2D, ID2D1Bitmap1)>,
+}
+
+impl HudRenderer {
+    // The device needs BGRA support, see create_d3d_device.
+    pub fn new(d3d_device: &ID3D11Device) -> Result<Self> {
+        let d2d_context = create_d2d_context(d3d_device)?;
+        let (text_brush, background_brush) = unsafe {
+            (
+                d2d_context.CreateSolidColorBrush(&HUD_TEXT_COLOR, None)?,
+                d2d_context.CreateSolidColorBrush(&HUD_BACKGROUND_COLOR, None)?,
+            )
+        };
+        Ok(Self {
+            d2d_context,
+            dwrite_factory: unsafe { DWriteCreateFactory(DWRITE_FACTORY_TYPE_SHARED)? },
+            text_brush,
+            background_brush,
+            text_format: None,
+            target: None,
+        })
+    }
+
+    // The texture has to be BGRA and bindable as a render target.
+    pub fn draw(&mut self, texture: &ID3D11Texture2D, text: &str) -> Result<()> {
+        let target = match self.target.as_ref() {
+            Some((target_texture, target)) if target_texture == texture => target.clone(),
+            _ => {
+                let target = create_target(&self.d2d_context, texture)?;
+                self.target = Some((texture.clone(), target.clone()));
+                target
+            }
+        };
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { texture.GetDesc(&mut desc) };
+        let (font_size, text_format) = match self.text_format.as_ref() {
+            Some((height, font_size, text_format)) if *height == desc.Height => {
+                (*font_size, text_format.clone())
+            }
+            _ => {
+                let font_size = (desc.Height as f32 * HUD_FONT_SIZE).max(HUD_MIN_FONT_SIZE);
+                let text_format = unsafe {
+                    self.dwrite_factory.CreateTextFormat(
+                        w!("Consolas"),
+                        None,
+                        DWRITE_FONT_WEIGHT_NORMAL,
+                        DWRITE_FONT_STYLE_NORMAL,
+                        DWRITE_FONT_STRETCH_NORMAL,
+                        font_size,
+                        w!("en-us"),
+                    )?
+                };
+                self.text_format = Some((desc.Height, font_size, text_format.clone()));
+                (font_size, text_format)
+            }
+        };
+
+        let text: Vec<u16> = text.encode_utf16().collect();
+        let margin = desc.Width.min(desc.Height) as f32 * MARGIN;
+        let padding = font_size * 0.25;
+        let text_rect = D2D_RECT_F {
+            left: margin + padding,
+            top: margin + padding,
+            right: margin + padding + text.len() as f32 * font_size * HUD_CHARACTER_WIDTH,
+            bottom: margin + padding + font_size * 1.2,
+        };
+        let background_rect = D2D_RECT_F {
+            left: margin,
+            top: margin,
+            right: text_rect.right + padding,
+            bottom: text_rect.bottom + padding,
+        };
+        unsafe {
+            self.d2d_context.SetTarget(&target);
+            self.d2d_context.BeginDraw();
+            self.d2d_context
+                .FillRectangle(&background_rect, &self.background_brush);
+            self.d2d_context.DrawText(
+                &text,
+                &text_format,
+                &text_rect,
+                &self.text_brush,
+                D2D1_DRAW_TEXT_OPTIONS_NONE,
+                DWRITE_MEASURING_MODE_NATURAL,
+            );
+            self.d2d_context.EndDraw(None, None)
+        }
+    }
+}
+
+fn create_d2d_context(d3d_device: &ID3D11Device) -> Result<ID2D1DeviceContext> {
+    unsafe {
+        // The renderers are created on one thread and draw on the encoder's
+        let factory: ID2D1Factory1 = D2D1CreateFactory(D2D1_FACTORY_TYPE_MULTI_THREADED, None)?;
+        let dxgi_device: IDXGIDevice = d3d_device.cast()?;
+        let d2d_device = factory.CreateDevice(&dxgi_device)?;
+        d2d_device.CreateDeviceContext(D2D1_DEVICE_CONTEXT_OPTIONS_NONE)
+    }
+}
+
+fn load_bitmap(d2d_context: &ID2D1DeviceContext, path: &Path) -> Result<ID2D1Bitmap1> {
+    unsafe {
+        let factory: IWICImagingFactory =
+            CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER)?;
+        let decoder = factory.CreateDecoderFromFilename(
+            &HSTRING::from(path.as_os_str()),
+            None,
+            GENERIC_READ,
+            WICDecodeMetadataCacheOnDemand,
+        )?;
+        let frame = decoder.GetFrame(0)?;
+        // Direct2D only draws premultiplied alpha
+        let converter = factory.CreateFormatConverter()?;
+        converter.Initialize(
+            &frame,
+            &GUID_WICPixelFormat32bppPBGRA,
+            WICBitmapDitherTypeNone,
+            None,
+            0.0,
+            WICBitmapPaletteTypeMedianCut,
+        )?;
+        d2d_context.CreateBitmapFromWicBitmap2(
+            &converter,
+            Some(&bitmap_properties(D2D1_BITMAP_OPTIONS_NONE)),
+        )
+    }
+}
+
+fn create_target(
+    d2d_context: &ID2D1DeviceContext,
+    texture: &ID3D11Texture2D,
+) -> Result<ID2D1Bitmap1> {
+    let surface: IDXGISurface = texture.cast()?;
+    unsafe {
+        d2d_context.CreateBitmapFromDxgiSurface(
+            &surface,
+            Some(&bitmap_properties(
+                D2D1_BITMAP_OPTIONS_TARGET | D2D1_BITMAP_OPTIONS_CANNOT_DRAW,
+            )),
+        )
+    }
+}
+
+// At 96 DPI, Direct2D's units are pixels.
+fn bitmap_properties(options: D2D1_BITMAP_OPTIONS) -> D2D1_BITMAP_PROPERTIES1 {
+    D2D1_BITMAP_PROPERTIES1 {
+        pixelFormat: D2D1_PIXEL_FORMAT {
+            format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            alphaMode: D2D1_ALPHA_MODE_PREMULTIPLIED,
+        },
+        dpiX: 96.0,
+        dpiY: 96.0,
+        bitmapOptions: options,
+        ..Default::default()
+    }
+}
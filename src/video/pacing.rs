@@ -0,0 +1,210 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use windows::{
+    core::{Error, Result},
+    Foundation::TimeSpan,
+    Win32::{
+        Foundation::E_INVALIDARG,
+        Graphics::{Dxgi::IDXGIOutput, Gdi::HMONITOR},
+    },
+};
+
+use crate::displays::{get_current_display_mode, get_display_output};
+
+// Drops frames that arrive sooner than the target frame rate allows. Kept
+// frames keep their original timestamps, we only ever pick which ones to
+// keep.
+pub struct FramePacer {
+    // In 100ns units, same as TimeSpan
+    interval: i64,
+    next_time: Option<i64>,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: u32) -> Self {
+        Self {
+            interval: 10_000_000 / frame_rate as i64,
+            next_time: None,
+        }
+    }
+
+    pub fn should_take(&mut self, time: TimeSpan) -> bool {
+        let time = time.Duration;
+        if let Some(next_time) = self.next_time {
+            // Leave some room for jitter, otherwise a source that runs at the
+            // target rate would have frames dropped whenever one is early.
+            if time + self.interval / 8 < next_time {
+                return false;
+            }
+            // Stay on the same grid unless we've fallen behind it.
+            let next_time = next_time + self.interval;
+            self.next_time = Some(if next_time <= time {
+                time + self.interval
+            } else {
+                next_time
+            });
+        } else {
+            self.next_time = Some(time + self.interval);
+        }
+        true
+    }
+}
+
+// Ticks once every few refreshes of a display, so that frames can be taken
+// on an even grid instead of whenever the capture delivers them. The
+// encoder's thread blocks in WaitForVBlank, so it wakes up on every refresh
+// (e.g. 144 times a second on a 144 Hz display) whether or not anything
+// changed, and every tick becomes an encoded frame. The clock can be moved to
+// another display while it runs, see VsyncRetargeter.
+pub struct VsyncClock {
+    output: IDXGIOutput,
+    refreshes_per_frame: u32,
+    // In 100ns units, same as TimeSpan
+    interval: i64,
+    frame_rate: u32,
+    next_display: Arc<Mutex<Option<HMONITOR>>>,
+}
+
+// Moves a VsyncClock over to another display, starting with its next tick.
+#[derive(Clone)]
+pub struct VsyncRetargeter {
+    next_display: Arc<Mutex<Option<HMONITOR>>>,
+}
+
+impl VsyncRetargeter {
+    pub fn retarget(&self, display_handle: HMONITOR) {
+        *self.next_display.lock().unwrap() = Some(display_handle);
+    }
+}
+
+impl VsyncClock {
+    // Takes every refresh when the frame rate is at or above the display's
+    // refresh rate, otherwise every nth one, whichever gets closest to it.
+    pub fn new(display_handle: HMONITOR, frame_rate: u32) -> Result<Self> {
+        let (output, refreshes_per_frame, interval) = pace_to_display(display_handle, frame_rate)?;
+        Ok(Self {
+            output,
+            refreshes_per_frame,
+            interval,
+            frame_rate,
+            next_display: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn retargeter(&self) -> VsyncRetargeter {
+        VsyncRetargeter {
+            next_display: self.next_display.clone(),
+        }
+    }
+
+    // How far apart the ticks are, in 100ns units.
+    pub fn interval(&self) -> i64 {
+        self.interval
+    }
+
+    // Blocks until the next tick.
+    pub fn wait(&mut self) -> Result<()> {
+        if let Some(display_handle) = self.next_display.lock().unwrap().take() {
+            match pace_to_display(display_handle, self.frame_rate) {
+                Ok((output, refreshes_per_frame, interval)) => {
+                    self.output = output;
+                    self.refreshes_per_frame = refreshes_per_frame;
+                    self.interval = interval;
+                }
+                // The old display still ticks, which keeps the recording going
+                Err(error) => warn!(
+                    "Unable to pace to the new display, staying with the old one: {}",
+                    error.message()
+                ),
+            }
+        }
+        for _ in 0..self.refreshes_per_frame {
+            unsafe { self.output.WaitForVBlank()? };
+        }
+        Ok(())
+    }
+}
+
+// The output to wait on, how many of its refreshes make a frame, and how far
+// apart that puts the frames.
+fn pace_to_display(display_handle: HMONITOR, frame_rate: u32) -> Result<(IDXGIOutput, u32, i64)> {
+    let output = get_display_output(display_handle)?.ok_or_else(|| {
+        Error::new(
+            E_INVALIDARG,
+            "The display isn't driven by any adapter, so it can't be used for vsync pacing!".into(),
+        )
+    })?;
+    let refresh_rate = get_current_display_mode(display_handle)?
+        .refresh_rate
+        .max(1);
+    let refreshes_per_frame = ((refresh_rate as f64 / frame_rate as f64).round() as u32).max(1);
+    let paced_frame_rate = refresh_rate as f64 / refreshes_per_frame as f64;
+    if (paced_frame_rate - frame_rate as f64).abs() >= 0.5 {
+        warn!(
+            "Pacing to {:.2} fps, the closest a {} Hz display gets to {} fps...",
+            paced_frame_rate, refresh_rate, frame_rate
+        );
+    }
+    Ok((
+        output,
+        refreshes_per_frame,
+        10_000_000 * refreshes_per_frame as i64 / refresh_rate as i64,
+    ))
+}
+
+// Ticks once every interval of wall clock time for a timelapse. Each frame
+// taken on a tick ends up one output frame after the previous one, however
+// long the interval is.
+pub struct TimelapseClock {
+    interval: Duration,
+    // In 100ns units, same as TimeSpan
+    frame_interval: i64,
+    next_tick: Option<Instant>,
+    frames_taken: i64,
+}
+
+impl TimelapseClock {
+    pub fn new(interval: Duration, frame_rate: u32) -> Self {
+        Self {
+            interval,
+            frame_interval: 10_000_000 / frame_rate as i64,
+            next_tick: None,
+            frames_taken: 0,
+        }
+    }
+
+    // None until the first tick, which happens whenever the first frame
+    // arrives.
+    pub fn next_tick(&self) -> Option<Instant> {
+        self.next_tick
+    }
+
+    // Schedules the next tick, staying on the same grid unless we've fallen
+    // behind it.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        self.next_tick = Some(match self.next_tick {
+            Some(next_tick) if next_tick + self.interval > now => next_tick + self.interval,
+            _ => now + self.interval,
+        });
+    }
+
+    // Counts a frame as taken on this tick. Returns the real time it stands
+    // for and its time in the output, both from the first frame.
+    pub fn take_frame(&mut self) -> (TimeSpan, TimeSpan) {
+        let index = self.frames_taken;
+        self.frames_taken += 1;
+        (
+            TimeSpan {
+                Duration: index * (self.interval.as_nanos() / 100) as i64,
+            },
+            TimeSpan {
+                Duration: index * self.frame_interval,
+            },
+        )
+    }
+}
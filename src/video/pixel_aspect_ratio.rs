@@ -0,0 +1,56 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PixelAspectRatio {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParsePixelAspectRatioError(&'static str);
+
+impl PixelAspectRatio {
+    pub const SQUARE: Self = Self {
+        numerator: 1,
+        denominator: 1,
+    };
+}
+
+impl Default for PixelAspectRatio {
+    fn default() -> Self {
+        Self::SQUARE
+    }
+}
+
+impl FromStr for PixelAspectRatio {
+    type Err = ParsePixelAspectRatioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: ParsePixelAspectRatioError = ParsePixelAspectRatioError(
+            "Invalid pixel aspect ratio value! Expecting: num:den (e.g. 1:1, 4:3), where both components are positive.",
+        );
+        let (numerator, denominator) = s.split_once(':').ok_or(ERROR)?;
+        let numerator: u32 = numerator.trim().parse().map_err(|_| ERROR)?;
+        let denominator: u32 = denominator.trim().parse().map_err(|_| ERROR)?;
+        if numerator == 0 || denominator == 0 {
+            return Err(ERROR);
+        }
+        Ok(Self {
+            numerator,
+            denominator,
+        })
+    }
+}
+
+impl Display for PixelAspectRatio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.numerator, self.denominator)
+    }
+}
+
+impl Display for ParsePixelAspectRatioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParsePixelAspectRatioError {}
@@ -0,0 +1,134 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, SyncSender},
+    thread::JoinHandle,
+};
+
+use windows::{
+    core::{Error, Result, HSTRING},
+    Foundation::TimeSpan,
+    Graphics::{DirectX::Direct3D11::IDirect3DSurface, SizeInt32},
+    Win32::{
+        Foundation::E_UNEXPECTED,
+        Graphics::Direct3D11::ID3D11Device,
+        System::Com::{CoInitializeEx, COINIT_MULTITHREADED},
+    },
+};
+
+use crate::image::save_bgra_png;
+
+use super::frame_sink::{surface_content_size, FrameReadback, FrameSink, READBACK_TEXTURES};
+
+// How many frames can be waiting to be encoded before the capture thread
+// has to wait on the writer (and the capture starts dropping frames).
+const MAX_PENDING_FRAMES: usize = 4;
+
+// Writes every frame to its own PNG, keeping the alpha channel. Frames are
+// named after the base path, "frames.png" becomes "frames-000001.png",
+// "frames-000002.png", and so on. PNG encoding is slow compared to a video
+// encoder, so expect dropped frames at high resolutions and frame rates.
+pub struct PngSequenceFrameSink {
+    readback: FrameReadback,
+    base_path: PathBuf,
+    frame_count: u64,
+    sender: Option<SyncSender<(PathBuf, Vec<u8>)>>,
+    writer_thread: Option<JoinHandle<Result<()>>>,
+}
+
+// The staging texture is only used from the capture thread
+unsafe impl Send for PngSequenceFrameSink {}
+
+impl PngSequenceFrameSink {
+    pub fn new<P: AsRef<Path>>(
+        d3d_device: &ID3D11Device,
+        size: SizeInt32,
+        base_path: P,
+    ) -> Result<Self> {
+        let readback = FrameReadback::new(d3d_device, size, READBACK_TEXTURES)?;
+        let (sender, receiver) = sync_channel::<(PathBuf, Vec<u8>)>(MAX_PENDING_FRAMES);
+        let (width, height) = (size.Width as u32, size.Height as u32);
+        let writer_thread = std::thread::Builder::new()
+            .name("PNG Writer Thread".to_owned())
+            .spawn(move || -> Result<()> {
+                unsafe { CoInitializeEx(None, COINIT_MULTITHREADED)? };
+                for (path, pixels) in receiver {
+                    save_bgra_png(&path, width, height, width * 4, &pixels)?;
+                }
+                Ok(())
+            })
+            .map_err(|_| {
+                Error::new(
+                    E_UNEXPECTED,
+                    HSTRING::from("Unable to create the PNG writer thread!"),
+                )
+            })?;
+        Ok(Self {
+            readback,
+            base_path: base_path.as_ref().to_owned(),
+            frame_count: 0,
+            sender: Some(sender),
+            writer_thread: Some(writer_thread),
+        })
+    }
+}
+
+impl PngSequenceFrameSink {
+    fn send_frame(&mut self, pixels: Vec<u8>) -> Result<()> {
+        self.frame_count += 1;
+        let path = png_sequence_path(&self.base_path, self.frame_count);
+        if let Some(sender) = self.sender.as_ref() {
+            // The writer only goes away if it failed, finalize reports why
+            if sender.send((path, pixels)).is_err() {
+                return self.finalize();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrameSink for PngSequenceFrameSink {
+    fn write_frame(
+        &mut self,
+        surface: &IDirect3DSurface,
+        size: SizeInt32,
+        timestamp: TimeSpan,
+    ) -> Result<bool> {
+        // Nothing to show while minimized
+        let Some(size) = surface_content_size(surface, size)? else {
+            return Ok(false);
+        };
+        if let Some((pixels, _)) = self.readback.submit(surface, size, timestamp)? {
+            let pixels = pixels.to_vec();
+            self.send_frame(pixels)?;
+        }
+        Ok(true)
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        // The last frame is still in flight
+        while let Some((pixels, _)) = self.readback.flush()? {
+            let pixels = pixels.to_vec();
+            self.send_frame(pixels)?;
+        }
+        self.sender.take();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            match writer_thread.join() {
+                Ok(result) => result,
+                Err(_) => Err(Error::new(
+                    E_UNEXPECTED,
+                    HSTRING::from("PNG writer thread failed unexpectedly!"),
+                )),
+            }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn png_sequence_path(base_path: &Path, index: u64) -> PathBuf {
+    let stem = base_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "frame".to_owned());
+    base_path.with_file_name(format!("{}-{:06}.png", stem, index))
+}
@@ -0,0 +1,57 @@
+use std::{fmt::Display, str::FromStr};
+
+// Trades encoding speed for quality on encoders that support it, through
+// CODECAPI_AVEncCommonQualityVsSpeed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Preset {
+    Quality,
+    Balanced,
+    Speed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParsePresetError(&'static str);
+
+impl Preset {
+    // 0 is the fastest and 100 the best quality.
+    pub fn quality_vs_speed(&self) -> u32 {
+        match self {
+            Preset::Quality => 100,
+            Preset::Balanced => 50,
+            Preset::Speed => 0,
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = ParsePresetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "quality" => Ok(Preset::Quality),
+            "balanced" => Ok(Preset::Balanced),
+            "speed" => Ok(Preset::Speed),
+            _ => Err(ParsePresetError(
+                "Invalid preset value! Expecting: quality, balanced, or speed.",
+            )),
+        }
+    }
+}
+
+impl Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Preset::Quality => "quality",
+            Preset::Balanced => "balanced",
+            Preset::Speed => "speed",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParsePresetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParsePresetError {}
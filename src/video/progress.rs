@@ -0,0 +1,126 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use windows::Foundation::TimeSpan;
+
+use super::encoding_session::VideoEncodingSettings;
+
+// Receives how far along a fixed-duration recording is, from 0.0 to 1.0.
+// Called from the encoder thread once per captured frame.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(f64) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F: 'static + Send + Sync + Fn(f64)>(callback: F) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub fn report(&self, progress: f64) {
+        (self.0)(progress)
+    }
+}
+
+impl Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressCallback").finish_non_exhaustive()
+    }
+}
+
+// Fires once the first frame has made it into the output, which can be
+// a while after the session was started.
+#[derive(Clone)]
+pub struct FirstFrameCallback(Arc<dyn Fn() + Send + Sync>);
+
+impl FirstFrameCallback {
+    pub fn new<F: 'static + Send + Sync + Fn()>(callback: F) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub fn notify(&self) {
+        (self.0)()
+    }
+}
+
+impl Debug for FirstFrameCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirstFrameCallback").finish_non_exhaustive()
+    }
+}
+
+// How much has been recorded so far, from the first frame's system relative
+// time to the latest one's. Cheap to clone and safe to read from any thread
+// while the encoder is running.
+#[derive(Clone, Default)]
+pub struct ElapsedTime {
+    frame_times: Arc<Mutex<Option<(TimeSpan, TimeSpan)>>>,
+}
+
+impl ElapsedTime {
+    pub fn get(&self) -> Duration {
+        if let Some((first_frame_time, last_frame_time)) = *self.frame_times.lock().unwrap() {
+            Duration::from(TimeSpan {
+                Duration: (last_frame_time.Duration - first_frame_time.Duration).max(0),
+            })
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    fn record(&self, frame_time: TimeSpan) {
+        let mut frame_times = self.frame_times.lock().unwrap();
+        let first_frame_time = frame_times.map_or(frame_time, |(first, _)| first);
+        *frame_times = Some((first_frame_time, frame_time));
+    }
+}
+
+// Measures the recording against the configured duration using the
+// frames' system relative time, so that time spent before the first
+// frame arrives doesn't count.
+pub struct DurationTracker {
+    duration: Option<Duration>,
+    progress_callback: Option<ProgressCallback>,
+    first_frame_time: Option<TimeSpan>,
+    elapsed_time: ElapsedTime,
+}
+
+impl DurationTracker {
+    pub fn new(settings: &VideoEncodingSettings) -> Self {
+        Self {
+            duration: settings.duration,
+            progress_callback: settings.progress_callback.clone(),
+            first_frame_time: None,
+            elapsed_time: ElapsedTime::default(),
+        }
+    }
+
+    pub fn elapsed_time(&self) -> ElapsedTime {
+        self.elapsed_time.clone()
+    }
+
+    // Returns false once the frame falls past the end of the recording.
+    pub fn update(&mut self, frame_time: TimeSpan) -> bool {
+        self.elapsed_time.record(frame_time);
+        let duration = if let Some(duration) = self.duration {
+            duration
+        } else {
+            return true;
+        };
+        let first_frame_time = *self.first_frame_time.get_or_insert(frame_time);
+        let elapsed = Duration::from(TimeSpan {
+            Duration: frame_time.Duration - first_frame_time.Duration,
+        });
+
+        let progress = if duration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / duration.as_secs_f64()).min(1.0)
+        };
+        if let Some(progress_callback) = self.progress_callback.as_ref() {
+            progress_callback.report(progress);
+        }
+        elapsed < duration
+    }
+}
@@ -0,0 +1,299 @@
+use windows::{
+    core::{Error, Result},
+    Foundation::TimeSpan,
+    Graphics::{Capture::GraphicsCaptureItem, DirectX::Direct3D11::IDirect3DSurface, SizeInt32},
+    Win32::{
+        Foundation::E_FAIL,
+        Graphics::{
+            Direct3D11::{
+                ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Texture2D,
+                D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX,
+                D3D11_CPU_ACCESS_READ, D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ,
+                D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING,
+            },
+            Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_NV12, DXGI_SAMPLE_DESC},
+        },
+        Media::MediaFoundation::IMFByteStream,
+    },
+};
+
+use crate::{capture::get_capture_item_size, d3d::get_d3d_interface_from_object};
+
+use super::{
+    encoding_session::{
+        require_capture_item, VideoEncoderSessionFactory, VideoEncodingSession,
+        VideoEncodingSettings,
+    },
+    frame_sink::{
+        surface_content_size, FrameReadback, FrameSink, FrameSinkSession, READBACK_TEXTURES,
+    },
+    input_color_space::InputColorSpace,
+    mf::processor::{TextureProcessor, VideoProcessor},
+    output_stream::OutputStream,
+    raw_format::RawFormat,
+    util::ensure_even_size,
+    CLEAR_COLOR,
+};
+
+const HEADER_MAGIC: [u8; 4] = *b"DRRV";
+const HEADER_VERSION: u32 = 1;
+
+// Writes uncompressed frames one after another, after a 20 byte header:
+//   magic "DRRV", version (u32), width (u32), height (u32), format fourcc
+// All integers are little endian. Frames are tightly packed, either BGRA8
+// rows or NV12 (a full size Y plane followed by a half height interleaved
+// UV plane). Every frame is the size of the capture item when the recording
+// started (rounded up to even for NV12); anything outside of the captured
+// content is black.
+pub struct RawFrameSink {
+    readback: RawReadback,
+    byte_stream: IMFByteStream,
+}
+
+enum RawReadback {
+    Bgra(FrameReadback),
+    Nv12(Nv12Readback),
+}
+
+// The byte stream and textures are only used from the capture thread
+unsafe impl Send for RawFrameSink {}
+
+impl RawFrameSink {
+    pub fn new(
+        d3d_device: &ID3D11Device,
+        size: SizeInt32,
+        format: RawFormat,
+        stream: OutputStream,
+    ) -> Result<Self> {
+        let (readback, size) = match format {
+            RawFormat::Bgra => (
+                RawReadback::Bgra(FrameReadback::new(d3d_device, size, READBACK_TEXTURES)?),
+                size,
+            ),
+            RawFormat::Nv12 => {
+                let size = ensure_even_size(size);
+                (
+                    RawReadback::Nv12(Nv12Readback::new(d3d_device, size)?),
+                    size,
+                )
+            }
+        };
+        let byte_stream = stream.to_byte_stream()?;
+
+        let mut header = Vec::with_capacity(20);
+        header.extend_from_slice(&HEADER_MAGIC);
+        header.extend_from_slice(&HEADER_VERSION.to_le_bytes());
+        header.extend_from_slice(&(size.Width as u32).to_le_bytes());
+        header.extend_from_slice(&(size.Height as u32).to_le_bytes());
+        header.extend_from_slice(&format.fourcc());
+        write_all(&byte_stream, &header)?;
+
+        Ok(Self {
+            readback,
+            byte_stream,
+        })
+    }
+}
+
+impl FrameSink for RawFrameSink {
+    fn write_frame(
+        &mut self,
+        surface: &IDirect3DSurface,
+        size: SizeInt32,
+        timestamp: TimeSpan,
+    ) -> Result<bool> {
+        // Nothing to show while minimized
+        let Some(size) = surface_content_size(surface, size)? else {
+            return Ok(false);
+        };
+        let pixels = match &mut self.readback {
+            RawReadback::Bgra(readback) => readback
+                .submit(surface, size, timestamp)?
+                .map(|(pixels, _)| pixels),
+            RawReadback::Nv12(readback) => Some(readback.read(surface, size)?),
+        };
+        if let Some(pixels) = pixels {
+            write_all(&self.byte_stream, pixels)?;
+        }
+        Ok(true)
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        if let RawReadback::Bgra(readback) = &mut self.readback {
+            // The last frame is still in flight
+            while let Some((pixels, _)) = readback.flush()? {
+                write_all(&self.byte_stream, pixels)?;
+            }
+        }
+        unsafe {
+            self.byte_stream.Flush()?;
+            self.byte_stream.Close()
+        }
+    }
+}
+
+// Converts frames to NV12 with the same video processor the mf backend
+// feeds its encoder from, then copies them back to the CPU.
+struct Nv12Readback {
+    d3d_context: ID3D11DeviceContext,
+    compose_texture: ID3D11Texture2D,
+    render_target_view: ID3D11RenderTargetView,
+    video_processor: VideoProcessor,
+    staging_texture: ID3D11Texture2D,
+    size: SizeInt32,
+    buffer: Vec<u8>,
+}
+
+impl Nv12Readback {
+    fn new(d3d_device: &ID3D11Device, size: SizeInt32) -> Result<Self> {
+        let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
+        let mut texture_desc = D3D11_TEXTURE2D_DESC {
+            Width: size.Width as u32,
+            Height: size.Height as u32,
+            ArraySize: 1,
+            MipLevels: 1,
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                ..Default::default()
+            },
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: (D3D11_BIND_RENDER_TARGET.0 | D3D11_BIND_SHADER_RESOURCE.0) as u32,
+            ..Default::default()
+        };
+        let compose_texture = unsafe {
+            let mut texture = None;
+            d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+            texture.unwrap()
+        };
+        let render_target_view = unsafe {
+            let mut rtv = None;
+            d3d_device.CreateRenderTargetView(&compose_texture, None, Some(&mut rtv))?;
+            rtv.unwrap()
+        };
+        let video_processor = VideoProcessor::new(
+            d3d_device.clone(),
+            DXGI_FORMAT_B8G8R8A8_UNORM,
+            InputColorSpace::Srgb,
+            size,
+            DXGI_FORMAT_NV12,
+            size,
+            true,
+        )?;
+
+        texture_desc.Format = DXGI_FORMAT_NV12;
+        texture_desc.Usage = D3D11_USAGE_STAGING;
+        texture_desc.BindFlags = 0;
+        texture_desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ.0 as u32;
+        let staging_texture = unsafe {
+            let mut texture = None;
+            d3d_device.CreateTexture2D(&texture_desc, None, Some(&mut texture))?;
+            texture.unwrap()
+        };
+
+        Ok(Self {
+            d3d_context,
+            compose_texture,
+            render_target_view,
+            video_processor,
+            staging_texture,
+            size,
+            buffer: vec![0u8; size.Width as usize * size.Height as usize * 3 / 2],
+        })
+    }
+
+    // Blocks until the GPU is done with the conversion.
+    fn read(&mut self, surface: &IDirect3DSurface, content_size: SizeInt32) -> Result<&[u8]> {
+        let texture: ID3D11Texture2D = get_d3d_interface_from_object(surface)?;
+        let region = D3D11_BOX {
+            left: 0,
+            top: 0,
+            front: 0,
+            right: content_size.Width.min(self.size.Width) as u32,
+            bottom: content_size.Height.min(self.size.Height) as u32,
+            back: 1,
+        };
+        unsafe {
+            self.d3d_context
+                .ClearRenderTargetView(&self.render_target_view, &CLEAR_COLOR);
+            self.d3d_context.CopySubresourceRegion(
+                &self.compose_texture,
+                0,
+                0,
+                0,
+                0,
+                &texture,
+                0,
+                Some(&region),
+            );
+        }
+        self.video_processor
+            .process_texture(&self.compose_texture)?;
+
+        let width = self.size.Width as usize;
+        let height = self.size.Height as usize;
+        unsafe {
+            self.d3d_context
+                .CopyResource(&self.staging_texture, self.video_processor.output_texture());
+            let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+            self.d3d_context.Map(
+                &self.staging_texture,
+                0,
+                D3D11_MAP_READ,
+                0,
+                Some(&mut mapped),
+            )?;
+            // The UV plane follows the Y plane, using the same pitch, for
+            // half as many rows again
+            let data = mapped.pData as *const u8;
+            let pitch = mapped.RowPitch as usize;
+            let rows = height + height / 2;
+            for (y, row) in self.buffer.chunks_exact_mut(width).take(rows).enumerate() {
+                let source = data.add(y * pitch);
+                std::ptr::copy_nonoverlapping(source, row.as_mut_ptr(), width);
+            }
+            self.d3d_context.Unmap(&self.staging_texture, 0);
+        }
+        Ok(&self.buffer)
+    }
+}
+
+// Byte streams can write less than they were given, which would shift every
+// frame after it.
+fn write_all(byte_stream: &IMFByteStream, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        let written = unsafe { byte_stream.Write(data)? } as usize;
+        if written == 0 {
+            return Err(Error::new(
+                E_FAIL,
+                "The output stopped accepting data!".into(),
+            ));
+        }
+        data = &data[written.min(data.len())..];
+    }
+    Ok(())
+}
+
+pub struct RawVideoEncodingSessionFactory {}
+
+impl RawVideoEncodingSessionFactory {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl VideoEncoderSessionFactory for RawVideoEncodingSessionFactory {
+    fn create_session(
+        &self,
+        d3d_device: ID3D11Device,
+        item: Option<GraphicsCaptureItem>,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
+    ) -> Result<Box<dyn VideoEncodingSession>> {
+        let item = require_capture_item(item)?;
+        let size = get_capture_item_size(&item)?;
+        let sink = RawFrameSink::new(&d3d_device, size, settings.raw_format, stream)?;
+        let session = FrameSinkSession::new(d3d_device, item, settings, sink)?;
+        Ok(Box::new(session))
+    }
+}
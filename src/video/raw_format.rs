@@ -0,0 +1,51 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RawFormat {
+    Bgra,
+    Nv12,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseRawFormatError(&'static str);
+
+impl RawFormat {
+    // How the format is identified in the raw file's header.
+    pub fn fourcc(&self) -> [u8; 4] {
+        match self {
+            RawFormat::Bgra => *b"BGRA",
+            RawFormat::Nv12 => *b"NV12",
+        }
+    }
+}
+
+impl FromStr for RawFormat {
+    type Err = ParseRawFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bgra" => Ok(RawFormat::Bgra),
+            "nv12" => Ok(RawFormat::Nv12),
+            _ => Err(ParseRawFormatError(
+                "Invalid raw format value! Expecting: bgra, or nv12.",
+            )),
+        }
+    }
+}
+
+impl Display for RawFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            RawFormat::Bgra => "bgra",
+            RawFormat::Nv12 => "nv12",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseRawFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseRawFormatError {}
@@ -0,0 +1,44 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scaler {
+    Auto,
+    Bilinear,
+    HighQuality,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseScalerError(&'static str);
+
+impl FromStr for Scaler {
+    type Err = ParseScalerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Scaler::Auto),
+            "bilinear" => Ok(Scaler::Bilinear),
+            "high-quality" => Ok(Scaler::HighQuality),
+            _ => Err(ParseScalerError(
+                "Invalid scaler value! Expecting: auto, bilinear, or high-quality.",
+            )),
+        }
+    }
+}
+
+impl Display for Scaler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            Scaler::Auto => "auto",
+            Scaler::Bilinear => "bilinear",
+            Scaler::HighQuality => "high-quality",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseScalerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseScalerError {}
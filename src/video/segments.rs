@@ -0,0 +1,384 @@
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::warn;
+use windows::core::Result;
+
+use super::output_stream::OutputStream;
+
+// Splits a recording into a series of files that each play on their own,
+// starting a new one on the first keyframe past every interval.
+#[derive(Clone, Debug)]
+pub struct SegmentSettings {
+    pub duration: Duration,
+    pub opener: SegmentOpener,
+    // The number of the first segment, 1 unless resuming a recording (see
+    // resume_segment_index).
+    pub first_index: u32,
+}
+
+// Opens the output for a segment, numbered from first_index. Called from the
+// thread writing the output at each rollover (never the encoder's, see
+// SEGMENT_WRITE_QUEUE_LENGTH), after the previous segment has been finalized.
+#[derive(Clone)]
+pub struct SegmentOpener(Arc<dyn Fn(u32) -> Result<OutputStream> + Send + Sync>);
+
+impl SegmentOpener {
+    pub fn new<F: 'static + Send + Sync + Fn(u32) -> Result<OutputStream>>(opener: F) -> Self {
+        Self(Arc::new(opener))
+    }
+
+    pub fn open(&self, index: u32) -> Result<OutputStream> {
+        (self.0)(index)
+    }
+}
+
+impl Debug for SegmentOpener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SegmentOpener").finish_non_exhaustive()
+    }
+}
+
+// Decides where a recording is split: on the first keyframe at or past each
+// boundary, one segment length apart from the first sample. Every segment
+// after the first is moved back to start where the first one did. Times are
+// in 100ns units.
+#[derive(Clone, Debug)]
+pub struct SegmentClock {
+    length: i64,
+    first_sample_time: Option<i64>,
+    next_boundary: i64,
+    time_offset: i64,
+}
+
+impl SegmentClock {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            length: ((duration.as_nanos() / 100) as i64).max(1),
+            first_sample_time: None,
+            next_boundary: 0,
+            time_offset: 0,
+        }
+    }
+
+    // Returns true if the sample starts a new segment. Either way it goes
+    // into the segment at its time less the time_offset.
+    pub fn place(&mut self, time: i64, is_keyframe: bool) -> bool {
+        let first_sample_time = match self.first_sample_time {
+            Some(first_sample_time) => first_sample_time,
+            None => {
+                self.first_sample_time = Some(time);
+                self.next_boundary = time + self.length;
+                time
+            }
+        };
+        if time < self.next_boundary || !is_keyframe {
+            return false;
+        }
+        self.time_offset = time - first_sample_time;
+        // A late keyframe can skip boundaries, the next one still lines up
+        // with the first sample.
+        while self.next_boundary <= time {
+            self.next_boundary += self.length;
+        }
+        true
+    }
+
+    // How far the current segment is moved back.
+    pub fn time_offset(&self) -> i64 {
+        self.time_offset
+    }
+}
+
+// Segments are named after the output with their number appended, e.g.
+// recording.mp4 gets recording-001.mp4, recording-002.mp4, and so on.
+pub fn segment_path(output_path: &Path, index: u32) -> PathBuf {
+    let stem = output_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "recording".to_owned());
+    let file_name = if let Some(extension) = output_path.extension() {
+        format!("{}-{:03}.{}", stem, index, extension.to_string_lossy())
+    } else {
+        format!("{}-{:03}", stem, index)
+    };
+    output_path.with_file_name(file_name)
+}
+
+// Where a resumed recording picks up: after the last segment of the output
+// that's already on disk, or at 1 if there aren't any. A last segment that
+// was never finalized (a plain MP4 without its moov box, as left behind by a
+// crash or power loss) can't be played, so it gets replaced instead.
+pub fn resume_segment_index(output_path: &Path) -> u32 {
+    let directory = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return 1;
+    };
+    let last_index = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| parse_segment_index(output_path, &entry.file_name().to_string_lossy()))
+        .max();
+    match last_index {
+        Some(index) if has_moov_box(&segment_path(output_path, index)) => index + 1,
+        Some(index) => {
+            warn!(
+                "\"{}\" was never finalized and can't be played, replacing it...",
+                segment_path(output_path, index).display()
+            );
+            index
+        }
+        None => 1,
+    }
+}
+
+// The reverse of segment_path.
+fn parse_segment_index(output_path: &Path, file_name: &str) -> Option<u32> {
+    let stem = output_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "recording".to_owned());
+    let rest = file_name.strip_prefix(&stem)?.strip_prefix('-')?;
+    let digits = if let Some(extension) = output_path.extension() {
+        rest.strip_suffix(extension.to_string_lossy().as_ref())?
+            .strip_suffix('.')?
+    } else {
+        rest
+    };
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Walks the file's top level boxes. The sink writer only writes the moov box
+// once it's finalized, unless the file is fragmented, where it comes first
+// and every fragment that made it to disk is playable.
+fn has_moov_box(path: &Path) -> bool {
+    let Ok(mut file) = File::open(path) else {
+        return false;
+    };
+    contains_moov_box(&mut file)
+}
+
+fn contains_moov_box<R: Read + Seek>(file: &mut R) -> bool {
+    loop {
+        let mut header = [0u8; 8];
+        if file.read_exact(&mut header).is_err() {
+            return false;
+        }
+        if &header[4..] == b"moov" {
+            return true;
+        }
+        let mut header_length = 8;
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The box runs to the end of the file
+            0 => return false,
+            1 => {
+                let mut large_size = [0u8; 8];
+                if file.read_exact(&mut large_size).is_err() {
+                    return false;
+                }
+                header_length = 16;
+                u64::from_be_bytes(large_size)
+            }
+            size => size as u64,
+        };
+        if size < header_length
+            || file
+                .seek(SeekFrom::Current((size - header_length) as i64))
+                .is_err()
+        {
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use super::{contains_moov_box, parse_segment_index, segment_path, SegmentClock};
+
+    // 10 seconds, in 100ns units
+    const LENGTH: i64 = 100_000_000;
+
+    #[test]
+    fn segment_path_numbering() {
+        assert_eq!(
+            segment_path(Path::new("recording.mp4"), 1),
+            PathBuf::from("recording-001.mp4")
+        );
+        assert_eq!(
+            segment_path(Path::new("videos/capture.mp4"), 42),
+            PathBuf::from("videos/capture-042.mp4")
+        );
+        assert_eq!(
+            segment_path(Path::new("capture"), 7),
+            PathBuf::from("capture-007")
+        );
+        assert_eq!(
+            segment_path(Path::new("capture.mp4"), 1234),
+            PathBuf::from("capture-1234.mp4")
+        );
+        assert_eq!(
+            segment_path(Path::new("my.capture.mp4"), 2),
+            PathBuf::from("my.capture-002.mp4")
+        );
+    }
+
+    #[test]
+    fn segment_index_parsing() {
+        let output_path = Path::new("videos/capture.mp4");
+        assert_eq!(parse_segment_index(output_path, "capture-001.mp4"), Some(1));
+        assert_eq!(
+            parse_segment_index(output_path, "capture-042.mp4"),
+            Some(42)
+        );
+        assert_eq!(
+            parse_segment_index(output_path, "capture-1234.mp4"),
+            Some(1234)
+        );
+        assert_eq!(
+            parse_segment_index(Path::new("capture"), "capture-007"),
+            Some(7)
+        );
+        assert_eq!(
+            parse_segment_index(Path::new("my.capture.mp4"), "my.capture-002.mp4"),
+            Some(2)
+        );
+        // The output itself and files that only look like segments
+        assert_eq!(parse_segment_index(output_path, "capture.mp4"), None);
+        assert_eq!(parse_segment_index(output_path, "capture-.mp4"), None);
+        assert_eq!(parse_segment_index(output_path, "capture-01a.mp4"), None);
+        assert_eq!(parse_segment_index(output_path, "capture-+1.mp4"), None);
+        assert_eq!(parse_segment_index(output_path, "capture-001.mkv"), None);
+        assert_eq!(parse_segment_index(output_path, "capture-001mp4"), None);
+        assert_eq!(parse_segment_index(output_path, "capture001.mp4"), None);
+        assert_eq!(parse_segment_index(output_path, "other-001.mp4"), None);
+        assert_eq!(
+            parse_segment_index(output_path, "capture-99999999999.mp4"),
+            None
+        );
+    }
+
+    fn mp4_box(name: &[u8; 4], payload_length: usize) -> Vec<u8> {
+        let mut data = ((payload_length + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(name);
+        data.resize(payload_length + 8, 0);
+        data
+    }
+
+    fn has_moov(data: Vec<u8>) -> bool {
+        contains_moov_box(&mut Cursor::new(data))
+    }
+
+    #[test]
+    fn moov_box_detection() {
+        // Finalized
+        let mut data = mp4_box(b"ftyp", 16);
+        data.extend(mp4_box(b"mdat", 100));
+        data.extend(mp4_box(b"moov", 20));
+        assert!(has_moov(data));
+        // Fragmented, moov comes first
+        let mut data = mp4_box(b"ftyp", 16);
+        data.extend(mp4_box(b"moov", 20));
+        data.extend(mp4_box(b"moof", 12));
+        assert!(has_moov(data));
+        // A 64 bit size
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&32u64.to_be_bytes());
+        data.resize(32, 0);
+        data.extend(mp4_box(b"moov", 0));
+        assert!(has_moov(data));
+    }
+
+    #[test]
+    fn moov_box_missing() {
+        assert!(!has_moov(Vec::new()));
+        // Never finalized
+        let mut data = mp4_box(b"ftyp", 16);
+        data.extend(mp4_box(b"mdat", 100));
+        assert!(!has_moov(data));
+        // Cut off in the middle of a header
+        let mut data = mp4_box(b"ftyp", 16);
+        data.extend_from_slice(&[0, 0, 0]);
+        assert!(!has_moov(data));
+        // Cut off in the middle of a 64 bit size
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        assert!(!has_moov(data));
+        // A box that runs to the end of the file
+        let mut data = 0u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"mdat");
+        data.extend(mp4_box(b"moov", 0));
+        assert!(!has_moov(data));
+        // A size smaller than its own header
+        let mut data = 4u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"mdat");
+        data.extend(mp4_box(b"moov", 0));
+        assert!(!has_moov(data));
+        // A box that claims to be longer than the file
+        let mut data = mp4_box(b"ftyp", 16);
+        data.extend_from_slice(&1000u32.to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend(mp4_box(b"moov", 0));
+        assert!(!has_moov(data));
+    }
+
+    #[test]
+    fn segment_clock_first_segment() {
+        let mut clock = SegmentClock::new(Duration::from_secs(10));
+        assert!(!clock.place(5_000, true));
+        assert!(!clock.place(5_000 + LENGTH - 1, true));
+        assert_eq!(clock.time_offset(), 0);
+    }
+
+    #[test]
+    fn segment_clock_waits_for_keyframe() {
+        let mut clock = SegmentClock::new(Duration::from_secs(10));
+        assert!(!clock.place(5_000, true));
+        assert!(!clock.place(5_000 + LENGTH, false));
+        assert!(!clock.place(5_000 + LENGTH + 300, false));
+        assert!(clock.place(5_000 + LENGTH + 600, true));
+        assert_eq!(clock.time_offset(), LENGTH + 600);
+        // Samples after the keyframe stay in its segment
+        assert!(!clock.place(5_000 + LENGTH + 900, true));
+        assert_eq!(clock.time_offset(), LENGTH + 600);
+    }
+
+    #[test]
+    fn segment_clock_boundaries_follow_first_sample() {
+        let mut clock = SegmentClock::new(Duration::from_secs(10));
+        assert!(!clock.place(5_000, true));
+        assert!(clock.place(5_000 + LENGTH + 600, true));
+        // The next boundary doesn't drift with the late keyframe
+        assert!(!clock.place(5_000 + 2 * LENGTH - 1, true));
+        assert!(clock.place(5_000 + 2 * LENGTH, true));
+        assert_eq!(clock.time_offset(), 2 * LENGTH);
+    }
+
+    #[test]
+    fn segment_clock_skips_missed_boundaries() {
+        let mut clock = SegmentClock::new(Duration::from_secs(10));
+        assert!(!clock.place(0, true));
+        assert!(clock.place(3 * LENGTH + 10, true));
+        assert_eq!(clock.time_offset(), 3 * LENGTH + 10);
+        assert!(!clock.place(4 * LENGTH - 1, true));
+        assert!(clock.place(4 * LENGTH, true));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    mem::size_of,
+    sync::atomic::{fence, AtomicI64, AtomicU64, Ordering},
+};
+
+use windows::{
+    core::{Error, Result, HSTRING},
+    Foundation::TimeSpan,
+    Graphics::{DirectX::Direct3D11::IDirect3DSurface, SizeInt32},
+    Win32::{
+        Foundation::{
+            CloseHandle, ERROR_ALREADY_EXISTS, E_INVALIDARG, HANDLE, INVALID_HANDLE_VALUE,
+        },
+        Graphics::{Direct3D11::ID3D11Device, Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM},
+        System::Memory::{
+            CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS,
+            MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+        },
+    },
+};
+
+use super::frame_sink::{surface_content_size, FrameReadback, FrameSink};
+
+// "DRFM" when read as bytes.
+pub const SHARED_MEMORY_MAGIC: u32 = u32::from_le_bytes(*b"DRFM");
+pub const SHARED_MEMORY_VERSION: u32 = 1;
+// How many frames the section holds. Readers that fall further behind than
+// this lose frames.
+pub const SHARED_MEMORY_SLOTS: usize = 3;
+
+// The start of the section. Everything is little endian, and the section is
+// laid out like this struct (repr(C), 88 bytes). The frames themselves start
+// at data_offset, one after the other, stride * height bytes each.
+//
+// To publish a frame the writer:
+// 1. Sets the slot's frame_index to u64::MAX.
+// 2. Copies the pixels into the slot, then sets its timestamp.
+// 3. Sets the slot's frame_index, and then frames_written, to the frame's
+//    index (release ordering).
+//
+// The newest frame is in slot (frames_written - 1) % slot_count. To read it,
+// load frames_written, check the slot's frame_index matches, copy the pixels
+// and then check frame_index again. If it changed, the writer got to the slot
+// in the meantime and the copy has to be thrown away.
+#[repr(C)]
+pub struct SharedMemoryHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    // Bytes from one row to the next, always width * 4.
+    pub stride: u32,
+    // A DXGI_FORMAT, always DXGI_FORMAT_B8G8R8A8_UNORM (87).
+    pub format: u32,
+    pub slot_count: u32,
+    pub data_offset: u32,
+    // Frames are counted from 1, so 0 means nothing has been written yet.
+    pub frames_written: AtomicU64,
+    pub slots: [SharedMemorySlot; SHARED_MEMORY_SLOTS],
+}
+
+#[repr(C)]
+pub struct SharedMemorySlot {
+    // u64::MAX while the frame is being written.
+    pub frame_index: AtomicU64,
+    // In 100ns units, relative to the first frame.
+    pub timestamp: AtomicI64,
+}
+
+// Publishes frames as BGRA8 into a named shared memory section (see
+// SharedMemoryHeader), for another process on the same machine to read
+// without encoding and decoding them. The frames are the capture's size and
+// the section goes away once every process has closed it.
+pub struct SharedMemoryFrameSink {
+    readback: FrameReadback,
+    section: SharedMemorySection,
+}
+
+struct SharedMemorySection {
+    mapping: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    data_offset: usize,
+    frame_size: usize,
+    frames_written: u64,
+}
+
+// The staging textures are only used from the capture thread
+unsafe impl Send for SharedMemoryFrameSink {}
+
+impl SharedMemoryFrameSink {
+    pub fn new(d3d_device: &ID3D11Device, size: SizeInt32, name: &str) -> Result<Self> {
+        Ok(Self {
+            // Readers want the newest frame. Pipelining would hold each one
+            // back until the next arrives, which it never does on a screen
+            // that doesn't change.
+            readback: FrameReadback::new(d3d_device, size, 1)?,
+            section: SharedMemorySection::new(size, name)?,
+        })
+    }
+}
+
+impl SharedMemorySection {
+    fn new(size: SizeInt32, name: &str) -> Result<Self> {
+        let stride = size.Width as usize * 4;
+        let frame_size = stride * size.Height as usize;
+        // Keeps the frames cache line aligned
+        let data_offset = (size_of::<SharedMemoryHeader>() + 63) & !63;
+        let section_size = (data_offset + frame_size * SHARED_MEMORY_SLOTS) as u64;
+
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                (section_size >> 32) as u32,
+                section_size as u32,
+                &HSTRING::from(name),
+            )?
+        };
+        // We'd get the existing section back, whatever its size
+        if Error::from_win32().code() == ERROR_ALREADY_EXISTS.to_hresult() {
+            unsafe {
+                let _ = CloseHandle(mapping);
+            }
+            return Err(Error::new(
+                E_INVALIDARG,
+                "A shared memory section with that name already exists!".into(),
+            ));
+        }
+        let view = unsafe { MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, 0) };
+        if view.Value.is_null() {
+            let error = Error::from_win32();
+            unsafe {
+                let _ = CloseHandle(mapping);
+            }
+            return Err(error);
+        }
+
+        unsafe {
+            (view.Value as *mut SharedMemoryHeader).write(SharedMemoryHeader {
+                magic: SHARED_MEMORY_MAGIC,
+                version: SHARED_MEMORY_VERSION,
+                width: size.Width as u32,
+                height: size.Height as u32,
+                stride: stride as u32,
+                format: DXGI_FORMAT_B8G8R8A8_UNORM.0,
+                slot_count: SHARED_MEMORY_SLOTS as u32,
+                data_offset: data_offset as u32,
+                frames_written: AtomicU64::new(0),
+                slots: std::array::from_fn(|_| SharedMemorySlot {
+                    frame_index: AtomicU64::new(0),
+                    timestamp: AtomicI64::new(0),
+                }),
+            });
+        }
+        Ok(Self {
+            mapping,
+            view,
+            data_offset,
+            frame_size,
+            frames_written: 0,
+        })
+    }
+
+    fn publish(&mut self, pixels: &[u8], timestamp: TimeSpan) {
+        let frame_index = self.frames_written + 1;
+        let slot_index = (self.frames_written % SHARED_MEMORY_SLOTS as u64) as usize;
+        let slot_offset = self.data_offset + slot_index * self.frame_size;
+        let view = self.view.Value as *mut u8;
+
+        let header = unsafe { &*(view as *const SharedMemoryHeader) };
+        let slot = &header.slots[slot_index];
+        slot.frame_index.store(u64::MAX, Ordering::Relaxed);
+        // Readers have to see the slot as busy before any of the new pixels
+        fence(Ordering::Release);
+        unsafe {
+            std::ptr::copy_nonoverlapping(pixels.as_ptr(), view.add(slot_offset), self.frame_size);
+        }
+        slot.timestamp.store(timestamp.Duration, Ordering::Relaxed);
+        slot.frame_index.store(frame_index, Ordering::Release);
+        header.frames_written.store(frame_index, Ordering::Release);
+        self.frames_written = frame_index;
+    }
+}
+
+impl FrameSink for SharedMemoryFrameSink {
+    fn write_frame(
+        &mut self,
+        surface: &IDirect3DSurface,
+        size: SizeInt32,
+        timestamp: TimeSpan,
+    ) -> Result<bool> {
+        // Nothing to show while minimized
+        let Some(size) = surface_content_size(surface, size)? else {
+            return Ok(false);
+        };
+        if let Some((pixels, timestamp)) = self.readback.submit(surface, size, timestamp)? {
+            self.section.publish(pixels, timestamp);
+        }
+        Ok(true)
+    }
+
+    // Readers can keep reading the last frames until they close the section.
+    fn finalize(&mut self) -> Result<()> {
+        while let Some((pixels, timestamp)) = self.readback.flush()? {
+            self.section.publish(pixels, timestamp);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemorySection {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = UnmapViewOfFile(self.view);
+            let _ = CloseHandle(self.mapping);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use log::{error, info, warn};
+use windows::{
+    core::Result,
+    Graphics::SizeInt32,
+    Win32::{
+        Graphics::{
+            Direct3D11::{ID3D11Device, ID3D11Texture2D, D3D11_TEXTURE2D_DESC},
+            Dxgi::Common::DXGI_FORMAT_B8G8R8A8_UNORM,
+        },
+        System::Com::{CoInitializeEx, COINIT_MULTITHREADED},
+    },
+};
+
+use crate::{d3d::StagingReadback, image::save_bgra_png};
+
+// Lets the session ask for a snapshot from any thread. The request gets
+// picked up with the next captured frame.
+#[derive(Clone, Default)]
+pub struct SnapshotRequester {
+    pending_path: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl SnapshotRequester {
+    pub fn request(&self, path: PathBuf) {
+        *self.pending_path.lock().unwrap() = Some(path);
+    }
+}
+
+struct PendingReadback {
+    path: PathBuf,
+    staging: StagingReadback,
+}
+
+// Takes snapshots of captured frames without stalling the encode loop. The
+// frame is copied to a staging texture, which is only read once the GPU is
+// done with it (checked on later frames), and the PNG is written on its own
+// thread.
+pub struct SnapshotTaker {
+    d3d_device: ID3D11Device,
+    requester: SnapshotRequester,
+    pending_readback: Option<PendingReadback>,
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl SnapshotTaker {
+    pub fn new(d3d_device: ID3D11Device, requester: SnapshotRequester) -> Result<Self> {
+        Ok(Self {
+            d3d_device,
+            requester,
+            pending_readback: None,
+            writers: Vec::new(),
+        })
+    }
+
+    pub fn on_frame(&mut self, texture: &ID3D11Texture2D) -> Result<()> {
+        if let Some(pending_readback) = self.pending_readback.take() {
+            if let Some(pending_readback) = self.try_complete(pending_readback, false)? {
+                // Still drawing, one snapshot at a time
+                self.pending_readback = Some(pending_readback);
+                return Ok(());
+            }
+        }
+        self.start_readback(texture)
+    }
+
+    // Called once the recording stops, so that no snapshot is lost. Waits
+    // for the readback in flight, takes a request that hasn't been picked up
+    // yet from the last frame (if there was one), and waits for the PNGs to
+    // be written.
+    pub fn finish(&mut self, last_frame: Option<&ID3D11Texture2D>) -> Result<()> {
+        if let Some(pending_readback) = self.pending_readback.take() {
+            self.try_complete(pending_readback, true)?;
+        }
+        if let Some(texture) = last_frame {
+            self.start_readback(texture)?;
+            if let Some(pending_readback) = self.pending_readback.take() {
+                self.try_complete(pending_readback, true)?;
+            }
+        } else if let Some(path) = self.requester.pending_path.lock().unwrap().take() {
+            warn!(
+                "No frames were captured, skipping snapshot {}...",
+                path.display()
+            );
+        }
+        for writer in self.writers.drain(..) {
+            let _ = writer.join();
+        }
+        Ok(())
+    }
+
+    fn start_readback(&mut self, texture: &ID3D11Texture2D) -> Result<()> {
+        let path = if let Some(path) = self.requester.pending_path.lock().unwrap().take() {
+            path
+        } else {
+            return Ok(());
+        };
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        unsafe { texture.GetDesc(&mut desc) };
+        // The PNG encoder only takes BGRA8
+        if desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM {
+            warn!(
+                "Snapshots are only supported when capturing at 8 bits, skipping {}...",
+                path.display()
+            );
+            return Ok(());
+        }
+        let size = SizeInt32 {
+            Width: desc.Width as i32,
+            Height: desc.Height as i32,
+        };
+        let mut staging = StagingReadback::new(&self.d3d_device, size, 1)?;
+        staging.copy(texture, None)?;
+        self.pending_readback = Some(PendingReadback { path, staging });
+        Ok(())
+    }
+
+    // Hands the readback back if the GPU isn't done with the copy yet,
+    // unless we're told to wait for it.
+    fn try_complete(
+        &mut self,
+        mut pending_readback: PendingReadback,
+        wait: bool,
+    ) -> Result<Option<PendingReadback>> {
+        if !wait && !pending_readback.staging.is_ready()? {
+            return Ok(Some(pending_readback));
+        }
+        let size = pending_readback.staging.size();
+        let (width, height) = (size.Width as u32, size.Height as u32);
+        let row_size = width as usize * 4;
+        let mut pixels = vec![0u8; row_size * height as usize];
+        // Only ever one copy in flight
+        let frame = pending_readback.staging.read()?.unwrap();
+        for (y, row) in pixels.chunks_exact_mut(row_size).enumerate() {
+            row.copy_from_slice(frame.row(y));
+        }
+        drop(frame);
+
+        let path = pending_readback.path;
+        self.writers.retain(|writer| !writer.is_finished());
+        self.writers.push(std::thread::spawn(move || {
+            let result = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }
+                .and_then(|_| save_bgra_png(&path, width, height, width * 4, &pixels));
+            match result {
+                Ok(_) => info!("Saved snapshot to \"{}\".", path.display()),
+                Err(error) => error!(
+                    "Error while saving snapshot: {:?} - {}",
+                    error.code(),
+                    error.message()
+                ),
+            }
+        }));
+        Ok(None)
+    }
+}
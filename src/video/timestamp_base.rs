@@ -0,0 +1,41 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimestampBase {
+    Zero,
+    Qpc,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseTimestampBaseError(&'static str);
+
+impl FromStr for TimestampBase {
+    type Err = ParseTimestampBaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zero" => Ok(TimestampBase::Zero),
+            "qpc" => Ok(TimestampBase::Qpc),
+            _ => Err(ParseTimestampBaseError(
+                "Invalid timestamp base value! Expecting: zero, or qpc.",
+            )),
+        }
+    }
+}
+
+impl Display for TimestampBase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            TimestampBase::Zero => "zero",
+            TimestampBase::Qpc => "qpc",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseTimestampBaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseTimestampBaseError {}
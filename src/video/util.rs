@@ -1,4 +1,7 @@
-use windows::Graphics::SizeInt32;
+use windows::{
+    Graphics::{RectInt32, SizeInt32},
+    Win32::Graphics::Direct3D11::{ID3D11Texture2D, D3D11_BOX, D3D11_TEXTURE2D_DESC},
+};
 
 pub fn ensure_even(value: i32) -> i32 {
     if value % 2 == 0 {
@@ -14,3 +17,78 @@ pub fn ensure_even_size(size: SizeInt32) -> SizeInt32 {
         Height: ensure_even(size.Height),
     }
 }
+
+// The part of a captured frame worth copying into the destination. Frame
+// pool textures can be larger than what's in them (e.g. a window that got
+// smaller), and copying all of it would bring along whatever was left in
+// the padding. It's also clamped to both textures, since the content can
+// outgrow the frame pool and a copy that doesn't fit is dropped by D3D.
+// Returns None when there's nothing to copy, e.g. for a minimized window.
+pub fn content_region(
+    content_size: SizeInt32,
+    source: &ID3D11Texture2D,
+    destination: &ID3D11Texture2D,
+) -> Option<D3D11_BOX> {
+    let (source_desc, destination_desc) = unsafe {
+        let mut source_desc = D3D11_TEXTURE2D_DESC::default();
+        source.GetDesc(&mut source_desc);
+        let mut destination_desc = D3D11_TEXTURE2D_DESC::default();
+        destination.GetDesc(&mut destination_desc);
+        (source_desc, destination_desc)
+    };
+    let width = content_size
+        .Width
+        .clamp(0, source_desc.Width.min(destination_desc.Width) as i32) as u32;
+    let height = content_size
+        .Height
+        .clamp(0, source_desc.Height.min(destination_desc.Height) as i32) as u32;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some(D3D11_BOX {
+        left: 0,
+        right: width,
+        top: 0,
+        bottom: height,
+        back: 1,
+        front: 0,
+    })
+}
+
+// Like content_region, but only the part of the content inside the crop,
+// which is in the capture's coordinates.
+pub fn crop_region(
+    crop: RectInt32,
+    content_size: SizeInt32,
+    source: &ID3D11Texture2D,
+    destination: &ID3D11Texture2D,
+) -> Option<D3D11_BOX> {
+    let (source_desc, destination_desc) = unsafe {
+        let mut source_desc = D3D11_TEXTURE2D_DESC::default();
+        source.GetDesc(&mut source_desc);
+        let mut destination_desc = D3D11_TEXTURE2D_DESC::default();
+        destination.GetDesc(&mut destination_desc);
+        (source_desc, destination_desc)
+    };
+    let left = crop.X.max(0);
+    let top = crop.Y.max(0);
+    let right = (crop.X + crop.Width)
+        .min(content_size.Width)
+        .min(source_desc.Width as i32)
+        .min(left + destination_desc.Width as i32);
+    let bottom = (crop.Y + crop.Height)
+        .min(content_size.Height)
+        .min(source_desc.Height as i32)
+        .min(top + destination_desc.Height as i32);
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(D3D11_BOX {
+        left: left as u32,
+        right: right as u32,
+        top: top as u32,
+        bottom: bottom as u32,
+        back: 1,
+        front: 0,
+    })
+}
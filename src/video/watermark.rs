@@ -0,0 +1,60 @@
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseWatermarkPositionError(&'static str);
+
+impl FromStr for WatermarkPosition {
+    type Err = ParseWatermarkPositionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tl" => Ok(WatermarkPosition::TopLeft),
+            "tr" => Ok(WatermarkPosition::TopRight),
+            "bl" => Ok(WatermarkPosition::BottomLeft),
+            "br" => Ok(WatermarkPosition::BottomRight),
+            _ => Err(ParseWatermarkPositionError(
+                "Invalid watermark position value! Expecting: tl, tr, bl, or br.",
+            )),
+        }
+    }
+}
+
+impl Display for WatermarkPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            WatermarkPosition::TopLeft => "tl",
+            WatermarkPosition::TopRight => "tr",
+            WatermarkPosition::BottomLeft => "bl",
+            WatermarkPosition::BottomRight => "br",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Display for ParseWatermarkPositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ParseWatermarkPositionError {}
+
+// An image drawn over every frame, e.g. a logo in the corner. Any format
+// WIC can decode works, transparency is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatermarkSettings {
+    pub path: PathBuf,
+    pub position: WatermarkPosition,
+    // From 0.0 (invisible) to 1.0, on top of the image's own alpha.
+    pub opacity: f32,
+    // The watermark's width as a fraction of the frame's width. None keeps
+    // the image's own size in capture pixels.
+    pub scale: Option<f32>,
+}
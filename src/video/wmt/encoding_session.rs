@@ -1,12 +1,10 @@
 use std::{thread::JoinHandle, time::Duration};
 
+use log::error;
 use windows::{
     core::{h, Result, HSTRING},
     Foundation::{TimeSpan, TypedEventHandler},
-    Graphics::{
-        Capture::{GraphicsCaptureItem, GraphicsCaptureSession},
-        SizeInt32,
-    },
+    Graphics::Capture::{GraphicsCaptureItem, GraphicsCaptureSession},
     Media::{
         Core::{
             MediaStreamSample, MediaStreamSource, MediaStreamSourceSampleRequest,
@@ -20,14 +18,20 @@ use windows::{
     Win32::{
         Foundation::E_UNEXPECTED,
         Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D},
+        Media::MediaFoundation::MFVideoFormat_H264,
     },
 };
 
 use crate::{
-    capture::CaptureFrameGeneratorStopSignal,
+    capture::{get_capture_item_size, CaptureFrameGeneratorStopSignal},
     d3d::create_direct3d_surface,
     video::{
-        encoding_session::{VideoEncoderSessionFactory, VideoEncodingSession},
+        encoding_session::{
+            require_capture_item, VideoEncoderSessionFactory, VideoEncodingSession,
+            VideoEncodingSettings,
+        },
+        output_stream::OutputStream,
+        progress::ElapsedTime,
         util::ensure_even_size,
     },
 };
@@ -46,15 +50,13 @@ impl VideoEncoderSessionFactory for WMTVideoEncodingSessionFactory {
     fn create_session(
         &self,
         d3d_device: ID3D11Device,
-        item: GraphicsCaptureItem,
-        borderless: bool,
-        resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
-        stream: IRandomAccessStream,
+        item: Option<GraphicsCaptureItem>,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
     ) -> Result<Box<dyn VideoEncodingSession>> {
+        let item = require_capture_item(item)?;
         Ok(Box::new(WMTVideoEncodingSession::new(
-            d3d_device, item, borderless, resolution, bit_rate, frame_rate, stream,
+            d3d_device, item, settings, stream,
         )?))
     }
 }
@@ -68,21 +70,21 @@ struct WMTVideoEncodingSession {
     capture_session: GraphicsCaptureSession,
     encoder_thread: Option<JoinHandle<Result<()>>>,
     stop_signal: CaptureFrameGeneratorStopSignal,
+    elapsed_time: ElapsedTime,
 }
 
 impl WMTVideoEncodingSession {
     pub fn new(
         d3d_device: ID3D11Device,
         item: GraphicsCaptureItem,
-        borderless: bool,
-        resolution: SizeInt32,
-        bit_rate: u32,
-        frame_rate: u32,
-        stream: IRandomAccessStream,
+        settings: &VideoEncodingSettings,
+        stream: OutputStream,
     ) -> Result<Self> {
-        let item_size = item.Size()?;
+        // The transcoder only takes WinRT streams
+        let stream = stream.to_random_access_stream()?;
+        let item_size = get_capture_item_size(&item)?;
         let input_size = ensure_even_size(item_size);
-        let output_size = ensure_even_size(resolution);
+        let output_size = ensure_even_size(settings.output_size(item_size));
 
         // Describe our output: H264 video with an MP4 container
         let encoding_profile = {
@@ -92,11 +94,19 @@ impl WMTVideoEncodingSession {
             video.SetSubtype(h!("H264"))?;
             video.SetWidth(output_size.Width as u32)?;
             video.SetHeight(output_size.Height as u32)?;
-            video.SetBitrate(bit_rate)?;
-            video.FrameRate()?.SetNumerator(frame_rate)?;
+            video.SetBitrate(settings.bit_rate.resolve(
+                output_size,
+                settings.frame_rate,
+                &MFVideoFormat_H264,
+            ))?;
+            video.FrameRate()?.SetNumerator(settings.frame_rate)?;
             video.FrameRate()?.SetDenominator(1)?;
-            video.PixelAspectRatio()?.SetNumerator(1)?;
-            video.PixelAspectRatio()?.SetDenominator(1)?;
+            video
+                .PixelAspectRatio()?
+                .SetNumerator(settings.pixel_aspect_ratio.numerator)?;
+            video
+                .PixelAspectRatio()?
+                .SetDenominator(settings.pixel_aspect_ratio.denominator)?;
             profile.SetVideo(&video)?;
             profile
         };
@@ -109,11 +119,18 @@ impl WMTVideoEncodingSession {
         )?;
         let video_descriptor = VideoStreamDescriptor::Create(&properties)?;
 
-        let mut sample_generator = SampleGenerator::new(d3d_device, item, input_size, output_size)?;
+        let mut sample_generator =
+            SampleGenerator::new(d3d_device, item, input_size, output_size, settings)?;
         let stop_signal = sample_generator.stop_signal();
+        let elapsed_time = sample_generator.elapsed_time();
+        settings.cancellation_token.on_cancelled({
+            let stop_signal = sample_generator.stop_signal();
+            move || stop_signal.signal()
+        });
         let mut first_timestamp: Option<TimeSpan> = None;
-        let capture_session = sample_generator.capture_session().clone();
-        if borderless {
+        let mut first_frame_callback = settings.first_frame_callback.clone();
+        let capture_session = sample_generator.capture_session();
+        if settings.borderless {
             capture_session.SetIsBorderRequired(false)?;
         }
 
@@ -150,6 +167,9 @@ impl WMTVideoEncodingSession {
                     let sample =
                         MediaStreamSample::CreateFromDirect3D11Surface(&surface, timestamp)?;
                     request.SetSample(&sample)?;
+                    if let Some(first_frame_callback) = first_frame_callback.take() {
+                        first_frame_callback.notify();
+                    }
                 } else {
                     request.SetSample(None)?;
                 }
@@ -157,7 +177,7 @@ impl WMTVideoEncodingSession {
             };
             let result = handler(&request, &mut sample_generator);
             if result.is_err() {
-                println!("Error during sample generation: {:?}", result);
+                error!("Error during sample generation: {:?}", result);
                 request.SetSample(None)?;
             }
             Ok(())
@@ -175,6 +195,7 @@ impl WMTVideoEncodingSession {
             capture_session,
             encoder_thread: None,
             stop_signal,
+            elapsed_time,
         })
     }
 }
@@ -261,6 +282,10 @@ impl VideoEncodingSession for WMTVideoEncodingSession {
             Ok(())
         }
     }
+
+    fn elapsed_time(&self) -> ElapsedTime {
+        self.elapsed_time.clone()
+    }
 }
 
 pub struct VideoEncoderInputSample {
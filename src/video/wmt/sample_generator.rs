@@ -1,14 +1,15 @@
+use log::error;
 use windows::{
     core::Result,
     Foundation::TimeSpan,
     Graphics::{
-        Capture::{Direct3D11CaptureFrame, GraphicsCaptureItem, GraphicsCaptureSession},
+        Capture::{GraphicsCaptureItem, GraphicsCaptureSession},
         SizeInt32,
     },
     Win32::Graphics::{
         Direct3D11::{
             ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Texture2D,
-            D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX, D3D11_TEXTURE2D_DESC,
+            D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_TEXTURE2D_DESC,
             D3D11_USAGE_DEFAULT,
         },
         Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC},
@@ -16,9 +17,16 @@ use windows::{
 };
 
 use crate::{
-    capture::{CaptureFrameGenerator, CaptureFrameGeneratorStopSignal},
-    d3d::get_d3d_interface_from_object,
-    video::CLEAR_COLOR,
+    cancellation::{CancellationToken, StopReason},
+    capture::{
+        CaptureFrame, CaptureFrameGenerator, CaptureFrameGeneratorStopSignal, DEFAULT_BUFFER_COUNT,
+    },
+    video::{
+        encoding_session::VideoEncodingSettings,
+        progress::{DurationTracker, ElapsedTime},
+        util::content_region,
+        CLEAR_COLOR,
+    },
 };
 
 use super::encoding_session::VideoEncoderInputSample;
@@ -32,6 +40,8 @@ pub struct SampleGenerator {
 
     frame_generator: CaptureFrameGenerator,
 
+    cancellation_token: CancellationToken,
+    duration_tracker: DurationTracker,
     seen_first_time_stamp: bool,
     first_timestamp: TimeSpan,
 }
@@ -43,6 +53,7 @@ impl SampleGenerator {
         item: GraphicsCaptureItem,
         input_size: SizeInt32,
         output_size: SizeInt32,
+        settings: &VideoEncodingSettings,
     ) -> Result<Self> {
         let d3d_context = unsafe { d3d_device.GetImmediateContext()? };
 
@@ -71,7 +82,13 @@ impl SampleGenerator {
             rtv.unwrap()
         };
 
-        let frame_generator = CaptureFrameGenerator::new(d3d_device.clone(), item, input_size)?;
+        let frame_generator = CaptureFrameGenerator::with_frame_pool_mode(
+            d3d_device.clone(),
+            item,
+            input_size,
+            DEFAULT_BUFFER_COUNT,
+            settings.frame_pool_mode,
+        )?;
 
         Ok(Self {
             d3d_device,
@@ -82,49 +99,70 @@ impl SampleGenerator {
 
             frame_generator,
 
+            cancellation_token: settings.cancellation_token.clone(),
+            duration_tracker: DurationTracker::new(settings),
             seen_first_time_stamp: false,
             first_timestamp: TimeSpan::default(),
         })
     }
 
-    pub fn capture_session(&self) -> &GraphicsCaptureSession {
-        self.frame_generator.session()
+    pub fn capture_session(&self) -> GraphicsCaptureSession {
+        // Only duplication has no session
+        self.frame_generator.session().unwrap()
     }
 
     pub fn generate(&mut self) -> Result<Option<VideoEncoderInputSample>> {
-        if let Some(frame) = self.frame_generator.try_get_next_frame()? {
+        while let Some(frame) = self.frame_generator.try_get_next_frame()? {
+            // Once we've reached the requested duration, end the recording
+            // as if it had been cancelled.
+            if !self.duration_tracker.update(frame.system_relative_time()) {
+                self.cancellation_token
+                    .cancel_with_reason(StopReason::MaxDurationReached);
+                break;
+            }
             let result = self.generate_from_frame(&frame);
             match result {
-                Ok(sample) => Ok(Some(sample)),
+                Ok(Some(sample)) => return Ok(Some(sample)),
+                // The frame was skipped, wait for the next one
+                Ok(None) => {}
                 Err(error) => {
-                    eprintln!(
+                    error!(
                         "Error during input sample generation: {:?} - {}",
                         error.code(),
                         error.message()
                     );
+                    self.cancellation_token
+                        .cancel_with_reason(StopReason::Error);
                     self.stop_capture()?;
-                    Ok(None)
+                    return Ok(None);
                 }
             }
-        } else {
-            self.stop_capture()?;
-            Ok(None)
         }
+        if self.frame_generator.source_closed() {
+            self.cancellation_token
+                .cancel_with_reason(StopReason::SourceClosed);
+        }
+        self.stop_capture()?;
+        Ok(None)
     }
 
     pub fn stop_signal(&self) -> CaptureFrameGeneratorStopSignal {
         self.frame_generator.stop_signal()
     }
 
+    pub fn elapsed_time(&self) -> ElapsedTime {
+        self.duration_tracker.elapsed_time()
+    }
+
     fn stop_capture(&mut self) -> Result<()> {
         self.frame_generator.stop_capture()
     }
 
     fn generate_from_frame(
         &mut self,
-        frame: &Direct3D11CaptureFrame,
-    ) -> Result<VideoEncoderInputSample> {
-        let frame_time = frame.SystemRelativeTime()?;
+        frame: &CaptureFrame,
+    ) -> Result<Option<VideoEncoderInputSample>> {
+        let frame_time = frame.system_relative_time();
 
         if !self.seen_first_time_stamp {
             self.first_timestamp = frame_time;
@@ -134,28 +172,19 @@ impl SampleGenerator {
         let timestamp = TimeSpan {
             Duration: frame_time.Duration - self.first_timestamp.Duration,
         };
-        let content_size = frame.ContentSize()?;
-        let frame_texture: ID3D11Texture2D = get_d3d_interface_from_object(&frame.Surface()?)?;
-        let desc = unsafe {
-            let mut desc = D3D11_TEXTURE2D_DESC::default();
-            frame_texture.GetDesc(&mut desc);
-            desc
-        };
-
-        // In order to support window resizing, we need to only copy out the part of
-        // the buffer that contains the window. If the window is smaller than the buffer,
-        // then it's a straight forward copy using the ContentSize. If the window is larger,
-        // we need to clamp to the size of the buffer. For simplicity, we always clamp.
-        let width = content_size.Width.clamp(0, desc.Width as i32) as u32;
-        let height = content_size.Height.clamp(0, desc.Height as i32) as u32;
-
-        let region = D3D11_BOX {
-            left: 0,
-            right: width,
-            top: 0,
-            bottom: height,
-            back: 1,
-            front: 0,
+        let content_size = frame.content_size();
+        let frame_texture = frame.texture();
+
+        // Only the part of the frame with content is copied, see content_region.
+        // A minimized window has nothing to show, so hold off on encoding
+        // until it's restored.
+        let region = if let Some(region) =
+            content_region(content_size, frame_texture, &self.compose_texture)
+        {
+            region
+        } else {
+            frame.close()?;
+            return Ok(None);
         };
 
         unsafe {
@@ -167,7 +196,7 @@ impl SampleGenerator {
                 0,
                 0,
                 0,
-                &frame_texture,
+                frame_texture,
                 0,
                 Some(&region),
             );
@@ -188,9 +217,12 @@ impl SampleGenerator {
                 .CopyResource(&sample_texture, &self.compose_texture);
 
             // Release the frame back to the frame pool
-            frame.Close()?;
+            frame.close()?;
 
-            Ok(VideoEncoderInputSample::new(timestamp, sample_texture))
+            Ok(Some(VideoEncoderInputSample::new(
+                timestamp,
+                sample_texture,
+            )))
         }
     }
 }
@@ -0,0 +1,52 @@
+use windows::Win32::{
+    Foundation::{BOOL, HWND, LPARAM},
+    UI::WindowsAndMessaging::{EnumWindows, GetWindowTextW, IsWindow, IsWindowVisible},
+};
+
+// The first visible top-level window with this title, ignoring case.
+pub fn find_window_by_title(title: &str) -> Option<HWND> {
+    let mut state = (title.to_lowercase(), None);
+    unsafe {
+        // Stopping the enumeration early is reported as a failure
+        let _ = EnumWindows(
+            Some(enum_window),
+            LPARAM(&mut state as *mut (String, Option<HWND>) as isize),
+        );
+    }
+    state.1
+}
+
+// Accepts handles in decimal or as 0x prefixed hex, as Spy++ shows them.
+pub fn parse_window_handle(value: &str) -> Option<HWND> {
+    let handle = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        isize::from_str_radix(hex, 16).ok()?
+    } else {
+        value.parse().ok()?
+    };
+    let window = HWND(handle);
+    if unsafe { IsWindow(window).as_bool() } {
+        Some(window)
+    } else {
+        None
+    }
+}
+
+extern "system" fn enum_window(window: HWND, state: LPARAM) -> BOOL {
+    // Only called during EnumWindows, while the state is alive
+    let (title, found) = unsafe { &mut *(state.0 as *mut (String, Option<HWND>)) };
+    unsafe {
+        if !IsWindowVisible(window).as_bool() {
+            return true.into();
+        }
+        let mut text = [0u16; 512];
+        let length = GetWindowTextW(window, &mut text);
+        if String::from_utf16_lossy(&text[..length as usize]).to_lowercase() == *title {
+            *found = Some(window);
+            return false.into();
+        }
+    }
+    true.into()
+}